@@ -0,0 +1,450 @@
+use crate::commands::{parse_rate, RunningEngine};
+use crate::tranasction::config::{
+    ConfigChange, DuplicatePolicy, LockPolicy, RateLimit, RateLimitPolicy,
+};
+use clap::ValueEnum;
+use smol_str::SmolStr;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+//the requests of the engine are applied one at a time between two transactions
+const REQUEST_CHANNEL_SIZE: usize = 16;
+//a request line and a few headers, a larger request is not from an admin client
+const MAX_REQUEST_SIZE: u64 = 8192;
+const DEFAULT_RATE_BURST: u32 = 10;
+
+//Handle to change the level of the logs of the running process
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+#[derive(clap::Args)]
+pub struct AdminArgs {
+    /// serve the http admin api on this address, e.g. 127.0.0.1:7879, to change the log level, the rate limit and
+    /// the lock and duplicate policies, to save a snapshot or flush the changed accounts, and to take or roll back to
+    /// a checkpoint while running. The api has no authentication, so it should only listen on a private interface
+    #[arg(long)]
+    admin_listen: Option<String>,
+}
+
+//Change requested by an admin request, the log level is changed by the connection and the others by the task that
+//owns the engine
+#[derive(Debug, PartialEq)]
+enum AdminCommand {
+    LogLevel(LevelFilter),
+    Engine(EngineCommand),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum EngineCommand {
+    Reconfigure(ConfigChange),
+    //save the state of the engine to the --snapshot file
+    Snapshot,
+    //write the accounts changed since the last flush in incremental mode
+    Flush,
+    //save the state of the engine in memory under the name, or roll it back to the state saved under the name
+    Checkpoint(SmolStr),
+    Rollback(SmolStr),
+}
+
+//Status and one line of text sent back to the admin client
+#[derive(Debug, PartialEq)]
+pub(crate) struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: 200,
+            body: body.into(),
+        }
+    }
+
+    fn error(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: body.into(),
+        }
+    }
+
+    fn engine_stopped() -> Self {
+        Self::error(503, "transaction engine has stopped")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+//Command of the engine sent by a connection, the response is sent back once it is applied
+pub(crate) struct EngineRequest {
+    command: EngineCommand,
+    reply: oneshot::Sender<Response>,
+}
+
+//Http admin api of the long-running commands, so that a setting can be changed without a restart that forces a
+//replay of the feed. The connections are accepted in their own task and the requests of the engine are applied by
+//the task that owns it, between two transactions:
+//PUT /log-level?level=debug
+//PUT /rate-limit?rate=50[&burst=10][&policy=defer] and DELETE /rate-limit
+//PUT /lock-policy?policy=allow-deposits
+//PUT /duplicate-policy?policy=idempotent-skip
+//POST /snapshot and POST /flush
+//POST /checkpoint?name=segment-5 and POST /rollback?name=segment-5
+pub struct AdminApi {
+    requests: mpsc::Receiver<EngineRequest>,
+    snapshot: Option<String>,
+    listener: JoinHandle<()>,
+}
+
+impl AdminApi {
+    //None without --admin-listen
+    pub async fn bind(
+        args: &AdminArgs,
+        log_level: LogLevelHandle,
+        snapshot: Option<&str>,
+    ) -> io::Result<Option<Self>> {
+        let Some(address) = &args.admin_listen else {
+            return Ok(None);
+        };
+        let listener = TcpListener::bind(address).await?;
+        let (tx, rx) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+        Ok(Some(Self {
+            requests: rx,
+            snapshot: snapshot.map(str::to_string),
+            listener: tokio::spawn(accept(listener, tx, log_level)),
+        }))
+    }
+
+    //apply the requests until the task is aborted
+    pub(crate) async fn run(mut self, mut engine: impl RunningEngine) {
+        while let Some(request) = self.requests.recv().await {
+            self.apply(request, &mut engine).await;
+        }
+    }
+
+    //the next request of the engine, never returns without an admin api
+    #[cfg(feature = "redis")]
+    pub(crate) async fn recv(admin: &mut Option<Self>) -> EngineRequest {
+        match admin {
+            Some(admin) => match admin.requests.recv().await {
+                Some(request) => request,
+                None => std::future::pending().await,
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    pub(crate) async fn apply(&self, request: EngineRequest, engine: &mut impl RunningEngine) {
+        let response = match request.command {
+            EngineCommand::Reconfigure(change) => {
+                if engine.reconfigure(change).await {
+                    Response::ok(format!("{change:?}"))
+                } else {
+                    Response::engine_stopped()
+                }
+            }
+            EngineCommand::Flush => {
+                if engine.flush().await {
+                    Response::ok("flushed")
+                } else {
+                    Response::engine_stopped()
+                }
+            }
+            EngineCommand::Snapshot => self.save_snapshot(engine).await,
+            EngineCommand::Checkpoint(name) => match engine.checkpoint(&name).await {
+                Some(Ok(())) => Response::ok(format!("saved checkpoint {name}")),
+                Some(Err(e)) => Response::error(400, e),
+                None => Response::engine_stopped(),
+            },
+            EngineCommand::Rollback(name) => match engine.rollback(&name).await {
+                Some(Ok(())) => Response::ok(format!("rolled back to {name}")),
+                Some(Err(e)) => Response::error(400, e),
+                None => Response::engine_stopped(),
+            },
+        };
+        //the client may have closed the connection
+        let _ = request.reply.send(response);
+    }
+
+    async fn save_snapshot(&self, engine: &mut impl RunningEngine) -> Response {
+        let Some(path) = &self.snapshot else {
+            return Response::error(400, "the snapshot request requires --snapshot");
+        };
+        let Some(snapshot) = engine.snapshot().await else {
+            return Response::engine_stopped();
+        };
+        match snapshot.save(path) {
+            Ok(()) => Response::ok(format!("saved to {path}")),
+            Err(e) => {
+                tracing::error!("Fail to save snapshot to {path}: {e}");
+                Response::error(500, format!("failed to save snapshot: {e}"))
+            }
+        }
+    }
+}
+
+impl Drop for AdminApi {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, tx: mpsc::Sender<EngineRequest>, log_level: LogLevelHandle) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(
+                    stream,
+                    peer.to_string(),
+                    tx.clone(),
+                    log_level.clone(),
+                ));
+            }
+            Err(e) => tracing::error!("Failed to accept admin connection: {e}"),
+        }
+    }
+}
+
+//one request per connection, the connection is closed once the response is written
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: String,
+    tx: mpsc::Sender<EngineRequest>,
+    log_level: LogLevelHandle,
+) {
+    let response = match read_request(&mut stream).await {
+        Ok((method, target)) => {
+            tracing::info!(peer, method, target, "Admin request");
+            match route(&method, &target) {
+                Ok(AdminCommand::LogLevel(level)) => match log_level
+                    .modify(|filter| *filter = level)
+                {
+                    Ok(()) => Response::ok(format!("log level {level}")),
+                    Err(e) => Response::error(500, format!("failed to change the log level: {e}")),
+                },
+                Ok(AdminCommand::Engine(command)) => {
+                    let (reply, rx) = oneshot::channel();
+                    match tx.send(EngineRequest { command, reply }).await {
+                        Ok(()) => rx.await.unwrap_or_else(|_| Response::engine_stopped()),
+                        Err(_) => Response::engine_stopped(),
+                    }
+                }
+                Err(response) => response,
+            }
+        }
+        Err(e) => Response::error(400, e),
+    };
+    if response.status != 200 {
+        tracing::error!(
+            peer,
+            status = response.status,
+            "Admin request failed: {}",
+            response.body
+        );
+    }
+    let http = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        response.status,
+        response.reason(),
+        response.body.len() + 1,
+        response.body
+    );
+    if let Err(e) = stream.write_all(http.as_bytes()).await {
+        tracing::error!(peer, "Failed to write admin response: {e}");
+    }
+}
+
+//the method and the target of the request line, the headers are read but not used since the parameters are in the
+//query string
+async fn read_request(stream: &mut TcpStream) -> Result<(String, String), String> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        match reader.read_line(&mut header).await {
+            Ok(0) => return Err("incomplete request".to_string()),
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => Ok((method.to_string(), target.to_string())),
+        _ => Err(format!("invalid request line {:?}", request_line.trim())),
+    }
+}
+
+fn route(method: &str, target: &str) -> Result<AdminCommand, Response> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let command = match (method, path) {
+        ("PUT", "/log-level") => {
+            let level = required(query, "level")?;
+            AdminCommand::LogLevel(
+                level
+                    .parse()
+                    .map_err(|_| bad_request(format!("invalid log level {level}")))?,
+            )
+        }
+        ("PUT", "/rate-limit") => {
+            let rate = parse_rate(required(query, "rate")?).map_err(bad_request)?;
+            let burst = match param(query, "burst") {
+                Some(burst) => burst
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|burst| *burst > 0)
+                    .ok_or_else(|| bad_request(format!("invalid burst {burst}")))?,
+                None => DEFAULT_RATE_BURST,
+            };
+            let policy = match param(query, "policy") {
+                Some(policy) => parse_enum::<RateLimitPolicy>(policy)?,
+                None => RateLimitPolicy::default(),
+            };
+            let limit = RateLimit {
+                rate,
+                burst,
+                policy,
+            };
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::RateLimit(Some(
+                limit,
+            ))))
+        }
+        ("DELETE", "/rate-limit") => {
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::RateLimit(None)))
+        }
+        ("PUT", "/lock-policy") => {
+            let policy = parse_enum::<LockPolicy>(required(query, "policy")?)?;
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::LockPolicy(policy)))
+        }
+        ("PUT", "/duplicate-policy") => {
+            let policy = parse_enum::<DuplicatePolicy>(required(query, "policy")?)?;
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::DuplicatePolicy(
+                policy,
+            )))
+        }
+        ("POST", "/snapshot") => AdminCommand::Engine(EngineCommand::Snapshot),
+        ("POST", "/flush") => AdminCommand::Engine(EngineCommand::Flush),
+        ("POST", "/checkpoint") => {
+            AdminCommand::Engine(EngineCommand::Checkpoint(required(query, "name")?.into()))
+        }
+        ("POST", "/rollback") => {
+            AdminCommand::Engine(EngineCommand::Rollback(required(query, "name")?.into()))
+        }
+        (
+            _,
+            "/log-level" | "/rate-limit" | "/lock-policy" | "/duplicate-policy" | "/snapshot"
+            | "/flush" | "/checkpoint" | "/rollback",
+        ) => {
+            return Err(Response::error(
+                405,
+                format!("{method} is not allowed on {path}"),
+            ))
+        }
+        _ => return Err(Response::error(404, format!("unknown path {path}"))),
+    };
+    Ok(command)
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn required<'a>(query: &'a str, name: &str) -> Result<&'a str, Response> {
+    param(query, name).ok_or_else(|| bad_request(format!("missing {name} parameter")))
+}
+
+//the values are the ones of the command line options, e.g. allow-deposits
+fn parse_enum<T: ValueEnum>(value: &str) -> Result<T, Response> {
+    T::from_str(value, true).map_err(|_| bad_request(format!("invalid policy {value}")))
+}
+
+fn bad_request(body: String) -> Response {
+    Response::error(400, body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{route, AdminCommand, EngineCommand};
+    use crate::tranasction::config::{
+        ConfigChange, DuplicatePolicy, LockPolicy, RateLimit, RateLimitPolicy,
+    };
+    use tracing_subscriber::filter::LevelFilter;
+
+    #[test]
+    fn route_requests() {
+        assert_eq!(
+            route("PUT", "/log-level?level=debug").unwrap(),
+            AdminCommand::LogLevel(LevelFilter::DEBUG)
+        );
+        assert_eq!(
+            route("PUT", "/rate-limit?policy=defer&rate=2.5").unwrap(),
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::RateLimit(Some(
+                RateLimit {
+                    rate: 2.5,
+                    burst: 10,
+                    policy: RateLimitPolicy::Defer,
+                }
+            ))))
+        );
+        assert_eq!(
+            route("DELETE", "/rate-limit").unwrap(),
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::RateLimit(None)))
+        );
+        assert_eq!(
+            route("PUT", "/lock-policy?policy=allow-deposits").unwrap(),
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::LockPolicy(
+                LockPolicy::AllowDeposits
+            )))
+        );
+        assert_eq!(
+            route("PUT", "/duplicate-policy?policy=idempotent-skip").unwrap(),
+            AdminCommand::Engine(EngineCommand::Reconfigure(ConfigChange::DuplicatePolicy(
+                DuplicatePolicy::IdempotentSkip
+            )))
+        );
+        assert_eq!(
+            route("POST", "/snapshot").unwrap(),
+            AdminCommand::Engine(EngineCommand::Snapshot)
+        );
+        assert_eq!(
+            route("POST", "/rollback?name=segment-5").unwrap(),
+            AdminCommand::Engine(EngineCommand::Rollback("segment-5".into()))
+        );
+    }
+
+    #[test]
+    fn route_errors() {
+        let status = |method, target| route(method, target).unwrap_err().status;
+        assert_eq!(status("PUT", "/log-level"), 400);
+        assert_eq!(status("PUT", "/log-level?level=loud"), 400);
+        assert_eq!(status("PUT", "/rate-limit?rate=0"), 400);
+        assert_eq!(status("PUT", "/rate-limit?rate=1&burst=0"), 400);
+        assert_eq!(status("PUT", "/lock-policy?policy=none"), 400);
+        assert_eq!(status("GET", "/flush"), 405);
+        assert_eq!(status("POST", "/checkpoint"), 400);
+        assert_eq!(status("GET", "/rollback?name=a"), 405);
+        assert_eq!(status("POST", "/restart"), 404);
+    }
+}
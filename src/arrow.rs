@@ -0,0 +1,220 @@
+use crate::models::Account;
+use crate::tranasction::ledger::Posting;
+use arrow_array::builder::{
+    BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use std::io::Write;
+use std::sync::Arc;
+
+//The amounts are written as decimals with the 4 decimal places of the engine, so that they are not read back as
+//floats or strings by Polars and DuckDB
+const AMOUNT_PRECISION: u8 = 38;
+const AMOUNT_SCALE: i8 = 4;
+
+fn amount_type() -> DataType {
+    DataType::Decimal128(AMOUNT_PRECISION, AMOUNT_SCALE)
+}
+
+fn amount_builder() -> Result<Decimal128Builder, ArrowError> {
+    Decimal128Builder::new().with_precision_and_scale(AMOUNT_PRECISION, AMOUNT_SCALE)
+}
+
+fn to_decimal(amount: f64) -> i128 {
+    (amount * 10f64.powi(AMOUNT_SCALE as i32)).round() as i128
+}
+
+//the ledger is null for the default ledger, so the schema is the same whether there are several ledgers or not
+fn ledger_value(ledger: &str) -> Option<&str> {
+    (!ledger.is_empty()).then_some(ledger)
+}
+
+fn write_batch(
+    writer: impl Write,
+    schema: Arc<Schema>,
+    columns: Vec<ArrayRef>,
+) -> Result<(), ArrowError> {
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let mut writer = FileWriter::try_new(writer, &schema)?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    Ok(())
+}
+
+//write the accounts as an arrow ipc file with the columns client,available,held,total,locked,ledger
+pub fn write_accounts<'a>(
+    writer: impl Write,
+    accounts: impl Iterator<Item = (&'a str, &'a Account)>,
+) -> Result<(), ArrowError> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", amount_type(), false),
+        Field::new("held", amount_type(), false),
+        Field::new("total", amount_type(), false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("ledger", DataType::Utf8, true),
+    ]));
+    let mut client = UInt16Builder::new();
+    let mut available = amount_builder()?;
+    let mut held = amount_builder()?;
+    let mut total = amount_builder()?;
+    let mut locked = BooleanBuilder::new();
+    let mut ledgers = StringBuilder::new();
+    for (ledger, account) in accounts {
+        client.append_value(account.client);
+        available.append_value(to_decimal(account.available));
+        held.append_value(to_decimal(account.held));
+        total.append_value(to_decimal(account.total));
+        locked.append_value(account.locked);
+        ledgers.append_option(ledger_value(ledger));
+    }
+    write_batch(
+        writer,
+        schema,
+        vec![
+            Arc::new(client.finish()),
+            Arc::new(available.finish()),
+            Arc::new(held.finish()),
+            Arc::new(total.finish()),
+            Arc::new(locked.finish()),
+            Arc::new(ledgers.finish()),
+        ],
+    )
+}
+
+//write the postings as an arrow ipc file with the columns of the csv journal, the tx column is a u64 whatever the
+//width of the tx ids
+pub fn write_postings(writer: impl Write, postings: &[Posting]) -> Result<(), ArrowError> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("debit", DataType::Utf8, false),
+        Field::new("credit", DataType::Utf8, false),
+        Field::new("amount", amount_type(), false),
+        Field::new("reference", DataType::Utf8, true),
+        Field::new("ledger", DataType::Utf8, true),
+    ]));
+    let mut client = UInt16Builder::new();
+    let mut tx = UInt64Builder::new();
+    let mut debit = StringBuilder::new();
+    let mut credit = StringBuilder::new();
+    let mut amount = amount_builder()?;
+    let mut reference = StringBuilder::new();
+    let mut ledger = StringBuilder::new();
+    for posting in postings {
+        client.append_value(posting.client);
+        //the conversion is a no-op with the u64-tx-ids feature
+        #[allow(clippy::useless_conversion)]
+        tx.append_value(u64::from(posting.tx));
+        debit.append_value(posting.debit.as_str());
+        credit.append_value(posting.credit.as_str());
+        amount.append_value(to_decimal(posting.amount));
+        reference.append_option(posting.reference.as_deref());
+        ledger.append_option(posting.ledger.as_deref().and_then(ledger_value));
+    }
+    write_batch(
+        writer,
+        schema,
+        vec![
+            Arc::new(client.finish()),
+            Arc::new(tx.finish()),
+            Arc::new(debit.finish()),
+            Arc::new(credit.finish()),
+            Arc::new(amount.finish()),
+            Arc::new(reference.finish()),
+            Arc::new(ledger.finish()),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_accounts, write_postings};
+    use crate::models::Account;
+    use crate::tranasction::ledger::{Posting, SubLedger};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt64Type};
+    use arrow_array::{Array, RecordBatch};
+    use arrow_ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    fn read(file: Vec<u8>) -> RecordBatch {
+        let mut reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+        batch
+    }
+
+    #[test]
+    fn accounts_round_trip() {
+        let accounts = [
+            Account {
+                client: 1,
+                available: 1.5,
+                held: 0.1234,
+                total: 1.6234,
+                locked: false,
+            },
+            Account {
+                client: 2,
+                locked: true,
+                ..Default::default()
+            },
+        ];
+        let mut file = vec![];
+        write_accounts(
+            &mut file,
+            [("", &accounts[0]), ("tenant-a", &accounts[1])].into_iter(),
+        )
+        .unwrap();
+
+        let batch = read(file);
+        assert_eq!(batch.num_rows(), 2);
+        let total = batch
+            .column_by_name("total")
+            .unwrap()
+            .as_primitive::<Decimal128Type>();
+        assert_eq!(total.value_as_string(0), "1.6234");
+        assert_eq!(total.value_as_string(1), "0.0000");
+        let ledger = batch.column_by_name("ledger").unwrap().as_string::<i32>();
+        assert!(ledger.is_null(0));
+        assert_eq!(ledger.value(1), "tenant-a");
+        assert!(batch
+            .column_by_name("locked")
+            .unwrap()
+            .as_boolean()
+            .value(1));
+    }
+
+    #[test]
+    fn postings_round_trip() {
+        let postings = [Posting {
+            client: 1,
+            tx: 7,
+            debit: SubLedger::Available,
+            credit: SubLedger::External,
+            amount: 2.0001,
+            reference: Some("REF-1".into()),
+            ledger: None,
+        }];
+        let mut file = vec![];
+        write_postings(&mut file, &postings).unwrap();
+
+        let batch = read(file);
+        assert_eq!(batch.num_rows(), 1);
+        let tx = batch
+            .column_by_name("tx")
+            .unwrap()
+            .as_primitive::<UInt64Type>();
+        assert_eq!(tx.value(0), 7);
+        let debit = batch.column_by_name("debit").unwrap().as_string::<i32>();
+        assert_eq!(debit.value(0), "available");
+        let amount = batch
+            .column_by_name("amount")
+            .unwrap()
+            .as_primitive::<Decimal128Type>();
+        assert_eq!(amount.value_as_string(0), "2.0001");
+    }
+}
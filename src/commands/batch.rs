@@ -0,0 +1,135 @@
+use super::{CsvArgs, EngineArgs, CHANNEL_SIZE, EXIT_IO_FAILURE};
+use crate::parser::age::AGE_EXTENSION;
+use crate::parser::csv_parser::CsvParser;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::ExitCode;
+use tokio::sync::mpsc;
+
+#[derive(clap::Args)]
+pub struct BatchArgs {
+    /// csv files of disjoint ledgers, each file is processed by its own parser and engine
+    #[arg(required = true)]
+    input_files: Vec<String>,
+    /// number of files processed at the same time
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    parallel_files: u16,
+    /// directory the account report of each file is written to, named after the file, e.g. the report of
+    /// partner_a.csv is partner_a_accounts.csv
+    #[arg(long, conflicts_with = "incremental")]
+    output_dir: String,
+    #[command(flatten)]
+    csv: CsvArgs,
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+//Row of the summary written to stdout once every file is processed
+#[derive(Serialize)]
+struct FileSummary<'a> {
+    input: &'a str,
+    output: &'a str,
+    rows: u64,
+    failed_rows: u64,
+    processed: u64,
+    rejected: u64,
+    error: String,
+}
+
+//Process independent files with their own parser and engine, up to --parallel-files at the same time, and write the
+//account report of each one to the output directory. A summary of every file is written to stdout, and the process
+//exits with 2 if any file can't be processed
+pub async fn run(args: BatchArgs) -> ExitCode {
+    let outputs = match output_paths(&args.input_files, &args.output_dir) {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = std::fs::create_dir_all(&args.output_dir) {
+        eprintln!("Failed to create {}: {e}", args.output_dir);
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+
+    //the summaries are in the order of the inputs, even if a later file finishes first
+    let summaries = futures_util::stream::iter(args.input_files.iter().zip(&outputs))
+        .map(|(input, output)| process_file(&args, input, output))
+        .buffered(args.parallel_files as usize)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for summary in &summaries {
+        if let Err(e) = wtr.serialize(summary) {
+            tracing::error!("Fail to write: {e}");
+        }
+    }
+    if summaries.iter().any(|summary| !summary.error.is_empty()) {
+        ExitCode::from(EXIT_IO_FAILURE)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+//two inputs with the same file name in different directories would overwrite each other's report. The report of an
+//encrypted input is named after the decrypted file, e.g. partner_a.csv.age has partner_a_accounts.csv
+fn output_paths(inputs: &[String], output_dir: &str) -> Result<Vec<String>, String> {
+    let mut seen = HashSet::new();
+    inputs
+        .iter()
+        .map(|input| {
+            let stem = Path::new(input.strip_suffix(AGE_EXTENSION).unwrap_or(input))
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| format!("{input} is not a file name"))?;
+            if !seen.insert(stem) {
+                return Err(format!("several inputs are named {stem}"));
+            }
+            let output = Path::new(output_dir).join(format!("{stem}_accounts.csv"));
+            Ok(output.to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+async fn process_file<'a>(args: &BatchArgs, input: &'a str, output: &'a str) -> FileSummary<'a> {
+    let mut summary = FileSummary {
+        input,
+        output,
+        rows: 0,
+        failed_rows: 0,
+        processed: 0,
+        rejected: 0,
+        error: String::new(),
+    };
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let mut parser = CsvParser::new(input.to_string(), args.csv.csv_options(), tx);
+    let mut engine = TransactionEngine::with_config(args.engine.engine_config());
+    let parser_handle = tokio::spawn(async move { parser.run().await });
+    let engine_handle = tokio::spawn(async move {
+        let stats = engine.run(rx).await;
+        (engine, stats)
+    });
+    let (parser_result, engine_result) = tokio::join!(parser_handle, engine_handle);
+
+    let result = match (parser_result, engine_result) {
+        (Ok(Ok(parse_stats)), Ok((engine, engine_stats))) => {
+            summary.rows = parse_stats.rows;
+            summary.failed_rows = parse_stats.failed;
+            summary.processed = engine_stats.processed;
+            summary.rejected = engine_stats.rejected;
+            engine.output_to_file(output)
+        }
+        (Ok(Err(e)), _) => Err(e),
+        (Err(e), _) => Err(anyhow::anyhow!("Parser failed: {e}")),
+        (_, Err(e)) => Err(anyhow::anyhow!("Transaction engine failed: {e}")),
+    };
+    if let Err(e) = result {
+        tracing::error!("Fail to process {input}: {e:#}");
+        summary.error = format!("{e:#}");
+    }
+    summary
+}
@@ -0,0 +1,44 @@
+use super::EXIT_IO_FAILURE;
+use crate::reconcile::{diff_accounts, load_state};
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// account report (csv) or snapshot (json) of the earlier run
+    before: String,
+    /// account report (csv) or snapshot (json) of the later run
+    after: String,
+}
+
+//print the changes of every client between two runs, exit with 1 if there is any like the diff tool
+pub fn run(args: DiffArgs) -> ExitCode {
+    let mut accounts = vec![];
+    for path in [&args.before, &args.after] {
+        match load_state(path) {
+            Ok(state) => accounts.push(state.accounts),
+            Err(e) => {
+                eprintln!("Failed to read accounts from {path}: {e}");
+                return ExitCode::from(EXIT_IO_FAILURE);
+            }
+        }
+    }
+
+    let diffs = diff_accounts(&accounts[0], &accounts[1]);
+    if diffs.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    println!("client,field,before,after,change");
+    for diff in &diffs {
+        //only the balances have a change, locked and missing accounts don't
+        let change = diff.change.map(|change| change.to_string());
+        println!(
+            "{},{},{},{},{}",
+            diff.client,
+            diff.field,
+            diff.left,
+            diff.right,
+            change.unwrap_or_default()
+        );
+    }
+    ExitCode::FAILURE
+}
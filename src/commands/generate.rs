@@ -0,0 +1,84 @@
+use super::EXIT_IO_FAILURE;
+use crate::models::TxId;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::io::BufWriter;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct GenerateArgs {
+    /// number of rows to generate
+    #[arg(long, default_value_t = 1000)]
+    rows: u32,
+    /// number of clients
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+    /// seed of the random generator, the same seed always generates the same file
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Row {
+    r#type: &'static str,
+    client: u16,
+    tx: TxId,
+    amount: Option<f64>,
+}
+
+//Generate a random transaction file to stdout for testing. Disputes, resolves and chargebacks refer to a previous
+//deposit, so some of them are rejected by the engine the same way as in a real file
+pub fn run(args: GenerateArgs) -> ExitCode {
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut deposits: Vec<(u16, TxId)> = vec![];
+    let mut wtr = csv::Writer::from_writer(BufWriter::new(std::io::stdout()));
+    for tx in 1..=TxId::from(args.rows) {
+        let client = rng.gen_range(1..=args.clients);
+        //amounts have at most 4 decimal places
+        let amount = rng.gen_range(1..=1_000_000) as f64 / 10000.0;
+        let row = match (rng.gen_range(0..100), deposits.is_empty()) {
+            (0..50, _) | (_, true) => {
+                deposits.push((client, tx));
+                Row {
+                    r#type: "deposit",
+                    client,
+                    tx,
+                    amount: Some(amount),
+                }
+            }
+            (50..80, _) => Row {
+                r#type: "withdrawal",
+                client,
+                tx,
+                amount: Some(amount),
+            },
+            (n, _) => {
+                let (client, tx) = deposits[rng.gen_range(0..deposits.len())];
+                let r#type = match n {
+                    80..90 => "dispute",
+                    90..96 => "resolve",
+                    _ => "chargeback",
+                };
+                Row {
+                    r#type,
+                    client,
+                    tx,
+                    amount: None,
+                }
+            }
+        };
+        if let Err(e) = wtr.serialize(row) {
+            eprintln!("Failed to write: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    if let Err(e) = wtr.flush() {
+        eprintln!("Failed to write: {e}");
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,308 @@
+use crate::models::{TransactionType, MAX_AMOUNT};
+use crate::parser::columns::ColumnPositions;
+use crate::parser::csv_parser::CsvOptions;
+use crate::tranasction::accounts_handle::AccountsHandle;
+use crate::tranasction::accrual::Accrual;
+use crate::tranasction::config::{
+    AccountFilter, ConfigChange, DuplicatePolicy, EngineConfig, LockPolicy,
+};
+use crate::tranasction::profile::ClientProfiles;
+use crate::tranasction::snapshot::Snapshot;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use crate::tranasction::validation::{ClientRange, ValidationRules};
+use smol_str::SmolStr;
+
+pub mod batch;
+pub mod diff;
+pub mod generate;
+pub mod process;
+pub mod quarantine;
+pub mod query;
+pub mod reconcile;
+pub mod repl;
+pub mod replay;
+pub mod reverse;
+pub mod serve;
+pub mod snapshot;
+#[cfg(feature = "redis")]
+pub mod stream;
+pub mod validate;
+
+//channel size should be configured based on benchmarking
+pub const CHANNEL_SIZE: usize = 10000;
+//exit codes other than success and failure, the schedulers rely on them to tell why a run failed
+pub const EXIT_IO_FAILURE: u8 = 2;
+pub const EXIT_PARSE_FAILURE: u8 = 3;
+pub const EXIT_INTEGRITY_FAILURE: u8 = 4;
+pub const EXIT_ERROR_BUDGET: u8 = 5;
+pub const EXIT_MEMORY_CAP: u8 = 6;
+
+//Input file and its csv dialect, shared by the commands that read a transaction file
+#[derive(clap::Args)]
+pub struct InputArgs {
+    /// csv file name, - to read from stdin
+    #[arg(required = true)]
+    pub input_file: Option<String>,
+    #[command(flatten)]
+    pub csv: CsvArgs,
+}
+
+impl InputArgs {
+    pub fn csv_options(&self) -> CsvOptions {
+        self.csv.csv_options()
+    }
+}
+
+//Csv dialect of the input files
+#[derive(clap::Args)]
+pub struct CsvArgs {
+    /// field delimiter of the csv file
+    #[arg(long, default_value = ",", value_parser = parse_ascii_byte)]
+    delimiter: u8,
+    /// quote character of the csv file
+    #[arg(long, default_value = "\"", value_parser = parse_ascii_byte)]
+    quote: u8,
+    /// the csv file doesn't have a header row
+    #[arg(long)]
+    no_header: bool,
+    /// column positions of type,client,tx,amount[,timestamp[,reference[,currency[,ledger]]]] when the file doesn't have a header, e.g. 1,0,2,3
+    #[arg(long, requires = "no_header")]
+    columns: Option<ColumnPositions>,
+    /// ledger (tenant) of the rows that don't have one in a ledger column, the accounts of each ledger are kept apart
+    #[arg(long)]
+    ledger: Option<SmolStr>,
+    /// age identity file to decrypt the .age inputs with. The inputs are piped through the age tool, which must be
+    /// installed, so that the decrypted rows are only kept in memory
+    #[arg(long)]
+    decrypt_identity: Option<String>,
+}
+
+impl CsvArgs {
+    pub fn csv_options(&self) -> CsvOptions {
+        CsvOptions {
+            delimiter: self.delimiter,
+            quote: self.quote,
+            has_headers: !self.no_header,
+            columns: self.columns.clone().unwrap_or_default(),
+            ledger: self.ledger.clone(),
+            decrypt_identity: self.decrypt_identity.clone(),
+        }
+    }
+}
+
+//Options of the transaction engine, shared by the commands that run the engine
+#[derive(clap::Args)]
+pub struct EngineArgs {
+    /// allow a resolved transaction to be disputed again
+    #[arg(long)]
+    allow_redispute: bool,
+    /// max number of times a resolved transaction can be disputed again
+    #[arg(long, default_value_t = 1, requires = "allow_redispute")]
+    max_redisputes: u32,
+    /// auto-resolve disputes that are not decided within this number of seconds, requires a timestamp column
+    #[arg(long)]
+    dispute_ttl: Option<u64>,
+    /// interest credited on the held amount of a dispute for every full day it is open, as a fraction of the amount
+    /// per day, or a penalty debited if negative. It is settled when the dispute is resolved or charged back and
+    /// requires a timestamp column
+    #[arg(long, allow_negative_numbers = true)]
+    pub held_accrual_rate: Option<f64>,
+    /// release the held funds of authorizations that are not captured within this number of seconds, requires a
+    /// timestamp column
+    #[arg(long)]
+    authorization_ttl: Option<u64>,
+    /// transactions that are still accepted once an account is locked
+    #[arg(long, value_enum, default_value_t = LockPolicy::BlockAll)]
+    lock_policy: LockPolicy,
+    /// what happens to a deposit, withdrawal or authorization whose tx id has already been applied
+    #[arg(long, value_enum, default_value_t = DuplicatePolicy::Reject)]
+    duplicate_policy: DuplicatePolicy,
+    /// apply the checkpoint,<name> and rollback,<name> rows of the input, they are rejected otherwise
+    #[arg(long)]
+    checkpoint_records: bool,
+    /// max number of checkpoints kept in memory, each of them is a full copy of the state and a new one over the
+    /// limit is rejected
+    #[arg(long, default_value_t = 16)]
+    max_checkpoints: usize,
+    /// write the accounts as they change on flush records, every --flush-every transactions and at the end,
+    /// instead of writing all the accounts at the end
+    #[arg(long)]
+    pub incremental: bool,
+    /// number of transactions between two flushes in incremental mode
+    #[arg(long, requires = "incremental")]
+    flush_every: Option<u64>,
+    /// transaction types to reject, e.g. dispute,resolve,chargeback when the disputes are handled by another system
+    #[arg(long, value_enum, value_delimiter = ',')]
+    disable: Vec<TransactionType>,
+    /// json file with the limits the transactions must meet (min_amount, max_amount, allowed_types, client_ranges,
+    /// currency), the transactions that break a rule are rejected
+    #[arg(long, value_parser = ValidationRules::load)]
+    validation_rules: Option<ValidationRules>,
+    /// reject the transactions whose amount is above this value, on top of the max_amount of the validation rules
+    #[arg(long, value_parser = parse_max_amount)]
+    max_amount: Option<f64>,
+    /// csv file with the profile of the clients (client,name,tier,country), joined into the account report. The
+    /// tier selects the tier rules of the validation rules
+    #[arg(long, value_parser = ClientProfiles::load)]
+    client_profiles: Option<ClientProfiles>,
+    /// only write the accounts of these clients or client ranges to the account report, e.g. 1,5,100-200
+    #[arg(long, value_delimiter = ',')]
+    clients: Vec<ClientRange>,
+    /// only write the locked accounts to the account report
+    #[arg(long)]
+    only_locked: bool,
+    /// skip the accounts whose balances are all zero in the account report
+    #[arg(long)]
+    nonzero_only: bool,
+}
+
+impl EngineArgs {
+    pub fn engine_config(&self) -> EngineConfig {
+        EngineConfig {
+            max_redisputes: if self.allow_redispute {
+                self.max_redisputes
+            } else {
+                0
+            },
+            dispute_ttl: self.dispute_ttl,
+            accrual: self
+                .held_accrual_rate
+                .map(|daily_rate| Accrual { daily_rate }),
+            authorization_ttl: self.authorization_ttl,
+            lock_policy: self.lock_policy,
+            duplicate_policy: self.duplicate_policy,
+            checkpoint_records: self.checkpoint_records,
+            max_checkpoints: Some(self.max_checkpoints),
+            incremental: self.incremental,
+            flush_every: self.flush_every,
+            disabled_types: self.disable.clone(),
+            validation: self.validation_rules(),
+            profiles: self.client_profiles.clone(),
+            account_filter: AccountFilter {
+                clients: self.clients.clone(),
+                only_locked: self.only_locked,
+                nonzero_only: self.nonzero_only,
+            },
+            ..Default::default()
+        }
+    }
+
+    //the long-running commands take the checkpoints through the admin api, so that a producer can't roll back the
+    //engine with a row
+    pub fn check_no_checkpoint_records(&self, command: &str) -> Result<(), String> {
+        if self.checkpoint_records {
+            return Err(format!(
+                "--checkpoint-records can't be used with {command}, the checkpoints are taken through the admin api"
+            ));
+        }
+        Ok(())
+    }
+
+    //the lowest of the two max amounts applies
+    fn validation_rules(&self) -> ValidationRules {
+        let mut rules = self.validation_rules.clone().unwrap_or_default();
+        if let Some(max) = self.max_amount {
+            rules.max_amount = Some(rules.max_amount.map_or(max, |rule| rule.min(max)));
+        }
+        rules
+    }
+}
+
+//What the scheduled actions and the admin api need from the engine of a long-running command, either the engine
+//itself or a handle to an engine running in another task. The methods of a handle return None or false once the
+//engine has stopped
+pub(crate) trait RunningEngine {
+    async fn snapshot(&mut self) -> Option<Snapshot>;
+    async fn sweep(&mut self, now: u64);
+    async fn settle_interest(&mut self, now: u64);
+    async fn reconfigure(&mut self, change: ConfigChange) -> bool;
+    async fn flush(&mut self) -> bool;
+    async fn checkpoint(&mut self, name: &str) -> Option<Result<(), String>>;
+    async fn rollback(&mut self, name: &str) -> Option<Result<(), String>>;
+}
+
+impl RunningEngine for TransactionEngine {
+    async fn snapshot(&mut self) -> Option<Snapshot> {
+        Some(TransactionEngine::snapshot(self))
+    }
+
+    async fn sweep(&mut self, now: u64) {
+        TransactionEngine::sweep(self, now);
+    }
+
+    async fn settle_interest(&mut self, now: u64) {
+        TransactionEngine::settle_interest(self, now);
+    }
+
+    async fn reconfigure(&mut self, change: ConfigChange) -> bool {
+        TransactionEngine::reconfigure(self, change);
+        true
+    }
+
+    async fn flush(&mut self) -> bool {
+        TransactionEngine::flush(self);
+        true
+    }
+
+    async fn checkpoint(&mut self, name: &str) -> Option<Result<(), String>> {
+        Some(TransactionEngine::checkpoint(self, name).map_err(|e| e.to_string()))
+    }
+
+    async fn rollback(&mut self, name: &str) -> Option<Result<(), String>> {
+        Some(TransactionEngine::rollback(self, name).map_err(|e| e.to_string()))
+    }
+}
+
+impl RunningEngine for AccountsHandle {
+    async fn snapshot(&mut self) -> Option<Snapshot> {
+        AccountsHandle::snapshot(self).await
+    }
+
+    async fn sweep(&mut self, now: u64) {
+        AccountsHandle::sweep(self, now).await;
+    }
+
+    async fn settle_interest(&mut self, now: u64) {
+        AccountsHandle::settle_interest(self, now).await;
+    }
+
+    async fn reconfigure(&mut self, change: ConfigChange) -> bool {
+        AccountsHandle::reconfigure(self, change).await
+    }
+
+    async fn flush(&mut self) -> bool {
+        AccountsHandle::flush(self).await
+    }
+
+    async fn checkpoint(&mut self, name: &str) -> Option<Result<(), String>> {
+        AccountsHandle::checkpoint(self, name).await
+    }
+
+    async fn rollback(&mut self, name: &str) -> Option<Result<(), String>> {
+        AccountsHandle::rollback(self, name).await
+    }
+}
+
+pub(crate) fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!(
+            "expected a positive number of transactions per second, got {s}"
+        )),
+    }
+}
+
+fn parse_max_amount(s: &str) -> Result<f64, String> {
+    let max = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(0.0..=MAX_AMOUNT).contains(&max) {
+        return Err(format!("expected an amount between 0 and {MAX_AMOUNT}"));
+    }
+    Ok(max)
+}
+
+fn parse_ascii_byte(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [b] if b.is_ascii() => Ok(*b),
+        _ => Err(format!("expected a single ascii character, got {s:?}")),
+    }
+}
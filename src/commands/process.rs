@@ -0,0 +1,683 @@
+use super::{
+    EngineArgs, InputArgs, CHANNEL_SIZE, EXIT_ERROR_BUDGET, EXIT_INTEGRITY_FAILURE,
+    EXIT_IO_FAILURE, EXIT_MEMORY_CAP, EXIT_PARSE_FAILURE,
+};
+use crate::models::FilePosition;
+use crate::parser::age::is_encrypted;
+use crate::parser::client_map::ClientMap;
+use crate::parser::csv_parser::CsvParser;
+use crate::parser::manifest::ManifestEntry;
+use crate::reconcile::{account_deltas, load_state};
+use crate::tranasction::applied::AppliedIds;
+use crate::tranasction::error_budget::ErrorBudget;
+use crate::tranasction::quarantine::QuarantineRules;
+use crate::tranasction::snapshot::Snapshot;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use crate::tui::{self, Probes};
+use core_affinity::CoreId;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader};
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+//What happens to a run whose input has balance assertions that fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MismatchPolicy {
+    //exit with 4 without writing the outputs, like an input that doesn't match its manifest
+    Fail,
+    //only log the failed assertions
+    Log,
+}
+
+#[derive(clap::Args)]
+pub struct ProcessArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+    /// save the state of the engine to this file once all the transactions are processed or the run is interrupted
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// resume from the file position saved in this snapshot instead of the first row
+    #[arg(long)]
+    resume: Option<String>,
+    /// start from the accounts of a previous run, either its account report (csv) or its snapshot (json) which also
+    /// restores the prior transactions so they can still be disputed
+    #[arg(long, conflicts_with = "resume")]
+    initial_accounts: Option<String>,
+    /// preview the input on top of this snapshot (json) or account report (csv): write the change of every account
+    /// the input changes (client,available,held,total,locked,ledger) instead of the accounts, and save nothing: every
+    /// option that writes a file other than the deltas is refused
+    #[arg(long, conflicts_with_all = [
+        "snapshot", "resume", "initial_accounts", "incremental", "archive", "audit", "events", "journal",
+        "rejects_output", "quarantine_output", "disputes_output", "accruals_output", "mismatches_output",
+        "applied_ids_output", "client_map_output",
+    ])]
+    what_if: Option<String>,
+    /// write the transactions that are still in dispute to this csv file
+    #[arg(long)]
+    disputes_output: Option<String>,
+    /// write the interest or penalty accrued by the decided disputes of every client (client,accrued,ledger) to this
+    /// csv file, see --held-accrual-rate
+    #[arg(long)]
+    accruals_output: Option<String>,
+    /// what happens when an assert_balance record of the input doesn't match the balances of its account
+    #[arg(long, value_enum, default_value_t = MismatchPolicy::Fail)]
+    on_balance_mismatch: MismatchPolicy,
+    /// write the failed balance assertions (client,balance,expected,actual,ledger) to this csv file
+    #[arg(long)]
+    mismatches_output: Option<String>,
+    /// exit with an error when the ratio of rows that can't be parsed reaches this value, between 0 and 1
+    #[arg(long, default_value_t = 1.0, value_parser = parse_ratio)]
+    max_parse_failure_rate: f64,
+    /// abort the run once more than this number of rows can't be parsed or are rejected by the engine
+    #[arg(long)]
+    max_errors: Option<u64>,
+    /// abort the run once the ratio of rows that can't be parsed or are rejected by the engine is over this value,
+    /// between 0 and 1. It is checked from the 1000th row, and on the whole input at the end
+    #[arg(long, value_parser = parse_ratio)]
+    max_error_rate: Option<f64>,
+    /// abort the run once the approximate memory of the accounts and transactions is over this number of megabytes.
+    /// With --archive the inactive accounts are archived first, and the run is only aborted if that is not enough
+    #[arg(long)]
+    max_memory_mb: Option<usize>,
+    /// write the transactions rejected by the validation rules or because their type is disabled
+    /// (client,tx,type,rule,reason) to this csv file
+    #[arg(long)]
+    rejects_output: Option<String>,
+    /// json file with the rules that park suspicious transactions for review instead of applying them (amount_above,
+    /// rapid_fire)
+    #[arg(long, value_parser = QuarantineRules::load, requires = "quarantine_output")]
+    quarantine_rules: Option<QuarantineRules>,
+    /// write the parked transactions to this csv file, they can be released or rejected with the quarantine command
+    #[arg(long, requires = "quarantine_rules")]
+    quarantine_output: Option<String>,
+    /// csv file of the transactions applied by previous runs (type,tx,ledger), written by --applied-ids-output. They
+    /// are skipped without an error and not counted, so that an old file can be processed again on top of the
+    /// carried-forward state
+    #[arg(long, value_parser = AppliedIds::load)]
+    applied_ids: Option<AppliedIds>,
+    /// write the transactions applied by this run and the ones of --applied-ids to this csv file, it can be the same
+    /// file as --applied-ids
+    #[arg(long)]
+    applied_ids_output: Option<String>,
+    /// csv file that maps the external client references of the input, e.g. UUIDs, to the internal client ids
+    /// (reference,client). The client column is then read as references, and a reference that isn't in the file gets
+    /// the id after the highest one. The reports have the internal ids
+    #[arg(long, value_parser = ClientMap::load)]
+    client_map: Option<ClientMap>,
+    /// write the map of the client references, with the ids assigned during this run, to this csv file. It can be
+    /// the same file as --client-map, and without --client-map every reference gets a new id starting from 1
+    #[arg(long)]
+    client_map_output: Option<String>,
+    /// move the accounts with zero balances and no open dispute out of memory to this csv file during the run, an
+    /// archived account is re-created by the next transaction of its client
+    #[arg(long)]
+    archive: Option<String>,
+    /// number of transactions between two archival sweeps
+    #[arg(long, default_value_t = 100_000, requires = "archive")]
+    archive_every: u64,
+    /// write every balance movement as a double-entry posting (client,tx,debit,credit,amount) to this csv file
+    #[arg(long)]
+    journal: Option<String>,
+    /// write the final accounts of every ledger to this arrow ipc (feather) file, the amounts are decimals with 4
+    /// decimal places
+    #[cfg(feature = "arrow")]
+    #[arg(long, conflicts_with = "what_if")]
+    arrow_output: Option<String>,
+    /// write the postings of the journal to this arrow ipc (feather) file
+    #[cfg(feature = "arrow")]
+    #[arg(long, conflicts_with = "what_if")]
+    arrow_journal: Option<String>,
+    /// append every applied transaction and expiry with the balances of the account before and after it and the
+    /// resulting state of the transaction to this csv file
+    #[arg(long)]
+    audit: Option<String>,
+    /// append every accepted transaction, checkpoint and rollback with the resulting state of the transaction to
+    /// this ndjson event log, which the replay command applies to a new engine to rebuild the state
+    #[arg(long)]
+    events: Option<String>,
+    /// verify the checksum and row count of the input against this manifest (filename,sha256,rows) before writing
+    /// any output
+    #[arg(long, conflicts_with_all = ["resume", "incremental"])]
+    manifest: Option<String>,
+    /// show a live dashboard on stderr while processing: throughput, channel depth, rejections by type, top accounts
+    /// by held funds, elapsed time and ETA
+    #[arg(long)]
+    tui: bool,
+    /// run the parser on a thread pinned to this core instead of a worker of the runtime
+    #[arg(long, value_parser = parse_core)]
+    parser_core: Option<usize>,
+    /// run the transaction engine on a thread pinned to this core instead of a worker of the runtime
+    #[arg(long, value_parser = parse_core)]
+    engine_core: Option<usize>,
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("expected a number between 0 and 1, got {s}")),
+    }
+}
+
+//the core must be one of the cores the process is allowed to run on
+fn parse_core(s: &str) -> Result<usize, String> {
+    let core = s.parse::<usize>().map_err(|e| e.to_string())?;
+    let cores = core_affinity::get_core_ids().unwrap_or_default();
+    if !cores.iter().any(|id| id.id == core) {
+        let ids = cores.iter().map(|id| id.id.to_string()).collect::<Vec<_>>();
+        return Err(format!("core {core} is not one of {}", ids.join(",")));
+    }
+    Ok(core)
+}
+
+//Run a stage of the pipeline as a task of the runtime, or on a thread of its own pinned to the core so that it
+//doesn't move between the workers, and the sockets of the machine, while it runs. The thread is not taken from the
+//blocking pool, whose threads are shared with the other blocking calls and keep their affinity once they are
+//returned to it. A panic of the stage is reported by the returned handle like the one of a task
+fn spawn_stage<F>(name: &str, core: Option<usize>, stage: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let Some(core) = core else {
+        return tokio::spawn(stage);
+    };
+    let runtime = Handle::current();
+    let (tx, rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            if !core_affinity::set_for_current(CoreId { id: core }) {
+                tracing::warn!("Fail to pin to core {core}");
+            }
+            let _ = tx.send(runtime.block_on(stage));
+        })
+        .expect("Fail to spawn the thread of a pinned stage");
+    let name = name.to_string();
+    tokio::spawn(async move {
+        rx.await
+            .unwrap_or_else(|_| panic!("The {name} thread panicked"))
+    })
+}
+
+//Opening state of a new input file. Unlike --resume, the position of a snapshot is dropped since the input is
+//another file
+fn load_initial_state(path: &str) -> anyhow::Result<Snapshot> {
+    Ok(Snapshot {
+        position: None,
+        ..load_state(path)?
+    })
+}
+
+//process the csv file and write the accounts to stdout
+pub async fn run(args: ProcessArgs) -> ExitCode {
+    if let Some(path) = &args.what_if {
+        return what_if(&args, path).await;
+    }
+    match run_pipeline(&args).await {
+        Ok(engine) => {
+            //the accounts are already written by the engine in incremental mode
+            if !args.engine.incremental {
+                engine.output();
+            }
+            ExitCode::SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+//process the csv file on top of the state and write the change of the accounts to stdout. The state is loaded twice,
+//once by the pipeline, so that the accounts before the run are kept aside
+async fn what_if(args: &ProcessArgs, path: &str) -> ExitCode {
+    let before = match load_initial_state(path) {
+        Ok(snapshot) => snapshot.ledger_accounts(),
+        Err(e) => {
+            tracing::error!("Fail to load snapshot from {path}: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let engine = match run_pipeline(args).await {
+        Ok(engine) => engine,
+        Err(code) => return code,
+    };
+    let deltas = account_deltas(
+        before.iter().map(|(ledger, account)| (ledger, account)),
+        engine.ledger_accounts(),
+    );
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for delta in &deltas {
+        if let Err(e) = wtr.serialize(delta) {
+            tracing::error!("Fail to write: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+//run the parser and the transaction engine until the whole file is processed. Returns the exit code of the process
+//if the run fails
+pub async fn run_pipeline(args: &ProcessArgs) -> Result<TransactionEngine, ExitCode> {
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let input_file = args.input.input_file.clone().unwrap_or_default();
+
+    let manifest_entry = match &args.manifest {
+        Some(path) => match ManifestEntry::load(path, &input_file) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::error!("Fail to load manifest from {path}: {e}");
+                return Err(ExitCode::from(EXIT_INTEGRITY_FAILURE));
+            }
+        },
+        None => None,
+    };
+
+    //the ETA is based on the size of the input, which is unknown for stdin
+    let input_size = std::fs::metadata(&input_file).ok().map(|m| m.len());
+    let channel = tx.downgrade();
+    let mut parser = CsvParser::new(input_file.clone(), args.input.csv_options(), tx);
+    if manifest_entry.is_some() {
+        parser.compute_checksum();
+    }
+    let client_map = args
+        .client_map
+        .clone()
+        .or_else(|| args.client_map_output.is_some().then(ClientMap::default));
+    if let Some(map) = client_map {
+        parser.client_map(map);
+    }
+    let mut config = args.engine.engine_config();
+    config.journal = args.journal.is_some();
+    #[cfg(feature = "arrow")]
+    {
+        config.journal |= args.arrow_journal.is_some();
+    }
+    config.audit = args.audit.is_some();
+    config.events = args.events.is_some();
+    config.record_rejects = args.rejects_output.is_some();
+    config.applied = args.applied_ids.clone();
+    config.record_applied = args.applied_ids_output.is_some();
+    config.quarantine = args.quarantine_rules.clone();
+    config.archive_every = args.archive.is_some().then_some(args.archive_every);
+    config.max_memory = args.max_memory_mb.map(|mb| mb * 1024 * 1024);
+    //the incremental rows have the columns of the first flush, which may come before the first row of a ledger
+    config.ledger_column =
+        args.engine.incremental && args.input.csv_options().has_ledgers(&input_file);
+    let mut transaction_engine = TransactionEngine::with_config(config);
+    let error_budget = (args.max_errors.is_some() || args.max_error_rate.is_some())
+        .then(|| Arc::new(ErrorBudget::new(args.max_errors, args.max_error_rate)));
+    if let Some(budget) = &error_budget {
+        parser.error_budget(budget.clone());
+        transaction_engine.error_budget(budget.clone());
+    }
+
+    if let Some(path) = &args.resume {
+        match Snapshot::load(path) {
+            Ok(mut snapshot) => {
+                if let Some(position) = snapshot.position.take() {
+                    parser.resume_from(position);
+                }
+                transaction_engine.restore(snapshot);
+            }
+            Err(e) => {
+                tracing::error!("Fail to load snapshot from {path}: {e}");
+                return Err(ExitCode::from(EXIT_IO_FAILURE));
+            }
+        }
+    }
+
+    //with a manifest, the files written while running are only moved to their path once the input matches it, and
+    //the rows of an encrypted input are applied as they are decrypted, before age may fail on a truncated file
+    let mut staged = StagedOutputs::new(manifest_entry.is_some() || is_encrypted(&input_file));
+    if let Some(path) = &args.archive {
+        if let Err(e) = transaction_engine.archive_to(&staged.stage(path, false, false)) {
+            tracing::error!("Fail to create archive {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+
+    if let Some(path) = &args.audit {
+        if let Err(e) = transaction_engine.audit_to(&staged.stage(path, true, true)) {
+            tracing::error!("Fail to open audit trail {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+
+    if let Some(path) = &args.events {
+        if let Err(e) = transaction_engine.events_to(&staged.stage(path, true, false)) {
+            tracing::error!("Fail to open event log {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+
+    if let Some(path) = args.initial_accounts.as_ref().or(args.what_if.as_ref()) {
+        match load_initial_state(path) {
+            Ok(snapshot) => transaction_engine.restore(snapshot),
+            Err(e) => {
+                tracing::error!("Fail to load initial accounts from {path}: {e}");
+                return Err(ExitCode::from(EXIT_IO_FAILURE));
+            }
+        }
+    }
+
+    //stop parsing on ctrl-c, or once the memory is over the max memory, so that the processed rows can still be
+    //saved in the snapshot
+    let stop = parser.stop_handle();
+    transaction_engine.stop_on_max_memory(stop.clone());
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    });
+
+    let dashboard = args.tui.then(|| {
+        let probes = Probes {
+            accounts: transaction_engine.accounts_handle(),
+            channel,
+            bytes_read: parser.progress_handle(),
+            input_size,
+        };
+        let (done_tx, done_rx) = oneshot::channel();
+        (done_tx, tokio::spawn(tui::run(probes, done_rx)))
+    });
+
+    let parser_handle = spawn_stage("parser", args.parser_core, async move {
+        let stats = parser.run().await;
+        (stats, parser.position(), parser.take_client_map())
+    });
+    let engine_handle = spawn_stage("engine", args.engine_core, async move {
+        let stats = transaction_engine.run(rx).await;
+        (transaction_engine, stats)
+    });
+
+    let (parser_result, engine_result) = tokio::join!(parser_handle, engine_handle);
+    //the terminal is restored before anything is written to stdout
+    if let Some((done, handle)) = dashboard {
+        let _ = done.send(());
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Dashboard failed: {e}"),
+            Err(e) => tracing::error!("Dashboard failed: {e}"),
+        }
+    }
+    let (engine, engine_stats) = engine_result.map_err(|e| {
+        tracing::error!("Transaction engine failed: {e}");
+        ExitCode::FAILURE
+    })?;
+    let (parse_stats, position, client_map) = parser_result.map_err(|e| {
+        tracing::error!("Parser failed: {e}");
+        ExitCode::FAILURE
+    })?;
+    let parse_stats = parse_stats.map_err(|e| {
+        tracing::error!("{e:#}");
+        staged.discard();
+        ExitCode::from(EXIT_IO_FAILURE)
+    })?;
+    let elapsed = started.elapsed();
+    tracing::info!(
+        "Parsed {} rows ({} failed), processed {} transactions ({} rejected, {} skipped as already applied) in {elapsed:?}, {:.0} rows/sec, {:.1} MB of memory",
+        parse_stats.rows,
+        parse_stats.failed,
+        engine_stats.processed,
+        engine_stats.rejected,
+        engine_stats.skipped,
+        parse_stats.rows as f64 / elapsed.as_secs_f64(),
+        engine_stats.memory as f64 / (1024.0 * 1024.0)
+    );
+    tracing::info!(
+        "Stage timings: parse {}, send wait {}, receive wait {}, apply {}",
+        parse_stats.parse,
+        parse_stats.send_wait,
+        engine_stats.receive_wait,
+        engine_stats.apply
+    );
+
+    //the parser stopped once the budget was exceeded, so the snapshot can be resumed after the bad segment is fixed
+    if let Some(budget) = error_budget.filter(|budget| budget.exceeded_at_end()) {
+        tracing::error!(
+            "{} errors in {} rows exceed the error budget, the run is aborted",
+            budget.errors(),
+            budget.rows()
+        );
+        save_aborted(args, &engine, position, client_map.as_ref(), &staged)?;
+        return Err(ExitCode::from(EXIT_ERROR_BUDGET));
+    }
+    //like the error budget, the parser stopped at the row that went over the max memory
+    if engine.memory_exceeded() {
+        tracing::error!(
+            "Memory usage of {} bytes is over --max-memory-mb, the run is aborted",
+            engine_stats.memory
+        );
+        save_aborted(args, &engine, position, client_map.as_ref(), &staged)?;
+        return Err(ExitCode::from(EXIT_MEMORY_CAP));
+    }
+    if let Some(entry) = &manifest_entry {
+        let sha256 = parse_stats.sha256.as_deref().unwrap_or_default();
+        if let Err(e) = entry.verify(sha256, parse_stats.rows) {
+            tracing::error!("Input doesn't match the manifest: {e}");
+            staged.discard();
+            return Err(ExitCode::from(EXIT_INTEGRITY_FAILURE));
+        }
+    }
+    if parse_stats.failed > 0 && parse_stats.failure_rate() >= args.max_parse_failure_rate {
+        tracing::error!(
+            "{} of {} rows failed to parse, which reaches the max parse failure rate {}",
+            parse_stats.failed,
+            parse_stats.rows,
+            args.max_parse_failure_rate
+        );
+        staged.discard();
+        return Err(ExitCode::from(EXIT_PARSE_FAILURE));
+    }
+    if let Some(path) = &args.mismatches_output {
+        if let Err(e) = engine.output_balance_mismatches(path) {
+            tracing::error!("Fail to write balance mismatches to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    let mismatches = engine.balance_mismatches().len();
+    if mismatches > 0 && args.on_balance_mismatch == MismatchPolicy::Fail {
+        tracing::error!("{mismatches} balances don't match the assertions of the input");
+        staged.discard();
+        return Err(ExitCode::from(EXIT_INTEGRITY_FAILURE));
+    }
+    staged.commit()?;
+    if let Some(path) = &args.snapshot {
+        save_snapshot(&engine, path, position)?;
+    }
+    save_client_map(args, client_map.as_ref())?;
+    if let Some(path) = &args.applied_ids_output {
+        if let Err(e) = engine.output_applied_ids(path) {
+            tracing::error!("Fail to write applied ids to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    if let Some(path) = &args.journal {
+        if let Err(e) = engine.output_journal(path) {
+            tracing::error!("Fail to write journal to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    #[cfg(feature = "arrow")]
+    if let Some(path) = &args.arrow_output {
+        if let Err(e) = engine.output_accounts_arrow(path) {
+            tracing::error!("Fail to write accounts to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    #[cfg(feature = "arrow")]
+    if let Some(path) = &args.arrow_journal {
+        if let Err(e) = engine.output_journal_arrow(path) {
+            tracing::error!("Fail to write journal to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    if let Some(path) = &args.rejects_output {
+        if let Err(e) = engine.output_rejects(path) {
+            tracing::error!("Fail to write rejects to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    if let Some(path) = &args.quarantine_output {
+        if let Err(e) = engine.output_quarantine(path) {
+            tracing::error!("Fail to write quarantine to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    if let Some(path) = &args.disputes_output {
+        if let Err(e) = engine.output_open_disputes(path) {
+            tracing::error!("Fail to write open disputes to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    if let Some(path) = &args.accruals_output {
+        if let Err(e) = engine.output_accruals(path) {
+            tracing::error!("Fail to write accruals to {path}: {e}");
+            return Err(ExitCode::from(EXIT_IO_FAILURE));
+        }
+    }
+    Ok(engine)
+}
+
+//an aborted run can be resumed from its snapshot, but it didn't read the whole input so it can't be verified against
+//a manifest: nothing is kept in this case
+fn save_aborted(
+    args: &ProcessArgs,
+    engine: &TransactionEngine,
+    position: Option<FilePosition>,
+    client_map: Option<&ClientMap>,
+    staged: &StagedOutputs,
+) -> Result<(), ExitCode> {
+    if args.manifest.is_some() {
+        tracing::error!(
+            "The input of an aborted run can't be verified against its manifest, no output is kept"
+        );
+        staged.discard();
+        return Ok(());
+    }
+    staged.commit()?;
+    if let Some(path) = &args.snapshot {
+        save_snapshot(engine, path, position)?;
+        save_client_map(args, client_map)?;
+    }
+    Ok(())
+}
+
+//Files that the engine writes while running (archive, audit trail, event log). With a manifest or an encrypted input
+//they are written to path.pending, and only appended to their path, or moved to it for the archive, once the run
+//passes its checks
+struct StagedOutputs {
+    enabled: bool,
+    outputs: Vec<StagedOutput>,
+}
+
+struct StagedOutput {
+    path: String,
+    //the audit trail and the event log are appended to their file, the archive replaces it
+    append: bool,
+    //the header line of the pending file is skipped when it is appended to a file that already has one
+    header: bool,
+}
+
+impl StagedOutput {
+    fn pending(&self) -> String {
+        format!("{}.pending", self.path)
+    }
+
+    fn commit(&self) -> std::io::Result<()> {
+        let pending = self.pending();
+        if !self.append {
+            return fs::rename(&pending, &self.path);
+        }
+        let mut reader = BufReader::new(File::open(&pending)?);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if self.header && file.metadata()?.len() > 0 {
+            reader.read_line(&mut String::new())?;
+        }
+        io::copy(&mut reader, &mut file)?;
+        fs::remove_file(&pending)
+    }
+}
+
+impl StagedOutputs {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            outputs: Vec::new(),
+        }
+    }
+
+    //path the engine writes the output to
+    fn stage(&mut self, path: &str, append: bool, header: bool) -> String {
+        if !self.enabled {
+            return path.to_string();
+        }
+        let output = StagedOutput {
+            path: path.to_string(),
+            append,
+            header,
+        };
+        let pending = output.pending();
+        self.outputs.push(output);
+        pending
+    }
+
+    fn commit(&self) -> Result<(), ExitCode> {
+        for output in &self.outputs {
+            if let Err(e) = output.commit() {
+                tracing::error!("Fail to write {}: {e}", output.path);
+                return Err(ExitCode::from(EXIT_IO_FAILURE));
+            }
+        }
+        Ok(())
+    }
+
+    fn discard(&self) {
+        for output in &self.outputs {
+            if let Err(e) = fs::remove_file(output.pending()) {
+                tracing::error!("Fail to remove {}: {e}", output.pending());
+            }
+        }
+    }
+}
+
+//the ids assigned by a run are only kept once its state is: after the checks of a complete run, or with the snapshot
+//of an aborted one, whose accounts already have these ids
+fn save_client_map(args: &ProcessArgs, map: Option<&ClientMap>) -> Result<(), ExitCode> {
+    let (Some(path), Some(map)) = (&args.client_map_output, map) else {
+        return Ok(());
+    };
+    map.write(path).map_err(|e| {
+        tracing::error!("Fail to write client map to {path}: {e:#}");
+        ExitCode::from(EXIT_IO_FAILURE)
+    })?;
+    tracing::info!(
+        "Mapped {} client references, {} new",
+        map.len(),
+        map.assigned()
+    );
+    Ok(())
+}
+
+fn save_snapshot(
+    engine: &TransactionEngine,
+    path: &str,
+    position: Option<FilePosition>,
+) -> Result<(), ExitCode> {
+    let mut snapshot = engine.snapshot();
+    snapshot.position = position;
+    snapshot.save(path).map_err(|e| {
+        tracing::error!("Fail to save snapshot to {path}: {e}");
+        ExitCode::from(EXIT_IO_FAILURE)
+    })
+}
@@ -0,0 +1,93 @@
+use super::EXIT_IO_FAILURE;
+use crate::models::{parse_tx_id, TxId};
+use crate::tranasction::quarantine::QuarantineRecord;
+use std::fs::File;
+use std::io::BufWriter;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct QuarantineArgs {
+    /// quarantine file written by --quarantine-output, the transactions that are not decided are kept in it
+    quarantine_file: String,
+    /// tx ids of the transactions to release, e.g. 3,7
+    #[arg(long, value_delimiter = ',', value_parser = parse_tx_id)]
+    release: Vec<TxId>,
+    /// tx ids of the transactions to reject
+    #[arg(long, value_delimiter = ',', value_parser = parse_tx_id)]
+    reject: Vec<TxId>,
+}
+
+fn read_records(path: &str) -> anyhow::Result<Vec<QuarantineRecord>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    Ok(rdr.deserialize().collect::<Result<_, _>>()?)
+}
+
+fn write_records<W: std::io::Write>(
+    writer: W,
+    records: &[&QuarantineRecord],
+) -> anyhow::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for record in records {
+        wtr.serialize(record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+//Write the released transactions to stdout as an input file for a later run, drop the rejected ones and keep the
+//others in the quarantine file. Nothing is changed if a tx id is not in the quarantine file
+pub fn run(args: QuarantineArgs) -> ExitCode {
+    if let Some(tx) = args.release.iter().find(|tx| args.reject.contains(tx)) {
+        eprintln!("Transaction {tx} cannot be both released and rejected");
+        return ExitCode::FAILURE;
+    }
+    let records = match read_records(&args.quarantine_file) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", args.quarantine_file);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    if let Some(tx) = args
+        .release
+        .iter()
+        .chain(&args.reject)
+        .find(|tx| !records.iter().any(|record| record.tx == **tx))
+    {
+        eprintln!("Transaction {tx} is not in quarantine");
+        return ExitCode::FAILURE;
+    }
+
+    let released = records
+        .iter()
+        .filter(|record| args.release.contains(&record.tx))
+        .collect::<Vec<_>>();
+    let pending = records
+        .iter()
+        .filter(|record| !args.release.contains(&record.tx) && !args.reject.contains(&record.tx))
+        .collect::<Vec<_>>();
+    for record in records
+        .iter()
+        .filter(|record| args.reject.contains(&record.tx))
+    {
+        tracing::info!(
+            client = record.client,
+            tx = record.tx,
+            rule = record.rule.as_str(),
+            "Rejected from quarantine"
+        );
+    }
+
+    if let Err(e) = write_records(BufWriter::new(std::io::stdout()), &released) {
+        eprintln!("Failed to write: {e}");
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+    if let Err(e) = File::create(&args.quarantine_file)
+        .map_err(Into::into)
+        .and_then(|file| write_records(BufWriter::new(file), &pending))
+    {
+        eprintln!("Failed to write {}: {e}", args.quarantine_file);
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,33 @@
+use super::EXIT_IO_FAILURE;
+use crate::repl::{Query, SnapshotIndex};
+use crate::tranasction::snapshot::Snapshot;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct QueryArgs {
+    /// snapshot file name
+    snapshot: String,
+    /// query to run against the snapshot, e.g. "account 1" or "top 10 by held". Same as the queries of the repl
+    #[arg(required = true, num_args = 1.., trailing_var_arg = true)]
+    query: Vec<String>,
+}
+
+//run a single query so that the snapshot can be queried from scripts
+pub fn run(args: QueryArgs) -> ExitCode {
+    let query = match args.query.join(" ").parse::<Query>() {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let snapshot = match Snapshot::load(&args.snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Failed to load snapshot from {}: {e}", args.snapshot);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    println!("{}", SnapshotIndex::new(snapshot).execute(&query));
+    ExitCode::SUCCESS
+}
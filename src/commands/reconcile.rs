@@ -0,0 +1,42 @@
+use super::process::{run_pipeline, ProcessArgs};
+use super::EXIT_IO_FAILURE;
+use crate::reconcile::{diff_accounts, read_accounts};
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct ReconcileArgs {
+    #[command(flatten)]
+    process: ProcessArgs,
+    /// expected account report
+    expected_accounts: String,
+}
+
+//exit with 1 if there is any discrepancy so that it can be used to gate data migrations
+pub async fn run(args: ReconcileArgs) -> ExitCode {
+    let expected_accounts = &args.expected_accounts;
+    let expected = match read_accounts(expected_accounts) {
+        Ok(expected) => expected,
+        Err(e) => {
+            eprintln!("Failed to read expected accounts from {expected_accounts}: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let engine = match run_pipeline(&args.process).await {
+        Ok(engine) => engine,
+        Err(code) => return code,
+    };
+
+    let diffs = diff_accounts(&expected, engine.accounts());
+    if diffs.is_empty() {
+        println!("No discrepancy found");
+        return ExitCode::SUCCESS;
+    }
+    println!("client,field,expected,actual");
+    for diff in &diffs {
+        println!(
+            "{},{},{},{}",
+            diff.client, diff.field, diff.left, diff.right
+        );
+    }
+    ExitCode::FAILURE
+}
@@ -0,0 +1,15 @@
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct ReplArgs {
+    /// snapshot file name
+    snapshot: String,
+}
+
+pub fn run(args: ReplArgs) -> ExitCode {
+    if let Err(e) = crate::repl::run(&args.snapshot) {
+        eprintln!("Failed to run repl: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,62 @@
+use super::{EngineArgs, EXIT_INTEGRITY_FAILURE, EXIT_IO_FAILURE, EXIT_PARSE_FAILURE};
+use crate::tranasction::events::EventRecord;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct ReplayArgs {
+    /// ndjson event log written with --events
+    events_file: String,
+    #[command(flatten)]
+    engine: EngineArgs,
+    /// save the state of the engine to this file once the events are applied
+    #[arg(long)]
+    snapshot: Option<String>,
+}
+
+//Rebuild the state of an engine by applying an event log to a new engine, and write the accounts to stdout. The
+//events were all accepted by the engine that recorded them, so an event that is rejected means that the engine
+//options are not the same, and the process exits with 4. A line that isn't an event stops the replay with 3
+pub fn run(args: ReplayArgs) -> ExitCode {
+    let file = match File::open(&args.events_file) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to open {}: {e}", args.events_file);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let mut engine = TransactionEngine::with_config(args.engine.engine_config());
+    let mut rejected = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let event = match line
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(serde_json::from_str::<EventRecord>(&line)?))
+        {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Failed to read event on line {}: {e}", number + 1);
+                return ExitCode::from(EXIT_PARSE_FAILURE);
+            }
+        };
+        if !engine.replay(&event) {
+            rejected += 1;
+        }
+    }
+
+    if let Some(path) = &args.snapshot {
+        if let Err(e) = engine.snapshot().save(path) {
+            eprintln!("Failed to save snapshot to {path}: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    engine.output();
+    if rejected > 0 {
+        eprintln!(
+            "{rejected} events were rejected, the engine options differ from the recording run"
+        );
+        return ExitCode::from(EXIT_INTEGRITY_FAILURE);
+    }
+    ExitCode::SUCCESS
+}
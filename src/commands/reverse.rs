@@ -0,0 +1,54 @@
+use super::EXIT_IO_FAILURE;
+use crate::models::{parse_tx_id, TxId};
+use crate::tranasction::config::EngineConfig;
+use crate::tranasction::snapshot::Snapshot;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use smol_str::SmolStr;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct ReverseArgs {
+    /// snapshot file name, the snapshot is updated with the reversed transactions
+    snapshot: String,
+    /// tx ids of the deposits or withdrawals to reverse, e.g. 3,7
+    #[arg(required = true, value_delimiter = ',', value_parser = parse_tx_id)]
+    tx: Vec<TxId>,
+    /// ledger of the transactions, the default ledger if not set
+    #[arg(long, default_value = "")]
+    ledger: SmolStr,
+    /// write the updated snapshot to this file instead of updating the snapshot in place
+    #[arg(long)]
+    output: Option<String>,
+}
+
+//Reverse previously applied transactions of a snapshot with their inverse entries, and write the accounts of the
+//snapshot to stdout. Nothing is saved if a transaction can't be reversed
+pub fn run(args: ReverseArgs) -> ExitCode {
+    let mut snapshot = match Snapshot::load(&args.snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Failed to load snapshot from {}: {e}", args.snapshot);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    //the position is kept so that a run can still be resumed from the updated snapshot
+    let position = snapshot.position.take();
+    let mut engine = TransactionEngine::with_config(EngineConfig::default());
+    engine.restore(snapshot);
+    for tx in &args.tx {
+        if let Err(e) = engine.reverse(&args.ledger, *tx) {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut snapshot = engine.snapshot();
+    snapshot.position = position;
+    let path = args.output.as_deref().unwrap_or(&args.snapshot);
+    if let Err(e) = snapshot.save(path) {
+        eprintln!("Failed to save snapshot to {path}: {e}");
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+    engine.output();
+    ExitCode::SUCCESS
+}
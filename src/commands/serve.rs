@@ -0,0 +1,508 @@
+use super::{parse_rate, EngineArgs, CHANNEL_SIZE, EXIT_IO_FAILURE};
+use crate::admin::{AdminApi, AdminArgs, LogLevelHandle};
+use crate::models::Transaction;
+use crate::parser::csv_parser::CsvOptions;
+use crate::repl::{to_csv, Query};
+use crate::scheduler::{ScheduleArgs, Scheduler};
+use crate::tranasction::accounts_handle::AccountsHandle;
+use crate::tranasction::config::{RateLimit, RateLimitPolicy};
+use crate::tranasction::transaction_engine::TransactionEngine;
+use crate::webhook::{WebhookArgs, Webhooks};
+use serde::Deserialize;
+use smol_str::SmolStr;
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinSet;
+
+#[derive(clap::Args)]
+pub struct ServeArgs {
+    /// address to listen on
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    listen: String,
+    /// listen on this unix domain socket instead of tcp, for producers on the same host
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "listen")]
+    listen_uds: Option<PathBuf>,
+    /// how the rows, the queries and their answers are framed on a connection
+    #[arg(long, value_enum, default_value_t = Framing::Line)]
+    framing: Framing,
+    #[command(flatten)]
+    engine: EngineArgs,
+    /// max number of transactions per second of each client, the transactions over the limit are rejected or
+    /// deferred according to --rate-limit-policy
+    #[arg(long, value_parser = parse_rate)]
+    rate_limit: Option<f64>,
+    /// number of transactions a client can send at once before the rate limit applies, this is also the max number
+    /// of deferred transactions of a client
+    #[arg(long, default_value_t = 10, requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_burst: u32,
+    /// what happens to the transactions of a client that is over its rate limit
+    #[arg(long, value_enum, default_value_t = RateLimitPolicy::Reject, requires = "rate_limit")]
+    rate_limit_policy: RateLimitPolicy,
+    /// save the state of the engine to this file on shutdown
+    #[arg(long)]
+    snapshot: Option<String>,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    #[command(flatten)]
+    admin: AdminArgs,
+    #[command(flatten)]
+    webhooks: WebhookArgs,
+}
+
+//Tcp or unix domain socket listener, the connections of both speak the same protocol
+enum Listener {
+    Tcp(TcpListener),
+    //the number of accepted connections is kept to name the peers
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf, u64),
+}
+
+impl Listener {
+    async fn bind(args: &ServeArgs) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = &args.listen_uds {
+            //a socket file left by a previous run that wasn't shut down cleanly would make the bind fail, but a socket
+            //that still accepts connections belongs to a running server and is left alone
+            if std::fs::symlink_metadata(path)
+                .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type()))
+            {
+                match std::os::unix::net::UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use"))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        std::fs::remove_file(path)?
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(Listener::Unix(UnixListener::bind(path)?, path.clone(), 0));
+        }
+        Ok(Listener::Tcp(TcpListener::bind(&args.listen).await?))
+    }
+
+    //accept the next connection and handle it in its own task. Unix domain socket peers don't have an address, so
+    //they are named by their connection number in the logs
+    async fn accept(
+        &mut self,
+        connections: &mut JoinSet<()>,
+        framing: Framing,
+        tx: &Sender<Vec<Transaction>>,
+        accounts: &AccountsHandle,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                connections.spawn(handle_connection(
+                    reader,
+                    writer,
+                    peer.to_string(),
+                    framing,
+                    tx.clone(),
+                    accounts.clone(),
+                ));
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path, accepted) => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                *accepted += 1;
+                let peer = format!("{}#{accepted}", path.display());
+                connections.spawn(handle_connection(
+                    reader,
+                    writer,
+                    peer,
+                    framing,
+                    tx.clone(),
+                    accounts.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    //remove the socket file so that the next run can bind it
+    fn close(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path, _) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::error!("Failed to remove {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+//Accept transactions over tcp or a unix domain socket until ctrl-c is received. Every line is a csv row without header
+//in the order of type,client,tx,amount[,timestamp], or an "account <client>" or "locked" query which is answered on the
+//same connection with the current balances, --framing msgpack sends them as msgpack arrays and strings instead. A row
+//that fails to parse is answered with an error. The checkpoints are taken and rolled back to through the admin api, not
+//by the producers, so --checkpoint-records is refused. The scheduled actions, the requests of the admin api and the
+//webhooks of the disputes run in the meantime. The accounts are written to stdout on shutdown, once the last
+//notifications are sent
+pub async fn run(args: ServeArgs, log_level: LogLevelHandle) -> ExitCode {
+    if let Err(e) = args.engine.check_no_checkpoint_records("serve") {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let scheduler = match Scheduler::new(
+        &args.schedule,
+        args.snapshot.as_deref(),
+        args.engine.held_accrual_rate,
+    ) {
+        Ok(scheduler) => scheduler,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut listener = match Listener::bind(&args).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {e}", args.address());
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let admin = match AdminApi::bind(&args.admin, log_level, args.snapshot.as_deref()).await {
+        Ok(admin) => admin,
+        Err(e) => {
+            eprintln!("Failed to listen for admin requests: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let mut config = args.engine.engine_config();
+    config.rate_limit = args.rate_limit.map(|rate| RateLimit {
+        rate,
+        burst: args.rate_burst,
+        policy: args.rate_limit_policy,
+    });
+    //the notifications of the webhooks are derived from the audit records
+    let webhooks = Webhooks::new(&args.webhooks);
+    config.audit |= webhooks.is_some();
+    let mut transaction_engine = TransactionEngine::with_config(config);
+    let accounts = transaction_engine.accounts_handle();
+    let webhook_handle = webhooks.map(|webhooks| {
+        let (records, handle) = webhooks.spawn();
+        transaction_engine.audit_sender(records);
+        handle
+    });
+    let engine_handle = tokio::spawn(async move {
+        transaction_engine.run(rx).await;
+        transaction_engine
+    });
+    let scheduler_handle =
+        (!scheduler.is_empty()).then(|| tokio::spawn(scheduler.run(accounts.clone())));
+    let admin_handle = admin.map(|admin| tokio::spawn(admin.run(accounts.clone())));
+
+    let mut connections = JoinSet::new();
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept(&mut connections, args.framing, &tx, &accounts) => {
+                if let Err(e) = accepted {
+                    tracing::error!("Failed to accept connection: {e}");
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    //the engine finishes once all the senders are dropped
+    for handle in scheduler_handle.into_iter().chain(admin_handle) {
+        handle.abort();
+    }
+    connections.shutdown().await;
+    drop(tx);
+    listener.close();
+
+    let engine = match engine_handle.await {
+        Ok(engine) => engine,
+        Err(e) => {
+            tracing::error!("Transaction engine failed: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(handle) = webhook_handle {
+        if let Err(e) = handle.await {
+            tracing::error!("Webhooks failed: {e}");
+        }
+    }
+    if let Some(path) = &args.snapshot {
+        if let Err(e) = engine.snapshot().save(path) {
+            tracing::error!("Fail to save snapshot to {path}: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    if !args.engine.incremental {
+        engine.output();
+    }
+    ExitCode::SUCCESS
+}
+
+impl ServeArgs {
+    fn address(&self) -> String {
+        #[cfg(unix)]
+        if let Some(path) = &self.listen_uds {
+            return path.display().to_string();
+        }
+        self.listen.clone()
+    }
+}
+
+//Framing of the messages of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Framing {
+    //a headerless csv row or a query per line, the answers and errors are lines too
+    Line,
+    //a msgpack array with the fields of a row (strings, numbers or nil for an empty field) or a msgpack string with a
+    //query, the answers and errors are msgpack strings
+    Msgpack,
+}
+
+//largest msgpack message, a producer that sends a larger one is disconnected instead of being buffered
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+//Field of a msgpack row, the numbers are written like in a csv file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Field {
+    Text(SmolStr),
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+}
+
+impl Field {
+    fn to_csv(field: Option<Field>) -> String {
+        match field {
+            Some(Field::Text(text)) => text.trim().to_string(),
+            Some(Field::Unsigned(n)) => n.to_string(),
+            Some(Field::Signed(n)) => n.to_string(),
+            Some(Field::Float(n)) => n.to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+//a msgpack string is a query and a msgpack array is a row
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Query(String),
+    Row(Vec<Option<Field>>),
+}
+
+//A connection of a producer. The errors are logged with its peer so that a misbehaving producer can be found, and
+//written back on the connection so that the producer knows which of its rows are not applied
+struct Connection<W> {
+    writer: W,
+    peer: String,
+    framing: Framing,
+    //the rows have no header and are in the default column order
+    options: CsvOptions,
+    tx: Sender<Vec<Transaction>>,
+    accounts: AccountsHandle,
+}
+
+impl<W: AsyncWrite + Unpin> Connection<W> {
+    //the handlers return false once the connection can't be used any more
+    async fn query(&mut self, query: &str) -> bool {
+        let answer = self.answer(query.trim()).await;
+        self.reply(&answer).await
+    }
+
+    async fn transaction(&mut self, transaction: Result<Transaction, String>) -> bool {
+        match transaction {
+            Ok(transaction) => {
+                if let Err(e) = self.tx.send(vec![transaction]).await {
+                    tracing::error!(
+                        peer = self.peer,
+                        "Failed to send transaction to engine: {e}"
+                    );
+                    return false;
+                }
+                true
+            }
+            Err(e) => self.error(&format!("Failed to parse: {e}")).await,
+        }
+    }
+
+    async fn answer(&self, query: &str) -> String {
+        match query.parse::<Query>() {
+            Ok(Query::Account(client)) => match self.accounts.account(client).await {
+                Some(account) => to_csv([account]),
+                None => format!("Account {client} not found"),
+            },
+            Ok(Query::Locked) => {
+                let locked = self.accounts.locked().await;
+                if locked.is_empty() {
+                    "No locked account".to_string()
+                } else {
+                    to_csv(locked)
+                }
+            }
+            Ok(_) => "Only account and locked queries are supported".to_string(),
+            Err(e) => e,
+        }
+    }
+
+    //log the error and write it back, returns false if it can't be written
+    async fn error(&mut self, error: &str) -> bool {
+        tracing::error!(peer = self.peer, "{error}");
+        self.reply(&format!("error: {error}")).await
+    }
+
+    async fn reply(&mut self, answer: &str) -> bool {
+        let bytes = match self.framing {
+            Framing::Line => format!("{answer}\n").into_bytes(),
+            Framing::Msgpack => match rmp_serde::to_vec(answer) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    tracing::error!(peer = self.peer, "Failed to encode answer: {e}");
+                    return false;
+                }
+            },
+        };
+        if let Err(e) = self.writer.write_all(&bytes).await {
+            tracing::error!(peer = self.peer, "Failed to write to connection: {e}");
+            return false;
+        }
+        true
+    }
+}
+
+async fn handle_connection<R, W>(
+    reader: R,
+    writer: W,
+    peer: String,
+    framing: Framing,
+    tx: Sender<Vec<Transaction>>,
+    accounts: AccountsHandle,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    tracing::info!("Accepted connection from {peer}");
+    let mut connection = Connection {
+        writer,
+        peer,
+        framing,
+        options: CsvOptions {
+            has_headers: false,
+            ..Default::default()
+        },
+        tx,
+        accounts,
+    };
+    match framing {
+        Framing::Line => read_lines(reader, &mut connection).await,
+        Framing::Msgpack => read_msgpack(reader, &mut connection).await,
+    }
+}
+
+async fn read_lines<R, W>(reader: R, connection: &mut Connection<W>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let handled = match lines.next_line().await {
+            Ok(Some(line)) if line.trim().is_empty() => true,
+            Ok(Some(line)) if !line.contains(',') => connection.query(&line).await,
+            Ok(Some(line)) => {
+                let transaction = connection.options.parse_line(&line);
+                connection.transaction(transaction).await
+            }
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!(
+                    peer = connection.peer,
+                    "Failed to read from connection: {e}"
+                );
+                return;
+            }
+        };
+        if !handled {
+            return;
+        }
+    }
+}
+
+//The messages are decoded as soon as they are complete. A message that isn't an array or a string is skipped with
+//an error, but bytes that aren't msgpack can't be skipped since the next message can't be found, so the connection
+//is closed
+async fn read_msgpack<R, W>(mut reader: R, connection: &mut Connection<W>)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        loop {
+            let mut cursor = io::Cursor::new(&buf[..]);
+            let decoded = rmp_serde::from_read::<_, Message>(&mut cursor);
+            let consumed = cursor.position() as usize;
+            let handled = match decoded {
+                Ok(Message::Query(query)) => connection.query(&query).await,
+                Ok(Message::Row(fields)) => {
+                    let record = fields.into_iter().map(Field::to_csv).collect();
+                    let transaction = connection.options.parse_record(&record);
+                    connection.transaction(transaction).await
+                }
+                Err(e) if is_incomplete(&e) => break,
+                Err(rmp_serde::decode::Error::Syntax(e)) => {
+                    connection.error(&format!("Failed to parse: {e}")).await
+                }
+                Err(e) => {
+                    connection.error(&format!("Invalid msgpack: {e}")).await;
+                    return;
+                }
+            };
+            buf.drain(..consumed);
+            if !handled {
+                return;
+            }
+        }
+        if buf.len() > MAX_MESSAGE_SIZE {
+            connection
+                .error(&format!("Message larger than {MAX_MESSAGE_SIZE} bytes"))
+                .await;
+            return;
+        }
+        match reader.read_buf(&mut buf).await {
+            Ok(0) => {
+                if !buf.is_empty() {
+                    tracing::error!(peer = connection.peer, "Connection closed in a message");
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!(
+                    peer = connection.peer,
+                    "Failed to read from connection: {e}"
+                );
+                return;
+            }
+        }
+    }
+}
+
+//the buffer ends before the message does
+fn is_incomplete(e: &rmp_serde::decode::Error) -> bool {
+    match e {
+        rmp_serde::decode::Error::InvalidMarkerRead(e)
+        | rmp_serde::decode::Error::InvalidDataRead(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
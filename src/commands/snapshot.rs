@@ -0,0 +1,35 @@
+use super::EXIT_IO_FAILURE;
+use crate::tranasction::snapshot::Snapshot;
+use std::io::BufWriter;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct SnapshotArgs {
+    /// snapshot file name
+    snapshot: String,
+}
+
+//write the accounts saved in a snapshot to stdout, in the same format as the process command
+pub fn run(args: SnapshotArgs) -> ExitCode {
+    let mut snapshot = match Snapshot::load(&args.snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Failed to load snapshot from {}: {e}", args.snapshot);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    snapshot.accounts.sort_by_key(|account| account.client);
+
+    let mut wtr = csv::Writer::from_writer(BufWriter::new(std::io::stdout()));
+    for account in &snapshot.accounts {
+        if let Err(e) = wtr.serialize(account) {
+            eprintln!("Failed to write: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    if let Err(e) = wtr.flush() {
+        eprintln!("Failed to write: {e}");
+        return ExitCode::from(EXIT_IO_FAILURE);
+    }
+    ExitCode::SUCCESS
+}
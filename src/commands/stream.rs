@@ -0,0 +1,241 @@
+use super::{EngineArgs, EXIT_IO_FAILURE};
+use crate::admin::{AdminApi, AdminArgs, LogLevelHandle};
+use crate::models::{Account, Transaction};
+use crate::parser::csv_parser::CsvOptions;
+use crate::scheduler::{ScheduleArgs, Scheduler};
+use crate::tranasction::audit::AuditRecord;
+use crate::tranasction::transaction_engine::TransactionEngine;
+use crate::webhook::{WebhookArgs, Webhooks};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamId, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, RedisResult};
+use std::process::ExitCode;
+use tokio::sync::mpsc::Sender;
+
+#[derive(clap::Args)]
+pub struct StreamArgs {
+    /// url of the redis server
+    #[arg(long, default_value = "redis://127.0.0.1/")]
+    redis_url: String,
+    /// redis stream the transactions are read from, every entry has a row field with a csv row without header in the
+    /// order of type,client,tx,amount[,timestamp]
+    #[arg(long)]
+    stream: String,
+    /// consumer group of the stream, it is created at the start of the stream if it doesn't exist
+    #[arg(long, default_value = "toy_payment")]
+    group: String,
+    /// name of this consumer in the group
+    #[arg(long, default_value = "toy_payment")]
+    consumer: String,
+    /// max number of entries read at once
+    #[arg(long, default_value_t = 100)]
+    count: usize,
+    /// publish the accounts changed by each read to this redis stream
+    #[arg(long)]
+    updates_stream: Option<String>,
+    #[command(flatten)]
+    engine: EngineArgs,
+    /// save the state of the engine to this file on shutdown
+    #[arg(long)]
+    snapshot: Option<String>,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    #[command(flatten)]
+    admin: AdminArgs,
+    #[command(flatten)]
+    webhooks: WebhookArgs,
+}
+
+//Consume transactions from a redis stream with a consumer group until ctrl-c is received. The entries of a read are
+//acked once they are applied, so the entries that were read but not applied by a previous run are pending and are
+//applied first. An entry applied right before a crash is applied again, use --duplicate-policy idempotent-skip to
+//ignore the replay. The scheduled actions and the requests of the admin api run between two reads, and the webhooks
+//of the disputes are notified in the meantime. The accounts are written to stdout on shutdown
+pub async fn run(args: StreamArgs, log_level: LogLevelHandle) -> ExitCode {
+    if let Err(e) = args.engine.check_no_checkpoint_records("stream") {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let scheduler = match Scheduler::new(
+        &args.schedule,
+        args.snapshot.as_deref(),
+        args.engine.held_accrual_rate,
+    ) {
+        Ok(scheduler) => scheduler,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut admin = match AdminApi::bind(&args.admin, log_level, args.snapshot.as_deref()).await {
+        Ok(admin) => admin,
+        Err(e) => {
+            eprintln!("Failed to listen for admin requests: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let mut con = match connect(&args).await {
+        Ok(con) => con,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {e}", args.redis_url);
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    };
+    let mut config = args.engine.engine_config();
+    //the changed accounts are only tracked in incremental mode
+    config.incremental |= args.updates_stream.is_some();
+    //the notifications of the webhooks are derived from the audit records
+    let webhooks = Webhooks::new(&args.webhooks).map(Webhooks::spawn);
+    config.audit |= webhooks.is_some();
+    let mut engine = TransactionEngine::with_config(config);
+
+    let (records, webhook_handle) = webhooks.unzip();
+    let result = consume(
+        &args,
+        &mut con,
+        &mut engine,
+        &scheduler,
+        &mut admin,
+        records.as_ref(),
+    )
+    .await;
+    //the last notifications are sent once the channel is closed
+    drop(records);
+    if let Some(handle) = webhook_handle {
+        if let Err(e) = handle.await {
+            tracing::error!("Webhooks failed: {e}");
+        }
+    }
+    if let Err(e) = &result {
+        tracing::error!("Failed to consume {}: {e}", args.stream);
+    }
+    if let Some(path) = &args.snapshot {
+        if let Err(e) = engine.snapshot().save(path) {
+            tracing::error!("Fail to save snapshot to {path}: {e}");
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    if !args.engine.incremental {
+        engine.output();
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::from(EXIT_IO_FAILURE),
+    }
+}
+
+async fn connect(args: &StreamArgs) -> RedisResult<MultiplexedConnection> {
+    let mut con = redis::Client::open(args.redis_url.as_str())?
+        .get_multiplexed_tokio_connection()
+        .await?;
+    let created: RedisResult<()> = con
+        .xgroup_create_mkstream(&args.stream, &args.group, "0")
+        .await;
+    match created {
+        Err(e) if e.code() != Some("BUSYGROUP") => Err(e),
+        _ => Ok(con),
+    }
+}
+
+//read the pending entries of the consumer, then the new entries, until ctrl-c is received
+async fn consume(
+    args: &StreamArgs,
+    con: &mut MultiplexedConnection,
+    engine: &mut TransactionEngine,
+    scheduler: &Scheduler,
+    admin: &mut Option<AdminApi>,
+    records: Option<&Sender<AuditRecord>>,
+) -> RedisResult<()> {
+    let options = CsvOptions {
+        has_headers: false,
+        ..Default::default()
+    };
+    let read_options = StreamReadOptions::default()
+        .group(&args.group, &args.consumer)
+        .count(args.count)
+        .block(1000);
+    //"0" reads the entries delivered to this consumer that are not acked, ">" the entries never delivered
+    let mut cursor = "0";
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    let keys = [&args.stream];
+    //kept across the reads, a new wait would miss a schedule that is due while a read returns
+    let next_schedule = scheduler.next();
+    tokio::pin!(next_schedule);
+    loop {
+        //the records of the last read or of the last scheduled sweep. The stream waits for the webhooks instead of
+        //dropping the records since the entries stay in redis until they are read
+        if let Some(records) = records {
+            for record in engine.take_audit_records() {
+                if records.send(record).await.is_err() {
+                    tracing::error!("Webhooks have stopped");
+                }
+            }
+        }
+        let ids = [cursor];
+        let reply: StreamReadReply = tokio::select! {
+            reply = con.xread_options(&keys, &ids, &read_options) => reply?,
+            (at, actions) = &mut next_schedule => {
+                scheduler.run_actions(at, &actions, engine).await;
+                next_schedule.set(scheduler.next());
+                continue;
+            }
+            request = AdminApi::recv(admin) => {
+                if let Some(admin) = admin {
+                    admin.apply(request, engine).await;
+                }
+                continue;
+            }
+            _ = &mut shutdown => return Ok(()),
+        };
+        let entries = reply
+            .keys
+            .into_iter()
+            .flat_map(|key| key.ids)
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            cursor = ">";
+            continue;
+        }
+        for entry in &entries {
+            match parse_entry(&options, entry) {
+                Ok(transaction) => {
+                    engine.process_transaction(transaction);
+                }
+                //an entry that can't be parsed is acked too, it would be read again forever otherwise
+                Err(e) => tracing::error!(id = entry.id, "Failed to parse: {e}"),
+            }
+        }
+        let ids = entries.iter().map(|entry| &entry.id).collect::<Vec<_>>();
+        let _: () = con.xack(&args.stream, &args.group, &ids).await?;
+        if let Some(updates) = &args.updates_stream {
+            for (ledger, account) in engine.take_changed_accounts() {
+                let _: () = con
+                    .xadd(updates, "*", &update_fields(&ledger, &account))
+                    .await?;
+            }
+        }
+    }
+}
+
+fn parse_entry(options: &CsvOptions, entry: &StreamId) -> Result<Transaction, String> {
+    let row = entry
+        .get::<String>("row")
+        .ok_or_else(|| "Missing row field".to_string())?;
+    options.parse_line(&row)
+}
+
+//fields of an account update, the ledger is only set for the accounts of a named ledger
+fn update_fields(ledger: &str, account: &Account) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("client", account.client.to_string()),
+        ("available", account.available.to_string()),
+        ("held", account.held.to_string()),
+        ("total", account.total.to_string()),
+        ("locked", account.locked.to_string()),
+    ];
+    if !ledger.is_empty() {
+        fields.push(("ledger", ledger.to_string()));
+    }
+    fields
+}
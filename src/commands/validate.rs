@@ -0,0 +1,39 @@
+use super::{InputArgs, CHANNEL_SIZE, EXIT_IO_FAILURE, EXIT_PARSE_FAILURE};
+use crate::parser::csv_parser::CsvParser;
+use std::process::ExitCode;
+use tokio::sync::mpsc;
+
+#[derive(clap::Args)]
+pub struct ValidateArgs {
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+//parse the csv file without processing the transactions. The rows that can't be parsed are logged and the process
+//exits with 3 if there is any
+pub async fn run(args: ValidateArgs) -> ExitCode {
+    let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE);
+    let input_file = args.input.input_file.clone().unwrap_or_default();
+    let mut parser = CsvParser::new(input_file, args.input.csv_options(), tx);
+
+    let drain_handle = tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    let stats = parser.run().await;
+    //drop the parser to close the channel
+    drop(parser);
+    let _ = drain_handle.await;
+
+    match stats {
+        Ok(stats) => {
+            println!("rows,failed\n{},{}", stats.rows, stats.failed);
+            if stats.failed > 0 {
+                ExitCode::from(EXIT_PARSE_FAILURE)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::from(EXIT_IO_FAILURE)
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//The models and the transaction engine are the core of the crate, they don't depend on tokio, clap or the file
+//system so that they can be compiled to wasm. The async engine loop is behind the runtime feature and the command
+//line tool behind the cli feature
+#[cfg(feature = "cli")]
+pub mod admin;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "cli")]
+pub mod commands;
+pub mod models;
+pub mod parser;
+#[cfg(feature = "cli")]
+pub mod reconcile;
+#[cfg(feature = "cli")]
+pub mod repl;
+#[cfg(feature = "cli")]
+pub mod scheduler;
+#[cfg(feature = "runtime")]
+pub mod timing;
+pub mod tranasction;
+#[cfg(feature = "cli")]
+pub mod tui;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "cli")]
+pub mod webhook;
@@ -1,42 +1,113 @@
-use crate::parser::csv_parser::CsvParser;
-use clap::Parser;
-use futures_util::future::join_all;
-use tokio::sync::mpsc;
-use tranasction::transaction_engine::TransactionEngine;
-
-mod models;
-mod parser;
-mod tranasction;
-
-//channel size should be configured based on benchmarking
-const CHANNEL_SIZE: usize = 10000;
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+use toy_payment::admin::LogLevelHandle;
+use toy_payment::commands;
+use toy_payment::commands::batch::BatchArgs;
+use toy_payment::commands::diff::DiffArgs;
+use toy_payment::commands::generate::GenerateArgs;
+use toy_payment::commands::process::ProcessArgs;
+use toy_payment::commands::quarantine::QuarantineArgs;
+use toy_payment::commands::query::QueryArgs;
+use toy_payment::commands::reconcile::ReconcileArgs;
+use toy_payment::commands::repl::ReplArgs;
+use toy_payment::commands::replay::ReplayArgs;
+use toy_payment::commands::reverse::ReverseArgs;
+use toy_payment::commands::serve::ServeArgs;
+use toy_payment::commands::snapshot::SnapshotArgs;
+#[cfg(feature = "redis")]
+use toy_payment::commands::stream::StreamArgs;
+use toy_payment::commands::validate::ValidateArgs;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload};
 
+//Without a subcommand, the arguments are the ones of the process command so that "toy_payment file.csv" still works
 #[derive(Parser)]
-#[command(about, long_about = None)]
+#[command(about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// csv file name
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+    /// format of the log file
+    #[arg(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 }
 
-#[tokio::main]
-async fn main() {
-    let file_appender = tracing_appender::rolling::hourly("logs/", "toy_payment_log.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    tracing_subscriber::fmt().with_writer(non_blocking).init();
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    /// one json object per line with the fields of the event, e.g. client, tx and type
+    Json,
+}
 
-    let args = Args::parse();
-    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+#[derive(Subcommand)]
+enum Command {
+    /// process the csv file and write the accounts to stdout, this is the default command
+    Process(ProcessArgs),
+    /// process independent csv files, several at a time, and write the accounts of each one to its own report
+    Batch(BatchArgs),
+    /// check that every row of the csv file can be parsed without processing the transactions
+    Validate(ValidateArgs),
+    /// generate a random transaction file to stdout
+    Generate(GenerateArgs),
+    /// accept transactions over tcp and write the accounts to stdout on ctrl-c
+    Serve(ServeArgs),
+    /// consume transactions from a redis stream and write the accounts to stdout on ctrl-c
+    #[cfg(feature = "redis")]
+    Stream(StreamArgs),
+    /// write the accounts saved in a snapshot to stdout
+    Snapshot(SnapshotArgs),
+    /// run a single query against a saved snapshot
+    Query(QueryArgs),
+    /// query a saved snapshot interactively
+    Repl(ReplArgs),
+    /// process the csv file and compare the accounts with an expected account report
+    Reconcile(ReconcileArgs),
+    /// compare the accounts of two account reports or snapshots
+    Diff(DiffArgs),
+    /// release or reject transactions parked by the quarantine rules
+    Quarantine(QuarantineArgs),
+    /// reverse deposits or withdrawals saved in a snapshot, e.g. to correct a deposit entered with a wrong amount
+    Reverse(ReverseArgs),
+    /// rebuild the accounts by applying an event log written with --events to a new engine
+    Replay(ReplayArgs),
+}
 
-    let mut parser = CsvParser::new(args.input_file, tx);
-    let mut transaction_engine = TransactionEngine::new(rx);
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
 
-    let mut handles = vec![];
-    handles.push(tokio::spawn(async move {
-        parser.run().await;
-    }));
-    handles.push(tokio::spawn(async move {
-        transaction_engine.run().await;
-    }));
+    let file_appender = tracing_appender::rolling::hourly("logs/", "toy_payment_log.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    //the level can be changed while running with the admin api of the long-running commands
+    let (level, log_level): (_, LogLevelHandle) = reload::Layer::new(LevelFilter::INFO);
+    let layer = fmt::layer().with_writer(non_blocking);
+    let layer = match args.log_format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(level)
+        .with(layer)
+        .init();
 
-    let _ = join_all(handles).await;
+    match args.command {
+        None => commands::process::run(args.process).await,
+        Some(Command::Process(args)) => commands::process::run(args).await,
+        Some(Command::Batch(args)) => commands::batch::run(args).await,
+        Some(Command::Validate(args)) => commands::validate::run(args).await,
+        Some(Command::Generate(args)) => commands::generate::run(args),
+        Some(Command::Serve(args)) => commands::serve::run(args, log_level).await,
+        #[cfg(feature = "redis")]
+        Some(Command::Stream(args)) => commands::stream::run(args, log_level).await,
+        Some(Command::Snapshot(args)) => commands::snapshot::run(args),
+        Some(Command::Query(args)) => commands::query::run(args),
+        Some(Command::Repl(args)) => commands::repl::run(args),
+        Some(Command::Reconcile(args)) => commands::reconcile::run(args).await,
+        Some(Command::Diff(args)) => commands::diff::run(args),
+        Some(Command::Quarantine(args)) => commands::quarantine::run(args),
+        Some(Command::Reverse(args)) => commands::reverse::run(args),
+        Some(Command::Replay(args)) => commands::replay::run(args),
+    }
 }
@@ -1,6 +1,46 @@
 use serde::{de, Serialize};
 use serde::{Deserialize, Deserializer};
 use smol_str::{SmolStr, StrExt};
+use std::num::IntErrorKind;
+use std::sync::Arc;
+
+//Id of a transaction. It is a u32 unless the u64-tx-ids feature is enabled, for upstream systems whose ids don't fit
+#[cfg(not(feature = "u64-tx-ids"))]
+pub type TxId = u32;
+#[cfg(feature = "u64-tx-ids")]
+pub type TxId = u64;
+
+//an id that is too large for the id width is reported with the max id, so that it isn't mistaken for a typo
+pub fn parse_tx_id(s: &str) -> Result<TxId, String> {
+    s.parse().map_err(|e: std::num::ParseIntError| {
+        if *e.kind() == IntErrorKind::PosOverflow {
+            format!(
+                "tx id {s} is out of range, the max is {} ({} bit ids)",
+                TxId::MAX,
+                TxId::BITS
+            )
+        } else {
+            format!("invalid tx id {s}: {e}")
+        }
+    })
+}
+
+//largest amount, and balance, whose 4 decimal places are all exact in a f64 (2^53 / 10^4)
+pub const MAX_AMOUNT: f64 = 900_719_925_474.099_1;
+
+//amounts are rounded to 4 decimal places. inf, nan and the amounts past MAX_AMOUNT are rejected instead of being
+//saturated, so that an adversarial row can't poison the balances
+pub fn parse_amount(s: &str) -> Result<f64, String> {
+    let amount = s
+        .parse::<f64>()
+        .map_err(|e| format!("invalid amount {s}: {e}"))?;
+    if !amount.is_finite() || amount.abs() > MAX_AMOUNT {
+        return Err(format!(
+            "amount {s} is out of range, the max is {MAX_AMOUNT}"
+        ));
+    }
+    Ok((amount * 10_000.0).round() / 10_000.0)
+}
 
 //Type of the transactions
 #[derive(Debug, PartialEq)]
@@ -10,7 +50,23 @@ pub enum Transaction {
     Dispute(TransactionDetail),
     Resolve(TransactionDetail),
     ChargeBack(TransactionDetail),
-    Unknown,
+    //returns funds of an earlier deposit, the tx is the one of the deposit
+    Refund(TransactionDetail),
+    //two-phase payment: an authorization holds the funds and a later capture settles them
+    Authorize(TransactionDetail),
+    Capture(TransactionDetail),
+    //control record that asks the engine to write the accounts changed since the last flush, it has no client or tx
+    Flush,
+    //control records that save the state of the engine under a name, and roll it back to that state so that a
+    //corrected tail of the input can be applied again. The name is in the client column
+    Checkpoint(SmolStr),
+    Rollback(SmolStr),
+    //control record with the balances the account of a client must have at this point of the input, e.g. the control
+    //totals of a partner
+    AssertBalance(BalanceAssertion),
+    //row of a type the engine doesn't know, with its lowercase type and the fields as read. It is only applied if a
+    //handler is registered for the type
+    Unknown(SmolStr, TransactionDetail, RawRecord),
 }
 
 //customer deserailizer to deserialzie each entry into the Transaction enum
@@ -24,66 +80,353 @@ impl<'de> Deserialize<'de> for Transaction {
             .first()
             .ok_or(serde::de::Error::custom("Cannot find type"))?
             .to_lowercase_smolstr();
+        if r#type == "flush" {
+            return Ok(Transaction::Flush);
+        }
+        if r#type == "checkpoint" || r#type == "rollback" {
+            let name = s
+                .get(1)
+                .filter(|name| !name.is_empty())
+                .cloned()
+                .ok_or(serde::de::Error::custom("Cannot find checkpoint name"))?;
+            return Ok(if r#type == "checkpoint" {
+                Transaction::Checkpoint(name)
+            } else {
+                Transaction::Rollback(name)
+            });
+        }
+        if r#type == "assert_balance" {
+            return BalanceAssertion::from_fields(&s)
+                .map(Transaction::AssertBalance)
+                .map_err(de::Error::custom);
+        }
         let client: u16 = s
             .get(1)
             .ok_or(serde::de::Error::custom("Cannot find client"))?
             .parse()
             .map_err(de::Error::custom)?;
-        let tx: u32 = s
-            .get(2)
-            .ok_or(serde::de::Error::custom("Cannot find tx"))?
-            .parse()
+        let tx = parse_tx_id(s.get(2).ok_or(serde::de::Error::custom("Cannot find tx"))?)
             .map_err(de::Error::custom)?;
-        //round to 4 decimal places
+        //the amount and the timestamp of a type the engine doesn't know are left to its handler, which gets them as
+        //read when they don't parse
+        let known = Transaction::is_known_type(&r#type);
         let amount: Option<f64> = match s.get(3) {
-            Some(amount) if !amount.is_empty() => Some(
-                (amount.parse::<f64>().map_err(de::Error::custom)? * 10_000.0).round() / 10_000.0,
-            ),
+            Some(amount) if !amount.is_empty() => match parse_amount(amount) {
+                Ok(amount) => Some(amount),
+                Err(_) if !known => None,
+                Err(e) => return Err(de::Error::custom(e)),
+            },
+            _ => None,
+        };
+
+        let timestamp: Option<u64> = match s.get(4) {
+            Some(timestamp) if !timestamp.is_empty() => match timestamp.parse() {
+                Ok(timestamp) => Some(timestamp),
+                Err(_) if !known => None,
+                Err(e) => return Err(de::Error::custom(e)),
+            },
             _ => None,
         };
 
-        let t = TransactionDetail::new(client, tx, amount);
-        Ok(match r#type.as_str() {
+        let reference = s.get(5).filter(|reference| !reference.is_empty()).cloned();
+        let currency = s.get(6).filter(|currency| !currency.is_empty()).cloned();
+        let ledger = s.get(7).filter(|ledger| !ledger.is_empty()).cloned();
+
+        let mut t = TransactionDetail::new(client, tx, amount);
+        t.timestamp = timestamp;
+        t.reference = reference;
+        t.currency = currency;
+        t.ledger = ledger;
+        let mut transaction = Transaction::from_type(r#type, t);
+        if let Transaction::Unknown(_, _, raw) = &mut transaction {
+            *raw = RawRecord::new(None, s);
+        }
+        Ok(transaction)
+    }
+}
+
+//Fields of a row of a type the engine doesn't know, as read, so that its handler can use the columns the engine
+//doesn't parse. The headers are the ones of the input, None if it has none, e.g. a row of serve. The fields of the
+//rows rebuilt from an event log are empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RawRecord {
+    headers: Option<Arc<[SmolStr]>>,
+    fields: Vec<SmolStr>,
+}
+
+impl RawRecord {
+    pub fn new(headers: Option<Arc<[SmolStr]>>, fields: Vec<SmolStr>) -> Self {
+        Self { headers, fields }
+    }
+
+    //field of the column with this header, the header is case insensitive
+    pub fn get(&self, header: &str) -> Option<&str> {
+        let position = self
+            .headers
+            .as_deref()?
+            .iter()
+            .position(|h| h.eq_ignore_ascii_case(header))?;
+        self.field(position)
+    }
+
+    pub fn field(&self, position: usize) -> Option<&str> {
+        self.fields.get(position).map(SmolStr::as_str)
+    }
+
+    pub fn fields(&self) -> &[SmolStr] {
+        &self.fields
+    }
+}
+
+//Type of the transaction without the detail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    #[cfg_attr(feature = "cli", value(name = "chargeback"))]
+    ChargeBack,
+    Refund,
+    Authorize,
+    Capture,
+}
+
+impl Transaction {
+    //transaction of a row of this lowercase type, Unknown if the engine doesn't know the type
+    pub fn from_type(r#type: SmolStr, t: TransactionDetail) -> Self {
+        match r#type.as_str() {
             "deposit" => Transaction::Deposit(t),
             "withdrawal" => Transaction::Withdrawal(t),
             "dispute" => Transaction::Dispute(t),
             "resolve" => Transaction::Resolve(t),
             "chargeback" => Transaction::ChargeBack(t),
-            _ => Transaction::Unknown,
+            "refund" => Transaction::Refund(t),
+            "authorize" => Transaction::Authorize(t),
+            "capture" => Transaction::Capture(t),
+            _ => Transaction::Unknown(r#type, t, RawRecord::default()),
+        }
+    }
+
+    fn is_known_type(r#type: &str) -> bool {
+        matches!(
+            r#type,
+            "deposit"
+                | "withdrawal"
+                | "dispute"
+                | "resolve"
+                | "chargeback"
+                | "refund"
+                | "authorize"
+                | "capture"
+        )
+    }
+
+    //lowercase type of the row, as written in the input
+    pub fn type_name(&self) -> &str {
+        match self {
+            Transaction::Deposit(_) => "deposit",
+            Transaction::Withdrawal(_) => "withdrawal",
+            Transaction::Dispute(_) => "dispute",
+            Transaction::Resolve(_) => "resolve",
+            Transaction::ChargeBack(_) => "chargeback",
+            Transaction::Refund(_) => "refund",
+            Transaction::Authorize(_) => "authorize",
+            Transaction::Capture(_) => "capture",
+            Transaction::Flush => "flush",
+            Transaction::Checkpoint(_) => "checkpoint",
+            Transaction::Rollback(_) => "rollback",
+            Transaction::AssertBalance(_) => "assert_balance",
+            Transaction::Unknown(r#type, ..) => r#type,
+        }
+    }
+
+    pub fn detail(&self) -> Option<&TransactionDetail> {
+        match self {
+            Transaction::Deposit(t)
+            | Transaction::Withdrawal(t)
+            | Transaction::Dispute(t)
+            | Transaction::Resolve(t)
+            | Transaction::ChargeBack(t)
+            | Transaction::Refund(t)
+            | Transaction::Authorize(t)
+            | Transaction::Capture(t)
+            | Transaction::Unknown(_, t, _) => Some(t),
+            Transaction::Flush
+            | Transaction::Checkpoint(_)
+            | Transaction::Rollback(_)
+            | Transaction::AssertBalance(_) => None,
+        }
+    }
+
+    pub fn detail_mut(&mut self) -> Option<&mut TransactionDetail> {
+        match self {
+            Transaction::Deposit(t)
+            | Transaction::Withdrawal(t)
+            | Transaction::Dispute(t)
+            | Transaction::Resolve(t)
+            | Transaction::ChargeBack(t)
+            | Transaction::Refund(t)
+            | Transaction::Authorize(t)
+            | Transaction::Capture(t)
+            | Transaction::Unknown(_, t, _) => Some(t),
+            Transaction::Flush
+            | Transaction::Checkpoint(_)
+            | Transaction::Rollback(_)
+            | Transaction::AssertBalance(_) => None,
+        }
+    }
+
+    pub fn transaction_type(&self) -> Option<TransactionType> {
+        match self {
+            Transaction::Deposit(_) => Some(TransactionType::Deposit),
+            Transaction::Withdrawal(_) => Some(TransactionType::Withdrawal),
+            Transaction::Dispute(_) => Some(TransactionType::Dispute),
+            Transaction::Resolve(_) => Some(TransactionType::Resolve),
+            Transaction::ChargeBack(_) => Some(TransactionType::ChargeBack),
+            Transaction::Refund(_) => Some(TransactionType::Refund),
+            Transaction::Authorize(_) => Some(TransactionType::Authorize),
+            Transaction::Capture(_) => Some(TransactionType::Capture),
+            Transaction::Flush
+            | Transaction::Checkpoint(_)
+            | Transaction::Rollback(_)
+            | Transaction::AssertBalance(_)
+            | Transaction::Unknown(..) => None,
+        }
+    }
+}
+
+//Expected balances of an assert_balance record. They are in the amount column as available/held/total, and a
+//balance that is left empty isn't checked, e.g. assert_balance,1,,//10.5 only checks the total of client 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceAssertion {
+    pub client: u16,
+    pub available: Option<f64>,
+    pub held: Option<f64>,
+    pub total: Option<f64>,
+    pub ledger: Option<SmolStr>,
+}
+
+impl BalanceAssertion {
+    //fields of the row in the canonical order type,client,tx,amount,timestamp,reference,currency,ledger
+    fn from_fields(s: &[SmolStr]) -> Result<Self, String> {
+        let client = s
+            .get(1)
+            .ok_or("Cannot find client")?
+            .parse()
+            .map_err(|e| format!("invalid client: {e}"))?;
+        let expected = s.get(3).ok_or("Cannot find expected balances")?;
+        let balances = expected
+            .split('/')
+            .map(|balance| match balance.trim() {
+                "" => Ok(None),
+                balance => parse_amount(balance).map(Some),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let [available, held, total] = balances[..] else {
+            return Err(format!(
+                "expected balances available/held/total, got {expected}"
+            ));
+        };
+        if available.is_none() && held.is_none() && total.is_none() {
+            return Err("Cannot find expected balances".to_string());
+        }
+        Ok(Self {
+            client,
+            available,
+            held,
+            total,
+            ledger: s.get(7).filter(|ledger| !ledger.is_empty()).cloned(),
         })
     }
 }
 
 //State of the transaction. Normal is either Deposit or Withdrawl that do not have any dispute
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum TranactionState {
     Normal,
     Dispute,
     Resolve,
     ChargeBack,
+    //states of an authorization, the held funds are either settled by a capture or released when it expires
+    Authorized,
+    Captured,
+    Released,
+    //a deposit or withdrawal cancelled by an operator with its inverse entry, it can't be disputed or refunded
+    Reversed,
 }
 
 //Detail of the transaction
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TransactionDetail {
     pub client: u16,
-    pub tx: u32,
+    pub tx: TxId,
     pub amount: Option<f64>,
     pub state: TranactionState,
+    //number of times the transaction has been disputed again after being resolved
+    #[serde(default)]
+    pub redisputes: u32,
+    //optional unix timestamp (in seconds) of the transaction
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    //time when the current dispute was opened, used to auto-resolve disputes that are not decided in time
+    #[serde(default)]
+    pub disputed_at: Option<u64>,
+    //time until which the interest or penalty of the current dispute is already settled by an interest run, the
+    //accrual starts from disputed_at otherwise
+    #[serde(default)]
+    pub accrued_until: Option<u64>,
+    //amount of a deposit that has been refunded so far
+    #[serde(default)]
+    pub refunded: f64,
+    //free-form reference of the row, e.g. the bank reference used for reconciliation
+    #[serde(default)]
+    pub reference: Option<SmolStr>,
+    //optional currency code of the amount, only checked by the validation rules
+    #[serde(default)]
+    pub currency: Option<SmolStr>,
+    //ledger (tenant) the transaction belongs to, the accounts and transactions of each ledger are kept apart
+    #[serde(default)]
+    pub ledger: Option<SmolStr>,
 }
 
 impl TransactionDetail {
-    pub fn new(client: u16, tx: u32, amount: Option<f64>) -> Self {
+    pub fn new(client: u16, tx: TxId, amount: Option<f64>) -> Self {
         Self {
             client,
             tx,
             amount,
             state: TranactionState::Normal,
+            redisputes: 0,
+            timestamp: None,
+            disputed_at: None,
+            accrued_until: None,
+            refunded: 0.0,
+            reference: None,
+            currency: None,
+            ledger: None,
         }
     }
+
+    //amount a dispute holds and a chargeback takes back: what is left of the transaction once its refunds are
+    //returned, rounded to the 4 decimal places of the amounts
+    pub fn disputable_amount(&self) -> Option<f64> {
+        self.amount
+            .map(|amount| ((amount - self.refunded) * 10_000.0).round() / 10_000.0)
+    }
 }
 
-#[derive(Default, Clone, Serialize, Debug)]
+//Position in the input file right after the last processed row, used to resume an interrupted run
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FilePosition {
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize, Debug)]
 pub struct Account {
     pub client: u16,
     pub available: f64,
@@ -103,13 +446,57 @@ impl Account {
 
 #[cfg(test)]
 mod test {
+    use crate::models::{parse_amount, parse_tx_id, BalanceAssertion, TxId, MAX_AMOUNT};
     use crate::models::{
-        Transaction,
-        Transaction::{ChargeBack, Deposit, Dispute, Resolve, Unknown, Withdrawal},
+        RawRecord, Transaction,
+        Transaction::{ChargeBack, Deposit, Dispute, Refund, Resolve, Unknown, Withdrawal},
         TransactionDetail,
     };
     use csv::ReaderBuilder;
 
+    #[test]
+    fn tx_id_range() {
+        assert_eq!(parse_tx_id("4294967295"), Ok(4294967295));
+        assert!(parse_tx_id("-1")
+            .unwrap_err()
+            .starts_with("invalid tx id -1"));
+        let too_large = (TxId::MAX as u128 + 1).to_string();
+        assert_eq!(
+            parse_tx_id(&too_large).unwrap_err(),
+            format!(
+                "tx id {too_large} is out of range, the max is {} ({} bit ids)",
+                TxId::MAX,
+                TxId::BITS
+            )
+        );
+
+        let data = format!("type,client,tx,amount\ndeposit,1,{too_large},1.0\n");
+        let mut rdr = ReaderBuilder::new().from_reader(data.as_bytes());
+        let e = rdr
+            .deserialize::<Transaction>()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("is out of range"));
+    }
+
+    #[test]
+    fn amount_range() {
+        assert_eq!(parse_amount("1.23456"), Ok(1.2346));
+        assert_eq!(parse_amount("900719925474.0991"), Ok(MAX_AMOUNT));
+        for amount in ["900719925475", "1e400", "inf", "-inf", "NaN"] {
+            assert_eq!(
+                parse_amount(amount),
+                Err(format!(
+                    "amount {amount} is out of range, the max is {MAX_AMOUNT}"
+                ))
+            );
+        }
+        assert!(parse_amount("1.2.3")
+            .unwrap_err()
+            .starts_with("invalid amount 1.2.3"));
+    }
+
     #[test]
     fn deserialize_fail() {
         //invalid transaction type
@@ -123,7 +510,34 @@ d,0,0,1.1
             .from_reader(data.as_bytes());
 
         let tx = rdr.deserialize::<Transaction>().next().unwrap().unwrap();
-        assert_eq!(tx, Unknown);
+        assert_eq!(
+            tx,
+            Unknown(
+                "d".into(),
+                TransactionDetail::new(0, 0, Some(1.1)),
+                RawRecord::new(None, vec!["d".into(), "0".into(), "0".into(), "1.1".into()])
+            )
+        );
+
+        //the amount of a type the engine doesn't know is left to its handler when it doesn't parse, but not the one
+        //of a deposit
+        let data = "\
+type,client,tx,amount
+bonus,1,2,50pts
+deposit,1,3,50pts
+";
+
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        let mut rows = rdr.deserialize::<Transaction>();
+        let Unknown(_, detail, raw) = rows.next().unwrap().unwrap() else {
+            panic!("expected an unknown transaction");
+        };
+        assert_eq!(detail, TransactionDetail::new(1, 2, None));
+        assert_eq!(raw.field(3), Some("50pts"));
+        assert!(rows.next().unwrap().is_err());
 
         //invalid number of fields
         let data = "\
@@ -166,6 +580,105 @@ deposit,0,0,101.111111
         assert_eq!(tx, Deposit(TransactionDetail::new(0, 0, Some(101.1111))));
     }
 
+    #[test]
+    fn deserialize_timestamp() {
+        let data = "\
+type,client,tx,amount,timestamp
+deposit,0,0,1,1700000000
+dispute,0,0,,1700000001
+resolve,0,0,,
+";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        let timestamps = rdr
+            .deserialize::<Transaction>()
+            .map(|tx| tx.unwrap().detail().unwrap().timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![Some(1700000000), Some(1700000001), None]);
+    }
+
+    #[test]
+    fn deserialize_flush() {
+        let data = "\
+type,client,tx,amount
+flush,,,
+FLUSH
+";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        for result in rdr.deserialize::<Transaction>() {
+            assert_eq!(result.unwrap(), Transaction::Flush);
+        }
+    }
+
+    #[test]
+    fn deserialize_checkpoint() {
+        let data = "\
+type,client,tx,amount
+checkpoint,segment-5,,
+ROLLBACK,segment-5
+rollback,,,
+";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        let mut txs = rdr.deserialize::<Transaction>();
+        assert_eq!(
+            txs.next().unwrap().unwrap(),
+            Transaction::Checkpoint("segment-5".into())
+        );
+        assert_eq!(
+            txs.next().unwrap().unwrap(),
+            Transaction::Rollback("segment-5".into())
+        );
+        assert!(txs.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn deserialize_assert_balance() {
+        let data = "\
+type,client,tx,amount,timestamp,reference,currency,ledger
+assert_balance,1,,10.5/0/10.5,,,,acme
+ASSERT_BALANCE,2,,//3
+assert_balance,3,,10.5/0
+assert_balance,3,,//
+assert_balance,,,1/1/2
+";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        let mut txs = rdr.deserialize::<Transaction>();
+        assert_eq!(
+            txs.next().unwrap().unwrap(),
+            Transaction::AssertBalance(BalanceAssertion {
+                client: 1,
+                available: Some(10.5),
+                held: Some(0.0),
+                total: Some(10.5),
+                ledger: Some("acme".into()),
+            })
+        );
+        assert_eq!(
+            txs.next().unwrap().unwrap(),
+            Transaction::AssertBalance(BalanceAssertion {
+                client: 2,
+                available: None,
+                held: None,
+                total: Some(3.0),
+                ledger: None,
+            })
+        );
+        for result in txs {
+            assert!(result.is_err());
+        }
+    }
+
     #[test]
     fn deserialize_withdraw() {
         let data = "\
@@ -221,4 +734,28 @@ chargeback,0,0
         let tx = rdr.deserialize::<Transaction>().next().unwrap().unwrap();
         assert_eq!(tx, ChargeBack(TransactionDetail::new(0, 0, None)));
     }
+
+    #[test]
+    fn deserialize_refund() {
+        let data = "\
+type,client,tx,amount
+refund,0,0,1.5
+refund,0,1
+";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+
+        let txs = rdr
+            .deserialize::<Transaction>()
+            .map(|tx| tx.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            txs,
+            vec![
+                Refund(TransactionDetail::new(0, 0, Some(1.5))),
+                Refund(TransactionDetail::new(0, 1, None))
+            ]
+        );
+    }
 }
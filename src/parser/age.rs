@@ -0,0 +1,228 @@
+use super::manifest::HashingReader;
+use anyhow::{anyhow, bail, Context};
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::JoinHandle;
+
+//the inputs are decrypted by the age command line tool, so that the crate doesn't implement the format itself
+const AGE_COMMAND: &str = "age";
+pub const AGE_EXTENSION: &str = ".age";
+
+//the inputs with the .age extension are decrypted, the other ones are read as they are
+pub fn is_encrypted(path: &str) -> bool {
+    path.ends_with(AGE_EXTENSION)
+}
+
+//Rows of an age-encrypted file. The file is piped through age and the rows are read from its output, so the
+//plaintext is only in memory and never touches the disk. The checksum is the one of the encrypted file, which is the
+//file listed by the manifest of the partner
+pub struct AgeReader {
+    child: Child,
+    stdout: ChildStdout,
+    //copies the encrypted file to age and returns its checksum
+    input: JoinHandle<io::Result<Option<String>>>,
+    //reads the errors of age while it runs, so that it doesn't block on a full stderr pipe
+    stderr: JoinHandle<String>,
+}
+
+impl AgeReader {
+    pub fn spawn(path: &str, identity: &str, checksum: bool) -> anyhow::Result<Self> {
+        Self::spawn_command(Command::new(AGE_COMMAND), path, identity, checksum)
+    }
+
+    //the command is age, or a stand-in in the tests
+    fn spawn_command(
+        mut command: Command,
+        path: &str,
+        identity: &str,
+        checksum: bool,
+    ) -> anyhow::Result<Self> {
+        let name = command.get_program().to_string_lossy().into_owned();
+        let file = File::open(path).with_context(|| format!("Failed to open csv file {path}"))?;
+        let mut child = command
+            .args(["--decrypt", "--identity", identity])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {name} to decrypt {path}"))?;
+        let (Some(mut stdin), Some(stdout), Some(mut err)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            bail!("Failed to connect to {name}");
+        };
+        let input = std::thread::spawn(move || {
+            let mut reader = HashingReader::new(file, checksum);
+            io::copy(&mut reader, &mut stdin)?;
+            Ok(reader.finalize())
+        });
+        let stderr = std::thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = err.read_to_string(&mut stderr);
+            stderr
+        });
+        Ok(Self {
+            child,
+            stdout,
+            input,
+            stderr,
+        })
+    }
+
+    //wait for age and return the checksum of the encrypted file. The parser can stop before the last row, on ctrl-c
+    //or once the error budget is exceeded, age is then stopped instead of reporting its broken pipe as a failure.
+    //The rows read before age fails are already parsed, so its error must fail the whole run
+    pub fn finish(mut self) -> anyhow::Result<Option<String>> {
+        let complete = matches!(self.stdout.read(&mut [0; 1]), Ok(0));
+        if !complete {
+            let _ = self.child.kill();
+        }
+        drop(self.stdout);
+        let status = self.child.wait()?;
+        let stderr = self.stderr.join().unwrap_or_default();
+        let checksum = self
+            .input
+            .join()
+            .map_err(|_| anyhow!("Failed to read the encrypted file"))?;
+        if !complete {
+            return Ok(None);
+        }
+        if !status.success() {
+            bail!("{AGE_COMMAND} failed with {status}: {}", stderr.trim());
+        }
+        Ok(checksum?)
+    }
+}
+
+impl Read for AgeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_encrypted, AgeReader, AGE_COMMAND};
+    use crate::parser::csv_parser::{CsvOptions, CsvParser};
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("toy_payment_{name}_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn encrypted_inputs() {
+        assert!(is_encrypted("partner_a.csv.age"));
+        assert!(!is_encrypted("partner_a.csv"));
+        assert!(!is_encrypted("-"));
+    }
+
+    //a stand-in for age that outputs the first rows and then fails, after more errors than a pipe can hold
+    #[cfg(unix)]
+    #[test]
+    fn failure_after_some_rows() {
+        let dir = temp_dir("age_stub");
+        let stub = dir.join("age.sh");
+        fs::write(
+            &stub,
+            "cat > /dev/null\n\
+             printf 'type,client,tx,amount\\ndeposit,1,1,1.0\\n'\n\
+             head -c 200000 /dev/zero | tr '\\0' x >&2\n\
+             echo ' truncated' >&2\n\
+             exit 1\n",
+        )
+        .unwrap();
+        let input = dir.join("input.csv.age");
+        fs::write(&input, "encrypted").unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg(&stub);
+        let mut age =
+            AgeReader::spawn_command(command, input.to_str().unwrap(), "key.txt", true).unwrap();
+        let mut rows = String::new();
+        age.read_to_string(&mut rows).unwrap();
+        assert_eq!(rows.lines().count(), 2);
+        let error = age.finish().unwrap_err().to_string();
+        assert!(error.ends_with("truncated"), "{}", &error[..40]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    //the rows decrypted before a truncated input is detected are parsed, but the run fails. Needs the age and
+    //age-keygen tools, the test is skipped without them
+    #[tokio::test(flavor = "multi_thread")]
+    async fn truncated_input() {
+        let installed = |tool: &str| {
+            Command::new(tool)
+                .arg("--version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok()
+        };
+        if !installed(AGE_COMMAND) || !installed("age-keygen") {
+            eprintln!("age is not installed, skipping");
+            return;
+        }
+        let dir = temp_dir("age_truncated");
+        let key = dir.join("key.txt");
+        let plain = dir.join("input.csv");
+        let encrypted = dir.join("input.csv.age");
+        assert!(Command::new("age-keygen")
+            .arg("-o")
+            .arg(&key)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+            .success());
+        let recipient = Command::new("age-keygen")
+            .arg("-y")
+            .arg(&key)
+            .output()
+            .unwrap()
+            .stdout;
+        //larger than the 64 KiB chunks of age, so that the first chunks are decrypted before the failure
+        let mut rows = "type,client,tx,amount\n".to_string();
+        for tx in 1..=10_000 {
+            rows.push_str(&format!("deposit,1,{tx},1.0\n"));
+        }
+        fs::write(&plain, rows).unwrap();
+        assert!(Command::new(AGE_COMMAND)
+            .arg("--encrypt")
+            .arg("--recipient")
+            .arg(String::from_utf8(recipient).unwrap().trim())
+            .arg("--output")
+            .arg(&encrypted)
+            .arg(&plain)
+            .status()
+            .unwrap()
+            .success());
+        let mut ciphertext = fs::read(&encrypted).unwrap();
+        ciphertext.truncate(ciphertext.len() - 100);
+        fs::write(&encrypted, ciphertext).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let options = CsvOptions {
+            decrypt_identity: Some(key.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut parser = CsvParser::new(encrypted.to_string_lossy().into_owned(), options, tx);
+        let consumer = tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(batch) = rx.recv().await {
+                received += batch.len();
+            }
+            received
+        });
+        let error = parser.run().await.unwrap_err();
+        drop(parser);
+        assert!(format!("{error:#}").starts_with("Failed to decrypt"));
+        assert!(consumer.await.unwrap() > 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
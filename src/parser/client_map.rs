@@ -0,0 +1,142 @@
+use ahash::AHashMap;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+//Row of the client map file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ClientMapping {
+    reference: SmolStr,
+    client: u16,
+}
+
+//Internal u16 ids of the external client references of the input, e.g. the UUIDs of the upstream system. The map is
+//loaded from the file written by the previous run (reference,client), and a reference that isn't in it gets the id
+//after the highest one in use, so the ids of the known clients never change
+#[derive(Debug, Clone)]
+pub struct ClientMap {
+    clients: AHashMap<SmolStr, u16>,
+    //None once every id is taken
+    next: Option<u16>,
+    //number of references that got a new id during this run
+    assigned: u64,
+}
+
+//the ids of an empty map start from 1
+impl Default for ClientMap {
+    fn default() -> Self {
+        Self {
+            clients: AHashMap::new(),
+            next: Some(1),
+            assigned: 0,
+        }
+    }
+}
+
+impl ClientMap {
+    //a reference or an id that is mapped twice is an error rather than a silent overwrite
+    pub fn new(mappings: impl IntoIterator<Item = (SmolStr, u16)>) -> Result<Self, String> {
+        let mut clients = AHashMap::new();
+        let mut ids = AHashMap::new();
+        for (reference, client) in mappings {
+            if let Some(other) = ids.insert(client, reference.clone()) {
+                return Err(format!(
+                    "client {client} is mapped to {other} and {reference}"
+                ));
+            }
+            if clients.insert(reference.clone(), client).is_some() {
+                return Err(format!("{reference} is mapped to several clients"));
+            }
+        }
+        let next = match clients.values().max() {
+            Some(max) => max.checked_add(1),
+            None => Some(1),
+        };
+        Ok(Self {
+            clients,
+            next,
+            assigned: 0,
+        })
+    }
+
+    //used as a clap value parser so that an invalid file is reported like any other invalid argument
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|e| format!("{path}: {e}"))?);
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mappings = rdr
+            .deserialize::<ClientMapping>()
+            .map(|mapping| mapping.map(|m| (m.reference, m.client)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{path}: {e}"))?;
+        Self::new(mappings).map_err(|e| format!("{path}: {e}"))
+    }
+
+    //internal id of the reference, a new reference gets the next free id
+    pub fn client(&mut self, reference: &str) -> Result<u16, String> {
+        if let Some(client) = self.clients.get(reference) {
+            return Ok(*client);
+        }
+        let Some(client) = self.next else {
+            return Err(format!("No client id left for {reference}"));
+        };
+        self.next = client.checked_add(1);
+        self.assigned += 1;
+        self.clients.insert(reference.into(), client);
+        Ok(client)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn assigned(&self) -> u64 {
+        self.assigned
+    }
+
+    //write the whole map, including the ids assigned during this run, sorted by client so that it can be loaded by
+    //the next run
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let mut mappings = self.clients.iter().collect::<Vec<_>>();
+        mappings.sort_unstable_by_key(|(_, client)| **client);
+        let file = File::create(path).with_context(|| format!("Failed to create {path}"))?;
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(file));
+        for (reference, client) in mappings {
+            wtr.serialize(ClientMapping {
+                reference: reference.clone(),
+                client: *client,
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClientMap;
+
+    #[test]
+    fn assign_clients() {
+        let mut map = ClientMap::new([("a1f0".into(), 3), ("9c2e".into(), 7)]).unwrap();
+        assert_eq!(map.client("9c2e"), Ok(7));
+        //a new reference gets the id after the highest one, and keeps it
+        assert_eq!(map.client("51bd"), Ok(8));
+        assert_eq!(map.client("51bd"), Ok(8));
+        assert_eq!((map.len(), map.assigned()), (3, 1));
+
+        assert_eq!(ClientMap::default().client("51bd"), Ok(1));
+        let mut full = ClientMap::new([("last".into(), u16::MAX)]).unwrap();
+        assert!(full.client("new").is_err());
+
+        assert!(ClientMap::new([("a1f0".into(), 3), ("9c2e".into(), 3)]).is_err());
+        assert!(ClientMap::new([("a1f0".into(), 3), ("a1f0".into(), 4)]).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use csv::StringRecord;
+use std::str::FromStr;
+
+//Column positions of each field. It is built from the header record if the file has one, otherwise it is
+//configured by the user. Amount is optional as files that only contain disputes may not have that column, timestamp,
+//reference, currency and ledger are optional extra columns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnPositions {
+    pub r#type: usize,
+    pub client: usize,
+    pub tx: usize,
+    pub amount: Option<usize>,
+    pub timestamp: Option<usize>,
+    pub reference: Option<usize>,
+    pub currency: Option<usize>,
+    pub ledger: Option<usize>,
+}
+
+impl Default for ColumnPositions {
+    fn default() -> Self {
+        Self {
+            r#type: 0,
+            client: 1,
+            tx: 2,
+            amount: Some(3),
+            timestamp: None,
+            reference: None,
+            currency: None,
+            ledger: None,
+        }
+    }
+}
+
+impl ColumnPositions {
+    //Map the columns by name, unknown columns are ignored
+    pub fn from_headers(headers: &StringRecord) -> Result<Self, String> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.eq_ignore_ascii_case(name))
+        };
+        let required =
+            |name: &str| find(name).ok_or(format!("Cannot find column {name} in header"));
+        Ok(Self {
+            r#type: required("type")?,
+            client: required("client")?,
+            tx: required("tx")?,
+            amount: find("amount"),
+            timestamp: find("timestamp"),
+            reference: find("reference"),
+            currency: find("currency"),
+            ledger: find("ledger"),
+        })
+    }
+
+    //Copy the fields of the record into the order expected by the Transaction deserializer
+    //(type, client, tx, amount, timestamp, reference, currency, ledger). Stop at the first missing required field so the deserializer reports
+    //which one cannot be found, missing optional fields are left empty
+    pub fn to_canonical(&self, record: &StringRecord, canonical: &mut StringRecord) {
+        canonical.clear();
+        for position in [self.r#type, self.client, self.tx] {
+            match record.get(position) {
+                Some(field) => canonical.push_field(field),
+                None => return,
+            }
+        }
+        for position in [
+            self.amount,
+            self.timestamp,
+            self.reference,
+            self.currency,
+            self.ledger,
+        ] {
+            canonical.push_field(position.and_then(|p| record.get(p)).unwrap_or_default());
+        }
+    }
+}
+
+//Parse the positions from a comma separated list in the order of type,client,tx,amount and optionally timestamp
+//reference, currency and ledger. e.g. "1,0,2,3"
+impl FromStr for ColumnPositions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let positions = s
+            .split(',')
+            .map(|p| p.trim().parse::<usize>().map_err(|e| format!("{p}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        match positions[..] {
+            [r#type, client, tx, amount, ref optional @ ..] if optional.len() <= 4 => Ok(Self {
+                r#type,
+                client,
+                tx,
+                amount: Some(amount),
+                timestamp: optional.first().copied(),
+                reference: optional.get(1).copied(),
+                currency: optional.get(2).copied(),
+                ledger: optional.get(3).copied(),
+            }),
+            _ => Err(format!(
+                "expected 4 to 8 positions (type,client,tx,amount[,timestamp[,reference[,currency[,ledger]]]]), got {}",
+                positions.len()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ColumnPositions;
+
+    #[test]
+    fn column_positions_from_str() {
+        assert_eq!(
+            "1, 0,3,2".parse::<ColumnPositions>().unwrap(),
+            ColumnPositions {
+                r#type: 1,
+                client: 0,
+                tx: 3,
+                amount: Some(2),
+                timestamp: None,
+                reference: None,
+                currency: None,
+                ledger: None
+            }
+        );
+        assert_eq!(
+            "0,1,2,3,4".parse::<ColumnPositions>().unwrap().timestamp,
+            Some(4)
+        );
+        assert_eq!(
+            "0,1,2,3,4,5".parse::<ColumnPositions>().unwrap().reference,
+            Some(5)
+        );
+        assert_eq!(
+            "0,1,2,3,4,5,6,7".parse::<ColumnPositions>().unwrap().ledger,
+            Some(7)
+        );
+        assert!("0,1,2,3,4,5,6,7,8".parse::<ColumnPositions>().is_err());
+        assert!("1,0,3".parse::<ColumnPositions>().is_err());
+        assert!("1,0,3,a".parse::<ColumnPositions>().is_err());
+    }
+}
@@ -1,44 +1,775 @@
-use crate::models::Transaction;
-use csv::{ReaderBuilder, Trim};
+use super::age::{is_encrypted, AgeReader};
+use super::client_map::ClientMap;
+use super::columns::ColumnPositions;
+use super::manifest::HashingReader;
+use crate::models::{FilePosition, RawRecord, Transaction};
+use crate::timing::StageTiming;
+use crate::tranasction::error_budget::ErrorBudget;
+use anyhow::{anyhow, bail, Context};
+use csv::{Position, Reader, ReaderBuilder, StringRecord, Trim};
+use smol_str::SmolStr;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::Sender;
 use tracing::error;
 
+//Dialect of the input csv file
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub has_headers: bool,
+    pub columns: ColumnPositions,
+    //ledger of the rows that don't have a ledger column, e.g. when each tenant sends its own file
+    pub ledger: Option<SmolStr>,
+    //age identity file the .age inputs are decrypted with
+    pub decrypt_identity: Option<String>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            has_headers: true,
+            columns: ColumnPositions::default(),
+            ledger: None,
+            decrypt_identity: None,
+        }
+    }
+}
+
+impl CsvOptions {
+    fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .flexible(true)
+            .trim(Trim::All)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .has_headers(self.has_headers);
+        builder
+    }
+
+    //parse a single row that doesn't come from a file, e.g. a line received from a socket
+    pub fn parse_line(&self, line: &str) -> Result<Transaction, String> {
+        let mut rdr = self
+            .reader_builder()
+            .has_headers(false)
+            .from_reader(line.as_bytes());
+        let mut record = StringRecord::new();
+        if !rdr.read_record(&mut record).map_err(|e| e.to_string())? {
+            return Err("Empty row".to_string());
+        }
+        self.parse_record(&record)
+    }
+
+    //parse the fields of a single row, e.g. a msgpack array received from a socket
+    pub fn parse_record(&self, record: &StringRecord) -> Result<Transaction, String> {
+        if record.iter().all(str::is_empty) {
+            return Err("Empty row".to_string());
+        }
+        let mut canonical = StringRecord::new();
+        self.columns.to_canonical(record, &mut canonical);
+        let mut transaction = canonical.deserialize(None).map_err(|e| e.to_string())?;
+        self.apply_ledger(&mut transaction);
+        Ok(transaction)
+    }
+
+    //set the configured ledger on the transactions and balance assertions that don't have one
+    fn apply_ledger(&self, transaction: &mut Transaction) {
+        let Some(ledger) = &self.ledger else {
+            return;
+        };
+        if let Some(tx_detail) = transaction.detail_mut() {
+            tx_detail.ledger.get_or_insert_with(|| ledger.clone());
+        } else if let Transaction::AssertBalance(assertion) = transaction {
+            assertion.ledger.get_or_insert_with(|| ledger.clone());
+        }
+    }
+
+    //true if the rows of the input can have a ledger: a default ledger is configured, or the input has a ledger
+    //column. The header of stdin and of the encrypted inputs can't be read ahead, they are assumed not to have one
+    pub fn has_ledgers(&self, path: &str) -> bool {
+        if self.ledger.is_some() {
+            return true;
+        }
+        if !self.has_headers {
+            return self.columns.ledger.is_some();
+        }
+        if path == STDIN_PATH || is_encrypted(path) {
+            return false;
+        }
+        let Ok(mut rdr) = self.reader_builder().from_path(path) else {
+            return false;
+        };
+        self.columns(&mut rdr)
+            .is_ok_and(|columns| columns.ledger.is_some())
+    }
+
+    //use the header to locate the columns if there is one, otherwise fallback to the configured positions
+    fn columns<R: Read>(&self, rdr: &mut Reader<R>) -> Result<ColumnPositions, String> {
+        if self.has_headers {
+            let headers = rdr.headers().map_err(|e| e.to_string())?;
+            ColumnPositions::from_headers(headers)
+        } else {
+            Ok(self.columns.clone())
+        }
+    }
+}
+
+//replace the external reference in the client field of a canonical record by its internal id. The flush,
+//checkpoint and rollback records have no client, the name of a checkpoint is in that field
+fn remap_client(map: &mut ClientMap, canonical: &mut StringRecord) -> Result<(), String> {
+    let control = canonical.get(0).is_some_and(|r#type| {
+        ["flush", "checkpoint", "rollback"]
+            .iter()
+            .any(|control| r#type.eq_ignore_ascii_case(control))
+    });
+    let Some(reference) = canonical.get(1).filter(|r| !control && !r.is_empty()) else {
+        return Ok(());
+    };
+    let client = map.client(reference)?.to_string();
+    let mut remapped = StringRecord::with_capacity(canonical.as_slice().len(), canonical.len());
+    for (i, field) in canonical.iter().enumerate() {
+        remapped.push_field(if i == 1 { &client } else { field });
+    }
+    *canonical = remapped;
+    Ok(())
+}
+
+//Number of rows read by the parser and the ones that can't be parsed
+#[derive(Debug, Default, Clone)]
+pub struct ParseStats {
+    pub rows: u64,
+    pub failed: u64,
+    //time spent reading and deserializing a row
+    pub parse: StageTiming,
+    //time spent sending a batch to the engine, the engine is the bottleneck when it grows
+    pub send_wait: StageTiming,
+    //sha256 of the input, only computed if it is verified against a manifest
+    pub sha256: Option<String>,
+}
+
+impl ParseStats {
+    pub fn failure_rate(&self) -> f64 {
+        if self.rows == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.rows as f64
+        }
+    }
+}
+
+//input path that reads the rows from stdin, e.g. when the parser is part of a pipe
+pub const STDIN_PATH: &str = "-";
+
+//Reader that lets the runtime move the other tasks to another thread while it blocks. A pipe can block for a long
+//time waiting for the next row, and the engine task woken by the last row must not be stuck behind it
+struct BlockInPlace<R>(R);
+
+impl<R: Read> Read for BlockInPlace<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        tokio::task::block_in_place(|| self.0.read(buf))
+    }
+}
+
 pub struct CsvParser {
     path: String,
-    tx: Sender<Transaction>,
+    options: CsvOptions,
+    batcher: Batcher,
+    //position right after the last row that has been sent to the engine
+    position: Option<FilePosition>,
+    stop: Arc<AtomicBool>,
+    //bytes of the input read so far, shared with the progress display
+    bytes_read: Arc<AtomicU64>,
+    checksum: bool,
+    //the parser stops once the budget is exceeded
+    error_budget: Option<Arc<ErrorBudget>>,
+    //external client references of the input, None if the client column already has the internal ids
+    client_map: Option<ClientMap>,
 }
 
 impl CsvParser {
-    pub fn new(path: String, tx: Sender<Transaction>) -> Self {
-        Self { path, tx }
+    pub fn new(path: String, options: CsvOptions, tx: Sender<Vec<Transaction>>) -> Self {
+        Self {
+            path,
+            options,
+            batcher: Batcher::new(tx),
+            position: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            checksum: false,
+            error_budget: None,
+            client_map: None,
+        }
     }
 
-    pub async fn run(&mut self) {
-        let file = match File::open(&self.path) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to open csv file: {e:?}");
-                return;
+    //compute the sha256 of the input while parsing it
+    pub fn compute_checksum(&mut self) {
+        self.checksum = true;
+    }
+
+    //start parsing from the given position instead of the first row
+    pub fn resume_from(&mut self, position: FilePosition) {
+        self.position = Some(position);
+    }
+
+    pub fn position(&self) -> Option<FilePosition> {
+        self.position
+    }
+
+    //setting the returned flag stops the parser after the row being processed
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    //count the rows and the rows that can't be parsed in the budget, which is shared with the engine
+    pub fn error_budget(&mut self, budget: Arc<ErrorBudget>) {
+        self.error_budget = Some(budget);
+    }
+
+    //read the client column as external references and replace them by the ids of the map
+    pub fn client_map(&mut self, map: ClientMap) {
+        self.client_map = Some(map);
+    }
+
+    //the map with the ids assigned to the new references, once the input is parsed
+    pub fn take_client_map(&mut self) -> Option<ClientMap> {
+        self.client_map.take()
+    }
+
+    fn record_row(&self, failed: bool) {
+        if let Some(budget) = &self.error_budget {
+            budget.record_row(failed);
+        }
+    }
+
+    fn over_budget(&self) -> bool {
+        self.error_budget
+            .as_ref()
+            .is_some_and(|budget| budget.exceeded())
+    }
+
+    //the returned counter follows the number of bytes of the input that have been read
+    pub fn progress_handle(&self) -> Arc<AtomicU64> {
+        self.bytes_read.clone()
+    }
+
+    //returns an error if the file can't be read at all, the rows that can't be parsed are only counted
+    pub async fn run(&mut self) -> anyhow::Result<ParseStats> {
+        if self.path == STDIN_PATH {
+            if self.position.is_some() {
+                bail!("Cannot resume from stdin");
             }
-        };
+            let reader = HashingReader::new(BlockInPlace(std::io::stdin()), self.checksum);
+            let mut rdr = self.options.reader_builder().from_reader(reader);
+            let columns = self.read_columns(&mut rdr)?;
+            let mut stats = self.parse(&mut rdr, columns).await?;
+            stats.sha256 = rdr.into_inner().finalize();
+            return Ok(stats);
+        }
+
+        if is_encrypted(&self.path) {
+            return self.run_encrypted().await;
+        }
+        let file = File::open(&self.path)
+            .with_context(|| format!("Failed to open csv file {}", self.path))?;
 
         //Here I just use the default 8 KB buffer. If we want to change the buffer size, we can use with_capacity instead
-        let reader = BufReader::new(file);
-        let mut rdr = ReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .from_reader(reader);
-        for result in rdr.deserialize::<Transaction>() {
-            match result {
-                Ok(r) => {
-                    if let Err(e) = self.tx.send(r).await {
+        let reader = BufReader::new(HashingReader::new(file, self.checksum));
+        let mut rdr = self.options.reader_builder().from_reader(reader);
+        let columns = self.read_columns(&mut rdr)?;
+        if let Some(position) = self.position {
+            let mut pos = Position::new();
+            pos.set_byte(position.byte)
+                .set_line(position.line)
+                .set_record(position.record);
+            rdr.seek(pos)
+                .with_context(|| format!("Failed to seek to {position:?}"))?;
+        }
+        let mut stats = self.parse(&mut rdr, columns).await?;
+        stats.sha256 = rdr.into_inner().into_inner().finalize();
+        Ok(stats)
+    }
+
+    //the decrypted rows can't be seeked, and an error of age is reported before a parse error since an input that
+    //can't be decrypted has no header
+    async fn run_encrypted(&mut self) -> anyhow::Result<ParseStats> {
+        let Some(identity) = self.options.decrypt_identity.clone() else {
+            bail!("{} is encrypted, use --decrypt-identity", self.path);
+        };
+        if self.position.is_some() {
+            bail!("Cannot resume an encrypted input");
+        }
+        let mut age = AgeReader::spawn(&self.path, &identity, self.checksum)?;
+        let mut rdr = self
+            .options
+            .reader_builder()
+            .from_reader(BlockInPlace(&mut age));
+        let parsed = match self.read_columns(&mut rdr) {
+            Ok(columns) => self.parse(&mut rdr, columns).await,
+            Err(e) => Err(e),
+        };
+        drop(rdr);
+        let sha256 = age
+            .finish()
+            .with_context(|| format!("Failed to decrypt {}", self.path))?;
+        let mut stats = parsed?;
+        stats.sha256 = sha256;
+        Ok(stats)
+    }
+
+    fn read_columns<R: Read>(&self, rdr: &mut Reader<R>) -> anyhow::Result<ColumnPositions> {
+        self.options
+            .columns(rdr)
+            .map_err(|e| anyhow!("Invalid header: {e}"))
+    }
+
+    async fn parse<R: Read>(
+        &mut self,
+        rdr: &mut Reader<R>,
+        columns: ColumnPositions,
+    ) -> anyhow::Result<ParseStats> {
+        let mut stats = ParseStats::default();
+        let mut record = StringRecord::new();
+        let mut canonical = StringRecord::new();
+        //given with the rows of the unknown types, so that their handler can find the columns the engine ignores
+        let headers: Option<Arc<[SmolStr]>> = if self.options.has_headers {
+            Some(rdr.headers()?.iter().map(SmolStr::new).collect())
+        } else {
+            None
+        };
+        while !self.stop.load(Ordering::Relaxed) && !self.over_budget() {
+            let parsing = Instant::now();
+            let read = rdr.read_record(&mut record);
+            let pos = rdr.position();
+            self.bytes_read.store(pos.byte(), Ordering::Relaxed);
+            self.position = Some(FilePosition {
+                byte: pos.byte(),
+                line: pos.line(),
+                record: pos.record(),
+            });
+            match read {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!("Failed to parse: {e}");
+                    stats.rows += 1;
+                    stats.failed += 1;
+                    self.record_row(true);
+                    continue;
+                }
+            }
+            stats.rows += 1;
+            columns.to_canonical(&record, &mut canonical);
+            let remapped = match &mut self.client_map {
+                Some(map) => remap_client(map, &mut canonical),
+                None => Ok(()),
+            };
+            if let Err(e) = remapped {
+                error!(
+                    line = record.position().map(|p| p.line()),
+                    "Failed to map client: {e}"
+                );
+                stats.failed += 1;
+                self.record_row(true);
+                continue;
+            }
+            match canonical.deserialize::<Transaction>(None) {
+                Ok(mut r) => {
+                    self.record_row(false);
+                    if let Transaction::Unknown(_, _, raw) = &mut r {
+                        *raw = RawRecord::new(
+                            headers.clone(),
+                            record.iter().map(SmolStr::new).collect(),
+                        );
+                    }
+                    self.options.apply_ledger(&mut r);
+                    stats.parse.record(parsing.elapsed());
+                    if let Err(e) = self.batcher.push(r, &mut stats.send_wait).await {
                         error!("Failed to send transaction to engine: {e}");
                     }
                 }
-                Err(e) => error!("Failed to parse: {e}"),
+                Err(e) => {
+                    error!(
+                        line = record.position().map(|p| p.line()),
+                        "Failed to parse: {e}"
+                    );
+                    stats.failed += 1;
+                    self.record_row(true);
+                }
             }
         }
+        //the rows read before the end of the input or the stop are all sent, so that the position is right
+        if let Err(e) = self.batcher.send(&mut stats.send_wait).await {
+            error!("Failed to send transaction to engine: {e}");
+        }
+        Ok(stats)
+    }
+}
+
+//largest batch of rows sent to the engine
+const MAX_BATCH_SIZE: usize = 256;
+
+//Groups the rows sent to the engine in batches, so that the cost of the channel is paid once per batch instead of
+//once per row. The size of the next batch follows the occupancy of the channel: it doubles while the engine is
+//behind (the channel is at least half full) and halves while the engine is idle (the channel is empty), so the
+//batches are large on bursts and the rows are not held back when the engine keeps up
+struct Batcher {
+    tx: Sender<Vec<Transaction>>,
+    batch: Vec<Transaction>,
+    size: usize,
+}
+
+impl Batcher {
+    fn new(tx: Sender<Vec<Transaction>>) -> Self {
+        Self {
+            tx,
+            batch: Vec::with_capacity(1),
+            size: 1,
+        }
+    }
+
+    //a flush record is sent right away, the accounts are expected on stdout once it is read
+    async fn push(
+        &mut self,
+        transaction: Transaction,
+        send_wait: &mut StageTiming,
+    ) -> Result<(), SendError<Vec<Transaction>>> {
+        let flush = transaction == Transaction::Flush;
+        self.batch.push(transaction);
+        if flush || self.batch.len() >= self.size {
+            self.send(send_wait).await?;
+        }
+        Ok(())
+    }
+
+    //send the pending rows, if any
+    async fn send(
+        &mut self,
+        send_wait: &mut StageTiming,
+    ) -> Result<(), SendError<Vec<Transaction>>> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let capacity = self.tx.max_capacity();
+        self.size = next_batch_size(self.size, capacity - self.tx.capacity(), capacity);
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.size));
+        let sending = Instant::now();
+        self.tx.send(batch).await?;
+        send_wait.record(sending.elapsed());
+        Ok(())
+    }
+}
+
+fn next_batch_size(size: usize, depth: usize, capacity: usize) -> usize {
+    if depth == 0 {
+        (size / 2).max(1)
+    } else if depth * 2 >= capacity {
+        (size * 2).min(MAX_BATCH_SIZE)
+    } else {
+        size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_batch_size, remap_client, CsvOptions, CsvParser, ParseStats, MAX_BATCH_SIZE};
+    use crate::models::{
+        FilePosition,
+        Transaction::{self, Deposit, Dispute, Withdrawal},
+        TransactionDetail,
+    };
+    use crate::parser::client_map::ClientMap;
+    use csv::StringRecord;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+
+    //run the parser on a file with the data and collect the rows it sends to the engine, every row must parse
+    fn parse_all(options: &CsvOptions, data: &str) -> Vec<Transaction> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "toy_payment_parse_all_{}_{}.csv",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, data).unwrap();
+        let (transactions, _) = run_parser(options, &path, None);
+        std::fs::remove_file(&path).unwrap();
+        transactions
+    }
+
+    //rows sent to the engine by a run of the parser from the position, if any, and the position after the last one
+    fn run_parser(
+        options: &CsvOptions,
+        path: &Path,
+        position: Option<FilePosition>,
+    ) -> (Vec<Transaction>, Option<FilePosition>) {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut parser = CsvParser::new(path.to_string_lossy().into_owned(), options.clone(), tx);
+        if let Some(position) = position {
+            parser.resume_from(position);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let ((stats, position), transactions) = runtime.block_on(async move {
+            let parsing = async move {
+                //the parser is dropped once it has run, which closes the channel
+                let stats = parser.run().await.unwrap();
+                (stats, parser.position())
+            };
+            let receiving = async {
+                let mut transactions = vec![];
+                while let Some(batch) = rx.recv().await {
+                    transactions.extend(batch);
+                }
+                transactions
+            };
+            tokio::join!(parsing, receiving)
+        });
+        assert_eq!(stats.failed, 0);
+        (transactions, position)
+    }
+
+    #[test]
+    fn resume_from_position() {
+        let options = CsvOptions::default();
+        let path =
+            std::env::temp_dir().join(format!("toy_payment_resume_{}.csv", std::process::id()));
+        let applied = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+";
+        std::fs::write(&path, applied).unwrap();
+        let (transactions, position) = run_parser(&options, &path, None);
+        assert_eq!(transactions.len(), 2);
+
+        //the rest of the input is read from the saved position, without the header and the rows already applied
+        std::fs::write(
+            &path,
+            format!("{applied}withdrawal,1,3,0.5\ndeposit,2,4,1.0\n"),
+        )
+        .unwrap();
+        let (transactions, resumed) = run_parser(&options, &path, position);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            transactions,
+            vec![
+                Withdrawal(TransactionDetail::new(1, 3, Some(0.5))),
+                Deposit(TransactionDetail::new(2, 4, Some(1.0)))
+            ]
+        );
+        assert_eq!(resumed.map(|p| p.record), Some(5));
+    }
+
+    #[test]
+    fn semicolon_without_header() {
+        let options = CsvOptions {
+            delimiter: b';',
+            has_headers: false,
+            ..Default::default()
+        };
+        let data = "\
+deposit;1;1;1.5
+withdrawal;1;2;0.5
+";
+        assert_eq!(
+            parse_all(&options, data),
+            vec![
+                Deposit(TransactionDetail::new(1, 1, Some(1.5))),
+                Withdrawal(TransactionDetail::new(1, 2, Some(0.5)))
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_quote_and_positions() {
+        let options = CsvOptions {
+            delimiter: b';',
+            quote: b'\'',
+            has_headers: false,
+            columns: "3,2,1,0".parse().unwrap(),
+            ..Default::default()
+        };
+        let data = "\
+'1.5';7;3;'deposit'
+;8;4;'deposit'
+";
+        assert_eq!(
+            parse_all(&options, data),
+            vec![
+                Deposit(TransactionDetail::new(3, 7, Some(1.5))),
+                Deposit(TransactionDetail::new(4, 8, None))
+            ]
+        );
+
+        let data = "\
+1.5;7;3;deposit
+";
+        assert_eq!(
+            parse_all(&options, data),
+            vec![Deposit(TransactionDetail::new(3, 7, Some(1.5)))]
+        );
+    }
+
+    #[test]
+    fn map_columns_by_header() {
+        let options = CsvOptions::default();
+        //reordered columns with an extra note column
+        let data = "\
+client, Note ,amount,tx,type
+1,first,1.5,1,deposit
+1,,,1,dispute
+";
+        assert_eq!(
+            parse_all(&options, data),
+            vec![
+                Deposit(TransactionDetail::new(1, 1, Some(1.5))),
+                Dispute(TransactionDetail::new(1, 1, None))
+            ]
+        );
+
+        //amount column is optional
+        let data = "\
+type,client,tx
+dispute,1,1
+";
+        assert_eq!(
+            parse_all(&options, data),
+            vec![Dispute(TransactionDetail::new(1, 1, None))]
+        );
+
+        //missing required column
+        let data = "\
+type,client,amount
+deposit,1,1.0
+";
+        let mut rdr = options.reader_builder().from_reader(data.as_bytes());
+        assert_eq!(
+            options.columns(&mut rdr).unwrap_err(),
+            "Cannot find column tx in header"
+        );
+    }
+
+    #[test]
+    fn reference_column() {
+        let options = CsvOptions::default();
+        let data = "\
+type,client,tx,amount,reference
+deposit,1,1,1.5,BANK-REF-001
+deposit,1,2,1.0,
+";
+        let mut with_reference = TransactionDetail::new(1, 1, Some(1.5));
+        with_reference.reference = Some("BANK-REF-001".into());
+        assert_eq!(
+            parse_all(&options, data),
+            vec![
+                Deposit(with_reference),
+                Deposit(TransactionDetail::new(1, 2, Some(1.0)))
+            ]
+        );
+    }
+
+    #[test]
+    fn ledger_column_and_default() {
+        let options = CsvOptions {
+            ledger: Some("acme".into()),
+            ..Default::default()
+        };
+        let data = "\
+type,client,tx,amount,ledger
+deposit,1,1,1.5,globex
+deposit,1,2,1.0,
+";
+        let mut globex = TransactionDetail::new(1, 1, Some(1.5));
+        globex.ledger = Some("globex".into());
+        let mut acme = TransactionDetail::new(1, 2, Some(1.0));
+        acme.ledger = Some("acme".into());
+        assert_eq!(
+            parse_all(&options, data),
+            vec![Deposit(globex), Deposit(acme)]
+        );
+        assert_eq!(
+            options.parse_line("dispute,1,2"),
+            Ok(Dispute(TransactionDetail {
+                ledger: Some("acme".into()),
+                ..TransactionDetail::new(1, 2, None)
+            }))
+        );
+    }
+
+    #[test]
+    fn remap_clients() {
+        let options = CsvOptions::default();
+        let mut map = ClientMap::new([("6f1c2a9e-uuid".into(), 4)]).unwrap();
+        let data = "\
+type,client,tx,amount
+deposit,6f1c2a9e-uuid,1,1.5
+deposit,0b3d77f1-uuid,2,1.0
+checkpoint,segment-1,,
+";
+        let mut rdr = options.reader_builder().from_reader(data.as_bytes());
+        let columns = options.columns(&mut rdr).unwrap();
+        let mut canonical = StringRecord::new();
+        let transactions = rdr
+            .records()
+            .map(|record| {
+                columns.to_canonical(&record.unwrap(), &mut canonical);
+                remap_client(&mut map, &mut canonical).unwrap();
+                canonical.deserialize(None).unwrap()
+            })
+            .collect::<Vec<Transaction>>();
+        assert_eq!(
+            transactions,
+            vec![
+                Deposit(TransactionDetail::new(4, 1, Some(1.5))),
+                Deposit(TransactionDetail::new(5, 2, Some(1.0))),
+                Transaction::Checkpoint("segment-1".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_size() {
+        //the engine is behind
+        assert_eq!(next_batch_size(4, 50, 100), 8);
+        assert_eq!(next_batch_size(MAX_BATCH_SIZE, 100, 100), MAX_BATCH_SIZE);
+        assert_eq!(next_batch_size(4, 10, 100), 4);
+        //the engine is idle
+        assert_eq!(next_batch_size(4, 0, 100), 2);
+        assert_eq!(next_batch_size(1, 0, 100), 1);
+    }
+
+    #[test]
+    fn parse_stats_failure_rate() {
+        assert_eq!(ParseStats::default().failure_rate(), 0.0);
+        let stats = ParseStats {
+            rows: 4,
+            failed: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.failure_rate(), 0.25);
+    }
+
+    #[test]
+    fn parse_line() {
+        let options = CsvOptions {
+            has_headers: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            options.parse_line("deposit, 1, 2, 1.5"),
+            Ok(Deposit(TransactionDetail::new(1, 2, Some(1.5))))
+        );
+        assert!(options.parse_line("deposit").is_err());
+        assert!(options.parse_line("").is_err());
     }
 }
@@ -0,0 +1,121 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+//Checksum and row count of a file received from a partner. The manifest is a csv file with the header
+//filename,sha256,rows and one row per file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ManifestEntry {
+    pub filename: String,
+    pub sha256: String,
+    pub rows: u64,
+}
+
+impl ManifestEntry {
+    //find the entry of the input file in the manifest, the entries are matched by file name only
+    pub fn load(manifest_path: &str, input_path: &str) -> anyhow::Result<Self> {
+        let filename = Path::new(input_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(input_path);
+        let reader = BufReader::new(File::open(manifest_path)?);
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for entry in rdr.deserialize::<ManifestEntry>() {
+            let entry = entry?;
+            if entry.filename == filename {
+                return Ok(entry);
+            }
+        }
+        anyhow::bail!("Cannot find {filename} in manifest {manifest_path}")
+    }
+
+    pub fn verify(&self, sha256: &str, rows: u64) -> Result<(), String> {
+        if !self.sha256.eq_ignore_ascii_case(sha256) {
+            return Err(format!(
+                "Checksum of {} is {sha256}, expected {}",
+                self.filename, self.sha256
+            ));
+        }
+        if self.rows != rows {
+            return Err(format!(
+                "{} has {rows} rows, expected {}",
+                self.filename, self.rows
+            ));
+        }
+        Ok(())
+    }
+}
+
+//Reader that computes the sha256 of the bytes read through it, so that the file is verified while it is parsed
+//instead of being read twice
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, enabled: bool) -> Self {
+        Self {
+            inner,
+            hasher: enabled.then(Sha256::new),
+        }
+    }
+
+    //hex encoded sha256 of the bytes read so far, None if hashing is not enabled
+    pub fn finalize(self) -> Option<String> {
+        self.hasher.map(|hasher| format!("{:x}", hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+//the checksum only makes sense for the whole file, so it is dropped once the reader seeks
+impl<R: Seek> Seek for HashingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.hasher = None;
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HashingReader, ManifestEntry};
+    use std::io::Read;
+
+    #[test]
+    fn hash_while_reading() {
+        let mut reader = HashingReader::new("abc".as_bytes(), true);
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "abc");
+        assert_eq!(
+            reader.finalize().unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(HashingReader::new("abc".as_bytes(), false).finalize(), None);
+    }
+
+    #[test]
+    fn verify() {
+        let entry = ManifestEntry {
+            filename: "transactions.csv".to_string(),
+            sha256: "ABCD".to_string(),
+            rows: 2,
+        };
+        assert!(entry.verify("abcd", 2).is_ok());
+        assert!(entry.verify("abce", 2).is_err());
+        assert!(entry.verify("abcd", 3).is_err());
+    }
+}
@@ -1 +1,10 @@
+#[cfg(feature = "cli")]
+pub mod age;
+#[cfg(feature = "cli")]
+pub mod client_map;
+//the columns are also mapped by the wasm engine, so they don't depend on the cli
+pub mod columns;
+#[cfg(feature = "cli")]
 pub mod csv_parser;
+#[cfg(feature = "cli")]
+pub mod manifest;
@@ -0,0 +1,283 @@
+use crate::models::Account;
+use crate::tranasction::snapshot::Snapshot;
+use ahash::AHashMap;
+use serde::Serialize;
+use smol_str::SmolStr;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+
+//amounts are rounded to 4 decimal places, so anything smaller than half of the last digit is the same amount
+const AMOUNT_TOLERANCE: f64 = 0.00005;
+
+//A field of an account that is different between two account reports. A missing account is reported with the
+//field "account"
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccountDiff {
+    pub client: u16,
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+    //right minus left rounded to 4 decimal places, only for the balances
+    pub change: Option<f64>,
+}
+
+pub fn read_accounts(path: &str) -> anyhow::Result<Vec<Account>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    Ok(rdr.deserialize().collect::<Result<Vec<Account>, _>>()?)
+}
+
+//State written by a run, from its snapshot if the path is json, otherwise from its account report, in which case
+//only the accounts are known
+pub fn load_state(path: &str) -> anyhow::Result<Snapshot> {
+    if path.ends_with(".json") {
+        Snapshot::load(path)
+    } else {
+        Ok(Snapshot {
+            accounts: read_accounts(path)?,
+            ..Default::default()
+        })
+    }
+}
+
+//Compare 2 account reports field by field, the result is sorted by client
+pub fn diff_accounts<'a>(
+    left: impl IntoIterator<Item = &'a Account>,
+    right: impl IntoIterator<Item = &'a Account>,
+) -> Vec<AccountDiff> {
+    let left = left
+        .into_iter()
+        .map(|a| (a.client, a))
+        .collect::<AHashMap<_, _>>();
+    let right = right
+        .into_iter()
+        .map(|a| (a.client, a))
+        .collect::<AHashMap<_, _>>();
+    let mut clients = left.keys().chain(right.keys()).copied().collect::<Vec<_>>();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut diffs = vec![];
+    for client in clients {
+        match (left.get(&client), right.get(&client)) {
+            (Some(l), Some(r)) => {
+                for (field, l, r) in [
+                    ("available", l.available, r.available),
+                    ("held", l.held, r.held),
+                    ("total", l.total, r.total),
+                ] {
+                    if (l - r).abs() > AMOUNT_TOLERANCE {
+                        diffs.push(AccountDiff {
+                            client,
+                            field,
+                            left: l.to_string(),
+                            right: r.to_string(),
+                            change: Some(((r - l) * 10_000.0).round() / 10_000.0),
+                        });
+                    }
+                }
+                if l.locked != r.locked {
+                    diffs.push(AccountDiff {
+                        client,
+                        field: "locked",
+                        left: l.locked.to_string(),
+                        right: r.locked.to_string(),
+                        change: None,
+                    });
+                }
+            }
+            (l, r) => diffs.push(AccountDiff {
+                client,
+                field: "account",
+                left: presence(l),
+                right: presence(r),
+                change: None,
+            }),
+        }
+    }
+    diffs
+}
+
+//Change of an account between the state before and after a run. The balances are the differences and locked is the
+//state after, a new account changes from zero balances. The ledger is empty for the default ledger
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccountDelta {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    pub ledger: SmolStr,
+}
+
+//the accounts that changed between the two states, sorted by ledger and client
+pub fn account_deltas<'a>(
+    before: impl IntoIterator<Item = (&'a SmolStr, &'a Account)>,
+    after: impl IntoIterator<Item = (&'a SmolStr, &'a Account)>,
+) -> Vec<AccountDelta> {
+    let before = before
+        .into_iter()
+        .map(|(ledger, a)| ((ledger, a.client), a))
+        .collect::<AHashMap<_, _>>();
+    let after = after
+        .into_iter()
+        .map(|(ledger, a)| ((ledger, a.client), a))
+        .collect::<BTreeMap<_, _>>();
+    let change = |before: f64, after: f64| ((after - before) * 10_000.0).round() / 10_000.0;
+    after
+        .into_iter()
+        .filter_map(|((ledger, client), a)| {
+            let b = before
+                .get(&(ledger, client))
+                .copied()
+                .cloned()
+                .unwrap_or(Account::new(client));
+            let delta = AccountDelta {
+                client,
+                available: change(b.available, a.available),
+                held: change(b.held, a.held),
+                total: change(b.total, a.total),
+                locked: a.locked,
+                ledger: ledger.clone(),
+            };
+            let changed = [delta.available, delta.held, delta.total]
+                .iter()
+                .any(|d| d.abs() > AMOUNT_TOLERANCE)
+                || b.locked != a.locked;
+            changed.then_some(delta)
+        })
+        .collect()
+}
+
+fn presence(account: Option<&&Account>) -> String {
+    match account {
+        Some(_) => "present".to_string(),
+        None => "missing".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{account_deltas, diff_accounts, load_state, AccountDelta, AccountDiff};
+    use crate::models::{Account, TransactionDetail};
+    use crate::tranasction::snapshot::Snapshot;
+    use smol_str::SmolStr;
+
+    fn account(client: u16, available: f64, held: f64, locked: bool) -> Account {
+        Account {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let left = vec![
+            account(1, 1.0, 0.0, false),
+            account(2, 2.0, 1.0, false),
+            account(3, 1.00001, 0.0, false),
+            account(4, 1.0, 0.0, false),
+            account(6, 0.1, 0.0, false),
+        ];
+        let right = vec![
+            account(5, 1.0, 0.0, false),
+            account(4, 1.0, 0.0, false),
+            account(3, 1.0, 0.0, false),
+            account(2, 2.0, 0.5, true),
+            account(6, 0.3, 0.0, false),
+        ];
+        let diff = |client, field, left: &str, right: &str, change| AccountDiff {
+            client,
+            field,
+            left: left.to_string(),
+            right: right.to_string(),
+            change,
+        };
+        //the change of 0.1 to 0.3 is 0.2, not the 0.19999999999999998 of the f64 subtraction
+        assert_eq!(
+            diff_accounts(&left, &right),
+            vec![
+                diff(1, "account", "present", "missing", None),
+                diff(2, "held", "1", "0.5", Some(-0.5)),
+                diff(2, "total", "3", "2.5", Some(-0.5)),
+                diff(2, "locked", "false", "true", None),
+                diff(5, "account", "missing", "present", None),
+                diff(6, "available", "0.1", "0.3", Some(0.2)),
+                diff(6, "total", "0.1", "0.3", Some(0.2)),
+            ]
+        );
+        assert!(diff_accounts(&left, &left).is_empty());
+    }
+
+    #[test]
+    fn load_report_and_snapshot() {
+        let accounts = vec![account(1, 1.5, 0.0, false), account(2, 0.0, 2.0, true)];
+        let dir = std::env::temp_dir();
+        let report = dir.join(format!("toy_payment_state_{}.csv", std::process::id()));
+        std::fs::write(
+            &report,
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,0,2,2,true\n",
+        )
+        .unwrap();
+        let snapshot = dir.join(format!("toy_payment_state_{}.json", std::process::id()));
+        Snapshot {
+            accounts: accounts.clone(),
+            deposits: vec![TransactionDetail::new(1, 1, Some(1.5))],
+            ..Default::default()
+        }
+        .save(snapshot.to_str().unwrap())
+        .unwrap();
+
+        let from_report = load_state(report.to_str().unwrap()).unwrap();
+        let from_snapshot = load_state(snapshot.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&report).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+        assert!(diff_accounts(&from_report.accounts, &accounts).is_empty());
+        assert!(from_report.deposits.is_empty());
+        assert!(diff_accounts(&from_snapshot.accounts, &accounts).is_empty());
+        assert_eq!(from_snapshot.deposits.len(), 1);
+    }
+
+    #[test]
+    fn deltas() {
+        let default = SmolStr::default();
+        let tenant = SmolStr::new("tenant-a");
+        let before = [
+            (&default, account(1, 1.0, 0.0, false)),
+            (&default, account(2, 2.0, 0.0, false)),
+            (&tenant, account(1, 5.0, 0.0, false)),
+        ];
+        let after = [
+            (&default, account(1, 1.0, 0.0, false)),
+            (&default, account(2, 0.5, 1.0, true)),
+            (&default, account(3, 4.0, 0.0, false)),
+            (&tenant, account(1, 5.25, 0.0, false)),
+        ];
+        let deltas = account_deltas(
+            before.iter().map(|(l, a)| (*l, a)),
+            after.iter().map(|(l, a)| (*l, a)),
+        );
+        let delta = |client, available, held, locked, ledger: &SmolStr| AccountDelta {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            ledger: ledger.clone(),
+        };
+        assert_eq!(
+            deltas,
+            vec![
+                delta(2, -1.5, 1.0, true, &default),
+                delta(3, 4.0, 0.0, false, &default),
+                delta(1, 0.25, 0.0, false, &tenant),
+            ]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::models::{parse_tx_id, Account, TranactionState, TransactionDetail, TxId};
+use crate::tranasction::snapshot::Snapshot;
+use ahash::AHashMap;
+use serde::Serialize;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+const HELP: &str = "\
+Commands:
+  account <client>                      show the account of a client
+  tx <tx>                               show the deposit/withdrawal transaction
+  locked                                list all the locked accounts
+  top <n> by <available|held|total>     list the top n accounts by balance
+  help                                  show this message
+  quit                                  exit";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Balance {
+    Available,
+    Held,
+    Total,
+}
+
+impl Balance {
+    fn of(&self, account: &Account) -> f64 {
+        match self {
+            Balance::Available => account.available,
+            Balance::Held => account.held,
+            Balance::Total => account.total,
+        }
+    }
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "available" => Ok(Balance::Available),
+            "held" => Ok(Balance::Held),
+            "total" => Ok(Balance::Total),
+            _ => Err(format!(
+                "Unknown balance {s}, expected available, held or total"
+            )),
+        }
+    }
+}
+
+//Queries supported by the repl
+#[derive(Debug, PartialEq, Eq)]
+pub enum Query {
+    Account(u16),
+    Tx(TxId),
+    Locked,
+    Top(usize, Balance),
+    Help,
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["account", client] => Ok(Query::Account(
+                client
+                    .parse()
+                    .map_err(|e| format!("Invalid client {client}: {e}"))?,
+            )),
+            ["tx", tx] => Ok(Query::Tx(parse_tx_id(tx)?)),
+            ["locked"] => Ok(Query::Locked),
+            ["top", n, "by", balance] => Ok(Query::Top(
+                n.parse().map_err(|e| format!("Invalid number {n}: {e}"))?,
+                balance.parse()?,
+            )),
+            ["help"] => Ok(Query::Help),
+            _ => Err(format!(
+                "Unknown command: {s}. Type help for the list of commands"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TransactionRow<'a> {
+    r#type: &'a str,
+    client: u16,
+    tx: TxId,
+    amount: Option<f64>,
+    state: &'a TranactionState,
+    reference: Option<&'a str>,
+}
+
+impl<'a> TransactionRow<'a> {
+    fn new(r#type: &'a str, detail: &'a TransactionDetail) -> Self {
+        Self {
+            r#type,
+            client: detail.client,
+            tx: detail.tx,
+            amount: detail.amount,
+            state: &detail.state,
+            reference: detail.reference.as_deref(),
+        }
+    }
+}
+
+//Snapshot indexed by client and tx id so that queries don't need to scan the whole snapshot
+pub struct SnapshotIndex {
+    accounts: AHashMap<u16, Account>,
+    deposits: AHashMap<TxId, TransactionDetail>,
+    withdrawals: AHashMap<TxId, TransactionDetail>,
+}
+
+impl SnapshotIndex {
+    pub fn new(snapshot: Snapshot) -> Self {
+        Self {
+            accounts: snapshot
+                .accounts
+                .into_iter()
+                .map(|account| (account.client, account))
+                .collect(),
+            deposits: snapshot.deposits.into_iter().map(|t| (t.tx, t)).collect(),
+            withdrawals: snapshot
+                .withdrawals
+                .into_iter()
+                .map(|t| (t.tx, t))
+                .collect(),
+        }
+    }
+
+    pub fn execute(&self, query: &Query) -> String {
+        match query {
+            Query::Account(client) => match self.accounts.get(client) {
+                Some(account) => to_csv([account]),
+                None => format!("Account {client} not found"),
+            },
+            Query::Tx(tx) => {
+                //deposits and withdrawals have their own id space
+                let rows = self
+                    .deposits
+                    .get(tx)
+                    .map(|detail| TransactionRow::new("deposit", detail))
+                    .into_iter()
+                    .chain(
+                        self.withdrawals
+                            .get(tx)
+                            .map(|detail| TransactionRow::new("withdrawal", detail)),
+                    )
+                    .collect::<Vec<_>>();
+                if rows.is_empty() {
+                    format!("Transaction {tx} not found")
+                } else {
+                    to_csv(rows)
+                }
+            }
+            Query::Locked => {
+                let mut locked = self
+                    .accounts
+                    .values()
+                    .filter(|account| account.locked)
+                    .collect::<Vec<_>>();
+                locked.sort_by_key(|account| account.client);
+                if locked.is_empty() {
+                    "No locked account".to_string()
+                } else {
+                    to_csv(locked)
+                }
+            }
+            Query::Top(n, balance) => {
+                let mut accounts = self.accounts.values().collect::<Vec<_>>();
+                accounts.sort_by(|a, b| {
+                    balance
+                        .of(b)
+                        .total_cmp(&balance.of(a))
+                        .then(a.client.cmp(&b.client))
+                });
+                to_csv(accounts.into_iter().take(*n))
+            }
+            Query::Help => HELP.to_string(),
+        }
+    }
+}
+
+pub fn to_csv<T: Serialize>(rows: impl IntoIterator<Item = T>) -> String {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        if let Err(e) = wtr.serialize(row) {
+            return format!("Fail to write: {e}");
+        }
+    }
+    match wtr.into_inner() {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).trim_end().to_string(),
+        Err(e) => format!("Fail to write: {e}"),
+    }
+}
+
+pub fn run(snapshot_path: &str) -> anyhow::Result<()> {
+    let index = SnapshotIndex::new(Snapshot::load(snapshot_path)?);
+    println!("Loaded snapshot {snapshot_path}. Type help for the list of commands");
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        stdout.flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+        match line {
+            "" => continue,
+            "quit" | "exit" => break,
+            _ => match line.parse::<Query>() {
+                Ok(query) => println!("{}", index.execute(&query)),
+                Err(e) => println!("{e}"),
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Balance, Query, SnapshotIndex};
+    use crate::models::{Account, TransactionDetail};
+    use crate::tranasction::snapshot::Snapshot;
+
+    fn get_index() -> SnapshotIndex {
+        let account = |client, available, held, locked| Account {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+        };
+        SnapshotIndex::new(Snapshot {
+            accounts: vec![
+                account(1, 10.0, 0.0, false),
+                account(2, 1.0, 5.0, true),
+                account(3, 3.0, 2.0, false),
+            ],
+            deposits: vec![TransactionDetail::new(1, 1, Some(10.0))],
+            withdrawals: vec![TransactionDetail::new(3, 1, Some(1.0))],
+            authorizations: vec![],
+            position: None,
+            accruals: Default::default(),
+            ledgers: Default::default(),
+        })
+    }
+
+    #[test]
+    fn parse_query() {
+        assert_eq!("account 42".parse::<Query>(), Ok(Query::Account(42)));
+        assert_eq!(" tx  1003 ".parse::<Query>(), Ok(Query::Tx(1003)));
+        assert_eq!("locked".parse::<Query>(), Ok(Query::Locked));
+        assert_eq!(
+            "top 10 by held".parse::<Query>(),
+            Ok(Query::Top(10, Balance::Held))
+        );
+        assert!("account abc".parse::<Query>().is_err());
+        assert!("top 10 by nothing".parse::<Query>().is_err());
+        assert!("balance 1".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn execute_query() {
+        let index = get_index();
+        assert_eq!(
+            index.execute(&Query::Account(2)),
+            "client,available,held,total,locked\n2,1.0,5.0,6.0,true"
+        );
+        assert_eq!(index.execute(&Query::Account(4)), "Account 4 not found");
+        assert_eq!(
+            index.execute(&Query::Tx(1)),
+            "type,client,tx,amount,state,reference\ndeposit,1,1,10.0,Normal,\nwithdrawal,3,1,1.0,Normal,"
+        );
+        assert_eq!(
+            index.execute(&Query::Locked),
+            "client,available,held,total,locked\n2,1.0,5.0,6.0,true"
+        );
+        assert_eq!(
+            index.execute(&Query::Top(2, Balance::Held)),
+            "client,available,held,total,locked\n2,1.0,5.0,6.0,true\n3,3.0,2.0,5.0,false"
+        );
+    }
+}
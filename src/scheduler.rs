@@ -0,0 +1,340 @@
+use crate::commands::RunningEngine;
+use crate::models::{Account, TranactionState};
+use crate::tranasction::snapshot::Snapshot;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+//Batch actions run at a time of the day while serve or stream keep running, in the order they are listed here
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ScheduledAction {
+    //expire the disputes and authorizations that are past their ttl at the time of the schedule
+    Sweep,
+    //credit the interest, or debit the penalty, accrued so far on the held amount of the open disputes, see
+    //--held-accrual-rate
+    Interest,
+    //save the state of the engine to the snapshot file
+    Snapshot,
+    //append the totals of the accounts to the summary file
+    Summary,
+}
+
+//Actions run every day at a UTC time, parsed from e.g. 23:59=sweep,snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    //seconds since midnight
+    at: u64,
+    actions: Vec<ScheduledAction>,
+}
+
+impl Schedule {
+    //first time of the schedule strictly after now, so that a schedule that just ran waits for the next day
+    fn next_run(&self, now: u64) -> u64 {
+        let today = now - now % SECS_PER_DAY + self.at;
+        if today > now {
+            today
+        } else {
+            today + SECS_PER_DAY
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (time, actions) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <HH:MM>=<action>[,<action>], got {s}"))?;
+        let invalid_time = || format!("invalid time {time}, expected HH:MM");
+        let (hours, minutes) = time.split_once(':').ok_or_else(invalid_time)?;
+        let hours = hours
+            .parse::<u64>()
+            .ok()
+            .filter(|hours| *hours < 24)
+            .ok_or_else(invalid_time)?;
+        let minutes = minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| *minutes < 60)
+            .ok_or_else(invalid_time)?;
+        let actions = actions
+            .split(',')
+            .map(|action| ScheduledAction::from_str(action.trim(), true))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            at: hours * 3600 + minutes * 60,
+            actions,
+        })
+    }
+}
+
+#[derive(clap::Args)]
+pub struct ScheduleArgs {
+    /// run batch actions every day at a UTC time while running, e.g. 23:59=sweep,interest,snapshot,summary. sweep
+    /// expires the disputes and authorizations past their ttl, interest settles the --held-accrual-rate accrued so far
+    /// by the open disputes, snapshot saves the state to the --snapshot file and summary appends the totals of the
+    /// accounts to --summary-output. Can be repeated
+    #[arg(long)]
+    schedule: Vec<Schedule>,
+    /// csv file the summary action appends a row to (time,accounts,locked,available,held,total,open_disputes)
+    #[arg(long)]
+    summary_output: Option<String>,
+}
+
+//Row of the summary file
+#[derive(Serialize)]
+struct Summary {
+    time: u64,
+    accounts: usize,
+    locked: usize,
+    available: f64,
+    held: f64,
+    total: f64,
+    open_disputes: usize,
+}
+
+impl Summary {
+    fn new(time: u64, snapshot: &Snapshot) -> Self {
+        let accounts = snapshot.ledger_accounts();
+        //amounts have 4 decimal places, round so that the float error of the sums doesn't show
+        let sum = |balance: fn(&Account) -> f64| {
+            //sum() of no accounts is -0.0
+            let sum = accounts
+                .iter()
+                .fold(0.0, |sum, (_, account)| sum + balance(account));
+            (sum * 10_000.0).round() / 10_000.0
+        };
+        Self {
+            time,
+            accounts: accounts.len(),
+            locked: accounts
+                .iter()
+                .filter(|(_, account)| account.locked)
+                .count(),
+            available: sum(|account| account.available),
+            held: sum(|account| account.held),
+            total: sum(|account| account.total),
+            open_disputes: open_disputes(snapshot),
+        }
+    }
+}
+
+fn open_disputes(snapshot: &Snapshot) -> usize {
+    let disputes = snapshot
+        .deposits
+        .iter()
+        .chain(&snapshot.withdrawals)
+        .filter(|t| t.state == TranactionState::Dispute)
+        .count();
+    disputes + snapshot.ledgers.values().map(open_disputes).sum::<usize>()
+}
+
+//Runs the scheduled actions against the in-memory state of a long-lived engine, instead of separate invocations
+//against a snapshot that miss the transactions in flight
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    snapshot: Option<String>,
+    summary_output: Option<String>,
+}
+
+impl Scheduler {
+    //the files of the scheduled actions must be set
+    pub fn new(
+        args: &ScheduleArgs,
+        snapshot: Option<&str>,
+        held_accrual_rate: Option<f64>,
+    ) -> Result<Self, String> {
+        let scheduled = |action| {
+            args.schedule
+                .iter()
+                .any(|schedule| schedule.actions.contains(&action))
+        };
+        if scheduled(ScheduledAction::Snapshot) && snapshot.is_none() {
+            return Err("the snapshot action requires --snapshot".to_string());
+        }
+        if scheduled(ScheduledAction::Interest) && held_accrual_rate.is_none() {
+            return Err("the interest action requires --held-accrual-rate".to_string());
+        }
+        if scheduled(ScheduledAction::Summary) && args.summary_output.is_none() {
+            return Err("the summary action requires --summary-output".to_string());
+        }
+        Ok(Self {
+            schedules: args.schedule.clone(),
+            snapshot: snapshot.map(str::to_string),
+            summary_output: args.summary_output.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    //wait until the next scheduled time and return it with its actions, the actions of the schedules at the same
+    //time are merged. Never returns without a schedule
+    pub async fn next(&self) -> (u64, Vec<ScheduledAction>) {
+        let now = unix_now();
+        let Some(at) = self.schedules.iter().map(|s| s.next_run(now)).min() else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(Duration::from_secs(at - now)).await;
+        let mut actions = self
+            .schedules
+            .iter()
+            .filter(|schedule| schedule.next_run(now) == at)
+            .flat_map(|schedule| schedule.actions.iter().copied())
+            .collect::<Vec<_>>();
+        actions.sort();
+        actions.dedup();
+        (at, actions)
+    }
+
+    //run the actions of every schedule until the task is aborted
+    pub(crate) async fn run(self, mut engine: impl RunningEngine) {
+        loop {
+            let (at, actions) = self.next().await;
+            self.run_actions(at, &actions, &mut engine).await;
+        }
+    }
+
+    //a failed action is logged and the next ones still run
+    pub(crate) async fn run_actions(
+        &self,
+        at: u64,
+        actions: &[ScheduledAction],
+        engine: &mut impl RunningEngine,
+    ) {
+        for action in actions {
+            tracing::info!(?action, "Running scheduled action");
+            let result = match action {
+                ScheduledAction::Sweep => {
+                    engine.sweep(at).await;
+                    Ok(())
+                }
+                ScheduledAction::Interest => {
+                    engine.settle_interest(at).await;
+                    Ok(())
+                }
+                ScheduledAction::Snapshot => self.save_snapshot(engine).await,
+                ScheduledAction::Summary => self.write_summary(at, engine).await,
+            };
+            if let Err(e) = result {
+                tracing::error!(?action, "Fail to run scheduled action: {e}");
+            }
+        }
+    }
+
+    async fn save_snapshot(&self, engine: &mut impl RunningEngine) -> anyhow::Result<()> {
+        let (Some(path), Some(snapshot)) = (&self.snapshot, engine.snapshot().await) else {
+            anyhow::bail!("Transaction engine has stopped");
+        };
+        snapshot.save(path)
+    }
+
+    //the header is only written to a new file, so the rows of every day are appended to the same file
+    async fn write_summary(&self, at: u64, engine: &mut impl RunningEngine) -> anyhow::Result<()> {
+        let (Some(path), Some(snapshot)) = (&self.summary_output, engine.snapshot().await) else {
+            anyhow::bail!("Transaction engine has stopped");
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(is_new)
+            .from_writer(file);
+        wtr.serialize(Summary::new(at, &snapshot))?;
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_disputes, Schedule, ScheduledAction, Summary, SECS_PER_DAY};
+    use crate::models::{Account, TranactionState, TransactionDetail};
+    use crate::tranasction::snapshot::Snapshot;
+
+    #[test]
+    fn parse_schedule() {
+        let schedule = "23:59=Sweep, interest,snapshot"
+            .parse::<Schedule>()
+            .unwrap();
+        assert_eq!(
+            schedule,
+            Schedule {
+                at: 23 * 3600 + 59 * 60,
+                actions: vec![
+                    ScheduledAction::Sweep,
+                    ScheduledAction::Interest,
+                    ScheduledAction::Snapshot
+                ],
+            }
+        );
+        for invalid in [
+            "23:59",
+            "24:00=sweep",
+            "12:60=sweep",
+            "1200=sweep",
+            "12:00=fee",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn next_run() {
+        let schedule = "01:00=summary".parse::<Schedule>().unwrap();
+        let midnight = 100 * SECS_PER_DAY;
+        assert_eq!(schedule.next_run(midnight), midnight + 3600);
+        //a schedule that just ran waits for the next day
+        assert_eq!(
+            schedule.next_run(midnight + 3600),
+            midnight + SECS_PER_DAY + 3600
+        );
+        assert_eq!(
+            schedule.next_run(midnight + 7200),
+            midnight + SECS_PER_DAY + 3600
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let mut disputed = TransactionDetail::new(1, 1, Some(0.1));
+        disputed.state = TranactionState::Dispute;
+        let snapshot = Snapshot {
+            accounts: vec![
+                Account {
+                    client: 1,
+                    available: 0.2,
+                    held: 0.1,
+                    total: 0.3,
+                    locked: false,
+                },
+                Account {
+                    client: 2,
+                    available: 0.1,
+                    total: 0.1,
+                    locked: true,
+                    ..Default::default()
+                },
+            ],
+            deposits: vec![disputed, TransactionDetail::new(2, 2, Some(0.1))],
+            ..Default::default()
+        };
+        assert_eq!(open_disputes(&snapshot), 1);
+        let summary = Summary::new(0, &snapshot);
+        assert_eq!((summary.accounts, summary.locked), (2, 1));
+        assert_eq!(summary.available, 0.3);
+        assert_eq!(summary.total, 0.4);
+    }
+}
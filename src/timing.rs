@@ -0,0 +1,69 @@
+use hdrhistogram::Histogram;
+use std::fmt;
+use std::time::Duration;
+
+//Latency histogram of a stage of the pipeline, in nanoseconds. The histogram has a fixed precision of 3 significant
+//digits, so recording a value doesn't allocate once the range of the values has been seen
+#[derive(Debug, Clone)]
+pub struct StageTiming {
+    histogram: Histogram<u64>,
+}
+
+impl Default for StageTiming {
+    fn default() -> Self {
+        Self {
+            //3 significant digits is always a valid precision
+            histogram: Histogram::new(3).unwrap(),
+        }
+    }
+}
+
+impl StageTiming {
+    pub fn record(&mut self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        //the histogram grows to fit the value, so the record only fails if it can't grow
+        let _ = self.histogram.record(nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.histogram.len()
+    }
+
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        Duration::from_nanos(self.histogram.value_at_quantile(quantile))
+    }
+}
+
+impl fmt::Display for StageTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:?} p99 {:?}",
+            self.quantile(0.5),
+            self.quantile(0.99)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StageTiming;
+    use std::time::Duration;
+
+    #[test]
+    fn quantiles() {
+        let mut timing = StageTiming::default();
+        for micros in 1..=100 {
+            timing.record(Duration::from_micros(micros));
+        }
+        assert_eq!(timing.count(), 100);
+        //the values are rounded to 3 significant digits
+        assert!(
+            timing.quantile(0.5).abs_diff(Duration::from_micros(50)) < Duration::from_nanos(100)
+        );
+        assert!(
+            timing.quantile(0.99).abs_diff(Duration::from_micros(99)) < Duration::from_nanos(100)
+        );
+        assert_eq!(timing.to_string(), "p50 50.015µs p99 99.007µs");
+    }
+}
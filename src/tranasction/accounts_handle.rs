@@ -0,0 +1,135 @@
+use super::config::ConfigChange;
+use super::snapshot::Snapshot;
+use super::transaction_engine::EngineStats;
+use crate::models::Account;
+use smol_str::SmolStr;
+use tokio::sync::{mpsc, oneshot};
+
+//the queries are answered between two transactions, so the channel doesn't need to be large
+const QUERY_CHANNEL_SIZE: usize = 100;
+
+//Queries sent to the engine by the other tasks, the answer is sent back on the oneshot channel
+pub enum AccountQuery {
+    Account(u16, oneshot::Sender<Option<Account>>),
+    Locked(oneshot::Sender<Vec<Account>>),
+    //the n accounts with the most held funds
+    TopHeld(usize, oneshot::Sender<Vec<Account>>),
+    Stats(oneshot::Sender<EngineStats>),
+    //the state of the engine, e.g. for a scheduled snapshot
+    Snapshot(oneshot::Sender<Snapshot>),
+    //expire the disputes and authorizations as of this unix time, the answer is sent once they are
+    Sweep(u64, oneshot::Sender<()>),
+    //settle the accruals of the open disputes as of this unix time, the answer is sent once they are
+    Interest(u64, oneshot::Sender<()>),
+    Reconfigure(ConfigChange, oneshot::Sender<()>),
+    //write the accounts changed since the last flush in incremental mode
+    Flush(oneshot::Sender<()>),
+    //save or roll back to a checkpoint, the answer is the error if it is refused
+    Checkpoint(SmolStr, oneshot::Sender<Result<(), String>>),
+    Rollback(SmolStr, oneshot::Sender<Result<(), String>>),
+}
+
+//Handle to read the accounts while the engine keeps processing transactions. The engine owns the accounts, so the
+//reads go through a channel instead of a lock on the account map. The accounts are the ones of the default ledger,
+//the accounts of the other ledgers can't be queried
+#[derive(Clone)]
+pub struct AccountsHandle {
+    tx: mpsc::Sender<AccountQuery>,
+}
+
+impl AccountsHandle {
+    pub fn channel() -> (Self, mpsc::Receiver<AccountQuery>) {
+        let (tx, rx) = mpsc::channel(QUERY_CHANNEL_SIZE);
+        (Self { tx }, rx)
+    }
+
+    //returns None if the client doesn't have an account or the engine has stopped
+    pub async fn account(&self, client: u16) -> Option<Account> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(AccountQuery::Account(client, tx)).await.ok()?;
+        rx.await.ok().flatten()
+    }
+
+    //locked accounts sorted by client, empty if the engine has stopped
+    pub async fn locked(&self) -> Vec<Account> {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(AccountQuery::Locked(tx)).await.is_err() {
+            return vec![];
+        }
+        rx.await.unwrap_or_default()
+    }
+    //the n accounts with the most held funds, sorted by held funds, empty if the engine has stopped
+    pub async fn top_held(&self, n: usize) -> Vec<Account> {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(AccountQuery::TopHeld(n, tx)).await.is_err() {
+            return vec![];
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    //counters of the transactions processed so far, None if the engine has stopped
+    pub async fn stats(&self) -> Option<EngineStats> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(AccountQuery::Stats(tx)).await.ok()?;
+        rx.await.ok()
+    }
+
+    //None if the engine has stopped
+    pub async fn snapshot(&self) -> Option<Snapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(AccountQuery::Snapshot(tx)).await.ok()?;
+        rx.await.ok()
+    }
+
+    //returns once the engine has expired them, or right away if it has stopped
+    pub async fn sweep(&self, now: u64) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(AccountQuery::Sweep(now, tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    //returns once the engine has settled them, or right away if it has stopped
+    pub async fn settle_interest(&self, now: u64) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(AccountQuery::Interest(now, tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    //false if the engine has stopped
+    pub async fn reconfigure(&self, change: ConfigChange) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(AccountQuery::Reconfigure(change, tx))
+            .await
+            .is_ok()
+            && rx.await.is_ok()
+    }
+
+    //false if the engine has stopped
+    pub async fn flush(&self) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(AccountQuery::Flush(tx)).await.is_ok() && rx.await.is_ok()
+    }
+
+    //None if the engine has stopped
+    pub async fn checkpoint(&self, name: &str) -> Option<Result<(), String>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(AccountQuery::Checkpoint(name.into(), tx))
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+
+    //None if the engine has stopped
+    pub async fn rollback(&self, name: &str) -> Option<Result<(), String>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(AccountQuery::Rollback(name.into(), tx))
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+}
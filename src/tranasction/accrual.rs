@@ -0,0 +1,64 @@
+use serde::Serialize;
+use smol_str::SmolStr;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+//Interest, or penalty if the rate is negative, accrued on the amount held by a dispute for every full day it is
+//open. It is only known once the dispute is decided, so it is credited to or debited from the available fund of the
+//client when the dispute is resolved, auto-resolved or charged back, or for the full days so far by an interest run.
+//Disputes without a timestamp accrue nothing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Accrual {
+    //fraction of the held amount per day, e.g. 0.0001 is 1 basis point a day
+    pub daily_rate: f64,
+}
+
+impl Accrual {
+    //amount accrued on held from the opening of the dispute to its decision, rounded to 4 decimal places like the
+    //amounts of the transactions
+    pub fn amount(&self, held: f64, disputed_at: u64, decided_at: u64) -> f64 {
+        let days = decided_at.saturating_sub(disputed_at) / SECS_PER_DAY;
+        (held * self.daily_rate * days as f64 * 10_000.0).round() / 10_000.0
+    }
+
+    //end of the last full day from disputed_at to decided_at, the time the next accrual starts from once this one is
+    //settled
+    pub fn settled_until(&self, disputed_at: u64, decided_at: u64) -> u64 {
+        let days = decided_at.saturating_sub(disputed_at) / SECS_PER_DAY;
+        disputed_at + days * SECS_PER_DAY
+    }
+}
+
+//Row of the accrual report, the sum of the amounts accrued by the decided disputes of a client
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccruedAmount {
+    pub client: u16,
+    pub accrued: f64,
+    pub ledger: SmolStr,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Accrual, SECS_PER_DAY};
+
+    #[test]
+    fn accrued_amount() {
+        let interest = Accrual { daily_rate: 0.001 };
+        assert_eq!(interest.amount(100.0, 0, 3 * SECS_PER_DAY), 0.3);
+        //only the full days accrue
+        assert_eq!(interest.amount(100.0, 0, SECS_PER_DAY - 1), 0.0);
+        assert_eq!(interest.amount(100.0, 10, 5), 0.0);
+
+        let penalty = Accrual {
+            daily_rate: -0.0005,
+        };
+        assert_eq!(penalty.amount(10.0, 0, 2 * SECS_PER_DAY), -0.01);
+
+        //the partial day is accrued by the next settlement
+        assert_eq!(
+            interest.settled_until(10, 2 * SECS_PER_DAY + 20),
+            2 * SECS_PER_DAY + 10
+        );
+        assert_eq!(interest.settled_until(10, 5), 10);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::models::{Transaction, TransactionType, TxId};
+use ahash::AHashSet;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::BufReader;
+
+//Row of the applied ids file: a transaction applied by a run. The type is part of the key since the disputes,
+//resolves and chargebacks share the tx id of the transaction they refer to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AppliedId {
+    pub r#type: TransactionType,
+    pub tx: TxId,
+    //empty for the default ledger
+    pub ledger: SmolStr,
+}
+
+impl AppliedId {
+    //None for the control records and the types the engine doesn't know
+    pub fn new(tx: &Transaction) -> Option<Self> {
+        let r#type = tx.transaction_type()?;
+        let detail = tx.detail()?;
+        Some(Self {
+            r#type,
+            tx: detail.tx,
+            ledger: detail.ledger.clone().unwrap_or_default(),
+        })
+    }
+}
+
+//Transactions applied by the previous runs, loaded from the applied ids file they wrote (type,tx,ledger). Re-running
+//an old file against the carried-forward state then skips its transactions instead of rejecting them as duplicates.
+//A transaction is only known by its type and tx id, so a dispute of a transaction that a previous run disputed is
+//skipped too, even if it is a new dispute after a resolve
+#[derive(Debug, Clone, Default)]
+pub struct AppliedIds {
+    ids: AHashSet<AppliedId>,
+}
+
+impl AppliedIds {
+    //used as a clap value parser so that an invalid file is reported like any other invalid argument
+    #[cfg(feature = "runtime")]
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|e| format!("{path}: {e}"))?);
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        rdr.deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub fn contains(&self, id: &AppliedId) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AppliedId> {
+        self.ids.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl FromIterator<AppliedId> for AppliedIds {
+    fn from_iter<I: IntoIterator<Item = AppliedId>>(iter: I) -> Self {
+        Self {
+            ids: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AppliedId;
+    use crate::models::{Transaction, TransactionDetail, TransactionType};
+
+    #[test]
+    fn applied_id() {
+        let mut detail = TransactionDetail::new(1, 7, None);
+        detail.ledger = Some("eur".into());
+        assert_eq!(
+            AppliedId::new(&Transaction::Dispute(detail)),
+            Some(AppliedId {
+                r#type: TransactionType::Dispute,
+                tx: 7,
+                ledger: "eur".into(),
+            })
+        );
+        assert_eq!(AppliedId::new(&Transaction::Flush), None);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::models::{Account, BalanceAssertion};
+use serde::Serialize;
+use smol_str::SmolStr;
+
+//Balance of an account that doesn't match the one asserted by the input, written to the mismatch report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceMismatch {
+    pub client: u16,
+    //available, held or total
+    pub balance: &'static str,
+    pub expected: f64,
+    pub actual: f64,
+    pub ledger: Option<SmolStr>,
+}
+
+//the balances of the account that differ from the expected ones. The balances are compared at the 4 decimal places
+//of the amounts, so that the float error of the sums doesn't fail the assertion
+pub fn check(assertion: &BalanceAssertion, account: &Account) -> Vec<BalanceMismatch> {
+    [
+        ("available", assertion.available, account.available),
+        ("held", assertion.held, account.held),
+        ("total", assertion.total, account.total),
+    ]
+    .into_iter()
+    .filter_map(|(balance, expected, actual)| {
+        let expected = expected?;
+        let actual = (actual * 10_000.0).round() / 10_000.0;
+        (expected != actual).then(|| BalanceMismatch {
+            client: assertion.client,
+            balance,
+            expected,
+            actual,
+            ledger: assertion.ledger.clone(),
+        })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::check;
+    use crate::models::{Account, BalanceAssertion};
+
+    #[test]
+    fn check_balances() {
+        let account = Account {
+            client: 1,
+            available: 0.1 + 0.2,
+            held: 1.0,
+            total: 1.3,
+            locked: false,
+        };
+        let mut assertion = BalanceAssertion {
+            client: 1,
+            available: Some(0.3),
+            held: None,
+            total: Some(1.3),
+            ledger: None,
+        };
+        assert!(check(&assertion, &account).is_empty());
+
+        assertion.held = Some(2.0);
+        let mismatches = check(&assertion, &account);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            (
+                mismatches[0].balance,
+                mismatches[0].expected,
+                mismatches[0].actual
+            ),
+            ("held", 2.0, 1.0)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::models::{Account, TranactionState, TransactionType, TxId};
+use serde::Serialize;
+use smol_str::SmolStr;
+
+//What changed the account: an accepted transaction, a dispute or authorization that expired when the clock moved, the
+//reversal of a transaction by an operator, or a row of an unknown type applied by a handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Refund,
+    Authorize,
+    Capture,
+    DisputeExpired,
+    AuthorizationExpired,
+    Reversal,
+    Custom,
+}
+
+impl From<TransactionType> for AuditEvent {
+    fn from(transaction_type: TransactionType) -> Self {
+        match transaction_type {
+            TransactionType::Deposit => AuditEvent::Deposit,
+            TransactionType::Withdrawal => AuditEvent::Withdrawal,
+            TransactionType::Dispute => AuditEvent::Dispute,
+            TransactionType::Resolve => AuditEvent::Resolve,
+            TransactionType::ChargeBack => AuditEvent::Chargeback,
+            TransactionType::Refund => AuditEvent::Refund,
+            TransactionType::Authorize => AuditEvent::Authorize,
+            TransactionType::Capture => AuditEvent::Capture,
+        }
+    }
+}
+
+//Balances of the account before and after an event, and the state of the transaction the event refers to once it
+//is applied, e.g. Dispute for the deposit of an accepted dispute. The after balances of a record are the before
+//balances of the next record of the same account, so the final balances can be derived from the first deposit
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub event: AuditEvent,
+    pub client: u16,
+    pub tx: TxId,
+    pub available_before: f64,
+    pub held_before: f64,
+    pub total_before: f64,
+    pub available_after: f64,
+    pub held_after: f64,
+    pub total_after: f64,
+    pub locked: bool,
+    pub state: Option<TranactionState>,
+    pub ledger: Option<SmolStr>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        event: AuditEvent,
+        tx: TxId,
+        before: &Account,
+        after: &Account,
+        state: Option<TranactionState>,
+        ledger: Option<SmolStr>,
+    ) -> Self {
+        Self {
+            event,
+            client: after.client,
+            tx,
+            available_before: before.available,
+            held_before: before.held,
+            total_before: before.total,
+            available_after: after.available,
+            held_after: after.held,
+            total_after: after.total,
+            locked: after.locked,
+            state,
+            ledger,
+        }
+    }
+}
@@ -0,0 +1,1075 @@
+use super::audit::{AuditEvent, AuditRecord};
+use super::config::{DuplicatePolicy, EngineConfig, LockPolicy};
+use super::errors::{
+    AccountLockError, AuthorizeError, CaptureError, ChargebackError, DepositError, DisputeError,
+    DuplicateTransactionError, RefundError, ResolveError, ReversalError, TransactionErrors,
+    WithdrawalError,
+};
+use super::handler::{AccountHandle, TransactionHandler};
+use super::ledger::{Journal, SubLedger};
+use super::snapshot::Snapshot;
+use super::transaction_engine::TransactionOutcome;
+use crate::models::{
+    Account, RawRecord, TranactionState, TransactionDetail, TransactionType, TxId,
+};
+use ahash::AHashMap;
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::mem::size_of;
+
+pub(super) const TRANSACTION_MAP_SIZE: usize = 10000;
+//client id is u16
+pub(super) const ACCOUNT_MAP_SIZE: usize = u16::MAX as usize;
+
+//kind of the stored transaction, deposits and withdrawals have their own id space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum TransactionKind {
+    Deposit,
+    Withdrawal,
+}
+
+impl TransactionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+//What a book needs from the engine to process a transaction: the policies, the journal that every balance mutation
+//is posted to, the clock and the audit trail the expiries are recorded to, if it is kept
+pub(super) struct Context<'a> {
+    pub config: &'a EngineConfig,
+    pub journal: &'a mut Journal,
+    pub now: Option<u64>,
+    pub audit: Option<&'a mut Vec<AuditRecord>>,
+}
+
+//Accounts and transactions of one ledger. The ledgers are fully isolated, the same client or tx id in two ledgers
+//are different clients and transactions
+#[derive(Default)]
+pub(super) struct Book {
+    //map that stores all the deposit and withdrawal transactions
+    pub withdrawal_transactions: AHashMap<TxId, TransactionDetail>,
+    pub deposit_transactions: AHashMap<TxId, TransactionDetail>,
+    //authorizations have their own id space, they are kept after they are captured or released
+    pub authorizations: AHashMap<TxId, TransactionDetail>,
+    pub accounts: AHashMap<u16, Account>,
+    //open disputes ordered by the time they expire
+    pub dispute_deadlines: BTreeSet<(u64, TransactionKind, TxId)>,
+    //pending authorizations ordered by the time they expire
+    pub authorization_deadlines: BTreeSet<(u64, TxId)>,
+    //clients whose account changed since the last flush, only tracked in incremental mode
+    pub changed: BTreeSet<u16>,
+    //sum of the interest or penalty accrued by the decided disputes of each client
+    pub accruals: AHashMap<u16, f64>,
+}
+
+impl Book {
+    pub fn with_capacity(transactions: usize, accounts: usize) -> Self {
+        Self {
+            withdrawal_transactions: AHashMap::with_capacity(transactions),
+            deposit_transactions: AHashMap::with_capacity(transactions),
+            accounts: AHashMap::with_capacity(accounts),
+            ..Default::default()
+        }
+    }
+
+    //get the account of the client, the transaction is rejected if the account is locked and the lock policy
+    //doesn't allow this type of transaction
+    fn get_unlocked_account(
+        accounts: &mut AHashMap<u16, Account>,
+        client: u16,
+        transaction_type: TransactionType,
+        lock_policy: LockPolicy,
+    ) -> anyhow::Result<&mut Account> {
+        let account = accounts.entry(client).or_insert(Account::new(client));
+        if account.locked && !lock_policy.allows(transaction_type) {
+            bail!(TransactionErrors::AccountLock(AccountLockError { client },))
+        } else {
+            Ok(account)
+        }
+    }
+
+    // helper function to check if transaction id already exists. Returns true if the transaction is an exact replay
+    // that the duplicate policy ignores, the caller then returns Skipped without applying it
+    fn check_dup_transaction_id(
+        transactions: &AHashMap<TxId, TransactionDetail>,
+        tx_detail: &TransactionDetail,
+        policy: DuplicatePolicy,
+    ) -> anyhow::Result<bool> {
+        let Some(applied) = transactions.get(&tx_detail.tx) else {
+            return Ok(false);
+        };
+        let conflicts = Self::conflicts(applied, tx_detail);
+        let conflicts = match policy {
+            DuplicatePolicy::Reject => vec![],
+            DuplicatePolicy::IdempotentSkip | DuplicatePolicy::ErrorOnConflict
+                if conflicts.is_empty() =>
+            {
+                tracing::debug!(
+                    client = tx_detail.client,
+                    tx = tx_detail.tx,
+                    "Skipped replay"
+                );
+                return Ok(true);
+            }
+            DuplicatePolicy::IdempotentSkip => vec![],
+            DuplicatePolicy::ErrorOnConflict => conflicts,
+        };
+        bail!(TransactionErrors::DuplicateTransaction(
+            DuplicateTransactionError {
+                tx: tx_detail.tx,
+                conflicts
+            },
+        ))
+    }
+
+    //fields of the input row that differ between the applied transaction and its replay. The timestamp of a replay
+    //without one is not compared since the engine may have set it
+    fn conflicts(applied: &TransactionDetail, replay: &TransactionDetail) -> Vec<&'static str> {
+        let mut conflicts = vec![];
+        if applied.client != replay.client {
+            conflicts.push("client");
+        }
+        if applied.amount != replay.amount {
+            conflicts.push("amount");
+        }
+        if replay.timestamp.is_some() && applied.timestamp != replay.timestamp {
+            conflicts.push("timestamp");
+        }
+        if applied.reference != replay.reference {
+            conflicts.push("reference");
+        }
+        if applied.currency != replay.currency {
+            conflicts.push("currency");
+        }
+        conflicts
+    }
+
+    pub fn process_deposit(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<TransactionOutcome> {
+        if Self::check_dup_transaction_id(
+            &self.deposit_transactions,
+            &tx_detail,
+            ctx.config.duplicate_policy,
+        )? {
+            return Ok(TransactionOutcome::Skipped);
+        }
+        if let Some(amount) = tx_detail.amount {
+            if amount > 0.0 {
+                let account = Self::get_unlocked_account(
+                    &mut self.accounts,
+                    tx_detail.client,
+                    TransactionType::Deposit,
+                    ctx.config.lock_policy,
+                )?;
+                ctx.journal
+                    .try_post(
+                        account,
+                        &tx_detail,
+                        SubLedger::External,
+                        SubLedger::Available,
+                        amount,
+                    )
+                    .map_err(TransactionErrors::Overflow)?;
+                if self
+                    .deposit_transactions
+                    .insert(tx_detail.tx, tx_detail)
+                    .is_none()
+                {
+                    //if map is full, try to resesrve additional space
+                    if self.deposit_transactions.len() == self.deposit_transactions.capacity() {
+                        if let Err(e) = self.deposit_transactions.try_reserve(TRANSACTION_MAP_SIZE)
+                        {
+                            tracing::error!(
+                                "Fail to reserve capacity for the deposit transaction map: {e}"
+                            );
+                        }
+                    }
+                }
+                return Ok(TransactionOutcome::Applied);
+            }
+        }
+
+        bail!(TransactionErrors::Deposit(DepositError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    pub fn process_withdrawal(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<TransactionOutcome> {
+        if Self::check_dup_transaction_id(
+            &self.withdrawal_transactions,
+            &tx_detail,
+            ctx.config.duplicate_policy,
+        )? {
+            return Ok(TransactionOutcome::Skipped);
+        }
+        if let Some(amount) = tx_detail.amount {
+            let account = Self::get_unlocked_account(
+                &mut self.accounts,
+                tx_detail.client,
+                TransactionType::Withdrawal,
+                ctx.config.lock_policy,
+            )?;
+            //if the amount is > 0 and if available fund is > the withdraw amount
+            if amount > 0.0 && account.available >= amount {
+                ctx.journal.post(
+                    account,
+                    &tx_detail,
+                    SubLedger::Available,
+                    SubLedger::External,
+                    amount,
+                );
+                if self
+                    .withdrawal_transactions
+                    .insert(tx_detail.tx, tx_detail)
+                    .is_none()
+                {
+                    //if map is full, try to resesrve additional space
+                    if self.withdrawal_transactions.len() == self.withdrawal_transactions.capacity()
+                    {
+                        if let Err(e) = self
+                            .withdrawal_transactions
+                            .try_reserve(TRANSACTION_MAP_SIZE)
+                        {
+                            tracing::error!(
+                                "Fail to reserve capacity for the withdrawal transaction map: {e}"
+                            );
+                        }
+                    }
+                }
+                return Ok(TransactionOutcome::Applied);
+            }
+        }
+
+        bail!(TransactionErrors::Withdrawal(WithdrawalError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    //A transaction can be disputed if it has never been disputed. A resolved transaction can only be disputed again
+    //if re-dispute is allowed and it hasn't reached the max number of re-disputes
+    fn is_disputable(config: &EngineConfig, tx_detail: &TransactionDetail) -> bool {
+        match tx_detail.state {
+            TranactionState::Normal => true,
+            TranactionState::Resolve => tx_detail.redisputes < config.max_redisputes,
+            _ => false,
+        }
+    }
+
+    //mark the transaction as disputed, counting it as a re-dispute if it was resolved before
+    fn open_dispute(tx_detail: &mut TransactionDetail, opened_at: Option<u64>) {
+        if tx_detail.state == TranactionState::Resolve {
+            tx_detail.redisputes += 1;
+        }
+        tx_detail.state = TranactionState::Dispute;
+        tx_detail.disputed_at = opened_at;
+        tx_detail.accrued_until = None;
+    }
+
+    //schedule the auto-resolve of a dispute if the dispute ttl is configured and we know when it was opened
+    fn schedule_dispute_expiry(
+        &mut self,
+        config: &EngineConfig,
+        kind: TransactionKind,
+        tx: TxId,
+        disputed_at: Option<u64>,
+    ) {
+        if let (Some(ttl), Some(disputed_at)) = (config.dispute_ttl, disputed_at) {
+            self.dispute_deadlines
+                .insert((disputed_at.saturating_add(ttl), kind, tx));
+        }
+    }
+
+    //Credit the interest, or debit the penalty, accrued on the held amount of a dispute that is decided at
+    //decided_at, since it was opened or last settled by an interest run. Like a chargeback, a penalty can leave a
+    //negative available fund
+    fn settle_accrual(
+        ctx: &mut Context,
+        accruals: &mut AHashMap<u16, f64>,
+        account: &mut Account,
+        disputed: &TransactionDetail,
+        decided_at: Option<u64>,
+    ) {
+        let (Some(accrual), Some(amount), Some(disputed_at), Some(decided_at)) = (
+            ctx.config.accrual,
+            disputed.disputable_amount(),
+            disputed.accrued_until.or(disputed.disputed_at),
+            decided_at,
+        ) else {
+            return;
+        };
+        let accrued = accrual.amount(amount, disputed_at, decided_at);
+        if accrued > 0.0 {
+            ctx.journal.post(
+                account,
+                disputed,
+                SubLedger::External,
+                SubLedger::Available,
+                accrued,
+            );
+        } else if accrued < 0.0 {
+            ctx.journal.post(
+                account,
+                disputed,
+                SubLedger::Available,
+                SubLedger::External,
+                -accrued,
+            );
+        } else {
+            return;
+        }
+        let total = accruals.entry(account.client).or_default();
+        *total = ((*total + accrued) * 10_000.0).round() / 10_000.0;
+    }
+
+    //Settle the interest or penalty accrued so far by the open disputes, for the full days since they were opened or
+    //last settled, e.g. on an end-of-day interest run. The days left are settled when the dispute is decided
+    pub fn settle_open_accruals(&mut self, ctx: &mut Context, now: u64) {
+        let Some(accrual) = ctx.config.accrual else {
+            return;
+        };
+        //sorted so that the postings of the journal are in the same order on every run
+        let mut open = [
+            (TransactionKind::Deposit, &self.deposit_transactions),
+            (TransactionKind::Withdrawal, &self.withdrawal_transactions),
+        ]
+        .into_iter()
+        .flat_map(|(kind, transactions)| {
+            transactions
+                .values()
+                .filter(|t| t.state == TranactionState::Dispute)
+                .map(move |t| (kind, t.tx))
+        })
+        .collect::<Vec<_>>();
+        open.sort_unstable();
+        for (kind, tx) in open {
+            let transactions = match kind {
+                TransactionKind::Deposit => &mut self.deposit_transactions,
+                TransactionKind::Withdrawal => &mut self.withdrawal_transactions,
+            };
+            let Some(tx_detail) = transactions.get_mut(&tx) else {
+                continue;
+            };
+            let Some(from) = tx_detail.accrued_until.or(tx_detail.disputed_at) else {
+                continue;
+            };
+            let account = self
+                .accounts
+                .entry(tx_detail.client)
+                .or_insert(Account::new(tx_detail.client));
+            Self::settle_accrual(ctx, &mut self.accruals, account, tx_detail, Some(now));
+            tx_detail.accrued_until = Some(accrual.settled_until(from, now));
+            if ctx.config.incremental {
+                self.changed.insert(tx_detail.client);
+            }
+        }
+    }
+
+    //Auto-resolve a dispute that is not decided before the deadline. The funds are released even if the account
+    //is locked since the dispute window is closed by the network regardless of the state of the account
+    fn expire_dispute(
+        &mut self,
+        ctx: &mut Context,
+        kind: TransactionKind,
+        tx: TxId,
+        deadline: u64,
+    ) {
+        let transactions = match kind {
+            TransactionKind::Deposit => &mut self.deposit_transactions,
+            TransactionKind::Withdrawal => &mut self.withdrawal_transactions,
+        };
+        let Some(tx_detail) = transactions.get_mut(&tx) else {
+            return;
+        };
+        //the dispute may have been decided or re-opened after the deadline was scheduled
+        let ttl = ctx.config.dispute_ttl.unwrap_or_default();
+        if tx_detail.state != TranactionState::Dispute
+            || tx_detail.disputed_at.map(|at| at.saturating_add(ttl)) != Some(deadline)
+        {
+            return;
+        }
+        let account = self
+            .accounts
+            .entry(tx_detail.client)
+            .or_insert(Account::new(tx_detail.client));
+        let before = ctx.audit.is_some().then(|| account.clone());
+        let resolved = match kind {
+            TransactionKind::Deposit => Self::resolve_deposit(ctx.journal, account, tx_detail),
+            TransactionKind::Withdrawal => {
+                Self::resolve_withdrawal(ctx.journal, account, tx_detail)
+            }
+        };
+        if resolved {
+            Self::settle_accrual(ctx, &mut self.accruals, account, tx_detail, Some(deadline));
+        }
+        let client = tx_detail.client;
+        if ctx.config.incremental {
+            self.changed.insert(client);
+        }
+        if let (Some(audit), Some(before), true) = (&mut ctx.audit, before, resolved) {
+            audit.push(AuditRecord::new(
+                AuditEvent::DisputeExpired,
+                tx,
+                &before,
+                account,
+                Some(tx_detail.state.clone()),
+                tx_detail.ledger.clone(),
+            ));
+        }
+        if resolved {
+            tracing::info!(
+                client,
+                tx,
+                "type" = kind.as_str(),
+                "Dispute of tx {tx} for client {client} expired at {deadline} and is auto-resolved"
+            );
+        } else {
+            tracing::error!(
+                client,
+                tx,
+                "type" = kind.as_str(),
+                "Fail to auto-resolve expired dispute of tx {tx}"
+            );
+        }
+    }
+
+    //The doc mentioned that during a dispute, the held fund is increased by the dispute amount and the available fund is decreased by. I assume that
+    //this is referring to a dispute for a withdrawal transaction as it simply means moving fund from the the available fund to the held fund. For disputing a
+    // withdrawal, I don't think we should decrease the avaiable fund as the client as disputing an incorrect amount being debit from his/her account. So for the dispute
+    //of a withdrawal transaction, I decided to increment the held fund only, which means the total fund will increase. However, since the client can't really use that amount yet,
+    //so I believe it's fine.
+    pub fn process_dispute(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<()> {
+        //ignore the dispute if the account is locked
+        let account = Self::get_unlocked_account(
+            &mut self.accounts,
+            tx_detail.client,
+            TransactionType::Dispute,
+            ctx.config.lock_policy,
+        )?;
+        //if the dispute transaction is a deposit
+        if let Some(dispute_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
+            if let Some(amount) = dispute_tx_detail.disputable_amount() {
+                //a fully refunded deposit has nothing left to dispute
+                if tx_detail.client == dispute_tx_detail.client
+                    && Self::is_disputable(ctx.config, dispute_tx_detail)
+                    && amount > 0.0
+                    && account.available >= amount
+                {
+                    //Move the dispute amount from available to held, total doesn't change
+                    ctx.journal.post(
+                        account,
+                        &tx_detail,
+                        SubLedger::Available,
+                        SubLedger::Held,
+                        amount,
+                    );
+                    Self::open_dispute(dispute_tx_detail, tx_detail.timestamp.or(ctx.now));
+                    let disputed_at = dispute_tx_detail.disputed_at;
+                    self.schedule_dispute_expiry(
+                        ctx.config,
+                        TransactionKind::Deposit,
+                        tx_detail.tx,
+                        disputed_at,
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        //if the dispute transaction is a withdraw
+        else if let Some(dispute_tx_detail) = self.withdrawal_transactions.get_mut(&tx_detail.tx)
+        {
+            if let Some(amount) = dispute_tx_detail.disputable_amount() {
+                if tx_detail.client == dispute_tx_detail.client
+                    && Self::is_disputable(ctx.config, dispute_tx_detail)
+                {
+                    //increase the held and total. Since the increased amount is held, increasing the total should be
+                    //fine
+                    ctx.journal
+                        .try_post(
+                            account,
+                            &tx_detail,
+                            SubLedger::External,
+                            SubLedger::Held,
+                            amount,
+                        )
+                        .map_err(TransactionErrors::Overflow)?;
+                    Self::open_dispute(dispute_tx_detail, tx_detail.timestamp.or(ctx.now));
+                    let disputed_at = dispute_tx_detail.disputed_at;
+                    self.schedule_dispute_expiry(
+                        ctx.config,
+                        TransactionKind::Withdrawal,
+                        tx_detail.tx,
+                        disputed_at,
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!(TransactionErrors::Dispute(DisputeError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    pub fn process_resolve(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<()> {
+        //ignore the resolve if the account is locked
+        let account = Self::get_unlocked_account(
+            &mut self.accounts,
+            tx_detail.client,
+            TransactionType::Resolve,
+            ctx.config.lock_policy,
+        )?;
+
+        let decided_at = tx_detail.timestamp.or(ctx.now);
+        //resolve disputed deposit transaction
+        if let Some(resolve_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
+            if tx_detail.client == resolve_tx_detail.client
+                && Self::resolve_deposit(ctx.journal, account, resolve_tx_detail)
+            {
+                Self::settle_accrual(
+                    ctx,
+                    &mut self.accruals,
+                    account,
+                    resolve_tx_detail,
+                    decided_at,
+                );
+                return Ok(());
+            }
+        }
+        //resolve disputed withdraw transaction
+        else if let Some(resolve_tx_detail) = self.withdrawal_transactions.get_mut(&tx_detail.tx)
+        {
+            if tx_detail.client == resolve_tx_detail.client
+                && Self::resolve_withdrawal(ctx.journal, account, resolve_tx_detail)
+            {
+                Self::settle_accrual(
+                    ctx,
+                    &mut self.accruals,
+                    account,
+                    resolve_tx_detail,
+                    decided_at,
+                );
+                return Ok(());
+            }
+        }
+
+        bail!(TransactionErrors::Resolve(ResolveError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    fn resolve_deposit(
+        journal: &mut Journal,
+        account: &mut Account,
+        resolve_tx_detail: &mut TransactionDetail,
+    ) -> bool {
+        if let Some(amount) = resolve_tx_detail.disputable_amount() {
+            if resolve_tx_detail.state == TranactionState::Dispute && account.held >= amount {
+                //Move the amount from the held back to the available
+                journal.post(
+                    account,
+                    resolve_tx_detail,
+                    SubLedger::Held,
+                    SubLedger::Available,
+                    amount,
+                );
+                resolve_tx_detail.state = TranactionState::Resolve;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn resolve_withdrawal(
+        journal: &mut Journal,
+        account: &mut Account,
+        resolve_tx_detail: &mut TransactionDetail,
+    ) -> bool {
+        if let Some(amount) = resolve_tx_detail.disputable_amount() {
+            if resolve_tx_detail.state == TranactionState::Dispute && account.held >= amount {
+                //decrease the held and total
+                journal.post(
+                    account,
+                    resolve_tx_detail,
+                    SubLedger::Held,
+                    SubLedger::External,
+                    amount,
+                );
+                resolve_tx_detail.state = TranactionState::Resolve;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn process_chargeback(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<()> {
+        //ignore the chargeback if the account is locked
+        let account = Self::get_unlocked_account(
+            &mut self.accounts,
+            tx_detail.client,
+            TransactionType::ChargeBack,
+            ctx.config.lock_policy,
+        )?;
+        //chargeback disputed deposit transaction
+        if let Some(chargeback_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
+            if let Some(amount) = chargeback_tx_detail.disputable_amount() {
+                if tx_detail.client == chargeback_tx_detail.client
+                    && chargeback_tx_detail.state == TranactionState::Dispute
+                    && account.held >= amount
+                {
+                    //the held amount goes back to the source of the deposit
+                    ctx.journal.post(
+                        account,
+                        &tx_detail,
+                        SubLedger::Held,
+                        SubLedger::External,
+                        amount,
+                    );
+                    account.locked = true;
+                    chargeback_tx_detail.state = TranactionState::ChargeBack;
+                    Self::settle_accrual(
+                        ctx,
+                        &mut self.accruals,
+                        account,
+                        chargeback_tx_detail,
+                        tx_detail.timestamp.or(ctx.now),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        //chargeback disputed withdraw transaction
+        else if let Some(chargeback_tx_detail) =
+            self.withdrawal_transactions.get_mut(&tx_detail.tx)
+        {
+            if let Some(amount) = chargeback_tx_detail.disputable_amount() {
+                if tx_detail.client == chargeback_tx_detail.client
+                    && chargeback_tx_detail.state == TranactionState::Dispute
+                    && account.held >= amount
+                {
+                    //Move the amount from held back to avaiable
+                    ctx.journal.post(
+                        account,
+                        &tx_detail,
+                        SubLedger::Held,
+                        SubLedger::Available,
+                        amount,
+                    );
+                    account.locked = true;
+                    chargeback_tx_detail.state = TranactionState::ChargeBack;
+                    Self::settle_accrual(
+                        ctx,
+                        &mut self.accruals,
+                        account,
+                        chargeback_tx_detail,
+                        tx_detail.timestamp.or(ctx.now),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        bail!(TransactionErrors::Chargeback(ChargebackError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    //A refund returns the given amount of a deposit, or what is left of it if no amount is given, to the source of
+    //the deposit. A deposit can be refunded in several parts but not while it is disputed or after a chargeback
+    pub fn process_refund(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<()> {
+        let account = Self::get_unlocked_account(
+            &mut self.accounts,
+            tx_detail.client,
+            TransactionType::Refund,
+            ctx.config.lock_policy,
+        )?;
+        if let Some(deposit) = self.deposit_transactions.get_mut(&tx_detail.tx) {
+            if let Some(amount) = deposit.amount {
+                //amounts have 4 decimal places, round so that the parts of a refund add up to the deposit
+                let remaining = ((amount - deposit.refunded) * 10_000.0).round() / 10_000.0;
+                let refund = tx_detail.amount.unwrap_or(remaining);
+                if tx_detail.client == deposit.client
+                    && matches!(
+                        deposit.state,
+                        TranactionState::Normal | TranactionState::Resolve
+                    )
+                    && refund > 0.0
+                    && refund <= remaining
+                    && account.available >= refund
+                {
+                    ctx.journal.post(
+                        account,
+                        &tx_detail,
+                        SubLedger::Available,
+                        SubLedger::External,
+                        refund,
+                    );
+                    deposit.refunded += refund;
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!(TransactionErrors::Refund(RefundError { tx: tx_detail.tx },))
+    }
+
+    //An authorization holds the amount without settling it, the funds stay in the account until the authorization
+    //is captured or released
+    pub fn process_authorize(
+        &mut self,
+        ctx: &mut Context,
+        mut tx_detail: TransactionDetail,
+    ) -> anyhow::Result<TransactionOutcome> {
+        if Self::check_dup_transaction_id(
+            &self.authorizations,
+            &tx_detail,
+            ctx.config.duplicate_policy,
+        )? {
+            return Ok(TransactionOutcome::Skipped);
+        }
+        if let Some(amount) = tx_detail.amount {
+            let account = Self::get_unlocked_account(
+                &mut self.accounts,
+                tx_detail.client,
+                TransactionType::Authorize,
+                ctx.config.lock_policy,
+            )?;
+            if amount > 0.0 && account.available >= amount {
+                ctx.journal.post(
+                    account,
+                    &tx_detail,
+                    SubLedger::Available,
+                    SubLedger::Held,
+                    amount,
+                );
+                tx_detail.state = TranactionState::Authorized;
+                tx_detail.timestamp = tx_detail.timestamp.or(ctx.now);
+                if let (Some(ttl), Some(authorized_at)) =
+                    (ctx.config.authorization_ttl, tx_detail.timestamp)
+                {
+                    self.authorization_deadlines
+                        .insert((authorized_at.saturating_add(ttl), tx_detail.tx));
+                }
+                self.authorizations.insert(tx_detail.tx, tx_detail);
+                return Ok(TransactionOutcome::Applied);
+            }
+        }
+
+        bail!(TransactionErrors::Authorize(AuthorizeError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    //A capture settles an authorization for the given amount, which can be lower than the authorized amount, or for
+    //the authorized amount if no amount is given. The part of the held amount that is not captured goes back to
+    //available
+    pub fn process_capture(
+        &mut self,
+        ctx: &mut Context,
+        tx_detail: TransactionDetail,
+    ) -> anyhow::Result<()> {
+        let account = Self::get_unlocked_account(
+            &mut self.accounts,
+            tx_detail.client,
+            TransactionType::Capture,
+            ctx.config.lock_policy,
+        )?;
+        if let Some(authorization) = self.authorizations.get_mut(&tx_detail.tx) {
+            if let Some(authorized) = authorization.amount {
+                let captured = tx_detail.amount.unwrap_or(authorized);
+                if tx_detail.client == authorization.client
+                    && authorization.state == TranactionState::Authorized
+                    && captured > 0.0
+                    && captured <= authorized
+                    && account.held >= authorized
+                {
+                    ctx.journal.post(
+                        account,
+                        &tx_detail,
+                        SubLedger::Held,
+                        SubLedger::External,
+                        captured,
+                    );
+                    if captured < authorized {
+                        ctx.journal.post(
+                            account,
+                            &tx_detail,
+                            SubLedger::Held,
+                            SubLedger::Available,
+                            authorized - captured,
+                        );
+                    }
+                    authorization.state = TranactionState::Captured;
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!(TransactionErrors::Capture(CaptureError {
+            tx: tx_detail.tx
+        },))
+    }
+
+    //A row of a type the engine doesn't know is given to the handler registered for its type. The row isn't stored,
+    //so it can't be disputed and its tx id isn't checked for duplicates
+    pub fn process_unknown(
+        &mut self,
+        ctx: &mut Context,
+        handler: &dyn TransactionHandler,
+        tx_detail: TransactionDetail,
+        record: RawRecord,
+    ) -> anyhow::Result<()> {
+        let account = self
+            .accounts
+            .entry(tx_detail.client)
+            .or_insert(Account::new(tx_detail.client));
+        let mut handle = AccountHandle::new(account, &tx_detail);
+        handler.handle(&tx_detail, &record, &mut handle)?;
+        let (handled, postings) = handle.finish();
+        *account = handled;
+        ctx.journal.append(postings);
+        Ok(())
+    }
+
+    //Release the held amount of an authorization that is not captured before it expires. Like an expired dispute,
+    //the funds are released even if the account is locked
+    fn expire_authorization(&mut self, ctx: &mut Context, tx: TxId) {
+        let Some(authorization) = self.authorizations.get_mut(&tx) else {
+            return;
+        };
+        let Some(amount) = authorization.amount else {
+            return;
+        };
+        let client = authorization.client;
+        let account = self.accounts.entry(client).or_insert(Account::new(client));
+        if authorization.state != TranactionState::Authorized || account.held < amount {
+            return;
+        }
+        let before = ctx.audit.is_some().then(|| account.clone());
+        ctx.journal.post(
+            account,
+            authorization,
+            SubLedger::Held,
+            SubLedger::Available,
+            amount,
+        );
+        authorization.state = TranactionState::Released;
+        if let (Some(audit), Some(before)) = (&mut ctx.audit, before) {
+            audit.push(AuditRecord::new(
+                AuditEvent::AuthorizationExpired,
+                tx,
+                &before,
+                account,
+                Some(TranactionState::Released),
+                authorization.ledger.clone(),
+            ));
+        }
+        if ctx.config.incremental {
+            self.changed.insert(client);
+        }
+        tracing::info!(
+            client,
+            tx,
+            "type" = "authorize",
+            "Authorization {tx} for client {client} expired and is released"
+        );
+    }
+
+    //auto-resolve all the disputes and release all the authorizations that expire at the timestamp or before
+    pub fn expire_until(&mut self, ctx: &mut Context, timestamp: u64) {
+        while let Some(&(deadline, kind, tx)) = self.dispute_deadlines.first() {
+            if deadline > timestamp {
+                break;
+            }
+            self.dispute_deadlines.pop_first();
+            self.expire_dispute(ctx, kind, tx, deadline);
+        }
+        while let Some(&(deadline, tx)) = self.authorization_deadlines.first() {
+            if deadline > timestamp {
+                break;
+            }
+            self.authorization_deadlines.pop_first();
+            self.expire_authorization(ctx, tx);
+        }
+    }
+
+    //Cancel a deposit or withdrawal applied by mistake with its inverse entry and return the client. Deposits are
+    //looked up first like a dispute. Only a transaction that is neither disputed, charged back nor refunded can be
+    //reversed, and like a chargeback the reversal of a deposit can leave a negative available fund. It is applied
+    //even if the account is locked since it is an operator correction
+    pub fn reverse(&mut self, ctx: &mut Context, tx: TxId) -> anyhow::Result<u16> {
+        let (transaction, credit, debit) =
+            if let Some(deposit) = self.deposit_transactions.get_mut(&tx) {
+                let reversible = deposit.refunded == 0.0;
+                (
+                    Some(deposit).filter(|_| reversible),
+                    SubLedger::Available,
+                    SubLedger::External,
+                )
+            } else {
+                (
+                    self.withdrawal_transactions.get_mut(&tx),
+                    SubLedger::External,
+                    SubLedger::Available,
+                )
+            };
+        if let Some(transaction) = transaction {
+            if let (Some(amount), TranactionState::Normal | TranactionState::Resolve) =
+                (transaction.amount, &transaction.state)
+            {
+                let client = transaction.client;
+                let account = self.accounts.entry(client).or_insert(Account::new(client));
+                ctx.journal
+                    .try_post(account, transaction, credit, debit, amount)
+                    .map_err(TransactionErrors::Overflow)?;
+                transaction.state = TranactionState::Reversed;
+                if ctx.config.incremental {
+                    self.changed.insert(client);
+                }
+                return Ok(client);
+            }
+        }
+
+        bail!(TransactionErrors::Reversal(ReversalError { tx }))
+    }
+
+    //state of the stored transaction a transaction of this type refers to, disputes, resolves and chargebacks refer
+    //to a deposit first like process_dispute
+    pub fn state(&self, transaction_type: TransactionType, tx: TxId) -> Option<&TranactionState> {
+        let transaction = match transaction_type {
+            TransactionType::Deposit | TransactionType::Refund => {
+                self.deposit_transactions.get(&tx)
+            }
+            TransactionType::Withdrawal => self.withdrawal_transactions.get(&tx),
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::ChargeBack => {
+                self.deposit_transactions
+                    .get(&tx)
+                    .or_else(|| self.withdrawal_transactions.get(&tx))
+            }
+            TransactionType::Authorize | TransactionType::Capture => self.authorizations.get(&tx),
+        };
+        transaction.map(|t| &t.state)
+    }
+
+    //deposits and withdrawals that are still in dispute
+    pub fn open_disputes(&self) -> impl Iterator<Item = (TransactionKind, &TransactionDetail)> {
+        self.deposit_transactions
+            .values()
+            .map(|t| (TransactionKind::Deposit, t))
+            .chain(
+                self.withdrawal_transactions
+                    .values()
+                    .map(|t| (TransactionKind::Withdrawal, t)),
+            )
+            .filter(|(_, t)| t.state == TranactionState::Dispute)
+    }
+
+    //remove the unlocked accounts with zero balances and no open dispute, and return them sorted by client. Such an
+    //account is the same as a new one, so it is re-created by the next transaction of the client
+    pub fn archive_inactive(&mut self) -> Vec<Account> {
+        let disputed = self
+            .open_disputes()
+            .map(|(_, t)| t.client)
+            .collect::<BTreeSet<_>>();
+        let mut archived = vec![];
+        self.accounts.retain(|client, account| {
+            let inactive = account.available == 0.0
+                && account.held == 0.0
+                && !account.locked
+                && !disputed.contains(client);
+            if inactive {
+                archived.push(account.clone());
+            }
+            !inactive
+        });
+        archived.sort_by_key(|account| account.client);
+        archived
+    }
+
+    //approximate number of bytes taken by the entries of the maps, a hash map entry also has a control byte. The
+    //spare capacity of the maps and the strings too long to be inlined are not counted
+    pub fn memory_usage(&self) -> usize {
+        let transactions = self.deposit_transactions.len()
+            + self.withdrawal_transactions.len()
+            + self.authorizations.len();
+        transactions * (size_of::<TxId>() + size_of::<TransactionDetail>() + 1)
+            + self.accounts.len() * (size_of::<u16>() + size_of::<Account>() + 1)
+            + self.dispute_deadlines.len() * size_of::<(u64, TransactionKind, TxId)>()
+            + self.authorization_deadlines.len() * size_of::<(u64, TxId)>()
+            + self.accruals.len() * (size_of::<u16>() + size_of::<f64>() + 1)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            accounts: self.accounts.values().cloned().collect(),
+            deposits: self.deposit_transactions.values().cloned().collect(),
+            withdrawals: self.withdrawal_transactions.values().cloned().collect(),
+            authorizations: self.authorizations.values().cloned().collect(),
+            accruals: self
+                .accruals
+                .iter()
+                .map(|(client, accrued)| (*client, *accrued))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    //restore the accounts and transactions of the ledger from a previous run
+    pub fn restore(&mut self, config: &EngineConfig, snapshot: Snapshot) {
+        self.accounts
+            .extend(snapshot.accounts.into_iter().map(|a| (a.client, a)));
+        self.deposit_transactions
+            .extend(snapshot.deposits.into_iter().map(|t| (t.tx, t)));
+        self.withdrawal_transactions
+            .extend(snapshot.withdrawals.into_iter().map(|t| (t.tx, t)));
+        self.authorizations
+            .extend(snapshot.authorizations.into_iter().map(|t| (t.tx, t)));
+        self.accruals.extend(snapshot.accruals);
+
+        //reschedule the open disputes
+        let open_disputes = self
+            .open_disputes()
+            .map(|(kind, t)| (kind, t.tx, t.disputed_at))
+            .collect::<Vec<_>>();
+        for (kind, tx, disputed_at) in open_disputes {
+            self.schedule_dispute_expiry(config, kind, tx, disputed_at);
+        }
+
+        //reschedule the pending authorizations
+        if let Some(ttl) = config.authorization_ttl {
+            self.authorization_deadlines.extend(
+                self.authorizations
+                    .values()
+                    .filter(|t| t.state == TranactionState::Authorized)
+                    .filter_map(|t| Some((t.timestamp?.saturating_add(ttl), t.tx))),
+            );
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+//Source of the time of the engine in seconds, which decides when the disputes and authorizations expire. The engine
+//only moves forward, so a clock that goes back is ignored until it catches up
+pub trait Clock: Send {
+    //time when a transaction with this timestamp is processed, None if the time is still unknown
+    fn now(&mut self, timestamp: Option<u64>) -> Option<u64>;
+}
+
+//The time is the latest timestamp of the input, so that replaying a file expires the same disputes. This is the
+//default clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InputClock;
+
+impl Clock for InputClock {
+    fn now(&mut self, timestamp: Option<u64>) -> Option<u64> {
+        timestamp
+    }
+}
+
+//The time is the unix time of the machine, for live feeds without timestamps
+#[cfg(feature = "runtime")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "runtime")]
+impl Clock for SystemClock {
+    fn now(&mut self, _timestamp: Option<u64>) -> Option<u64> {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs())
+    }
+}
+
+//The time is set by the caller and the timestamps of the input are ignored, e.g. by a test that moves the time
+//forward. The clones share the same time, so one can be kept while the other is given to the engine
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.now.fetch_add(secs, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&mut self, _timestamp: Option<u64>) -> Option<u64> {
+        Some(self.now.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,160 @@
+use super::accrual::Accrual;
+use super::applied::AppliedIds;
+use super::profile::ClientProfiles;
+use super::quarantine::QuarantineRules;
+use super::validation::{ClientRange, ValidationRules};
+use crate::models::{Account, TransactionType};
+
+//Which transactions are still accepted once an account is locked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LockPolicy {
+    //reject every transaction
+    #[default]
+    BlockAll,
+    //accept deposits so the client can repay a negative balance caused by a chargeback
+    AllowDeposits,
+    //accept everything but withdrawals, refunds and authorizations
+    AllowCreditsAndDisputes,
+}
+
+impl LockPolicy {
+    pub fn allows(&self, transaction_type: TransactionType) -> bool {
+        match self {
+            LockPolicy::BlockAll => false,
+            LockPolicy::AllowDeposits => transaction_type == TransactionType::Deposit,
+            LockPolicy::AllowCreditsAndDisputes => !matches!(
+                transaction_type,
+                TransactionType::Withdrawal | TransactionType::Refund | TransactionType::Authorize
+            ),
+        }
+    }
+}
+
+//What happens to a deposit, withdrawal or authorization whose tx id has already been applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DuplicatePolicy {
+    //reject every duplicate
+    #[default]
+    Reject,
+    //ignore the exact replays of the applied transaction without an error, e.g. the retries of the producer, and
+    //reject the other duplicates
+    IdempotentSkip,
+    //ignore the exact replays and report the duplicates that differ from the applied transaction as conflicts,
+    //naming the fields that differ
+    ErrorOnConflict,
+}
+
+//What happens to a transaction of a client that is over its rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum RateLimitPolicy {
+    //reject the transaction
+    #[default]
+    Reject,
+    //queue the transaction until the client has a token again, up to burst queued transactions per client
+    Defer,
+}
+
+//Token bucket of each client: it holds up to burst tokens and gets rate tokens per second, and every transaction
+//takes one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+    pub policy: RateLimitPolicy,
+}
+
+//Setting of a running engine changed without restarting it, e.g. by the admin api. The other settings only apply
+//to a new engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigChange {
+    //None removes the rate limit and applies the deferred transactions right away
+    RateLimit(Option<RateLimit>),
+    LockPolicy(LockPolicy),
+    DuplicatePolicy(DuplicatePolicy),
+}
+
+//Accounts written to the account report, so that a consumer can get the slice it cares about instead of every
+//account. Every account is written by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountFilter {
+    //an empty list selects every client
+    pub clients: Vec<ClientRange>,
+    pub only_locked: bool,
+    //skip the accounts whose balances are all zero
+    pub nonzero_only: bool,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account: &Account) -> bool {
+        (self.clients.is_empty()
+            || self
+                .clients
+                .iter()
+                .any(|range| range.contains(account.client)))
+            && (!self.only_locked || account.locked)
+            && (!self.nonzero_only
+                || account.available != 0.0
+                || account.held != 0.0
+                || account.total != 0.0)
+    }
+}
+
+//Policies of the transaction engine. The default follows the behaviour described in the spec
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    //max number of times a resolved transaction can be disputed again, 0 means a resolved transaction is final
+    pub max_redisputes: u32,
+    //number of seconds after which an undecided dispute is auto-resolved, only applies to inputs with timestamps
+    pub dispute_ttl: Option<u64>,
+    //interest or penalty on the held amount of a dispute, settled when the dispute is decided
+    pub accrual: Option<Accrual>,
+    //number of seconds after which an authorization that is not captured releases its held funds
+    pub authorization_ttl: Option<u64>,
+    pub lock_policy: LockPolicy,
+    pub duplicate_policy: DuplicatePolicy,
+    //write the accounts changed since the last flush on a flush record, every flush_every transactions and at the end
+    //of the input, instead of writing all the accounts at the end
+    pub incremental: bool,
+    pub flush_every: Option<u64>,
+    //number of transactions between two sweeps that archive the inactive accounts
+    pub archive_every: Option<u64>,
+    //keep the postings of the journal so that they can be written at the end
+    pub journal: bool,
+    //keep the balances before and after every applied state change until they are taken or written
+    pub audit: bool,
+    //keep the accepted transactions as events until they are taken or written, so that the state can be rebuilt
+    pub events: bool,
+    //types handled by another system, their transactions are rejected before the validation rules
+    pub disabled_types: Vec<TransactionType>,
+    //rules checked before a transaction is applied
+    pub validation: ValidationRules,
+    //keep the transactions rejected by the validation rules or because their type is disabled, so that they can be
+    //written at the end
+    pub record_rejects: bool,
+    //transactions applied by the previous runs, they are skipped without an error
+    pub applied: Option<AppliedIds>,
+    //keep the ids of the applied transactions so that they can be written at the end for the next runs
+    pub record_applied: bool,
+    //rules that park suspicious transactions for review instead of applying them
+    pub quarantine: Option<QuarantineRules>,
+    //limit of the transactions received by run for each client, for live feeds that are not trusted
+    pub rate_limit: Option<RateLimit>,
+    //kyc data joined into the account report, the tier of a client also selects its tier rules in the validation
+    pub profiles: Option<ClientProfiles>,
+    //accounts written to the account report, in full or incrementally
+    pub account_filter: AccountFilter,
+    //the account reports have a ledger column even before a ledger other than the default one is seen, e.g. when
+    //the input has a ledger column, so that the incremental rows have it from the first flush
+    pub ledger_column: bool,
+    //max number of bytes of the memory usage of the engine, once it is over the inactive accounts are archived if
+    //there is an archive, and run stops the producer if it is still over
+    pub max_memory: Option<usize>,
+    //apply the checkpoint and rollback rows of the input, they are rejected otherwise since a single row could roll
+    //back the whole engine
+    pub checkpoint_records: bool,
+    //max number of checkpoints kept at once, each of them is a full copy of the state
+    pub max_checkpoints: Option<usize>,
+}
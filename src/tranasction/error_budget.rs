@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+//the error rate of the first rows is noise, e.g. a bad first row is a rate of 1, so the rate is only checked during
+//the run once this many rows are read
+const MIN_ROWS_FOR_RATE: u64 = 1000;
+
+//Number of failures a run can afford: the rows that can't be parsed and the transactions rejected by the engine. It is
+//shared by the parser, which counts the rows and stops once the budget is exceeded, and the engine
+#[derive(Debug, Default)]
+pub struct ErrorBudget {
+    max_errors: Option<u64>,
+    max_rate: Option<f64>,
+    rows: AtomicU64,
+    errors: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl ErrorBudget {
+    pub fn new(max_errors: Option<u64>, max_rate: Option<f64>) -> Self {
+        Self {
+            max_errors,
+            max_rate,
+            ..Default::default()
+        }
+    }
+
+    //a row read by the parser, failed if it can't be parsed
+    pub fn record_row(&self, failed: bool) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.record_error();
+        }
+    }
+
+    //a row that can't be parsed or a transaction rejected by the engine
+    pub fn record_error(&self) {
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        let rows = self.rows.load(Ordering::Relaxed);
+        if self.over(errors, rows, MIN_ROWS_FOR_RATE) {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+    }
+
+    //true once the budget is exceeded during the run
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    //true if the budget was exceeded during the run, or if the error rate of all the rows is over the max once the
+    //whole input is processed
+    pub fn exceeded_at_end(&self) -> bool {
+        self.exceeded() || self.over(self.errors(), self.rows(), 0)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows.load(Ordering::Relaxed)
+    }
+
+    fn over(&self, errors: u64, rows: u64, min_rows: u64) -> bool {
+        self.max_errors.is_some_and(|max| errors > max)
+            || self.max_rate.is_some_and(|max| {
+                rows > 0 && rows >= min_rows && errors as f64 / rows as f64 > max
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorBudget, MIN_ROWS_FOR_RATE};
+
+    #[test]
+    fn max_errors() {
+        let budget = ErrorBudget::new(Some(2), None);
+        budget.record_row(true);
+        budget.record_row(false);
+        budget.record_error();
+        assert!(!budget.exceeded());
+        budget.record_row(true);
+        assert!(budget.exceeded());
+        assert_eq!((budget.errors(), budget.rows()), (3, 3));
+    }
+
+    #[test]
+    fn max_rate() {
+        let budget = ErrorBudget::new(None, Some(0.1));
+        //a bad first row doesn't abort the run
+        budget.record_row(true);
+        for _ in 1..MIN_ROWS_FOR_RATE {
+            budget.record_row(false);
+        }
+        assert!(!budget.exceeded());
+        for _ in 0..200 {
+            budget.record_row(true);
+        }
+        assert!(budget.exceeded());
+
+        //the rate of all the rows is checked once the input is read
+        let budget = ErrorBudget::new(None, Some(0.1));
+        budget.record_row(false);
+        budget.record_row(true);
+        assert!(!budget.exceeded());
+        assert!(budget.exceeded_at_end());
+    }
+}
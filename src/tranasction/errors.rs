@@ -1,3 +1,5 @@
+use crate::models::{TransactionType, TxId};
+use smol_str::SmolStr;
 use std::fmt;
 use thiserror::Error;
 
@@ -13,15 +15,78 @@ pub enum TransactionErrors {
     Resolve(ResolveError),
     #[error("Chargeback error for tx {0}")]
     Chargeback(ChargebackError),
+    #[error("Refund error for tx {0}")]
+    Refund(RefundError),
+    #[error("Authorize error for tx {0}")]
+    Authorize(AuthorizeError),
+    #[error("Capture error for tx {0}")]
+    Capture(CaptureError),
+    #[error("Reversal error for tx {0}")]
+    Reversal(ReversalError),
     #[error("Account {0} is locked")]
     AccountLock(AccountLockError),
+    #[error("Balance of account {0} would overflow")]
+    Overflow(OverflowError),
+    #[error("Unknown checkpoint {0}")]
+    Checkpoint(CheckpointError),
+    #[error("Checkpoint {0} is over the max number of checkpoints")]
+    CheckpointLimit(CheckpointError),
+    #[error("{0} rows are not allowed")]
+    ControlRecord(SmolStr),
+    #[error("Unknown transaction type {0}")]
+    UnknownType(SmolStr),
+    #[error("{} balances of client {} don't match the assertion", .0.failed, .0.client)]
+    BalanceAssertion(BalanceAssertionError),
     #[error("Duplicate transaction id {0}")]
     DuplicateTransaction(DuplicateTransactionError),
+    #[error("Validation error: {0}")]
+    Validation(ValidationError),
+    #[cfg(feature = "runtime")]
+    #[error("Client {0} is over its rate limit")]
+    RateLimit(RateLimitError),
+}
+
+//Violation of a validation rule
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ValidationError {
+    #[error("{0:?} transactions are not allowed")]
+    TypeNotAllowed(TransactionType),
+    #[error("{0:?} transactions are disabled")]
+    TypeDisabled(TransactionType),
+    #[error("client {0} is not allowed")]
+    ClientNotAllowed(u16),
+    #[error("amount {amount} is below the minimum {min}")]
+    AmountBelowMin { amount: f64, min: f64 },
+    #[error("amount {amount} is above the maximum {max}")]
+    AmountAboveMax { amount: f64, max: f64 },
+    #[error("currency {} is not {required}", currency.as_deref().unwrap_or("(missing)"))]
+    Currency {
+        currency: Option<SmolStr>,
+        required: SmolStr,
+    },
+    //violation of a validator of a library user, the rule is the name written to the rejects file
+    #[error("{reason}")]
+    Custom { rule: &'static str, reason: String },
+}
+
+impl ValidationError {
+    //name of the rule in the validation file
+    pub fn rule(&self) -> &'static str {
+        match self {
+            ValidationError::TypeNotAllowed(_) => "allowed_types",
+            ValidationError::TypeDisabled(_) => "disabled",
+            ValidationError::ClientNotAllowed(_) => "client_ranges",
+            ValidationError::AmountBelowMin { .. } => "min_amount",
+            ValidationError::AmountAboveMax { .. } => "max_amount",
+            ValidationError::Currency { .. } => "currency",
+            ValidationError::Custom { rule, .. } => rule,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct DepositError {
-    pub tx: u32,
+    pub tx: TxId,
 }
 
 impl fmt::Display for DepositError {
@@ -32,7 +97,7 @@ impl fmt::Display for DepositError {
 
 #[derive(Debug)]
 pub struct WithdrawalError {
-    pub tx: u32,
+    pub tx: TxId,
 }
 
 impl fmt::Display for WithdrawalError {
@@ -43,7 +108,7 @@ impl fmt::Display for WithdrawalError {
 
 #[derive(Debug)]
 pub struct DisputeError {
-    pub tx: u32,
+    pub tx: TxId,
 }
 
 impl fmt::Display for DisputeError {
@@ -54,7 +119,7 @@ impl fmt::Display for DisputeError {
 
 #[derive(Debug)]
 pub struct ResolveError {
-    pub tx: u32,
+    pub tx: TxId,
 }
 
 impl fmt::Display for ResolveError {
@@ -65,7 +130,7 @@ impl fmt::Display for ResolveError {
 
 #[derive(Debug)]
 pub struct ChargebackError {
-    pub tx: u32,
+    pub tx: TxId,
 }
 
 impl fmt::Display for ChargebackError {
@@ -74,6 +139,50 @@ impl fmt::Display for ChargebackError {
     }
 }
 
+#[derive(Debug)]
+pub struct RefundError {
+    pub tx: TxId,
+}
+
+impl fmt::Display for RefundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tx)
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthorizeError {
+    pub tx: TxId,
+}
+
+impl fmt::Display for AuthorizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tx)
+    }
+}
+
+#[derive(Debug)]
+pub struct CaptureError {
+    pub tx: TxId,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tx)
+    }
+}
+
+#[derive(Debug)]
+pub struct ReversalError {
+    pub tx: TxId,
+}
+
+impl fmt::Display for ReversalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tx)
+    }
+}
+
 #[derive(Debug)]
 pub struct AccountLockError {
     pub client: u16,
@@ -85,13 +194,62 @@ impl fmt::Display for AccountLockError {
     }
 }
 
+#[derive(Debug)]
+pub struct OverflowError {
+    pub client: u16,
+    pub tx: TxId,
+}
+
+impl fmt::Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} with tx {}", self.client, self.tx)
+    }
+}
+
+//balances of an assert_balance row that differ from the account, the mismatches are kept by the engine
+#[derive(Debug)]
+pub struct BalanceAssertionError {
+    pub client: u16,
+    pub failed: usize,
+}
+
+#[derive(Debug)]
+pub struct CheckpointError {
+    pub name: SmolStr,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[cfg(feature = "runtime")]
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub client: u16,
+}
+
+#[cfg(feature = "runtime")]
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.client)
+    }
+}
+
 #[derive(Debug)]
 pub struct DuplicateTransactionError {
-    pub tx: u32,
+    pub tx: TxId,
+    //fields that differ from the applied transaction, only reported with the error-on-conflict policy
+    pub conflicts: Vec<&'static str>,
 }
 
 impl fmt::Display for DuplicateTransactionError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.tx)
+        if self.conflicts.is_empty() {
+            write!(f, "{}", self.tx)
+        } else {
+            write!(f, "{} conflicts on {}", self.tx, self.conflicts.join(", "))
+        }
     }
 }
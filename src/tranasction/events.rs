@@ -0,0 +1,119 @@
+use crate::models::{TranactionState, Transaction, TransactionDetail, TxId};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+//type of the sweeps of a running engine, which expire the disputes and authorizations without a row
+const SWEEP: &str = "sweep";
+//type of the interest runs, which settle the accruals of the open disputes
+const INTEREST: &str = "interest";
+
+//An event of the event log: an accepted transaction, a checkpoint or rollback, a sweep or an interest run, in the
+//order they are applied. The fields of the row are kept so that applying the log to a new engine rebuilds its state,
+//and the state is the one of the transaction the event refers to once it is applied, e.g. Dispute for the deposit of
+//an accepted dispute. The disputes and authorizations that expire when the clock moves are not events, the replay
+//expires them again from the timestamps of the rows
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub r#type: SmolStr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TxId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<f64>,
+    //timestamp of the row, or time of a sweep or interest run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<SmolStr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<SmolStr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ledger: Option<SmolStr>,
+    //name of a checkpoint or rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<SmolStr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<TranactionState>,
+}
+
+impl EventRecord {
+    pub fn new(transaction: &Transaction) -> Self {
+        let detail = transaction.detail();
+        let name = match transaction {
+            Transaction::Checkpoint(name) | Transaction::Rollback(name) => Some(name.clone()),
+            _ => None,
+        };
+        Self {
+            r#type: transaction.type_name().into(),
+            client: detail.map(|t| t.client),
+            tx: detail.map(|t| t.tx),
+            amount: detail.and_then(|t| t.amount),
+            timestamp: detail.and_then(|t| t.timestamp),
+            reference: detail.and_then(|t| t.reference.clone()),
+            currency: detail.and_then(|t| t.currency.clone()),
+            ledger: detail.and_then(|t| t.ledger.clone()),
+            name,
+            state: None,
+        }
+    }
+
+    pub fn sweep(now: u64) -> Self {
+        Self::at(SWEEP, now)
+    }
+
+    pub fn interest(now: u64) -> Self {
+        Self::at(INTEREST, now)
+    }
+
+    fn at(r#type: &'static str, now: u64) -> Self {
+        Self {
+            r#type: SmolStr::new_static(r#type),
+            client: None,
+            tx: None,
+            amount: None,
+            timestamp: Some(now),
+            reference: None,
+            currency: None,
+            ledger: None,
+            name: None,
+            state: None,
+        }
+    }
+
+    //the time of a sweep, None for the other events
+    pub fn sweep_time(&self) -> Option<u64> {
+        (self.r#type == SWEEP).then_some(self.timestamp).flatten()
+    }
+
+    //the time of an interest run, None for the other events
+    pub fn interest_time(&self) -> Option<u64> {
+        (self.r#type == INTEREST)
+            .then_some(self.timestamp)
+            .flatten()
+    }
+
+    //the row the event was made from
+    pub fn transaction(&self) -> Result<Transaction, String> {
+        match self.r#type.as_str() {
+            "checkpoint" | "rollback" => {
+                let name = self.name.clone().ok_or("Cannot find checkpoint name")?;
+                Ok(if self.r#type == "checkpoint" {
+                    Transaction::Checkpoint(name)
+                } else {
+                    Transaction::Rollback(name)
+                })
+            }
+            _ => {
+                let client = self.client.ok_or("Cannot find client")?;
+                let tx = self.tx.ok_or("Cannot find tx")?;
+                let mut t = TransactionDetail::new(client, tx, self.amount);
+                t.timestamp = self.timestamp;
+                t.reference = self.reference.clone();
+                t.currency = self.currency.clone();
+                t.ledger = self.ledger.clone();
+                Ok(Transaction::from_type(self.r#type.clone(), t))
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use super::errors::TransactionErrors;
+use super::ledger::{Journal, SubLedger};
+use crate::models::{Account, RawRecord, TransactionDetail};
+
+//Extension point for the rows of a type the engine doesn't know, e.g. proprietary adjustment records. A handler is
+//registered for a type with TransactionEngineBuilder::handler and receives the rows of that type, parsed and as read,
+//with the account of their client. The amount and the timestamp of the detail are None when they don't parse, the
+//handler can read them from the record. An error rejects the row and leaves the account untouched
+pub trait TransactionHandler: Send {
+    fn handle(
+        &self,
+        tx: &TransactionDetail,
+        record: &RawRecord,
+        account: &mut AccountHandle,
+    ) -> anyhow::Result<()>;
+}
+
+impl<F> TransactionHandler for F
+where
+    F: Fn(&TransactionDetail, &RawRecord, &mut AccountHandle) -> anyhow::Result<()> + Send,
+{
+    fn handle(
+        &self,
+        tx: &TransactionDetail,
+        record: &RawRecord,
+        account: &mut AccountHandle,
+    ) -> anyhow::Result<()> {
+        self(tx, record, account)
+    }
+}
+
+//Account given to a handler. The balances can only be changed with postings, so the journal stays balanced and the
+//total is kept in line with available and held. The changes are made to a copy that replaces the account once the
+//handler succeeds
+pub struct AccountHandle<'a> {
+    account: Account,
+    journal: Journal,
+    source: &'a TransactionDetail,
+}
+
+impl<'a> AccountHandle<'a> {
+    pub(super) fn new(account: &Account, source: &'a TransactionDetail) -> Self {
+        Self {
+            account: account.clone(),
+            journal: Journal::new(true),
+            source,
+        }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    //move the amount from the credit sub-ledger to the debit sub-ledger, e.g. from External to Available to credit
+    //the client. The posting is rejected if a balance would go past MAX_AMOUNT
+    pub fn post(&mut self, credit: SubLedger, debit: SubLedger, amount: f64) -> anyhow::Result<()> {
+        self.journal
+            .try_post(&mut self.account, self.source, credit, debit, amount)
+            .map_err(|e| TransactionErrors::Overflow(e).into())
+    }
+
+    //the handler decides whether a locked account can be adjusted, and can lock or unlock it
+    pub fn set_locked(&mut self, locked: bool) {
+        self.account.locked = locked;
+    }
+
+    //the account and the postings to record to the journal of the engine
+    pub(super) fn finish(self) -> (Account, Journal) {
+        (self.account, self.journal)
+    }
+}
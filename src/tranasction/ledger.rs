@@ -0,0 +1,246 @@
+use super::errors::OverflowError;
+use crate::models::{Account, TransactionDetail, TxId, MAX_AMOUNT};
+use serde::Serialize;
+use smol_str::SmolStr;
+
+//Sub-ledgers of a client. External is the world outside of the engine, e.g. the bank account of the client, so a
+//deposit moves funds from external to available and a withdrawal moves them back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubLedger {
+    Available,
+    Held,
+    External,
+}
+
+impl SubLedger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubLedger::Available => "available",
+            SubLedger::Held => "held",
+            SubLedger::External => "external",
+        }
+    }
+}
+
+//A balanced entry of the journal. The amount is debited to the sub-ledger the funds move to and credited to the
+//sub-ledger they come from, so the sum of all the sub-ledgers never changes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Posting {
+    pub client: u16,
+    pub tx: TxId,
+    pub debit: SubLedger,
+    pub credit: SubLedger,
+    pub amount: f64,
+    //reference of the transaction the posting is recorded for, e.g. the bank reference of a deposit
+    pub reference: Option<SmolStr>,
+    pub ledger: Option<SmolStr>,
+}
+
+impl Posting {
+    fn new(
+        account: &Account,
+        source: &TransactionDetail,
+        credit: SubLedger,
+        debit: SubLedger,
+        amount: f64,
+    ) -> Self {
+        Self {
+            client: account.client,
+            tx: source.tx,
+            debit,
+            credit,
+            amount,
+            reference: source.reference.clone(),
+            ledger: source.ledger.clone(),
+        }
+    }
+
+    //total is derived from available and held instead of being updated on its own, so it can't drift
+    fn apply(&self, account: &mut Account) {
+        if let Some(balance) = balance_mut(account, self.credit) {
+            *balance -= self.amount;
+        }
+        if let Some(balance) = balance_mut(account, self.debit) {
+            *balance += self.amount;
+        }
+        account.total = account.available + account.held;
+    }
+
+    fn overflows(&self, account: &Account) -> bool {
+        let mut after = account.clone();
+        self.apply(&mut after);
+        [after.available, after.held, after.total]
+            .iter()
+            .any(|balance| balance.abs() > MAX_AMOUNT)
+    }
+}
+
+//external funds are not tracked by the engine
+fn balance_mut(account: &mut Account, sub_ledger: SubLedger) -> Option<&mut f64> {
+    match sub_ledger {
+        SubLedger::Available => Some(&mut account.available),
+        SubLedger::Held => Some(&mut account.held),
+        SubLedger::External => None,
+    }
+}
+
+//Every balance mutation of the engine goes through the journal. The postings are only kept if the journal is
+//recorded, since they grow with the number of transactions
+#[derive(Debug, Default)]
+pub struct Journal {
+    postings: Option<Vec<Posting>>,
+}
+
+impl Journal {
+    pub fn new(record: bool) -> Self {
+        Self {
+            postings: record.then(Vec::new),
+        }
+    }
+
+    //move the amount from the credit sub-ledger to the debit sub-ledger of the account, the posting is recorded for
+    //the tx and reference of the source transaction
+    pub fn post(
+        &mut self,
+        account: &mut Account,
+        source: &TransactionDetail,
+        credit: SubLedger,
+        debit: SubLedger,
+        amount: f64,
+    ) {
+        let posting = Posting::new(account, source, credit, debit, amount);
+        self.record(account, posting);
+    }
+
+    //post the funds that come from external unless a balance of the account would go past MAX_AMOUNT, where it
+    //would lose its decimal places. The account is left untouched if it would
+    pub fn try_post(
+        &mut self,
+        account: &mut Account,
+        source: &TransactionDetail,
+        credit: SubLedger,
+        debit: SubLedger,
+        amount: f64,
+    ) -> Result<(), OverflowError> {
+        let posting = Posting::new(account, source, credit, debit, amount);
+        if posting.overflows(account) {
+            return Err(OverflowError {
+                client: account.client,
+                tx: source.tx,
+            });
+        }
+        self.record(account, posting);
+        Ok(())
+    }
+
+    fn record(&mut self, account: &mut Account, posting: Posting) {
+        posting.apply(account);
+        if let Some(postings) = &mut self.postings {
+            postings.push(posting);
+        }
+    }
+
+    //record the postings of another journal, their balances are already applied to the accounts
+    pub fn append(&mut self, other: Journal) {
+        if let (Some(postings), Some(other)) = (&mut self.postings, other.postings) {
+            postings.extend(other);
+        }
+    }
+
+    //drop the postings made after a checkpoint
+    pub fn truncate(&mut self, len: usize) {
+        if let Some(postings) = &mut self.postings {
+            postings.truncate(len);
+        }
+    }
+
+    pub fn postings(&self) -> &[Posting] {
+        self.postings.as_deref().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Journal, SubLedger};
+    use crate::models::{Account, TransactionDetail, MAX_AMOUNT};
+
+    #[test]
+    fn post() {
+        let mut account = Account::new(1);
+        let mut journal = Journal::new(true);
+        let mut source = TransactionDetail::new(1, 1, Some(3.0));
+        source.reference = Some("REF-1".into());
+        journal.post(
+            &mut account,
+            &source,
+            SubLedger::External,
+            SubLedger::Available,
+            3.0,
+        );
+        journal.post(
+            &mut account,
+            &source,
+            SubLedger::Available,
+            SubLedger::Held,
+            1.0,
+        );
+        journal.post(
+            &mut account,
+            &source,
+            SubLedger::Held,
+            SubLedger::External,
+            1.0,
+        );
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 2.0);
+        assert_eq!(journal.postings().len(), 3);
+        assert_eq!(journal.postings()[1].debit, SubLedger::Held);
+        assert_eq!(journal.postings()[2].reference.as_deref(), Some("REF-1"));
+
+        let mut journal = Journal::new(false);
+        let source = TransactionDetail::new(1, 2, Some(1.0));
+        journal.post(
+            &mut account,
+            &source,
+            SubLedger::External,
+            SubLedger::Held,
+            1.0,
+        );
+        assert_eq!(account.total, 3.0);
+        assert!(journal.postings().is_empty());
+    }
+
+    #[test]
+    fn try_post() {
+        let mut account = Account::new(1);
+        let mut journal = Journal::new(true);
+        let source = TransactionDetail::new(1, 1, Some(MAX_AMOUNT));
+        let mut post_max = || {
+            journal.try_post(
+                &mut account,
+                &source,
+                SubLedger::External,
+                SubLedger::Available,
+                MAX_AMOUNT,
+            )
+        };
+        assert!(post_max().is_ok());
+        assert!(post_max().is_err());
+        //the second posting would overflow, so it is neither applied nor recorded
+        assert_eq!(account.available, MAX_AMOUNT);
+        assert_eq!(account.total, MAX_AMOUNT);
+        assert_eq!(journal.postings().len(), 1);
+        //moving funds between the sub-ledgers of a full account is fine
+        assert!(journal
+            .try_post(
+                &mut account,
+                &source,
+                SubLedger::Available,
+                SubLedger::Held,
+                1.0,
+            )
+            .is_ok());
+    }
+}
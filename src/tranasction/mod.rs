@@ -1,2 +1,22 @@
-mod errors;
+#[cfg(feature = "runtime")]
+pub mod accounts_handle;
+pub mod accrual;
+pub mod applied;
+pub mod assertion;
+pub mod audit;
+mod book;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "runtime")]
+pub mod error_budget;
+pub mod errors;
+pub mod events;
+pub mod handler;
+pub mod ledger;
+pub mod profile;
+pub mod quarantine;
+#[cfg(feature = "runtime")]
+pub mod rate_limit;
+pub mod snapshot;
 pub mod transaction_engine;
+pub mod validation;
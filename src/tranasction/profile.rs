@@ -0,0 +1,73 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::BufReader;
+
+//KYC data of a client, joined into the account report. The client is the key of the profile so it isn't written
+//again next to the account
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientProfile {
+    #[serde(skip_serializing)]
+    pub client: u16,
+    pub name: SmolStr,
+    pub tier: SmolStr,
+    pub country: SmolStr,
+}
+
+//Profiles by client, loaded from a csv file with the columns client,name,tier,country
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientProfiles {
+    profiles: AHashMap<u16, ClientProfile>,
+    //written for the clients without a profile, so that every row of the report has the same columns
+    missing: ClientProfile,
+}
+
+impl ClientProfiles {
+    //used as a clap value parser so that an invalid file is reported like any other invalid argument. A client that
+    //is listed twice is an error rather than a silent overwrite
+    #[cfg(feature = "runtime")]
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|e| format!("{path}: {e}"))?);
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut profiles = AHashMap::new();
+        for profile in rdr.deserialize::<ClientProfile>() {
+            let profile = profile.map_err(|e| format!("{path}: {e}"))?;
+            if let Some(duplicate) = profiles.insert(profile.client, profile) {
+                return Err(format!(
+                    "{path}: client {} has several profiles",
+                    duplicate.client
+                ));
+            }
+        }
+        Ok(Self {
+            profiles,
+            missing: ClientProfile::default(),
+        })
+    }
+
+    pub fn get(&self, client: u16) -> Option<&ClientProfile> {
+        self.profiles.get(&client)
+    }
+
+    //the profile of the client, or empty columns if it has none
+    pub fn columns(&self, client: u16) -> &ClientProfile {
+        self.get(client).unwrap_or(&self.missing)
+    }
+}
+
+impl FromIterator<ClientProfile> for ClientProfiles {
+    fn from_iter<I: IntoIterator<Item = ClientProfile>>(iter: I) -> Self {
+        Self {
+            profiles: iter
+                .into_iter()
+                .map(|profile| (profile.client, profile))
+                .collect(),
+            missing: ClientProfile::default(),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::models::{TransactionDetail, TransactionType, TxId};
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::collections::VecDeque;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::BufReader;
+
+//More than max_transactions transactions of the same client within window seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RapidFire {
+    pub max_transactions: usize,
+    pub window: u64,
+}
+
+//Rules that flag suspicious transactions for review, loaded from a json file, e.g.
+//{"amount_above": 5000, "rapid_fire": {"max_transactions": 5, "window": 60}}
+//A flagged transaction is parked instead of applied. The rapid fire rule needs a timestamp column
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuarantineRules {
+    pub amount_above: Option<f64>,
+    pub rapid_fire: Option<RapidFire>,
+}
+
+impl QuarantineRules {
+    //used as a clap value parser so that an invalid file is reported like any other invalid argument
+    #[cfg(feature = "runtime")]
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|e| format!("{path}: {e}"))?);
+        serde_json::from_reader(reader).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+//A parked transaction. The transaction columns come first with the names of the input file, so that the released
+//rows can be processed again as an input file (the rule and reason columns are ignored by the parser)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantineRecord {
+    pub r#type: TransactionType,
+    pub client: u16,
+    pub tx: TxId,
+    pub amount: Option<f64>,
+    pub timestamp: Option<u64>,
+    pub reference: Option<SmolStr>,
+    pub currency: Option<SmolStr>,
+    pub ledger: Option<SmolStr>,
+    pub rule: SmolStr,
+    pub reason: String,
+}
+
+impl QuarantineRecord {
+    fn new(
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+        rule: &str,
+        reason: String,
+    ) -> Self {
+        Self {
+            r#type: transaction_type,
+            client: tx_detail.client,
+            tx: tx_detail.tx,
+            amount: tx_detail.amount,
+            timestamp: tx_detail.timestamp,
+            reference: tx_detail.reference.clone(),
+            currency: tx_detail.currency.clone(),
+            ledger: tx_detail.ledger.clone(),
+            rule: rule.into(),
+            reason,
+        }
+    }
+}
+
+//State of the quarantine stage: the recent activity of the clients for the rapid fire rule and the parked
+//transactions
+#[derive(Debug, Clone, Default)]
+pub struct Quarantine {
+    rules: QuarantineRules,
+    //timestamps of the transactions of each client within the rapid fire window, by ledger and client
+    recent: AHashMap<(SmolStr, u16), VecDeque<u64>>,
+    parked: Vec<QuarantineRecord>,
+}
+
+impl Quarantine {
+    pub fn new(rules: QuarantineRules) -> Self {
+        Self {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    //park the transaction and return true if it breaks a rule. Every transaction counts towards the activity of its
+    //client, including the ones that are parked
+    pub fn check(
+        &mut self,
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+    ) -> bool {
+        let mut flagged = None;
+        if let (Some(rapid_fire), Some(timestamp)) = (self.rules.rapid_fire, tx_detail.timestamp) {
+            let recent = self
+                .recent
+                .entry((
+                    tx_detail.ledger.clone().unwrap_or_default(),
+                    tx_detail.client,
+                ))
+                .or_default();
+            while recent
+                .front()
+                .is_some_and(|t| t + rapid_fire.window <= timestamp)
+            {
+                recent.pop_front();
+            }
+            recent.push_back(timestamp);
+            if recent.len() > rapid_fire.max_transactions {
+                flagged = Some((
+                    "rapid_fire",
+                    format!(
+                        "{} transactions within {} seconds",
+                        recent.len(),
+                        rapid_fire.window
+                    ),
+                ));
+            }
+        }
+        if let (Some(above), Some(amount)) = (self.rules.amount_above, tx_detail.amount) {
+            if amount > above {
+                flagged = Some(("amount_above", format!("Amount {amount} is above {above}")));
+            }
+        }
+        let Some((rule, reason)) = flagged else {
+            return false;
+        };
+        self.parked.push(QuarantineRecord::new(
+            transaction_type,
+            tx_detail,
+            rule,
+            reason,
+        ));
+        true
+    }
+
+    pub fn parked(&self) -> &[QuarantineRecord] {
+        &self.parked
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Quarantine, QuarantineRules, RapidFire};
+    use crate::models::{TransactionDetail, TransactionType};
+
+    #[test]
+    fn check() {
+        let mut quarantine = Quarantine::new(QuarantineRules {
+            amount_above: Some(100.0),
+            rapid_fire: Some(RapidFire {
+                max_transactions: 2,
+                window: 10,
+            }),
+        });
+        let at = |client, tx, amount, timestamp| TransactionDetail {
+            timestamp: Some(timestamp),
+            ..TransactionDetail::new(client, tx, Some(amount))
+        };
+        assert!(!quarantine.check(TransactionType::Deposit, &at(1, 1, 10.0, 0)));
+        assert!(quarantine.check(TransactionType::Deposit, &at(1, 2, 200.0, 1)));
+        //the third transaction of client 1 within 10 seconds
+        assert!(quarantine.check(TransactionType::Withdrawal, &at(1, 3, 1.0, 9)));
+        assert!(!quarantine.check(TransactionType::Deposit, &at(2, 4, 1.0, 9)));
+        //the first transaction has left the window
+        assert!(quarantine.check(TransactionType::Deposit, &at(1, 5, 1.0, 10)));
+        assert!(!quarantine.check(TransactionType::Deposit, &at(1, 6, 1.0, 30)));
+
+        let parked = quarantine
+            .parked()
+            .iter()
+            .map(|record| (record.tx, record.rule.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parked,
+            vec![(2, "amount_above"), (3, "rapid_fire"), (5, "rapid_fire")]
+        );
+    }
+}
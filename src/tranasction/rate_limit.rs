@@ -0,0 +1,241 @@
+use super::config::{RateLimit, RateLimitPolicy};
+use super::errors::{RateLimitError, TransactionErrors};
+use crate::models::Transaction;
+use ahash::AHashMap;
+use smol_str::SmolStr;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+//Tokens of a client and its transactions waiting for a token with the defer policy
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    deferred: VecDeque<Transaction>,
+}
+
+//Outcome of a transaction that goes through the limiter
+#[derive(Debug)]
+pub enum Admission {
+    Admitted(Transaction),
+    Deferred,
+    Rejected(Transaction, TransactionErrors),
+}
+
+//Token buckets by ledger and client. The time is passed in by the caller so that the buckets can be tested without
+//waiting
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: AHashMap<(SmolStr, u16), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: AHashMap::new(),
+        }
+    }
+
+    //The transactions without a client, e.g. the flush records, are always admitted. A client that has deferred
+    //transactions doesn't get a token before them, so that its transactions are applied in order
+    pub fn admit(&mut self, transaction: Transaction, now: Instant) -> Admission {
+        let Some(tx_detail) = transaction.detail() else {
+            return Admission::Admitted(transaction);
+        };
+        let client = tx_detail.client;
+        let key = (tx_detail.ledger.clone().unwrap_or_default(), client);
+        let limit = self.limit;
+        let bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: limit.burst as f64,
+            refilled: now,
+            deferred: VecDeque::new(),
+        });
+        bucket.refill(&limit, now);
+        if bucket.deferred.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Admitted(transaction);
+        }
+        if limit.policy == RateLimitPolicy::Defer && bucket.deferred.len() < limit.burst as usize {
+            bucket.deferred.push_back(transaction);
+            return Admission::Deferred;
+        }
+        Admission::Rejected(
+            transaction,
+            TransactionErrors::RateLimit(RateLimitError { client }),
+        )
+    }
+
+    //a bucket keeps its deferred transactions and its tokens, up to the new burst
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        self.limit = limit;
+        for bucket in self.buckets.values_mut() {
+            bucket.tokens = bucket.tokens.min(limit.burst as f64);
+        }
+    }
+
+    //the deferred transactions of every client, in the order they are received for each client
+    pub fn into_deferred(self) -> Vec<Transaction> {
+        self.buckets
+            .into_values()
+            .flat_map(|bucket| bucket.deferred)
+            .collect()
+    }
+
+    //deferred transactions whose client has a token again, in the order they are received for each client
+    pub fn release(&mut self, now: Instant) -> Vec<Transaction> {
+        let mut released = vec![];
+        for bucket in self.buckets.values_mut() {
+            if bucket.deferred.is_empty() {
+                continue;
+            }
+            bucket.refill(&self.limit, now);
+            while bucket.tokens >= 1.0 {
+                let Some(transaction) = bucket.deferred.pop_front() else {
+                    break;
+                };
+                bucket.tokens -= 1.0;
+                released.push(transaction);
+            }
+        }
+        released
+    }
+
+    //when the next deferred transaction gets a token, None if no transaction is deferred
+    pub fn next_release(&self) -> Option<Instant> {
+        self.buckets
+            .values()
+            .filter(|bucket| !bucket.deferred.is_empty())
+            .map(|bucket| {
+                let missing = (1.0 - bucket.tokens).max(0.0);
+                bucket.refilled + Duration::from_secs_f64(missing / self.limit.rate)
+            })
+            .min()
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Admission, RateLimiter};
+    use crate::models::{Transaction, TransactionDetail, TxId};
+    use crate::tranasction::config::{RateLimit, RateLimitPolicy};
+    use std::time::{Duration, Instant};
+
+    fn deposit(client: u16, tx: TxId) -> Transaction {
+        Transaction::Deposit(TransactionDetail::new(client, tx, Some(1.0)))
+    }
+
+    #[test]
+    fn reject() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            rate: 2.0,
+            burst: 2,
+            policy: RateLimitPolicy::Reject,
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            limiter.admit(deposit(1, 1), start),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            limiter.admit(deposit(1, 2), start),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            limiter.admit(deposit(1, 3), start),
+            Admission::Rejected(..)
+        ));
+        //the other clients have their own bucket
+        assert!(matches!(
+            limiter.admit(deposit(2, 4), start),
+            Admission::Admitted(_)
+        ));
+        //a token every half second
+        let later = start + Duration::from_millis(500);
+        assert!(matches!(
+            limiter.admit(deposit(1, 5), later),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            limiter.admit(deposit(1, 6), later),
+            Admission::Rejected(..)
+        ));
+        assert!(matches!(
+            limiter.admit(Transaction::Flush, later),
+            Admission::Admitted(_)
+        ));
+    }
+
+    #[test]
+    fn defer() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            rate: 1.0,
+            burst: 1,
+            policy: RateLimitPolicy::Defer,
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            limiter.admit(deposit(1, 1), start),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            limiter.admit(deposit(1, 2), start),
+            Admission::Deferred
+        ));
+        //only burst transactions are queued per client
+        assert!(matches!(
+            limiter.admit(deposit(1, 3), start),
+            Admission::Rejected(..)
+        ));
+        assert_eq!(limiter.next_release(), Some(start + Duration::from_secs(1)));
+        assert!(limiter
+            .release(start + Duration::from_millis(500))
+            .is_empty());
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.release(later), vec![deposit(1, 2)]);
+        assert_eq!(limiter.next_release(), None);
+        assert!(matches!(
+            limiter.admit(deposit(1, 4), later),
+            Admission::Deferred
+        ));
+    }
+
+    #[test]
+    fn change_limit() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            rate: 1.0,
+            burst: 3,
+            policy: RateLimitPolicy::Defer,
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            limiter.admit(deposit(1, 1), start),
+            Admission::Admitted(_)
+        ));
+        //the tokens of the bucket are capped to the new burst
+        limiter.set_limit(RateLimit {
+            rate: 1.0,
+            burst: 1,
+            policy: RateLimitPolicy::Defer,
+        });
+        assert!(matches!(
+            limiter.admit(deposit(1, 2), start),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            limiter.admit(deposit(1, 3), start),
+            Admission::Deferred
+        ));
+        assert_eq!(limiter.into_deferred(), vec![deposit(1, 3)]);
+    }
+}
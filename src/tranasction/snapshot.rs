@@ -0,0 +1,73 @@
+use crate::models::{Account, FilePosition, TransactionDetail};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::collections::BTreeMap;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::{BufReader, BufWriter, Write};
+use std::mem::size_of;
+
+//State of the transaction engine at the end of a run. It is saved as json so it can be inspected without re-running
+//the whole input
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub accounts: Vec<Account>,
+    pub deposits: Vec<TransactionDetail>,
+    pub withdrawals: Vec<TransactionDetail>,
+    #[serde(default)]
+    pub authorizations: Vec<TransactionDetail>,
+    //position of the input file the snapshot was taken at
+    #[serde(default)]
+    pub position: Option<FilePosition>,
+    //amounts accrued by the decided disputes of each client
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub accruals: BTreeMap<u16, f64>,
+    //state of the ledgers other than the default one, which is the top level of the snapshot
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ledgers: BTreeMap<SmolStr, Snapshot>,
+}
+
+impl Snapshot {
+    //approximate number of bytes taken by the accounts and transactions of every ledger, e.g. of a checkpoint
+    pub fn memory_usage(&self) -> usize {
+        let transactions = self.deposits.len() + self.withdrawals.len() + self.authorizations.len();
+        self.accounts.len() * size_of::<Account>()
+            + transactions * size_of::<TransactionDetail>()
+            + self
+                .ledgers
+                .values()
+                .map(Snapshot::memory_usage)
+                .sum::<usize>()
+    }
+
+    //accounts of every ledger with the id of their ledger, the id of the default ledger is empty
+    pub fn ledger_accounts(&self) -> Vec<(SmolStr, Account)> {
+        let default = self
+            .accounts
+            .iter()
+            .map(|account| (SmolStr::default(), account.clone()));
+        let ledgers = self.ledgers.iter().flat_map(|(ledger, snapshot)| {
+            snapshot
+                .accounts
+                .iter()
+                .map(move |account| (ledger.clone(), account.clone()))
+        });
+        default.chain(ledgers).collect()
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl Snapshot {
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
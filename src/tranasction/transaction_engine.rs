@@ -1,310 +1,1684 @@
+#[cfg(feature = "runtime")]
+use super::accounts_handle::{AccountQuery, AccountsHandle};
+use super::accrual::AccruedAmount;
+use super::applied::AppliedId;
+use super::assertion::{self, BalanceMismatch};
+use super::audit::{AuditEvent, AuditRecord};
+use super::book::{Book, Context, TransactionKind, ACCOUNT_MAP_SIZE, TRANSACTION_MAP_SIZE};
+use super::clock::{Clock, InputClock};
+#[cfg(feature = "runtime")]
+use super::config::RateLimit;
+use super::config::{ConfigChange, EngineConfig};
+#[cfg(feature = "runtime")]
+use super::error_budget::ErrorBudget;
 use super::errors::{
-    AccountLockError, ChargebackError, DepositError, DisputeError, ResolveError, TransactionErrors,
-    WithdrawalError,
+    BalanceAssertionError, CheckpointError, ReversalError, TransactionErrors, ValidationError,
 };
-use crate::{
-    models::{Account, TranactionState, Transaction, TransactionDetail},
-    tranasction::errors::DuplicateTransactionError,
+use super::events::EventRecord;
+use super::handler::TransactionHandler;
+use super::ledger::Journal;
+#[cfg(feature = "runtime")]
+use super::profile::ClientProfiles;
+use super::quarantine::{Quarantine, QuarantineRecord};
+#[cfg(feature = "runtime")]
+use super::rate_limit::{Admission, RateLimiter};
+use super::snapshot::Snapshot;
+use super::validation::{RejectRecord, ValidationRules, Validator};
+use crate::models::{
+    Account, BalanceAssertion, TranactionState, Transaction, TransactionDetail, TransactionType,
+    TxId,
 };
+#[cfg(feature = "runtime")]
+use crate::timing::StageTiming;
 use ahash::AHashMap;
 use anyhow::bail;
-use std::io::BufWriter;
-use tokio::sync::mpsc::Receiver;
+use serde::Serialize;
+use smol_str::{SmolStr, StrExt};
+use std::collections::BTreeMap;
+#[cfg(feature = "runtime")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "runtime")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "runtime")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "runtime")]
+use std::sync::Arc;
+#[cfg(feature = "runtime")]
+use std::time::Instant;
+#[cfg(feature = "runtime")]
+use tokio::sync::mpsc::{Receiver, Sender};
 
-const TRANSACTION_MAP_SIZE: usize = 10000;
-//client id is u16
-const ACCOUNT_MAP_SIZE: usize = u16::MAX as usize;
+//id of the ledger of the transactions that don't have one
+const DEFAULT_LEDGER: &str = "";
+//number of transactions between two checks of the memory usage against the max memory
+#[cfg(feature = "runtime")]
+const MEMORY_CHECK_EVERY: u64 = 1024;
 
+//A transaction that is still in dispute, the amounts of all the open disputes of a client make up its held fund
+#[derive(Debug, PartialEq, Serialize)]
+pub struct OpenDispute {
+    client: u16,
+    tx: TxId,
+    amount: f64,
+    r#type: TransactionKind,
+    ledger: SmolStr,
+}
+
+//Row of the account report when there are several ledgers
+#[cfg(any(feature = "runtime", feature = "wasm"))]
+#[derive(Serialize)]
+pub(crate) struct LedgerAccount<'a> {
+    client: u16,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    ledger: &'a str,
+}
+
+#[cfg(any(feature = "runtime", feature = "wasm"))]
+impl<'a> LedgerAccount<'a> {
+    pub(crate) fn new(ledger: &'a str, account: &Account) -> Self {
+        Self {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            ledger,
+        }
+    }
+}
+
+//ledger column of the account report after the profile columns
+#[cfg(feature = "runtime")]
+#[derive(Serialize)]
+struct LedgerColumn<'a> {
+    ledger: &'a str,
+}
+
+//Write a row of the account report. The ledger column is only written if there are several ledgers and the
+//name,tier,country columns only if the client profiles are loaded, so that the default report doesn't change
+#[cfg(feature = "runtime")]
+fn write_account<W: std::io::Write>(
+    wtr: &mut csv::Writer<W>,
+    ledger: &str,
+    account: &Account,
+    has_ledgers: bool,
+    profiles: Option<&ClientProfiles>,
+) -> csv::Result<()> {
+    match (profiles.map(|p| p.columns(account.client)), has_ledgers) {
+        (None, false) => wtr.serialize(account),
+        (None, true) => wtr.serialize(LedgerAccount::new(ledger, account)),
+        (Some(profile), false) => wtr.serialize((account, profile)),
+        (Some(profile), true) => wtr.serialize((account, profile, LedgerColumn { ledger })),
+    }
+}
+
+//Number of transactions received by the engine and the ones that are rejected
+#[derive(Debug, Default, Clone)]
+pub struct EngineStats {
+    pub processed: u64,
+    pub rejected: u64,
+    //rejected transactions by type, the unknown transactions are only counted in rejected
+    pub rejected_by_type: BTreeMap<TransactionType, u64>,
+    //transactions applied by a previous run, they are not counted in processed
+    pub skipped: u64,
+    //approximate memory usage of the engine in bytes when the stats are read
+    pub memory: usize,
+    //time spent waiting for the next batch of transactions, a busy engine hardly waits
+    #[cfg(feature = "runtime")]
+    pub receive_wait: StageTiming,
+    //time spent applying a transaction
+    #[cfg(feature = "runtime")]
+    pub apply: StageTiming,
+}
+
+//Outcome of a transaction that is not rejected, see process_batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    //applied to the accounts, or a control record such as a checkpoint or a balance assertion that passed
+    Applied,
+    //parked for review by the quarantine rules
+    Quarantined,
+    //already applied, by a previous run according to the applied ids, or earlier in this run for an exact replay
+    //that the duplicate policy ignores. Nothing is recorded for it
+    Skipped,
+}
+
+//State of the engine saved under a name. The journal, the rejects, the audit trail and the failed balance assertions
+//are only appended to, so their lengths are enough to roll them back
+struct Checkpoint {
+    name: SmolStr,
+    snapshot: Snapshot,
+    now: Option<u64>,
+    postings: usize,
+    rejects: usize,
+    audit: usize,
+    mismatches: usize,
+    applied: usize,
+    quarantine: Option<Quarantine>,
+}
+
+//The engine owns the accounts and the transactions. It can be driven directly with process_transaction or
+//process_batch, or by run which receives the transactions from a channel
 pub struct TransactionEngine {
-    rx: Receiver<Transaction>,
-    //map that stores all the deposit and withdrawal transactions
-    withdrawal_transactions: AHashMap<u32, TransactionDetail>,
-    deposit_transactions: AHashMap<u32, TransactionDetail>,
-    accounts: AHashMap<u16, Account>,
+    //accounts and transactions of every ledger, the default ledger always exists
+    books: BTreeMap<SmolStr, Book>,
+    config: EngineConfig,
+    //latest time read from the clock, the clock is shared by all the ledgers
+    now: Option<u64>,
+    clock: Box<dyn Clock>,
+    //checked after the validation rules of the config
+    validators: Vec<Box<dyn Validator>>,
+    //handlers of the unknown transaction types by lowercase type
+    handlers: AHashMap<SmolStr, Box<dyn TransactionHandler>>,
+    #[cfg(feature = "runtime")]
+    since_flush: u64,
+    //stdout unless another writer is set
+    #[cfg(feature = "runtime")]
+    incremental_writer: Option<csv::Writer<Box<dyn Write + Send>>>,
+    //whether the incremental rows have a ledger column, decided by the first flush since the header is written then
+    #[cfg(feature = "runtime")]
+    incremental_ledgers: Option<bool>,
+    #[cfg(feature = "runtime")]
+    since_archive: u64,
+    //the inactive accounts are archived only once a file is set
+    #[cfg(feature = "runtime")]
+    archive_writer: Option<csv::Writer<BufWriter<File>>>,
+    #[cfg(feature = "runtime")]
+    since_memory_check: u64,
+    //set once the memory usage is over the max memory, after the inactive accounts are archived
+    #[cfg(feature = "runtime")]
+    memory_exceeded: bool,
+    //set with memory_exceeded so that the producer stops sending transactions
+    #[cfg(feature = "runtime")]
+    memory_stop: Option<Arc<AtomicBool>>,
+    //the audit records are appended to this file as they are made, instead of being kept until they are taken
+    #[cfg(feature = "runtime")]
+    audit_writer: Option<csv::Writer<BufWriter<File>>>,
+    //and sent to this channel, e.g. for the webhooks of the dispute workflow
+    #[cfg(feature = "runtime")]
+    audit_sender: Option<Sender<AuditRecord>>,
+    //the events are appended to this ndjson file as they are made
+    #[cfg(feature = "runtime")]
+    events_writer: Option<BufWriter<File>>,
+    //queries from the accounts handles, None until a handle is created
+    #[cfg(feature = "runtime")]
+    queries: Option<Receiver<AccountQuery>>,
+    //sender side of the query channel, cloned into each accounts handle
+    #[cfg(feature = "runtime")]
+    query_handle: Option<AccountsHandle>,
+    //counters of the transactions received by run, they can be read by the accounts handles while it runs
+    #[cfg(feature = "runtime")]
+    stats: EngineStats,
+    //token buckets of the clients in front of the transactions received by run, None without a rate limit
+    #[cfg(feature = "runtime")]
+    limiter: Option<RateLimiter>,
+    //the rejected transactions are counted in the budget shared with the parser
+    #[cfg(feature = "runtime")]
+    error_budget: Option<Arc<ErrorBudget>>,
+    //every balance mutation is posted to the journal
+    journal: Journal,
+    //transactions rejected by the validation rules, only kept if they are written at the end
+    rejects: Option<Vec<RejectRecord>>,
+    //balance assertions of the input that failed, in the order they are checked
+    mismatches: Vec<BalanceMismatch>,
+    //transactions applied by this run, only kept if they are written at the end
+    applied: Option<Vec<AppliedId>>,
+    //transactions parked for review, None if there is no quarantine rule
+    quarantine: Option<Quarantine>,
+    //in the order they are taken
+    checkpoints: Vec<Checkpoint>,
+    //accepted transactions, checkpoints, rollbacks and sweeps that are not written yet, only kept if the event log is
+    events: Option<Vec<EventRecord>>,
+    //balances before and after every applied state change that is not written yet, only kept if the audit trail is
+    audit: Option<Vec<AuditRecord>>,
 }
 
-impl TransactionEngine {
-    pub fn new(rx: Receiver<Transaction>) -> Self {
+//Builds an engine with the settings that are not part of the config: the capacity of the maps of the default
+//ledger, the clock, the validators and the handlers of library users, e.g.
+//TransactionEngine::builder().config(config).clock(ManualClock::new(0)).validator(check_tx_id).build()
+pub struct TransactionEngineBuilder {
+    config: EngineConfig,
+    transaction_capacity: usize,
+    account_capacity: usize,
+    clock: Box<dyn Clock>,
+    validators: Vec<Box<dyn Validator>>,
+    handlers: AHashMap<SmolStr, Box<dyn TransactionHandler>>,
+}
+
+impl Default for TransactionEngineBuilder {
+    fn default() -> Self {
         Self {
-            rx,
-            withdrawal_transactions: AHashMap::with_capacity(TRANSACTION_MAP_SIZE),
-            deposit_transactions: AHashMap::with_capacity(TRANSACTION_MAP_SIZE),
-            accounts: AHashMap::with_capacity(ACCOUNT_MAP_SIZE),
+            config: EngineConfig::default(),
+            transaction_capacity: TRANSACTION_MAP_SIZE,
+            account_capacity: ACCOUNT_MAP_SIZE,
+            clock: Box::new(InputClock),
+            validators: vec![],
+            handlers: AHashMap::new(),
         }
     }
+}
 
-    fn process_transaction(&mut self, tx: Transaction) {
-        match tx {
-            Transaction::Deposit(tx_detail) => {
-                if let Err(e) = self.process_deposit(tx_detail) {
-                    tracing::error!("Fail to deposit: {e:?}");
+impl TransactionEngineBuilder {
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    //initial capacity of the deposit and withdrawal maps of the default ledger, they still grow past it
+    pub fn transaction_capacity(mut self, capacity: usize) -> Self {
+        self.transaction_capacity = capacity;
+        self
+    }
+
+    //initial capacity of the account map of the default ledger, the default fits every client id
+    pub fn account_capacity(mut self, capacity: usize) -> Self {
+        self.account_capacity = capacity;
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    //the validators are checked in the order they are added
+    pub fn validator(mut self, validator: impl Validator + 'static) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    //apply the rows of this type with the handler instead of skipping them. The type is case insensitive, and a
+    //handler for a type the engine knows is never called
+    pub fn handler(mut self, r#type: &str, handler: impl TransactionHandler + 'static) -> Self {
+        self.handlers
+            .insert(r#type.to_lowercase_smolstr(), Box::new(handler));
+        self
+    }
+
+    pub fn build(self) -> TransactionEngine {
+        let config = self.config;
+        TransactionEngine {
+            journal: Journal::new(config.journal),
+            rejects: config.record_rejects.then(Vec::new),
+            mismatches: vec![],
+            applied: config.record_applied.then(Vec::new),
+            quarantine: config.quarantine.clone().map(Quarantine::new),
+            checkpoints: vec![],
+            audit: config.audit.then(Vec::new),
+            events: config.events.then(Vec::new),
+            #[cfg(feature = "runtime")]
+            limiter: config.rate_limit.map(RateLimiter::new),
+            #[cfg(feature = "runtime")]
+            error_budget: None,
+            //the other ledgers start empty since there can be many small ones
+            books: BTreeMap::from([(
+                SmolStr::new_static(DEFAULT_LEDGER),
+                Book::with_capacity(self.transaction_capacity, self.account_capacity),
+            )]),
+            config,
+            now: None,
+            clock: self.clock,
+            validators: self.validators,
+            handlers: self.handlers,
+            #[cfg(feature = "runtime")]
+            since_flush: 0,
+            #[cfg(feature = "runtime")]
+            incremental_writer: None,
+            #[cfg(feature = "runtime")]
+            incremental_ledgers: None,
+            #[cfg(feature = "runtime")]
+            since_archive: 0,
+            #[cfg(feature = "runtime")]
+            archive_writer: None,
+            #[cfg(feature = "runtime")]
+            since_memory_check: 0,
+            #[cfg(feature = "runtime")]
+            memory_exceeded: false,
+            #[cfg(feature = "runtime")]
+            memory_stop: None,
+            #[cfg(feature = "runtime")]
+            audit_writer: None,
+            #[cfg(feature = "runtime")]
+            audit_sender: None,
+            #[cfg(feature = "runtime")]
+            events_writer: None,
+            #[cfg(feature = "runtime")]
+            queries: None,
+            #[cfg(feature = "runtime")]
+            query_handle: None,
+            #[cfg(feature = "runtime")]
+            stats: EngineStats::default(),
+        }
+    }
+}
+
+impl TransactionEngine {
+    pub fn builder() -> TransactionEngineBuilder {
+        TransactionEngineBuilder::default()
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        Self::builder().config(config).build()
+    }
+
+    fn default_book(&self) -> &Book {
+        &self.books[DEFAULT_LEDGER]
+    }
+
+    //true once a transaction of a ledger other than the default one is received, or if the config asks for the
+    //ledger column
+    #[cfg(feature = "runtime")]
+    fn has_ledgers(&self) -> bool {
+        self.config.ledger_column || self.books.len() > 1
+    }
+
+    //handle to read the accounts from other tasks while the engine is running. The channel is created by the first
+    //call and the next ones return clones of its handle, so the handles given out earlier keep working. The queries
+    //are answered from the default ledger only
+    #[cfg(feature = "runtime")]
+    pub fn accounts_handle(&mut self) -> AccountsHandle {
+        self.query_handle
+            .get_or_insert_with(|| {
+                let (handle, queries) = AccountsHandle::channel();
+                self.queries = Some(queries);
+                handle
+            })
+            .clone()
+    }
+
+    //count the transactions rejected by run in the budget
+    #[cfg(feature = "runtime")]
+    pub fn error_budget(&mut self, budget: Arc<ErrorBudget>) {
+        self.error_budget = Some(budget);
+    }
+
+    //the queries are answered from the default ledger
+    #[cfg(feature = "runtime")]
+    fn answer(&mut self, query: AccountQuery) {
+        let accounts = &self.default_book().accounts;
+        //the requester may have given up waiting, so the result of send is ignored
+        match query {
+            AccountQuery::Account(client, tx) => {
+                let _ = tx.send(accounts.get(&client).cloned());
+            }
+            AccountQuery::Locked(tx) => {
+                let mut locked = accounts
+                    .values()
+                    .filter(|account| account.locked)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                locked.sort_by_key(|account| account.client);
+                let _ = tx.send(locked);
+            }
+            AccountQuery::TopHeld(n, tx) => {
+                let mut top = accounts
+                    .values()
+                    .filter(|account| account.held > 0.0)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                top.sort_by(|a, b| b.held.total_cmp(&a.held).then(a.client.cmp(&b.client)));
+                top.truncate(n);
+                let _ = tx.send(top);
+            }
+            AccountQuery::Stats(tx) => {
+                self.stats.memory = self.memory_usage();
+                let _ = tx.send(self.stats.clone());
+            }
+            AccountQuery::Snapshot(tx) => {
+                let _ = tx.send(self.snapshot());
+            }
+            AccountQuery::Sweep(now, tx) => {
+                self.sweep(now);
+                self.write_records();
+                let _ = tx.send(());
+            }
+            AccountQuery::Interest(now, tx) => {
+                self.settle_interest(now);
+                self.write_records();
+                let _ = tx.send(());
+            }
+            AccountQuery::Reconfigure(change, tx) => {
+                self.reconfigure(change);
+                let _ = tx.send(());
+            }
+            AccountQuery::Flush(tx) => {
+                self.flush();
+                let _ = tx.send(());
+            }
+            AccountQuery::Checkpoint(name, tx) => {
+                let result = self.checkpoint(&name).map_err(|e| e.to_string());
+                self.write_records();
+                let _ = tx.send(result);
+            }
+            AccountQuery::Rollback(name, tx) => {
+                let result = self.rollback(&name).map_err(|e| e.to_string());
+                self.write_records();
+                let _ = tx.send(result);
+            }
+        }
+    }
+
+    //returns false if the transaction is rejected
+    pub fn process_transaction(&mut self, tx: Transaction) -> bool {
+        self.process(tx).is_ok()
+    }
+
+    //process the transactions in order, without the channel of run, and return the outcome of each of them, e.g.
+    //for a library user that drives the engine from its own runtime. The rejected transactions are still logged. The
+    //error of a rejected transaction is a TransactionErrors that can be matched with downcast_ref, except the errors
+    //returned by a custom handler which are passed through as they are
+    pub fn process_batch(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Vec<anyhow::Result<TransactionOutcome>> {
+        transactions
+            .into_iter()
+            .map(|tx| self.process(tx))
+            .collect()
+    }
+
+    //returns the error of a rejected transaction
+    fn process(&mut self, tx: Transaction) -> anyhow::Result<TransactionOutcome> {
+        match &tx {
+            Transaction::Checkpoint(_) | Transaction::Rollback(_)
+                if !self.config.checkpoint_records =>
+            {
+                tracing::error!("Ignored {} row", tx.type_name());
+                bail!(TransactionErrors::ControlRecord(tx.type_name().into()));
+            }
+            Transaction::Checkpoint(name) => {
+                if let Err(e) = self.checkpoint(name) {
+                    tracing::error!("Fail to save checkpoint: {e}");
+                    return Err(e);
+                }
+                return Ok(TransactionOutcome::Applied);
+            }
+            Transaction::Rollback(name) => {
+                if let Err(e) = self.rollback(name) {
+                    tracing::error!("Fail to roll back: {e}");
+                    return Err(e);
                 }
+                return Ok(TransactionOutcome::Applied);
             }
+            Transaction::AssertBalance(assertion) => {
+                return self
+                    .assert_balance(assertion)
+                    .map(|()| TransactionOutcome::Applied)
+            }
+            //without the runtime there is no incremental report to write
+            Transaction::Flush => {
+                #[cfg(feature = "runtime")]
+                self.flush();
+                return Ok(TransactionOutcome::Applied);
+            }
+            _ => {}
+        }
+        //ignore unknown transaction, unless a handler is registered for its type
+        if let Transaction::Unknown(r#type, ..) = &tx {
+            if !self.handlers.contains_key(r#type) {
+                tracing::error!("type" = r#type.as_str(), "Skipped unknown transaction");
+                bail!(TransactionErrors::UnknownType(r#type.clone()));
+            }
+        }
+        let Some(TransactionDetail {
+            client,
+            tx: tx_id,
+            timestamp,
+            ledger,
+            ..
+        }) = tx.detail()
+        else {
+            tracing::error!("Skipped unknown transaction");
+            bail!(TransactionErrors::ControlRecord(tx.type_name().into()));
+        };
+        let (client, tx_id) = (*client, *tx_id);
+        let ledger = ledger.clone().unwrap_or_default();
+        if let Some(now) = self.clock.now(*timestamp) {
+            self.advance_clock(now);
+        }
+        if self.already_applied(&tx) {
+            tracing::debug!(client, tx = tx_id, "Skipped, applied by a previous run");
+            return Ok(TransactionOutcome::Skipped);
+        }
+        if let Err(e) = self.validate(&tx) {
+            tracing::error!(client, tx = tx_id, "Fail to validate: {e}");
+            return Err(e);
+        }
+        if self.park(&tx) {
+            tracing::info!(client, tx = tx_id, "Quarantined for review");
+            return Ok(TransactionOutcome::Quarantined);
+        }
+        let transaction_type = tx.transaction_type();
+        let event = match &tx {
+            Transaction::Unknown(..) => Some(AuditEvent::Custom),
+            _ => transaction_type.map(AuditEvent::from),
+        };
+        let book = self.books.entry(ledger).or_default();
+        let before = self.audit.is_some().then(|| {
+            book.accounts
+                .get(&client)
+                .cloned()
+                .unwrap_or(Account::new(client))
+        });
+        let tx_ledger = tx.detail().and_then(|t| t.ledger.clone());
+        let tx_event = self.events.is_some().then(|| EventRecord::new(&tx));
+        let applied_id = self
+            .applied
+            .is_some()
+            .then(|| AppliedId::new(&tx))
+            .flatten();
+        let mut ctx = Context {
+            config: &self.config,
+            journal: &mut self.journal,
+            now: self.now,
+            audit: None,
+        };
+        //client, tx and type are attached as fields so that the errors can be aggregated by the log collector
+        match tx {
+            Transaction::Deposit(tx_detail) => match book.process_deposit(&mut ctx, tx_detail) {
+                Ok(TransactionOutcome::Skipped) => return Ok(TransactionOutcome::Skipped),
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = "deposit",
+                        "Fail to deposit: {e}"
+                    );
+                    return Err(e);
+                }
+            },
             Transaction::Withdrawal(tx_detail) => {
-                if let Err(e) = self.process_withdrawal(tx_detail) {
-                    tracing::error!("Fail to withdraw: {e:?}");
+                match book.process_withdrawal(&mut ctx, tx_detail) {
+                    Ok(TransactionOutcome::Skipped) => return Ok(TransactionOutcome::Skipped),
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!(
+                            client,
+                            tx = tx_id,
+                            "type" = "withdrawal",
+                            "Fail to withdraw: {e}"
+                        );
+                        return Err(e);
+                    }
                 }
             }
             Transaction::Dispute(tx_detail) => {
-                if let Err(e) = self.process_dispute(tx_detail) {
-                    tracing::error!("Fail to dispute: {e:?}");
+                if let Err(e) = book.process_dispute(&mut ctx, tx_detail) {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = "dispute",
+                        "Fail to dispute: {e}"
+                    );
+                    return Err(e);
                 }
             }
             Transaction::Resolve(tx_detail) => {
-                if let Err(e) = self.process_resolve(tx_detail) {
-                    tracing::error!("Fail to resolve: {e:?}");
+                if let Err(e) = book.process_resolve(&mut ctx, tx_detail) {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = "resolve",
+                        "Fail to resolve: {e}"
+                    );
+                    return Err(e);
                 }
             }
             Transaction::ChargeBack(tx_detail) => {
-                if let Err(e) = self.process_chargeback(tx_detail) {
-                    tracing::error!("Fail to chargeback: {e:?}");
+                if let Err(e) = book.process_chargeback(&mut ctx, tx_detail) {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = "chargeback",
+                        "Fail to chargeback: {e}"
+                    );
+                    return Err(e);
+                }
+            }
+            Transaction::Refund(tx_detail) => {
+                if let Err(e) = book.process_refund(&mut ctx, tx_detail) {
+                    tracing::error!(client, tx = tx_id, "type" = "refund", "Fail to refund: {e}");
+                    return Err(e);
                 }
             }
-            //ignore unknown transaction
-            Transaction::Unknown => {
-                tracing::error!("Skipped unknown transaction");
+            Transaction::Authorize(tx_detail) => {
+                match book.process_authorize(&mut ctx, tx_detail) {
+                    Ok(TransactionOutcome::Skipped) => return Ok(TransactionOutcome::Skipped),
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!(
+                            client,
+                            tx = tx_id,
+                            "type" = "authorize",
+                            "Fail to authorize: {e}"
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+            Transaction::Capture(tx_detail) => {
+                if let Err(e) = book.process_capture(&mut ctx, tx_detail) {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = "capture",
+                        "Fail to capture: {e}"
+                    );
+                    return Err(e);
+                }
+            }
+            Transaction::Unknown(r#type, tx_detail, record) => {
+                let handler = self.handlers[&r#type].as_ref();
+                if let Err(e) = book.process_unknown(&mut ctx, handler, tx_detail, record) {
+                    tracing::error!(
+                        client,
+                        tx = tx_id,
+                        "type" = r#type.as_str(),
+                        "Fail to handle: {e}"
+                    );
+                    return Err(e);
+                }
             }
+            Transaction::Flush
+            | Transaction::Checkpoint(_)
+            | Transaction::Rollback(_)
+            | Transaction::AssertBalance(_) => {}
+        }
+        if self.config.incremental {
+            book.changed.insert(client);
+        }
+        let state = transaction_type.and_then(|t| book.state(t, tx_id).cloned());
+        if let (Some(audit), Some(before), Some(event)) = (&mut self.audit, before, event) {
+            if let Some(after) = book.accounts.get(&client) {
+                audit.push(AuditRecord::new(
+                    event,
+                    tx_id,
+                    &before,
+                    after,
+                    state.clone(),
+                    tx_ledger,
+                ));
+            }
+        }
+        if let Some(mut event) = tx_event {
+            event.state = state;
+            self.record_event(event);
         }
+        if let (Some(applied), Some(id)) = (&mut self.applied, applied_id) {
+            applied.push(id);
+        }
+        Ok(TransactionOutcome::Applied)
+    }
+
+    //true if the transaction is in the applied ids of the previous runs
+    fn already_applied(&self, tx: &Transaction) -> bool {
+        self.config
+            .applied
+            .as_ref()
+            .is_some_and(|applied| AppliedId::new(tx).is_some_and(|id| applied.contains(&id)))
     }
 
-    fn get_unlocked_account(
-        accounts: &mut AHashMap<u16, Account>,
-        client: u16,
-    ) -> anyhow::Result<&mut Account> {
-        let account = accounts.entry(client).or_insert(Account::new(client));
-        if account.locked {
-            bail!(TransactionErrors::AccountLock(AccountLockError { client },))
-        } else {
-            Ok(account)
+    //check the balances of the account against the ones asserted by the input, returns an error if any differs. An
+    //account that doesn't exist, or is archived, has zero balances
+    fn assert_balance(&mut self, assertion: &BalanceAssertion) -> anyhow::Result<()> {
+        let ledger = assertion.ledger.clone().unwrap_or_default();
+        let account = self
+            .books
+            .get(&ledger)
+            .and_then(|book| book.accounts.get(&assertion.client))
+            .cloned()
+            .unwrap_or(Account::new(assertion.client));
+        let mismatches = assertion::check(assertion, &account);
+        for mismatch in &mismatches {
+            tracing::error!(
+                client = mismatch.client,
+                ledger = ledger.as_str(),
+                "Balance assertion failed: expected {} {}, got {}",
+                mismatch.balance,
+                mismatch.expected,
+                mismatch.actual
+            );
         }
+        let failed = mismatches.len();
+        self.mismatches.extend(mismatches);
+        if failed > 0 {
+            bail!(TransactionErrors::BalanceAssertion(BalanceAssertionError {
+                client: assertion.client,
+                failed
+            }));
+        }
+        Ok(())
     }
 
-    // helper function to check if transaction id already exists
-    fn check_dup_transaction_id(
-        transactions: &AHashMap<u32, TransactionDetail>,
-        tx: u32,
-    ) -> anyhow::Result<()> {
-        if transactions.get(&tx).is_some() {
-            bail!(TransactionErrors::DuplicateTransaction(
-                DuplicateTransactionError { tx },
-            ))
+    //balance assertions of the input that failed since the start of the run
+    pub fn balance_mismatches(&self) -> &[BalanceMismatch] {
+        &self.mismatches
+    }
+
+    //write the transactions applied by the previous runs and by this one, so that the file can be loaded by the next
+    //run. The ids are sorted so that the same runs always write the same file
+    #[cfg(feature = "runtime")]
+    pub fn output_applied_ids(&self, path: &str) -> anyhow::Result<()> {
+        let mut ids = self
+            .config
+            .applied
+            .iter()
+            .flat_map(|applied| applied.iter())
+            .chain(self.applied.iter().flatten())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for id in ids {
+            wtr.serialize(id)?;
         }
+        wtr.flush()?;
         Ok(())
     }
 
-    fn process_deposit(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
-        Self::check_dup_transaction_id(&self.deposit_transactions, tx_detail.tx)?;
-        if let Some(amount) = tx_detail.amount {
-            if amount > 0.0 {
-                let account = Self::get_unlocked_account(&mut self.accounts, tx_detail.client)?;
-                account.available += amount;
-                account.total += amount;
-                if self
-                    .deposit_transactions
-                    .insert(tx_detail.tx, tx_detail)
-                    .is_none()
-                {
-                    //if map is full, try to resesrve additional space
-                    if self.deposit_transactions.len() == self.deposit_transactions.capacity() {
-                        if let Err(e) = self.deposit_transactions.try_reserve(TRANSACTION_MAP_SIZE)
-                        {
-                            tracing::error!(
-                                "Fail to reserve capacity for the deposit transaction map: {e}"
-                            );
-                        }
-                    }
-                }
-                return Ok(());
-            }
-        }
-
-        bail!(TransactionErrors::Deposit(DepositError {
-            tx: tx_detail.tx
-        },))
-    }
-
-    fn process_withdrawal(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
-        Self::check_dup_transaction_id(&self.withdrawal_transactions, tx_detail.tx)?;
-        if let Some(amount) = tx_detail.amount {
-            let account = Self::get_unlocked_account(&mut self.accounts, tx_detail.client)?;
-            //if the amount is > 0 and if available fund is > the withdraw amount
-            if amount > 0.0 && account.available >= amount {
-                account.available -= amount;
-                account.total -= amount;
-                if self
-                    .withdrawal_transactions
-                    .insert(tx_detail.tx, tx_detail)
-                    .is_none()
-                {
-                    //if map is full, try to resesrve additional space
-                    if self.withdrawal_transactions.len() == self.withdrawal_transactions.capacity()
-                    {
-                        if let Err(e) = self
-                            .withdrawal_transactions
-                            .try_reserve(TRANSACTION_MAP_SIZE)
-                        {
-                            tracing::error!(
-                                "Fail to reserve capacity for the withdrawal transaction map: {e}"
-                            );
-                        }
-                    }
-                }
-                return Ok(());
-            }
-        }
-
-        bail!(TransactionErrors::Withdrawal(WithdrawalError {
-            tx: tx_detail.tx
-        },))
-    }
-
-    //The doc mentioned that during a dispute, the held fund is increased by the dispute amount and the available fund is decreased by. I assume that
-    //this is referring to a dispute for a withdrawal transaction as it simply means moving fund from the the available fund to the held fund. For disputing a
-    // withdrawal, I don't think we should decrease the avaiable fund as the client as disputing an incorrect amount being debit from his/her account. So for the dispute
-    //of a withdrawal transaction, I decided to increment the held fund only, which means the total fund will increase. However, since the client can't really use that amount yet,
-    //so I believe it's fine.
-    fn process_dispute(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
-        //ignore the dispute if the account is locked
-        let account = Self::get_unlocked_account(&mut self.accounts, tx_detail.client)?;
-        //if the dispute transaction is a deposit
-        if let Some(dispute_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
-            if let Some(amount) = dispute_tx_detail.amount {
-                if tx_detail.client == dispute_tx_detail.client
-                    && dispute_tx_detail.state == TranactionState::Normal
-                    && account.available >= amount
-                {
-                    //Move the dispute amount from available to held, total doesn't change
-                    account.available -= amount;
-                    account.held += amount;
-                    dispute_tx_detail.state = TranactionState::Dispute;
-                    return Ok(());
-                }
+    #[cfg(feature = "runtime")]
+    pub fn output_balance_mismatches(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for mismatch in &self.mismatches {
+            wtr.serialize(mismatch)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    fn record_event(&mut self, event: EventRecord) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
+    }
+
+    //apply an event of the event log, returns false if it is rejected, which means that the engine doesn't have the
+    //config of the engine that recorded it
+    pub fn replay(&mut self, event: &EventRecord) -> bool {
+        if let Some(now) = event.sweep_time() {
+            self.sweep(now);
+            return true;
+        }
+        if let Some(now) = event.interest_time() {
+            self.settle_interest(now);
+            return true;
+        }
+        match event.transaction() {
+            //the checkpoints of the log are replayed even if the rows of the input are not allowed to take them
+            Ok(Transaction::Checkpoint(name)) => self.checkpoint(&name).is_ok(),
+            Ok(Transaction::Rollback(name)) => self.rollback(&name).is_ok(),
+            Ok(transaction) => self.process_transaction(transaction),
+            Err(e) => {
+                tracing::error!("Fail to replay event: {e}");
+                false
+            }
+        }
+    }
+
+    //check the transaction against the validation rules, the violation is recorded if the rejects are kept
+    fn validate(&mut self, tx: &Transaction) -> anyhow::Result<()> {
+        let (Some(transaction_type), Some(tx_detail)) = (tx.transaction_type(), tx.detail()) else {
+            return Ok(());
+        };
+        let result = self
+            .check_enabled(transaction_type)
+            .and_then(|_| self.config.validation.check(transaction_type, tx_detail))
+            .and_then(|_| match self.tier_rules(tx_detail.client) {
+                Some(rules) => rules.check(transaction_type, tx_detail),
+                None => Ok(()),
+            })
+            .and_then(|_| {
+                self.validators
+                    .iter()
+                    .try_for_each(|validator| validator.check(transaction_type, tx_detail))
+            });
+        if let Err(e) = result {
+            if let Some(rejects) = &mut self.rejects {
+                rejects.push(RejectRecord::new(transaction_type, tx_detail, &e));
             }
+            bail!(TransactionErrors::Validation(e))
         }
-        //if the dispute transaction is a withdraw
-        else if let Some(dispute_tx_detail) = self.withdrawal_transactions.get_mut(&tx_detail.tx)
+        Ok(())
+    }
+
+    fn check_enabled(&self, transaction_type: TransactionType) -> Result<(), ValidationError> {
+        if self.config.disabled_types.contains(&transaction_type) {
+            return Err(ValidationError::TypeDisabled(transaction_type));
+        }
+        Ok(())
+    }
+
+    //rules of the tier of the client, if it has a profile and its tier has rules
+    fn tier_rules(&self, client: u16) -> Option<&ValidationRules> {
+        let profile = self.config.profiles.as_ref()?.get(client)?;
+        self.config.validation.tiers.get(&profile.tier)
+    }
+
+    //true if the transaction is parked by the quarantine rules instead of being applied
+    fn park(&mut self, tx: &Transaction) -> bool {
+        let (Some(quarantine), Some(transaction_type), Some(tx_detail)) =
+            (&mut self.quarantine, tx.transaction_type(), tx.detail())
+        else {
+            return false;
+        };
+        quarantine.check(transaction_type, tx_detail)
+    }
+
+    //reverse a deposit or withdrawal of the ledger on behalf of an operator, see Book::reverse
+    pub fn reverse(&mut self, ledger: &str, tx: TxId) -> anyhow::Result<()> {
+        let Some(book) = self.books.get_mut(ledger) else {
+            bail!(TransactionErrors::Reversal(ReversalError { tx }))
+        };
+        let before = self.audit.is_some().then(|| {
+            let client = book
+                .deposit_transactions
+                .get(&tx)
+                .or_else(|| book.withdrawal_transactions.get(&tx))
+                .map(|t| t.client)
+                .unwrap_or_default();
+            book.accounts
+                .get(&client)
+                .cloned()
+                .unwrap_or(Account::new(client))
+        });
+        let mut ctx = Context {
+            config: &self.config,
+            journal: &mut self.journal,
+            now: self.now,
+            audit: None,
+        };
+        let client = book.reverse(&mut ctx, tx)?;
+        tracing::info!(client, tx, "Reversed by an operator");
+        if let (Some(audit), Some(before), Some(after)) =
+            (&mut self.audit, before, book.accounts.get(&client))
         {
-            if let Some(amount) = dispute_tx_detail.amount {
-                if tx_detail.client == dispute_tx_detail.client
-                    && dispute_tx_detail.state == TranactionState::Normal
-                {
-                    //increase the held and total. Since the increased amount is held, increasing the total should be
-                    //fine
-                    account.held += amount;
-                    account.total += amount;
-                    dispute_tx_detail.state = TranactionState::Dispute;
-                    return Ok(());
+            audit.push(AuditRecord::new(
+                AuditEvent::Reversal,
+                tx,
+                &before,
+                after,
+                Some(TranactionState::Reversed),
+                (!ledger.is_empty()).then(|| ledger.into()),
+            ));
+        }
+        Ok(())
+    }
+
+    //read the clock without a transaction, so that the disputes and authorizations expire while the input is idle
+    pub fn tick(&mut self) {
+        if let Some(now) = self.clock.now(None) {
+            self.advance_clock(now);
+        }
+    }
+
+    //change a setting without restarting the engine, the transactions already applied are not checked again
+    pub fn reconfigure(&mut self, change: ConfigChange) {
+        tracing::info!(?change, "Reconfigure transaction engine");
+        match change {
+            ConfigChange::LockPolicy(policy) => self.config.lock_policy = policy,
+            ConfigChange::DuplicatePolicy(policy) => self.config.duplicate_policy = policy,
+            ConfigChange::RateLimit(limit) => {
+                self.config.rate_limit = limit;
+                #[cfg(feature = "runtime")]
+                self.set_rate_limit(limit);
+            }
+        }
+    }
+
+    //the buckets are kept when the limit changes. Without a limit, the deferred transactions are applied right away
+    #[cfg(feature = "runtime")]
+    fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        match (&mut self.limiter, limit) {
+            (Some(limiter), Some(limit)) => limiter.set_limit(limit),
+            (None, Some(limit)) => self.limiter = Some(RateLimiter::new(limit)),
+            (limiter, None) => {
+                let deferred = limiter.take().map(RateLimiter::into_deferred);
+                for transaction in deferred.into_iter().flatten() {
+                    self.apply(transaction);
                 }
             }
         }
+    }
 
-        bail!(TransactionErrors::Dispute(DisputeError {
-            tx: tx_detail.tx
-        },))
+    //expire the disputes and authorizations as of this unix time whatever the clock, e.g. on an end-of-day sweep of a
+    //live feed whose rows don't move the clock. The time of the engine only moves forward
+    pub fn sweep(&mut self, now: u64) {
+        self.advance_clock(now);
+        self.record_event(EventRecord::sweep(now));
     }
 
-    fn process_resolve(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
-        //ignore the resolve if the account is locked
-        let account = Self::get_unlocked_account(&mut self.accounts, tx_detail.client)?;
+    //settle the interest or penalty accrued so far by the open disputes of every ledger, e.g. on an end-of-day
+    //interest run. The disputes and authorizations that are expired at this time are expired first
+    pub fn settle_interest(&mut self, now: u64) {
+        self.advance_clock(now);
+        let mut ctx = Context {
+            config: &self.config,
+            journal: &mut self.journal,
+            now: self.now,
+            audit: self.audit.as_mut(),
+        };
+        for book in self.books.values_mut() {
+            book.settle_open_accruals(&mut ctx, now);
+        }
+        self.record_event(EventRecord::interest(now));
+    }
 
-        //resolve disputed deposit transaction
-        if let Some(resolve_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
-            if let Some(amount) = resolve_tx_detail.amount {
-                if tx_detail.client == resolve_tx_detail.client
-                    && resolve_tx_detail.state == TranactionState::Dispute
-                    && account.held >= amount
-                {
-                    //Move the amount from the held back to the available
-                    account.held -= amount;
-                    account.available += amount;
-                    resolve_tx_detail.state = TranactionState::Resolve;
-                    return Ok(());
-                }
+    //move the clock forward, auto-resolve all the disputes and release all the authorizations that are expired in
+    //every ledger
+    fn advance_clock(&mut self, timestamp: u64) {
+        if self.now.is_some_and(|now| now >= timestamp) {
+            return;
+        }
+        self.now = Some(timestamp);
+        let mut ctx = Context {
+            config: &self.config,
+            journal: &mut self.journal,
+            now: self.now,
+            audit: self.audit.as_mut(),
+        };
+        for book in self.books.values_mut() {
+            book.expire_until(&mut ctx, timestamp);
+        }
+    }
+
+    //accounts of the default ledger
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.default_book().accounts.values()
+    }
+
+    //accounts of every ledger with the id of their ledger
+    pub fn ledger_accounts(&self) -> impl Iterator<Item = (&SmolStr, &Account)> {
+        self.books
+            .iter()
+            .flat_map(|(ledger, book)| book.accounts.values().map(move |account| (ledger, account)))
+    }
+
+    //accounts of every ledger that are selected by the account filter of the config
+    pub fn report_accounts(&self) -> impl Iterator<Item = (&SmolStr, &Account)> {
+        self.ledger_accounts()
+            .filter(|(_, account)| self.config.account_filter.matches(account))
+    }
+
+    //the report has a ledger column only if there are several ledgers, so that the default report doesn't change
+    #[cfg(feature = "runtime")]
+    pub fn output(&self) {
+        let writer = BufWriter::new(std::io::stdout());
+        let mut wtr = csv::Writer::from_writer(writer);
+        let has_ledgers = self.has_ledgers();
+        let profiles = self.config.profiles.as_ref();
+        self.report_accounts().for_each(|(ledger, account)| {
+            if let Err(e) = write_account(&mut wtr, ledger, account, has_ledgers, profiles) {
+                tracing::error!("Fail to write: {e}");
             }
+        });
+    }
+
+    //write the account report to a file instead of stdout
+    #[cfg(feature = "runtime")]
+    pub fn output_to_file(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        let has_ledgers = self.has_ledgers();
+        let profiles = self.config.profiles.as_ref();
+        for (ledger, account) in self.report_accounts() {
+            write_account(&mut wtr, ledger, account, has_ledgers, profiles)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //accounts changed since the last flush with their ledger, sorted by ledger and client
+    pub fn take_changed_accounts(&mut self) -> Vec<(SmolStr, Account)> {
+        self.books
+            .iter_mut()
+            .flat_map(|(ledger, book)| {
+                std::mem::take(&mut book.changed)
+                    .into_iter()
+                    .filter_map(|client| {
+                        let account = book.accounts.get(&client)?;
+                        Some((ledger.clone(), account.clone()))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    //same as a flush record: write the accounts changed since the last flush in incremental mode
+    #[cfg(feature = "runtime")]
+    pub fn flush(&mut self) {
+        if self.config.incremental {
+            self.flush_changed();
         }
-        //resolve disputed withdraw transaction
-        else if let Some(resolve_tx_detail) = self.withdrawal_transactions.get_mut(&tx_detail.tx)
+    }
+
+    //write the incremental rows to this writer instead of stdout, e.g. a file
+    #[cfg(feature = "runtime")]
+    pub fn incremental_to(&mut self, writer: impl Write + Send + 'static) {
+        self.incremental_writer = Some(csv::Writer::from_writer(Box::new(writer)));
+    }
+
+    //write the accounts changed since the last flush to stdout, the header is only written once. Whether the rows
+    //have a ledger column is decided by the first flush and kept, so that every row has the columns of the header.
+    //A ledger that first appears after a flush without the column is still written, without its ledger, unless the
+    //config asks for the column from the start
+    #[cfg(feature = "runtime")]
+    fn flush_changed(&mut self) {
+        self.since_flush = 0;
+        let accounts = self.take_changed_accounts();
+        let has_ledgers = *self
+            .incremental_ledgers
+            .get_or_insert(self.config.ledger_column || self.books.len() > 1);
+        let wtr = self
+            .incremental_writer
+            .get_or_insert_with(|| csv::Writer::from_writer(Box::new(std::io::stdout())));
+        let profiles = self.config.profiles.as_ref();
+        let filter = &self.config.account_filter;
+        for (ledger, account) in accounts
+            .iter()
+            .filter(|(_, account)| filter.matches(account))
         {
-            if let Some(amount) = resolve_tx_detail.amount {
-                if tx_detail.client == resolve_tx_detail.client
-                    && resolve_tx_detail.state == TranactionState::Dispute
-                    && account.held >= amount
-                {
-                    //decrease the held and total
-                    account.held -= amount;
-                    account.total -= amount;
-                    resolve_tx_detail.state = TranactionState::Resolve;
-                    return Ok(());
-                }
+            if !has_ledgers && !ledger.is_empty() {
+                tracing::error!(
+                    client = account.client,
+                    ledger = ledger.as_str(),
+                    "Account written without its ledger, the first flush had no ledger column"
+                );
             }
+            if let Err(e) = write_account(wtr, ledger, account, has_ledgers, profiles) {
+                tracing::error!("Fail to write: {e}");
+            }
+        }
+        if let Err(e) = wtr.flush() {
+            tracing::error!("Fail to flush: {e}");
+        }
+    }
+
+    //remove the accounts with zero balances and no open dispute from every ledger, they are re-created by the next
+    //transaction of their client
+    pub fn archive_inactive(&mut self) -> Vec<(SmolStr, Account)> {
+        self.books
+            .iter_mut()
+            .flat_map(|(ledger, book)| {
+                book.archive_inactive()
+                    .into_iter()
+                    .map(|account| (ledger.clone(), account))
+            })
+            .collect()
+    }
+
+    //archive the inactive accounts to this csv file every archive_every transactions while running. The rows always
+    //have a ledger column so that the sweeps of a multi-ledger run can be appended to the same file
+    #[cfg(feature = "runtime")]
+    pub fn archive_to(&mut self, path: &str) -> std::io::Result<()> {
+        self.archive_writer = Some(csv::Writer::from_writer(BufWriter::new(File::create(
+            path,
+        )?)));
+        Ok(())
+    }
+
+    #[cfg(feature = "runtime")]
+    fn archive(&mut self) {
+        self.since_archive = 0;
+        let Some(mut wtr) = self.archive_writer.take() else {
+            return;
+        };
+        let archived = self.archive_inactive();
+        tracing::info!("Archived {} inactive accounts", archived.len());
+        for (ledger, account) in &archived {
+            if let Err(e) = wtr.serialize(LedgerAccount::new(ledger, account)) {
+                tracing::error!("Fail to archive: {e}");
+            }
+        }
+        if let Err(e) = wtr.flush() {
+            tracing::error!("Fail to flush the archive: {e}");
         }
+        self.archive_writer = Some(wtr);
+    }
+
+    //approximate number of bytes taken by the accounts and transactions of every ledger, the checkpoints and the
+    //postings of the journal, which make up most of the memory of a long run
+    pub fn memory_usage(&self) -> usize {
+        self.books.values().map(Book::memory_usage).sum::<usize>()
+            + self
+                .checkpoints
+                .iter()
+                .map(|checkpoint| checkpoint.snapshot.memory_usage())
+                .sum::<usize>()
+            + std::mem::size_of_val(self.journal.postings())
+    }
+
+    //set this flag once the memory usage is over the max memory of the config, e.g. the stop handle of the parser.
+    //The transactions already sent are still applied, so that the state matches the position of the producer
+    #[cfg(feature = "runtime")]
+    pub fn stop_on_max_memory(&mut self, stop: Arc<AtomicBool>) {
+        self.memory_stop = Some(stop);
+    }
+
+    //true once the memory usage was over the max memory, even after the inactive accounts were archived
+    #[cfg(feature = "runtime")]
+    pub fn memory_exceeded(&self) -> bool {
+        self.memory_exceeded
+    }
 
-        bail!(TransactionErrors::Resolve(ResolveError {
-            tx: tx_detail.tx
-        },))
-    }
-
-    fn process_chargeback(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
-        //ignore the chargeback if the account is locked
-        let account = Self::get_unlocked_account(&mut self.accounts, tx_detail.client)?;
-        //chargeback disputed deposit transaction
-        if let Some(chargeback_tx_detail) = self.deposit_transactions.get_mut(&tx_detail.tx) {
-            if let Some(amount) = chargeback_tx_detail.amount {
-                if tx_detail.client == chargeback_tx_detail.client
-                    && chargeback_tx_detail.state == TranactionState::Dispute
-                    && account.held >= amount
-                {
-                    //Move the amount from the held back to the available
-                    account.held -= amount;
-                    account.total -= amount;
-                    account.locked = true;
-                    chargeback_tx_detail.state = TranactionState::ChargeBack;
-                    return Ok(());
+    //the inactive accounts are evicted to the archive first, if there is one, and the producer is stopped if that is
+    //not enough
+    #[cfg(feature = "runtime")]
+    fn check_memory(&mut self) {
+        self.since_memory_check = 0;
+        let Some(max) = self.config.max_memory else {
+            return;
+        };
+        if self.memory_exceeded || self.memory_usage() <= max {
+            return;
+        }
+        if self.archive_writer.is_some() {
+            self.archive();
+            if self.memory_usage() <= max {
+                return;
+            }
+        }
+        self.memory_exceeded = true;
+        tracing::error!(
+            "Memory usage of {} bytes is over the max memory of {max} bytes, the run is stopped",
+            self.memory_usage()
+        );
+        if let Some(stop) = &self.memory_stop {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    //audit records made since the last call, in the order the state changes are applied
+    pub fn take_audit_records(&mut self) -> Vec<AuditRecord> {
+        self.audit.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    //append the audit records to this csv file while running. The header is only written to a new or empty file,
+    //so that the records of several runs can be kept in the same file
+    #[cfg(feature = "runtime")]
+    pub fn audit_to(&mut self, path: &str) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        self.audit_writer = Some(
+            csv::WriterBuilder::new()
+                .has_headers(empty)
+                .from_writer(BufWriter::new(file)),
+        );
+        Ok(())
+    }
+
+    //send the audit records to this channel while running. A record is dropped with an error if the channel is full,
+    //so that a slow consumer doesn't stall the engine
+    #[cfg(feature = "runtime")]
+    pub fn audit_sender(&mut self, sender: Sender<AuditRecord>) {
+        self.audit_sender = Some(sender);
+    }
+
+    //events made since the last call, in the order they are applied
+    pub fn take_events(&mut self) -> Vec<EventRecord> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    //append the events to this ndjson file while running, one json object per line
+    #[cfg(feature = "runtime")]
+    pub fn events_to(&mut self, path: &str) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.events_writer = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    //write the audit records and the events made since the last write to their files
+    #[cfg(feature = "runtime")]
+    fn write_records(&mut self) {
+        if self.audit_writer.is_some() || self.audit_sender.is_some() {
+            self.write_audit();
+        }
+        if self.events_writer.is_some() {
+            self.write_events();
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    fn write_events(&mut self) {
+        let events = self.take_events();
+        let Some(wtr) = &mut self.events_writer else {
+            return;
+        };
+        for event in events {
+            let written = serde_json::to_writer(&mut *wtr, &event)
+                .map_err(std::io::Error::from)
+                .and_then(|_| wtr.write_all(b"\n"));
+            if let Err(e) = written {
+                tracing::error!("Fail to write the event log: {e}");
+            }
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    fn write_audit(&mut self) {
+        for record in self.take_audit_records() {
+            if let Some(wtr) = &mut self.audit_writer {
+                if let Err(e) = wtr.serialize(&record) {
+                    tracing::error!("Fail to write the audit trail: {e}");
+                }
+            }
+            if let Some(sender) = &self.audit_sender {
+                if let Err(e) = sender.try_send(record) {
+                    tracing::error!(tx = e.into_inner().tx, "Fail to send the audit record");
                 }
             }
         }
-        //chargeback disputed withdraw transaction
-        else if let Some(chargeback_tx_detail) =
-            self.withdrawal_transactions.get_mut(&tx_detail.tx)
+    }
+
+    //write the postings of the journal to a csv file, in the order they are posted
+    #[cfg(feature = "runtime")]
+    pub fn output_journal(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for posting in self.journal.postings() {
+            wtr.serialize(posting)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //write the accounts of every ledger to an arrow ipc file
+    #[cfg(feature = "arrow")]
+    pub fn output_accounts_arrow(&self, path: &str) -> anyhow::Result<()> {
+        crate::arrow::write_accounts(
+            BufWriter::new(File::create(path)?),
+            self.report_accounts()
+                .map(|(ledger, account)| (ledger.as_str(), account)),
+        )?;
+        Ok(())
+    }
+
+    //write the postings of the journal to an arrow ipc file, in the order they are posted
+    #[cfg(feature = "arrow")]
+    pub fn output_journal_arrow(&self, path: &str) -> anyhow::Result<()> {
+        crate::arrow::write_postings(BufWriter::new(File::create(path)?), self.journal.postings())?;
+        Ok(())
+    }
+
+    //write the transactions rejected by the validation rules to a csv file, in the order they are received
+    #[cfg(feature = "runtime")]
+    pub fn output_rejects(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for reject in self.rejects.as_deref().unwrap_or_default() {
+            wtr.serialize(reject)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //transactions parked by the quarantine rules, in the order they are received
+    pub fn quarantined(&self) -> &[QuarantineRecord] {
+        self.quarantine
+            .as_ref()
+            .map(Quarantine::parked)
+            .unwrap_or_default()
+    }
+
+    //write the parked transactions to a csv file that can be reviewed with the quarantine command
+    #[cfg(feature = "runtime")]
+    pub fn output_quarantine(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for record in self.quarantined() {
+            wtr.serialize(record)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //all the transactions that are still in dispute, sorted by ledger, client and tx
+    pub fn open_disputes(&self) -> Vec<OpenDispute> {
+        let mut open_disputes = self
+            .books
+            .iter()
+            .flat_map(|(ledger, book)| {
+                book.open_disputes().map(|(r#type, t)| OpenDispute {
+                    client: t.client,
+                    tx: t.tx,
+                    amount: t.disputable_amount().unwrap_or_default(),
+                    r#type,
+                    ledger: ledger.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        open_disputes.sort_by(|a, b| {
+            (&a.ledger, a.client, a.tx, a.r#type).cmp(&(&b.ledger, b.client, b.tx, b.r#type))
+        });
+        open_disputes
+    }
+
+    #[cfg(feature = "runtime")]
+    pub fn output_open_disputes(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for open_dispute in self.open_disputes() {
+            wtr.serialize(open_dispute)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //interest or penalty accrued by the decided disputes of every client that has some, sorted by ledger and client
+    pub fn accruals(&self) -> Vec<AccruedAmount> {
+        let mut accruals = self
+            .books
+            .iter()
+            .flat_map(|(ledger, book)| {
+                book.accruals.iter().map(|(client, accrued)| AccruedAmount {
+                    client: *client,
+                    accrued: *accrued,
+                    ledger: ledger.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        accruals.sort_by(|a, b| (&a.ledger, a.client).cmp(&(&b.ledger, b.client)));
+        accruals
+    }
+
+    #[cfg(feature = "runtime")]
+    pub fn output_accruals(&self, path: &str) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        for accrued in self.accruals() {
+            wtr.serialize(accrued)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    //the default ledger is at the top level of the snapshot and the other ledgers are nested in it
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ledgers: self
+                .books
+                .iter()
+                .filter(|(ledger, _)| !ledger.is_empty())
+                .map(|(ledger, book)| (ledger.clone(), book.snapshot()))
+                .collect(),
+            ..self.default_book().snapshot()
+        }
+    }
+
+    //restore the accounts and transactions from a previous run
+    pub fn restore(&mut self, mut snapshot: Snapshot) {
+        let ledgers = std::mem::take(&mut snapshot.ledgers);
+        for (ledger, snapshot) in
+            std::iter::once((SmolStr::new_static(DEFAULT_LEDGER), snapshot)).chain(ledgers)
+        {
+            self.books
+                .entry(ledger)
+                .or_default()
+                .restore(&self.config, snapshot);
+        }
+    }
+
+    //save the state of the engine under the name, replacing an earlier checkpoint of the same name. The whole state
+    //is copied, so a checkpoint costs as much memory as the engine, and a new one is rejected once the max number of
+    //checkpoints is kept
+    pub fn checkpoint(&mut self, name: &str) -> anyhow::Result<()> {
+        let replaced = self
+            .checkpoints
+            .iter()
+            .any(|checkpoint| checkpoint.name == name);
+        if !replaced
+            && self
+                .config
+                .max_checkpoints
+                .is_some_and(|max| self.checkpoints.len() >= max)
         {
-            if let Some(amount) = chargeback_tx_detail.amount {
-                if tx_detail.client == chargeback_tx_detail.client
-                    && chargeback_tx_detail.state == TranactionState::Dispute
-                    && account.held >= amount
-                {
-                    //Move the amount from held back to avaiable
-                    account.held -= amount;
-                    account.available += amount;
-                    account.locked = true;
-                    chargeback_tx_detail.state = TranactionState::ChargeBack;
-                    return Ok(());
+            bail!(TransactionErrors::CheckpointLimit(CheckpointError {
+                name: name.into()
+            }))
+        }
+        self.checkpoints
+            .retain(|checkpoint| checkpoint.name != name);
+        self.checkpoints.push(Checkpoint {
+            name: name.into(),
+            snapshot: self.snapshot(),
+            now: self.now,
+            postings: self.journal.postings().len(),
+            rejects: self.rejects.as_ref().map_or(0, Vec::len),
+            audit: self.audit.as_ref().map_or(0, Vec::len),
+            mismatches: self.mismatches.len(),
+            applied: self.applied.as_ref().map_or(0, Vec::len),
+            quarantine: self.quarantine.clone(),
+        });
+        tracing::info!(checkpoint = name, "Saved checkpoint");
+        self.record_event(EventRecord::new(&Transaction::Checkpoint(name.into())));
+        Ok(())
+    }
+
+    //roll the engine back to the state of the checkpoint, so that the transactions applied since can be applied
+    //again, e.g. a corrected tail of the input. The checkpoint is kept and the ones taken after it are dropped. The
+    //accounts already written by incremental flushes and the audit records already written or taken are not
+    //withdrawn, every account is written again by the next flush instead. The events are kept too, the rollback is
+    //an event itself so that the replay rolls back as well
+    pub fn rollback(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(position) = self
+            .checkpoints
+            .iter()
+            .position(|checkpoint| checkpoint.name == name)
+        else {
+            bail!(TransactionErrors::Checkpoint(CheckpointError {
+                name: name.into()
+            }))
+        };
+        self.checkpoints.truncate(position + 1);
+        let checkpoint = &self.checkpoints[position];
+        let snapshot = checkpoint.snapshot.clone();
+        self.now = checkpoint.now;
+        self.journal.truncate(checkpoint.postings);
+        if let Some(rejects) = &mut self.rejects {
+            rejects.truncate(checkpoint.rejects);
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.truncate(checkpoint.audit);
+        }
+        self.mismatches.truncate(checkpoint.mismatches);
+        if let Some(applied) = &mut self.applied {
+            applied.truncate(checkpoint.applied);
+        }
+        self.quarantine = checkpoint.quarantine.clone();
+        self.books = BTreeMap::from([(SmolStr::new_static(DEFAULT_LEDGER), Book::default())]);
+        self.restore(snapshot);
+        if self.config.incremental {
+            for book in self.books.values_mut() {
+                book.changed.extend(book.accounts.keys());
+            }
+        }
+        tracing::info!(checkpoint = name, "Rolled back");
+        self.record_event(EventRecord::new(&Transaction::Rollback(name.into())));
+        Ok(())
+    }
+
+    //process the batches of transactions received from the channel until it is closed, the queries of the accounts
+    //handles are answered between two batches. The transactions deferred by the rate limit are applied as soon as
+    //their client has a token again, and the engine waits for them once the channel is closed
+    #[cfg(feature = "runtime")]
+    pub async fn run(&mut self, mut rx: Receiver<Vec<Transaction>>) -> EngineStats {
+        loop {
+            let waiting = Instant::now();
+            let next_release = self.limiter.as_ref().and_then(RateLimiter::next_release);
+            let batch = tokio::select! {
+                batch = rx.recv() => match batch {
+                    Some(batch) => batch,
+                    None => break,
+                },
+                Some(query) = recv_query(&mut self.queries) => {
+                    self.answer(query);
+                    continue;
+                }
+                _ = sleep_until(next_release) => {
+                    self.release_deferred();
+                    continue;
                 }
+            };
+            self.stats.receive_wait.record(waiting.elapsed());
+            for transaction in batch {
+                self.handle(transaction);
+            }
+            self.write_records();
+        }
+        while let Some(at) = self.limiter.as_ref().and_then(RateLimiter::next_release) {
+            tokio::time::sleep_until(at.into()).await;
+            self.release_deferred();
+        }
+        self.write_records();
+        if self.config.incremental {
+            self.flush_changed();
+        }
+        if let Some(wtr) = &mut self.audit_writer {
+            if let Err(e) = wtr.flush() {
+                tracing::error!("Fail to flush the audit trail: {e}");
+            }
+        }
+        if let Some(wtr) = &mut self.events_writer {
+            if let Err(e) = wtr.flush() {
+                tracing::error!("Fail to flush the event log: {e}");
             }
         }
-        bail!(TransactionErrors::Chargeback(ChargebackError {
-            tx: tx_detail.tx
-        },))
+        //close the query channel so that the handles don't wait for an engine that has stopped, and the audit channel
+        //so that its consumer knows that every record is sent
+        self.queries = None;
+        self.query_handle = None;
+        self.audit_sender = None;
+        self.stats.memory = self.memory_usage();
+        std::mem::take(&mut self.stats)
     }
 
-    fn output(&self) {
-        let writer = BufWriter::new(std::io::stdout());
-        let mut wtr = csv::Writer::from_writer(writer);
-        self.accounts.values().for_each(|account| {
-            if let Err(e) = wtr.serialize(account.clone()) {
-                tracing::error!("Fail to write: {e}");
+    //apply the deferred transactions whose client has a token again
+    #[cfg(feature = "runtime")]
+    fn release_deferred(&mut self) {
+        let Some(limiter) = &mut self.limiter else {
+            return;
+        };
+        for transaction in limiter.release(Instant::now()) {
+            self.apply(transaction);
+        }
+    }
+
+    //process a transaction received by run unless the rate limit defers or rejects it
+    #[cfg(feature = "runtime")]
+    fn handle(&mut self, transaction: Transaction) {
+        let Some(limiter) = &mut self.limiter else {
+            self.apply(transaction);
+            return;
+        };
+        //the deferred transactions of the client go first
+        let now = Instant::now();
+        let released = limiter.release(now);
+        let admission = limiter.admit(transaction, now);
+        for transaction in released {
+            self.apply(transaction);
+        }
+        match admission {
+            Admission::Admitted(transaction) => self.apply(transaction),
+            Admission::Deferred => {}
+            Admission::Rejected(transaction, e) => {
+                let client = transaction.detail().map(|t| t.client);
+                let tx = transaction.detail().map(|t| t.tx);
+                tracing::error!(client, tx, "Fail to admit: {e}");
+                self.count(transaction.transaction_type(), false);
             }
-        });
+        }
     }
 
-    pub async fn run(&mut self) {
-        while let Some(transaction) = self.rx.recv().await {
+    //count a transaction received by run in the stats
+    #[cfg(feature = "runtime")]
+    fn count(&mut self, transaction_type: Option<TransactionType>, accepted: bool) {
+        self.stats.processed += 1;
+        if !accepted {
+            self.stats.rejected += 1;
+            if let Some(budget) = &self.error_budget {
+                budget.record_error();
+            }
+            if let Some(transaction_type) = transaction_type {
+                *self
+                    .stats
+                    .rejected_by_type
+                    .entry(transaction_type)
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    //apply a transaction received by run, and flush or archive the accounts when it is time to
+    #[cfg(feature = "runtime")]
+    fn apply(&mut self, transaction: Transaction) {
+        if transaction == Transaction::Flush {
+            self.flush();
+            return;
+        }
+        //control records are not counted as transactions
+        if matches!(
+            transaction,
+            Transaction::Checkpoint(_) | Transaction::Rollback(_) | Transaction::AssertBalance(_)
+        ) {
             self.process_transaction(transaction);
+            return;
+        }
+        //the transactions of a previous run are not counted, so that re-running its file doesn't change the counts
+        if self.already_applied(&transaction) {
+            self.stats.skipped += 1;
+            return;
+        }
+        let transaction_type = transaction.transaction_type();
+        let applying = Instant::now();
+        let outcome = self.process(transaction);
+        self.stats.apply.record(applying.elapsed());
+        //an exact replay ignored by the duplicate policy is counted like the transactions of a previous run
+        if matches!(outcome, Ok(TransactionOutcome::Skipped)) {
+            self.stats.skipped += 1;
+            return;
+        }
+        self.count(transaction_type, outcome.is_ok());
+        if let Some(every) = self.config.archive_every {
+            self.since_archive += 1;
+            if self.since_archive >= every {
+                self.archive();
+            }
         }
+        if self.config.max_memory.is_some() {
+            self.since_memory_check += 1;
+            if self.since_memory_check >= MEMORY_CHECK_EVERY {
+                self.check_memory();
+            }
+        }
+        if self.config.incremental {
+            self.since_flush += 1;
+            if self
+                .config
+                .flush_every
+                .is_some_and(|n| self.since_flush >= n)
+            {
+                self.flush_changed();
+            }
+        }
+    }
+}
+
+//wait until the instant, or forever if there is none
+#[cfg(feature = "runtime")]
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
 
-        self.output();
+#[cfg(feature = "runtime")]
+async fn recv_query(queries: &mut Option<Receiver<AccountQuery>>) -> Option<AccountQuery> {
+    match queries {
+        Some(queries) => queries.recv().await,
+        None => std::future::pending().await,
     }
 }
 
+//check_account takes every field of an account
 #[cfg(test)]
 #[path = "transaction_engine_test.rs"]
+#[allow(clippy::too_many_arguments)]
 mod transaction_engine_test;
@@ -1,14 +1,74 @@
 #[cfg(test)]
 mod tests {
-    use crate::models::Transaction::{ChargeBack, Deposit, Dispute, Resolve, Withdrawal};
-    use crate::models::{TranactionState, TransactionDetail};
-    use crate::TransactionEngine;
+    use crate::models::Transaction::{
+        Authorize, Capture, ChargeBack, Checkpoint, Deposit, Dispute, Refund, Resolve, Rollback,
+        Unknown, Withdrawal,
+    };
+    use crate::models::{
+        BalanceAssertion, RawRecord, TranactionState, Transaction, TransactionDetail,
+        TransactionType, TxId, MAX_AMOUNT,
+    };
+    use crate::tranasction::accrual::{Accrual, AccruedAmount};
+    use crate::tranasction::applied::AppliedId;
+    use crate::tranasction::audit::AuditEvent;
+    use crate::tranasction::book::{Book, Context};
+    use crate::tranasction::clock::ManualClock;
+    use crate::tranasction::config::{
+        AccountFilter, ConfigChange, DuplicatePolicy, EngineConfig, LockPolicy,
+    };
+    use crate::tranasction::errors::{TransactionErrors, WithdrawalError};
+    use crate::tranasction::handler::AccountHandle;
+    use crate::tranasction::ledger::SubLedger;
+    use crate::tranasction::profile::{ClientProfile, ClientProfiles};
+    use crate::tranasction::quarantine::QuarantineRules;
+    #[cfg(feature = "runtime")]
+    use crate::tranasction::transaction_engine::write_account;
+    use crate::tranasction::transaction_engine::{
+        OpenDispute, TransactionEngine, TransactionKind, TransactionOutcome,
+    };
+    use crate::tranasction::validation::{ClientRange, ValidationError, ValidationRules};
     use assert_approx_eq::assert_approx_eq;
+    use smol_str::SmolStr;
+    #[cfg(feature = "runtime")]
     use tokio::sync::mpsc;
 
+    //run the transactions directly on the default ledger to check the errors
+    macro_rules! process_on_default_ledger {
+        ($($name:ident),*) => {
+            impl TransactionEngine {
+                $(fn $name(&mut self, tx_detail: TransactionDetail) -> anyhow::Result<()> {
+                    let book = self.books.get_mut("").unwrap();
+                    let mut ctx = Context {
+                        config: &self.config,
+                        journal: &mut self.journal,
+                        now: self.now,
+                        audit: None,
+                    };
+                    book.$name(&mut ctx, tx_detail)?;
+                    Ok(())
+                })*
+
+                fn default_book_mut(&mut self) -> &mut Book {
+                    self.books.get_mut("").unwrap()
+                }
+            }
+        };
+    }
+
+    process_on_default_ledger!(
+        process_deposit,
+        process_withdrawal,
+        process_dispute,
+        process_resolve,
+        process_chargeback
+    );
+
     fn get_transaction_engine() -> TransactionEngine {
-        let (_, rx) = mpsc::channel(10);
-        TransactionEngine::new(rx)
+        get_transaction_engine_with_config(EngineConfig::default())
+    }
+
+    fn get_transaction_engine_with_config(config: EngineConfig) -> TransactionEngine {
+        TransactionEngine::with_config(config)
     }
 
     fn check_account(
@@ -21,20 +81,24 @@ mod tests {
         withdraws: usize,
         locked: bool,
     ) {
-        let account = engine.accounts.get(&account_id).unwrap();
+        let account = engine.default_book().accounts.get(&account_id).unwrap();
         assert_approx_eq!(account.available, available);
         assert_approx_eq!(account.total, total);
         assert_approx_eq!(account.held, held);
         assert_eq!(account.locked, locked);
-        assert_eq!(engine.deposit_transactions.len(), deposits);
-        assert_eq!(engine.withdrawal_transactions.len(), withdraws);
+        assert_eq!(engine.default_book().deposit_transactions.len(), deposits);
+        assert_eq!(
+            engine.default_book().withdrawal_transactions.len(),
+            withdraws
+        );
     }
 
-    fn check_transaction(engine: &TransactionEngine, tx: u32, state: TranactionState) {
+    fn check_transaction(engine: &TransactionEngine, tx: TxId, state: TranactionState) {
         let transaction = engine
+            .default_book()
             .deposit_transactions
             .get(&tx)
-            .or_else(|| engine.withdrawal_transactions.get(&tx))
+            .or_else(|| engine.default_book().withdrawal_transactions.get(&tx))
             .unwrap();
 
         assert_eq!(transaction.state, state);
@@ -49,14 +113,14 @@ mod tests {
             format!("{}", engine.process_deposit(tx).unwrap_err()),
             "Deposit error for tx 2"
         );
-        assert!(engine.accounts.is_empty(),);
-        assert!(engine.deposit_transactions.is_empty(),);
-        assert!(engine.withdrawal_transactions.is_empty(),);
+        assert!(engine.default_book().accounts.is_empty(),);
+        assert!(engine.default_book().deposit_transactions.is_empty(),);
+        assert!(engine.default_book().withdrawal_transactions.is_empty(),);
 
         //a valid transaction for client 1
         let tx = TransactionDetail::new(1, 2, Some(1.1111));
         let _ = engine.process_deposit(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //Dup transaction id
@@ -69,7 +133,7 @@ mod tests {
         //a valid transaction for client 1
         let tx = TransactionDetail::new(1, 3, Some(1.8889));
         let _ = engine.process_deposit(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 3.0, 0_f64, 3.0, 2, 0, false);
 
         //an invalid withdraw
@@ -78,12 +142,12 @@ mod tests {
             format!("{}", engine.process_withdrawal(tx).unwrap_err()),
             "Withdraw error for tx 4"
         );
-        assert!(engine.withdrawal_transactions.is_empty(),);
+        assert!(engine.default_book().withdrawal_transactions.is_empty(),);
 
         //a valid withdraw
         let tx = TransactionDetail::new(1, 4, Some(1.05));
         let _ = engine.process_withdrawal(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.95, 0_f64, 1.95, 2, 1, false);
 
         //an invalid withdraw with dup transaction id
@@ -105,7 +169,7 @@ mod tests {
         //Withdraw everything
         let tx = TransactionDetail::new(1, 5, Some(1.95));
         let _ = engine.process_withdrawal(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 2, 2, false);
     }
 
@@ -115,19 +179,19 @@ mod tests {
         //a deposit for client 1
         let tx = Deposit(TransactionDetail::new(1, 1, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //a deposit for client 2
         let tx = Deposit(TransactionDetail::new(2, 2, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
 
         //a deposit for client 3
         let tx = Deposit(TransactionDetail::new(3, 3, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 3);
+        assert_eq!(engine.default_book().accounts.len(), 3);
         check_account(&engine, 3, 1.1111, 0_f64, 1.1111, 3, 0, false);
 
         //a failed withdraw for client 4
@@ -140,19 +204,19 @@ mod tests {
         //a withdraw for client 3
         let tx = Withdrawal(TransactionDetail::new(3, 5, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 4);
+        assert_eq!(engine.default_book().accounts.len(), 4);
         check_account(&engine, 3, 0_f64, 0_f64, 0_f64, 3, 1, false);
 
         //a withdraw for client 2
         let tx = Withdrawal(TransactionDetail::new(2, 6, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 4);
+        assert_eq!(engine.default_book().accounts.len(), 4);
         check_account(&engine, 2, 0_f64, 0_f64, 0_f64, 3, 2, false);
 
         //a withdraw for client 1
         let tx = Withdrawal(TransactionDetail::new(1, 7, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 4);
+        assert_eq!(engine.default_book().accounts.len(), 4);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 3, 3, false);
     }
 
@@ -162,13 +226,13 @@ mod tests {
         //a deposit for client 1
         let tx = Deposit(TransactionDetail::new(1, 1, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //a deposit for client 2
         let tx = Deposit(TransactionDetail::new(2, 2, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
 
         //invalid dispute as transaction doesn't exist
@@ -188,7 +252,7 @@ mod tests {
         //valid dispute for client 1
         let tx = Dispute(TransactionDetail::new(1, 1, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 1.1111, 1.1111, 2, 0, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
         check_transaction(&engine, 1, TranactionState::Dispute);
@@ -217,7 +281,7 @@ mod tests {
         //valid resolve for client 1
         let tx = Resolve(TransactionDetail::new(1, 1, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 2, 0, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
         check_transaction(&engine, 1, TranactionState::Resolve);
@@ -236,19 +300,19 @@ mod tests {
         //a deposit for client 1
         let tx = Deposit(TransactionDetail::new(1, 1, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //a deposit for client 2
         let tx = Deposit(TransactionDetail::new(2, 2, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
 
         //a withdraw for client 1
         let tx = Withdrawal(TransactionDetail::new(1, 3, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 2, 1, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
 
@@ -269,7 +333,7 @@ mod tests {
         //valid dispute for client 1
         let tx = Dispute(TransactionDetail::new(1, 3, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 1.1111, 1.1111, 2, 1, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
         check_transaction(&engine, 3, TranactionState::Dispute);
@@ -291,7 +355,7 @@ mod tests {
         //valid resolve for client 1
         let tx = Resolve(TransactionDetail::new(1, 3, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 2, 1, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
         check_transaction(&engine, 3, TranactionState::Resolve);
@@ -310,13 +374,13 @@ mod tests {
         //a deposit for client 1
         let tx = Deposit(TransactionDetail::new(1, 1, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //a deposit for client 2
         let tx = Deposit(TransactionDetail::new(2, 2, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
 
         //invalid dispute as transaction doesn't exist
@@ -336,7 +400,7 @@ mod tests {
         //valid dispute for client 1
         let tx = Dispute(TransactionDetail::new(1, 1, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 1.1111, 1.1111, 2, 0, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
         check_transaction(&engine, 1, TranactionState::Dispute);
@@ -365,7 +429,7 @@ mod tests {
         //valid chargeback for client 1
         let tx = ChargeBack(TransactionDetail::new(1, 1, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 2, 0, true);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
         check_transaction(&engine, 1, TranactionState::ChargeBack);
@@ -384,19 +448,19 @@ mod tests {
         //a deposit for client 1
         let tx = Deposit(TransactionDetail::new(1, 1, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.default_book().accounts.len(), 1);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 1, 0, false);
 
         //a deposit for client 2
         let tx = Deposit(TransactionDetail::new(2, 2, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 0, false);
 
         //a withdraw for client 1
         let tx = Withdrawal(TransactionDetail::new(1, 3, Some(1.1111)));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 2, 1, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
 
@@ -417,7 +481,7 @@ mod tests {
         //valid dispute for client 1
         let tx = Dispute(TransactionDetail::new(1, 3, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 0_f64, 1.1111, 1.1111, 2, 1, false);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
         check_transaction(&engine, 3, TranactionState::Dispute);
@@ -439,7 +503,7 @@ mod tests {
         //valid chargeback for client 1
         let tx = ChargeBack(TransactionDetail::new(1, 3, None));
         let _ = engine.process_transaction(tx);
-        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.default_book().accounts.len(), 2);
         check_account(&engine, 1, 1.1111, 0_f64, 1.1111, 2, 1, true);
         check_account(&engine, 2, 1.1111, 0_f64, 1.1111, 2, 1, false);
         check_transaction(&engine, 3, TranactionState::ChargeBack);
@@ -451,4 +515,1577 @@ mod tests {
             "Account 1 is locked"
         );
     }
+
+    #[test]
+    fn test_redispute() {
+        //re-dispute is not allowed by default
+        let mut engine = get_transaction_engine();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        engine.process_transaction(Resolve(TransactionDetail::new(1, 1, None)));
+        let tx = TransactionDetail::new(1, 1, None);
+        assert_eq!(
+            format!("{}", engine.process_dispute(tx).unwrap_err()),
+            "Dispute error for tx 1"
+        );
+        check_account(&engine, 1, 1.0, 0_f64, 1.0, 1, 0, false);
+
+        //allow 1 re-dispute
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            max_redisputes: 1,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        engine.process_transaction(Resolve(TransactionDetail::new(1, 1, None)));
+        check_transaction(&engine, 1, TranactionState::Resolve);
+
+        //second dispute cycle
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        check_account(&engine, 1, 0_f64, 1.0, 1.0, 1, 0, false);
+        check_transaction(&engine, 1, TranactionState::Dispute);
+        engine.process_transaction(Resolve(TransactionDetail::new(1, 1, None)));
+        check_account(&engine, 1, 1.0, 0_f64, 1.0, 1, 0, false);
+
+        //max number of re-disputes reached
+        let tx = TransactionDetail::new(1, 1, None);
+        assert_eq!(
+            format!("{}", engine.process_dispute(tx).unwrap_err()),
+            "Dispute error for tx 1"
+        );
+
+        //a transaction that is charged back can't be disputed again
+        engine.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(1.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None)));
+        engine.process_transaction(ChargeBack(TransactionDetail::new(1, 2, None)));
+        check_transaction(&engine, 2, TranactionState::ChargeBack);
+        check_account(&engine, 1, 1.0, 0_f64, 1.0, 1, 1, true);
+    }
+
+    fn with_timestamp(tx_detail: TransactionDetail, timestamp: u64) -> TransactionDetail {
+        TransactionDetail {
+            timestamp: Some(timestamp),
+            ..tx_detail
+        }
+    }
+
+    #[test]
+    fn test_dispute_ttl() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            dispute_ttl: Some(100),
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 1, Some(1.0)),
+            0,
+        )));
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 6, Some(0.5)),
+            5,
+        )));
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 2, Some(2.0)),
+            10,
+        )));
+        engine.process_transaction(Withdrawal(with_timestamp(
+            TransactionDetail::new(1, 3, Some(0.5)),
+            20,
+        )));
+
+        //dispute all 3 transactions at different time
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            30,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 2, None),
+            40,
+        )));
+        //dispute without timestamp is opened at the latest time seen
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 3, None)));
+        check_account(&engine, 1, 0_f64, 3.5, 3.5, 3, 1, false);
+
+        //tx 2 is resolved before it expires
+        engine.process_transaction(Resolve(with_timestamp(
+            TransactionDetail::new(1, 2, None),
+            50,
+        )));
+        check_account(&engine, 1, 2.0, 1.5, 3.5, 3, 1, false);
+
+        //not expired yet
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(2, 4, Some(1.0)),
+            129,
+        )));
+        check_transaction(&engine, 1, TranactionState::Dispute);
+
+        //tx 1 and tx 3 expire
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(2, 5, Some(1.0)),
+            140,
+        )));
+        check_transaction(&engine, 1, TranactionState::Resolve);
+        check_transaction(&engine, 2, TranactionState::Resolve);
+        check_transaction(&engine, 3, TranactionState::Resolve);
+        check_account(&engine, 1, 3.0, 0_f64, 3.0, 5, 1, false);
+        assert!(engine.default_book().dispute_deadlines.is_empty());
+
+        //timestamps going backward don't move the clock
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            100,
+        )));
+        check_transaction(&engine, 1, TranactionState::Resolve);
+    }
+
+    #[test]
+    fn test_held_accrual() {
+        const DAY: u64 = 86_400;
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            accrual: Some(Accrual { daily_rate: 0.01 }),
+            dispute_ttl: Some(10 * DAY),
+            ..Default::default()
+        });
+        for client in 1..=3 {
+            engine.process_transaction(Deposit(with_timestamp(
+                TransactionDetail::new(client, client as TxId, Some(100.0)),
+                0,
+            )));
+        }
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            DAY,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(2, 2, None),
+            0,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(3, 3, None),
+            0,
+        )));
+
+        //2 full days of interest are credited when the dispute is resolved
+        engine.process_transaction(Resolve(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            3 * DAY + 100,
+        )));
+        check_account(&engine, 1, 102.0, 0_f64, 102.0, 3, 0, false);
+
+        //and when it is charged back
+        engine.process_transaction(ChargeBack(with_timestamp(
+            TransactionDetail::new(2, 2, None),
+            5 * DAY,
+        )));
+        check_account(&engine, 2, 5.0, 0_f64, 5.0, 3, 0, true);
+
+        //an expired dispute accrues until its deadline
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(4, 4, Some(1.0)),
+            20 * DAY,
+        )));
+        check_account(&engine, 3, 110.0, 0_f64, 110.0, 4, 0, false);
+
+        let accruals = engine.accruals();
+        assert_eq!(
+            accruals,
+            vec![
+                AccruedAmount {
+                    client: 1,
+                    accrued: 2.0,
+                    ledger: "".into(),
+                },
+                AccruedAmount {
+                    client: 2,
+                    accrued: 5.0,
+                    ledger: "".into(),
+                },
+                AccruedAmount {
+                    client: 3,
+                    accrued: 10.0,
+                    ledger: "".into(),
+                },
+            ]
+        );
+        assert_eq!(engine.snapshot().accruals.len(), 3);
+
+        //a negative rate debits a penalty
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            accrual: Some(Accrual { daily_rate: -0.01 }),
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 1, Some(100.0)),
+            0,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            0,
+        )));
+        engine.process_transaction(Resolve(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            2 * DAY,
+        )));
+        check_account(&engine, 1, 98.0, 0_f64, 98.0, 1, 0, false);
+    }
+
+    #[test]
+    fn test_settle_interest() {
+        const DAY: u64 = 86_400;
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            accrual: Some(Accrual { daily_rate: 0.01 }),
+            max_redisputes: 1,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 1, Some(100.0)),
+            0,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            100,
+        )));
+
+        //the full days are credited by the interest run while the dispute is open
+        engine.settle_interest(2 * DAY + 50);
+        check_account(&engine, 1, 1.0, 100.0, 101.0, 1, 0, false);
+        assert_eq!(engine.accruals()[0].accrued, 1.0);
+        //a second run on the same day has nothing left to settle
+        engine.settle_interest(2 * DAY + 60);
+        check_account(&engine, 1, 1.0, 100.0, 101.0, 1, 0, false);
+
+        //the days since the last run are settled when the dispute is decided
+        engine.process_transaction(Resolve(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            4 * DAY + 100,
+        )));
+        check_account(&engine, 1, 104.0, 0_f64, 104.0, 1, 0, false);
+        assert_eq!(engine.accruals()[0].accrued, 4.0);
+
+        //a dispute opened again accrues from its new opening time
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 1, None),
+            5 * DAY,
+        )));
+        assert_eq!(
+            engine.default_book().deposit_transactions[&1].accrued_until,
+            None
+        );
+    }
+
+    //lock client 1 with a chargeback of a 1.0 deposit, leaving 2.0 available
+    fn get_locked_engine(lock_policy: LockPolicy) -> TransactionEngine {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            lock_policy,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(2.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        engine.process_transaction(ChargeBack(TransactionDetail::new(1, 1, None)));
+        check_account(&engine, 1, 2.0, 0_f64, 2.0, 2, 0, true);
+        engine
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let mut engine = get_locked_engine(LockPolicy::BlockAll);
+        let tx = TransactionDetail::new(1, 3, Some(1.0));
+        assert!(engine.process_deposit(tx.clone()).is_err());
+        engine.reconfigure(ConfigChange::LockPolicy(LockPolicy::AllowDeposits));
+        engine.process_deposit(tx.clone()).unwrap();
+        check_account(&engine, 1, 3.0, 0_f64, 3.0, 3, 0, true);
+
+        //the replay is rejected until the duplicates are skipped
+        assert!(engine.process_deposit(tx.clone()).is_err());
+        engine.reconfigure(ConfigChange::DuplicatePolicy(
+            DuplicatePolicy::IdempotentSkip,
+        ));
+        engine.process_deposit(tx).unwrap();
+        check_account(&engine, 1, 3.0, 0_f64, 3.0, 3, 0, true);
+    }
+
+    #[test]
+    fn test_lock_policy() {
+        //block all
+        let mut engine = get_locked_engine(LockPolicy::BlockAll);
+        let tx = TransactionDetail::new(1, 3, Some(1.0));
+        assert_eq!(
+            format!("{}", engine.process_deposit(tx).unwrap_err()),
+            "Account 1 is locked"
+        );
+
+        //allow deposits only
+        let mut engine = get_locked_engine(LockPolicy::AllowDeposits);
+        engine
+            .process_deposit(TransactionDetail::new(1, 3, Some(1.0)))
+            .unwrap();
+        check_account(&engine, 1, 3.0, 0_f64, 3.0, 3, 0, true);
+        let tx = TransactionDetail::new(1, 3, None);
+        assert_eq!(
+            format!("{}", engine.process_dispute(tx).unwrap_err()),
+            "Account 1 is locked"
+        );
+        let tx = TransactionDetail::new(1, 4, Some(1.0));
+        assert_eq!(
+            format!("{}", engine.process_withdrawal(tx).unwrap_err()),
+            "Account 1 is locked"
+        );
+
+        //allow credits and disputes
+        let mut engine = get_locked_engine(LockPolicy::AllowCreditsAndDisputes);
+        engine
+            .process_deposit(TransactionDetail::new(1, 3, Some(1.0)))
+            .unwrap();
+        engine
+            .process_dispute(TransactionDetail::new(1, 3, None))
+            .unwrap();
+        check_account(&engine, 1, 2.0, 1.0, 3.0, 3, 0, true);
+        engine
+            .process_resolve(TransactionDetail::new(1, 3, None))
+            .unwrap();
+        check_account(&engine, 1, 3.0, 0_f64, 3.0, 3, 0, true);
+        let tx = TransactionDetail::new(1, 4, Some(1.0));
+        assert_eq!(
+            format!("{}", engine.process_withdrawal(tx).unwrap_err()),
+            "Account 1 is locked"
+        );
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut engine = get_transaction_engine();
+        engine
+            .process_deposit(TransactionDetail::new(2, 1, Some(5.0)))
+            .unwrap();
+        engine
+            .process_deposit(TransactionDetail::new(1, 2, Some(3.0)))
+            .unwrap();
+        engine
+            .process_withdrawal(TransactionDetail::new(1, 4, Some(1.0)))
+            .unwrap();
+        engine
+            .process_deposit(TransactionDetail::new(1, 3, Some(1.0)))
+            .unwrap();
+        //a resolved dispute is not reported
+        engine
+            .process_dispute(TransactionDetail::new(1, 3, None))
+            .unwrap();
+        engine
+            .process_resolve(TransactionDetail::new(1, 3, None))
+            .unwrap();
+        for (client, tx) in [(2, 1), (1, 2), (1, 4)] {
+            engine
+                .process_dispute(TransactionDetail::new(client, tx, None))
+                .unwrap();
+        }
+        assert_eq!(
+            engine.open_disputes(),
+            vec![
+                OpenDispute {
+                    client: 1,
+                    tx: 2,
+                    amount: 3.0,
+                    r#type: TransactionKind::Deposit,
+                    ledger: Default::default()
+                },
+                OpenDispute {
+                    client: 1,
+                    tx: 4,
+                    amount: 1.0,
+                    r#type: TransactionKind::Withdrawal,
+                    ledger: Default::default()
+                },
+                OpenDispute {
+                    client: 2,
+                    tx: 1,
+                    amount: 5.0,
+                    r#type: TransactionKind::Deposit,
+                    ledger: Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changed_accounts() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            incremental: true,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(TransactionDetail::new(2, 1, Some(1.0))));
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(1.0))));
+        //rejected transactions don't change the account
+        engine.process_transaction(Withdrawal(TransactionDetail::new(3, 3, Some(1.0))));
+        let clients = engine
+            .take_changed_accounts()
+            .iter()
+            .map(|(_, account)| account.client)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![1, 2]);
+        assert!(engine.take_changed_accounts().is_empty());
+
+        engine.process_transaction(Withdrawal(TransactionDetail::new(2, 3, Some(0.5))));
+        let changed = engine.take_changed_accounts();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1.client, 2);
+        assert_approx_eq!(changed[0].1.available, 0.5);
+    }
+
+    //writer whose output can still be read once it is moved into the engine
+    #[cfg(feature = "runtime")]
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "runtime")]
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_incremental_ledger_after_flush() {
+        let mut eur = TransactionDetail::new(2, 2, Some(3.0));
+        eur.ledger = Some("eur".into());
+        for ledger_column in [false, true] {
+            let mut engine = get_transaction_engine_with_config(EngineConfig {
+                incremental: true,
+                ledger_column,
+                ..Default::default()
+            });
+            let output = SharedBuffer::default();
+            engine.incremental_to(output.clone());
+            engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+            engine.flush();
+            engine.process_transaction(Deposit(eur.clone()));
+            engine.flush();
+
+            //the account of the ledger seen after the first flush is still written, with the columns of the header
+            let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+            let expected = if ledger_column {
+                "client,available,held,total,locked,ledger\n1,1.0,0.0,1.0,false,\n2,3.0,0.0,3.0,false,eur\n"
+            } else {
+                "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,3.0,0.0,3.0,false\n"
+            };
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    #[tokio::test]
+    async fn test_accounts_handle() {
+        let (tx, rx) = mpsc::channel(10);
+        let mut engine = get_transaction_engine();
+        let accounts = engine.accounts_handle();
+        //a later handle shares the channel, the first one keeps working
+        let other = engine.accounts_handle();
+        let engine_handle = tokio::spawn(async move { engine.run(rx).await });
+
+        for transaction in [
+            Deposit(TransactionDetail::new(1, 1, Some(2.0))),
+            Deposit(TransactionDetail::new(2, 2, Some(1.0))),
+            Dispute(TransactionDetail::new(2, 2, None)),
+            ChargeBack(TransactionDetail::new(2, 2, None)),
+        ] {
+            tx.send(vec![transaction]).await.unwrap();
+        }
+        //the queries can be answered before the queued transactions are processed
+        let locked = loop {
+            let locked = accounts.locked().await;
+            if !locked.is_empty() {
+                break locked;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(locked.len(), 1);
+        assert_eq!(locked[0].client, 2);
+        assert_approx_eq!(accounts.account(1).await.unwrap().available, 2.0);
+        assert!(accounts.account(3).await.is_none());
+        assert_approx_eq!(other.account(1).await.unwrap().available, 2.0);
+
+        //the engine stops once the transaction channel is closed even if there are handles
+        drop(tx);
+        engine_handle.await.unwrap();
+        assert!(accounts.account(1).await.is_none());
+        assert!(other.account(1).await.is_none());
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            journal: true,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(2.0))));
+        engine.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(0.5))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None)));
+        engine.process_transaction(ChargeBack(TransactionDetail::new(1, 2, None)));
+        //rejected transactions are not posted
+        engine.process_transaction(Withdrawal(TransactionDetail::new(1, 3, Some(5.0))));
+        check_account(&engine, 1, 2.0, 0_f64, 2.0, 1, 1, true);
+
+        let postings = engine
+            .journal
+            .postings()
+            .iter()
+            .map(|p| (p.tx, p.credit, p.debit, p.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            postings,
+            vec![
+                (1, SubLedger::External, SubLedger::Available, 2.0),
+                (2, SubLedger::Available, SubLedger::External, 0.5),
+                (2, SubLedger::External, SubLedger::Held, 0.5),
+                (2, SubLedger::Held, SubLedger::Available, 0.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit_trail() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            audit: true,
+            dispute_ttl: Some(100),
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 1, Some(2.0)),
+            0,
+        )));
+        engine.process_transaction(Withdrawal(with_timestamp(
+            TransactionDetail::new(1, 2, Some(0.5)),
+            10,
+        )));
+        engine.process_transaction(Dispute(with_timestamp(
+            TransactionDetail::new(1, 2, None),
+            20,
+        )));
+        //rejected transactions are not recorded
+        engine.process_transaction(Withdrawal(with_timestamp(
+            TransactionDetail::new(1, 3, Some(5.0)),
+            30,
+        )));
+        //the dispute expires before the deposit is applied
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 4, Some(1.0)),
+            200,
+        )));
+
+        let records = engine
+            .take_audit_records()
+            .into_iter()
+            .map(|r| {
+                (
+                    r.event,
+                    r.tx,
+                    (r.available_before, r.held_before, r.total_before),
+                    (r.available_after, r.held_after, r.total_after),
+                    r.state,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    AuditEvent::Deposit,
+                    1,
+                    (0.0, 0.0, 0.0),
+                    (2.0, 0.0, 2.0),
+                    Some(TranactionState::Normal)
+                ),
+                (
+                    AuditEvent::Withdrawal,
+                    2,
+                    (2.0, 0.0, 2.0),
+                    (1.5, 0.0, 1.5),
+                    Some(TranactionState::Normal)
+                ),
+                (
+                    AuditEvent::Dispute,
+                    2,
+                    (1.5, 0.0, 1.5),
+                    (1.5, 0.5, 2.0),
+                    Some(TranactionState::Dispute)
+                ),
+                (
+                    AuditEvent::DisputeExpired,
+                    2,
+                    (1.5, 0.5, 2.0),
+                    (1.5, 0.0, 1.5),
+                    Some(TranactionState::Resolve)
+                ),
+                (
+                    AuditEvent::Deposit,
+                    4,
+                    (1.5, 0.0, 1.5),
+                    (2.5, 0.0, 2.5),
+                    Some(TranactionState::Normal)
+                ),
+            ]
+        );
+        assert!(engine.take_audit_records().is_empty());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            journal: true,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(100.0))));
+        engine.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(30.0))));
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 3, Some(10.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 3, None)));
+
+        //the fat-finger deposit leaves a negative balance once the withdrawal is taken into account
+        assert!(engine.reverse("", 1).is_ok());
+        check_account(&engine, 1, -30.0, 10.0, -20.0, 2, 1, false);
+        check_transaction(&engine, 1, TranactionState::Reversed);
+        assert!(engine.reverse("", 2).is_ok());
+        check_account(&engine, 1, 0.0, 10.0, 10.0, 2, 1, false);
+        let last = engine.journal.postings().last().unwrap();
+        assert_eq!(
+            (last.tx, last.credit, last.debit, last.amount),
+            (2, SubLedger::External, SubLedger::Available, 30.0)
+        );
+
+        //a transaction is only reversed once, and a disputed or unknown one can't be reversed
+        assert!(engine.reverse("", 1).is_err());
+        assert!(engine.reverse("", 3).is_err());
+        assert!(engine.reverse("", 9).is_err());
+        assert!(engine.reverse("acme", 1).is_err());
+        //a reversed transaction can't be disputed
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 0.0, 10.0, 10.0, 2, 1, false);
+    }
+
+    #[test]
+    fn test_authorize_capture() {
+        let mut engine = get_transaction_engine();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0))));
+        engine.process_transaction(Authorize(TransactionDetail::new(1, 1, Some(2.0))));
+        engine.process_transaction(Authorize(TransactionDetail::new(1, 2, Some(1.0))));
+        check_account(&engine, 1, 2.0, 3.0, 5.0, 1, 0, false);
+
+        //not enough available funds and duplicate authorization
+        assert!(!engine.process_transaction(Authorize(TransactionDetail::new(1, 3, Some(2.5)))));
+        assert!(!engine.process_transaction(Authorize(TransactionDetail::new(1, 2, Some(1.0)))));
+
+        //full capture
+        assert!(engine.process_transaction(Capture(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 2.0, 1.0, 3.0, 1, 0, false);
+
+        //capture for more than authorized, by another client, then a partial capture
+        assert!(!engine.process_transaction(Capture(TransactionDetail::new(1, 2, Some(1.5)))));
+        assert!(!engine.process_transaction(Capture(TransactionDetail::new(2, 2, None))));
+        assert!(engine.process_transaction(Capture(TransactionDetail::new(1, 2, Some(0.25)))));
+        check_account(&engine, 1, 2.75, 0_f64, 2.75, 1, 0, false);
+
+        //an authorization is only captured once
+        assert!(!engine.process_transaction(Capture(TransactionDetail::new(1, 2, None))));
+        assert_eq!(
+            engine.default_book().authorizations[&1].state,
+            TranactionState::Captured
+        );
+        assert_eq!(
+            engine.default_book().authorizations[&2].state,
+            TranactionState::Captured
+        );
+    }
+
+    #[test]
+    fn test_authorization_ttl() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            authorization_ttl: Some(100),
+            lock_policy: LockPolicy::AllowCreditsAndDisputes,
+            ..Default::default()
+        });
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(1, 1, Some(3.0)),
+            0,
+        )));
+        engine.process_transaction(Authorize(with_timestamp(
+            TransactionDetail::new(1, 1, Some(1.0)),
+            10,
+        )));
+        //authorization without timestamp is made at the latest time seen
+        engine.process_transaction(Authorize(TransactionDetail::new(1, 2, Some(1.0))));
+        check_account(&engine, 1, 1.0, 2.0, 3.0, 1, 0, false);
+
+        //tx 2 is captured before it expires, tx 1 expires
+        engine.process_transaction(Capture(with_timestamp(
+            TransactionDetail::new(1, 2, None),
+            105,
+        )));
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(2, 2, Some(1.0)),
+            110,
+        )));
+        assert_eq!(
+            engine.default_book().authorizations[&1].state,
+            TranactionState::Released
+        );
+        assert_eq!(
+            engine.default_book().authorizations[&2].state,
+            TranactionState::Captured
+        );
+        check_account(&engine, 1, 2.0, 0_f64, 2.0, 2, 0, false);
+        assert!(engine.default_book().authorization_deadlines.is_empty());
+
+        //a locked account can't authorize
+        engine
+            .default_book_mut()
+            .accounts
+            .get_mut(&1)
+            .unwrap()
+            .locked = true;
+        assert!(!engine.process_transaction(Authorize(TransactionDetail::new(1, 3, Some(1.0)))));
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut engine = get_transaction_engine();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(2.0))));
+        engine.process_transaction(Deposit(TransactionDetail::new(2, 3, Some(1.0))));
+
+        //partial refunds until the deposit is fully refunded
+        assert!(engine.process_transaction(Refund(TransactionDetail::new(1, 1, Some(0.3)))));
+        assert!(engine.process_transaction(Refund(TransactionDetail::new(1, 1, Some(0.7)))));
+        assert!(!engine.process_transaction(Refund(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 2.0, 0_f64, 2.0, 3, 0, false);
+
+        //unknown deposit, deposit of another client and more than deposited
+        assert!(!engine.process_transaction(Refund(TransactionDetail::new(1, 4, None))));
+        assert!(!engine.process_transaction(Refund(TransactionDetail::new(1, 3, None))));
+        assert!(!engine.process_transaction(Refund(TransactionDetail::new(1, 2, Some(2.5)))));
+
+        //disputed deposit can't be refunded until it is resolved
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None)));
+        assert!(!engine.process_transaction(Refund(TransactionDetail::new(1, 2, None))));
+        engine.process_transaction(Resolve(TransactionDetail::new(1, 2, None)));
+        assert!(engine.process_transaction(Refund(TransactionDetail::new(1, 2, None))));
+        check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 3, 0, false);
+        check_account(&engine, 2, 1.0, 0_f64, 1.0, 3, 0, false);
+    }
+
+    #[test]
+    fn test_refund_then_chargeback() {
+        let mut engine = get_transaction_engine();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(100.0))));
+        assert!(engine.process_transaction(Refund(TransactionDetail::new(1, 1, Some(40.0)))));
+        //only what is left of the deposit is held and charged back, the refunded part already left the account
+        assert!(engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 0_f64, 60.0, 60.0, 1, 0, false);
+        assert!(engine.process_transaction(ChargeBack(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 0_f64, 0_f64, 0_f64, 1, 0, true);
+
+        //a fully refunded deposit can't be disputed
+        engine.process_transaction(Deposit(TransactionDetail::new(2, 2, Some(5.0))));
+        engine.process_transaction(Refund(TransactionDetail::new(2, 2, None)));
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(2, 2, None))));
+    }
+
+    #[test]
+    fn test_validation_rules() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            validation: ValidationRules {
+                max_amount: Some(10.0),
+                allowed_types: Some(vec![TransactionType::Deposit, TransactionType::Withdrawal]),
+                ..Default::default()
+            },
+            record_rejects: true,
+            ..Default::default()
+        });
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(10.0)))));
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(10.5)))));
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 10.0, 0_f64, 10.0, 1, 0, false);
+
+        let rejects = engine
+            .rejects
+            .as_deref()
+            .unwrap()
+            .iter()
+            .map(|r| (r.tx, r.r#type, r.rule))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            vec![
+                (2, TransactionType::Deposit, "max_amount"),
+                (1, TransactionType::Dispute, "allowed_types"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_types() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            disabled_types: vec![TransactionType::Dispute, TransactionType::ChargeBack],
+            record_rejects: true,
+            ..Default::default()
+        });
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(10.0)))));
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        assert!(!engine.process_transaction(ChargeBack(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 10.0, 0_f64, 10.0, 1, 0, false);
+        check_transaction(&engine, 1, TranactionState::Normal);
+
+        let rejects = engine
+            .rejects
+            .as_deref()
+            .unwrap()
+            .iter()
+            .map(|r| (r.r#type, r.rule, r.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            vec![
+                (
+                    TransactionType::Dispute,
+                    "disabled",
+                    "Dispute transactions are disabled"
+                ),
+                (
+                    TransactionType::ChargeBack,
+                    "disabled",
+                    "ChargeBack transactions are disabled"
+                ),
+            ]
+        );
+    }
+
+    fn profiles() -> ClientProfiles {
+        [
+            ClientProfile {
+                client: 1,
+                name: "Alice".into(),
+                tier: "retail".into(),
+                country: "FR".into(),
+            },
+            ClientProfile {
+                client: 2,
+                name: "Bob".into(),
+                tier: "premium".into(),
+                country: "US".into(),
+            },
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_tier_rules() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            validation: ValidationRules {
+                max_amount: Some(1000.0),
+                tiers: [(
+                    "retail".into(),
+                    ValidationRules {
+                        max_amount: Some(100.0),
+                        ..Default::default()
+                    },
+                )]
+                .into(),
+                ..Default::default()
+            },
+            profiles: Some(profiles()),
+            ..Default::default()
+        });
+        //retail client
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(500.0)))));
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(100.0)))));
+        //premium client, the tier has no rules
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(2, 3, Some(500.0)))));
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(2, 4, Some(1500.0)))));
+        //client without a profile
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(3, 5, Some(500.0)))));
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_profile_columns() {
+        let profiles = profiles();
+        let accounts = [
+            crate::models::Account::new(1),
+            crate::models::Account::new(3),
+        ];
+        let report = |has_ledgers| {
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            for account in &accounts {
+                write_account(&mut wtr, "tenant-a", account, has_ledgers, Some(&profiles)).unwrap();
+            }
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        assert_eq!(
+            report(false),
+            "client,available,held,total,locked,name,tier,country\n\
+             1,0.0,0.0,0.0,false,Alice,retail,FR\n\
+             3,0.0,0.0,0.0,false,,,\n"
+        );
+        assert_eq!(
+            report(true).lines().next(),
+            Some("client,available,held,total,locked,name,tier,country,ledger")
+        );
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let mut engine = get_transaction_engine();
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(
+            1,
+            1,
+            Some(MAX_AMOUNT)
+        ))));
+        assert!(engine.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(1.0)))));
+        //the deposit would take the balance past the max, it is rejected and can't be disputed
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(1, 3, Some(2.0)))));
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 3, None))));
+        check_account(
+            &engine,
+            1,
+            MAX_AMOUNT - 1.0,
+            0_f64,
+            MAX_AMOUNT - 1.0,
+            1,
+            1,
+            false,
+        );
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 4, Some(1.0)))));
+        check_account(&engine, 1, MAX_AMOUNT, 0_f64, MAX_AMOUNT, 2, 1, false);
+
+        //disputing the withdrawal would hold the withdrawn funds on top of the max
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None))));
+        check_transaction(&engine, 2, TranactionState::Normal);
+    }
+
+    #[test]
+    fn test_builder_clock() {
+        let clock = ManualClock::new(0);
+        let mut engine = TransactionEngine::builder()
+            .config(EngineConfig {
+                dispute_ttl: Some(100),
+                ..Default::default()
+            })
+            .transaction_capacity(16)
+            .account_capacity(16)
+            .clock(clock.clone())
+            .build();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(1.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        check_transaction(&engine, 1, TranactionState::Dispute);
+
+        //the timestamps of the input don't move a manual clock
+        engine.process_transaction(Deposit(with_timestamp(
+            TransactionDetail::new(2, 2, Some(1.0)),
+            1000,
+        )));
+        check_transaction(&engine, 1, TranactionState::Dispute);
+
+        clock.advance(99);
+        engine.tick();
+        check_transaction(&engine, 1, TranactionState::Dispute);
+        clock.advance(1);
+        engine.tick();
+        check_transaction(&engine, 1, TranactionState::Resolve);
+        check_account(&engine, 1, 1.0, 0_f64, 1.0, 2, 0, false);
+    }
+
+    #[test]
+    fn test_builder_validator() {
+        let mut engine = TransactionEngine::builder()
+            .config(EngineConfig {
+                validation: ValidationRules {
+                    max_amount: Some(10.0),
+                    ..Default::default()
+                },
+                record_rejects: true,
+                ..Default::default()
+            })
+            .validator(|_, tx_detail: &TransactionDetail| {
+                if tx_detail.tx.is_multiple_of(2) {
+                    Ok(())
+                } else {
+                    Err(ValidationError::Custom {
+                        rule: "even_tx",
+                        reason: format!("tx {} is not even", tx_detail.tx),
+                    })
+                }
+            })
+            .build();
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(1.0)))));
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(1, 3, Some(1.0)))));
+        //the rules of the config are checked first
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(1, 5, Some(11.0)))));
+        check_account(&engine, 1, 1.0, 0_f64, 1.0, 1, 0, false);
+
+        let rejects = engine
+            .rejects
+            .as_deref()
+            .unwrap()
+            .iter()
+            .map(|r| (r.tx, r.rule, r.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            vec![
+                (3, "even_tx", "tx 3 is not even"),
+                (5, "max_amount", "amount 11 is above the maximum 10"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_handler() {
+        let mut engine = TransactionEngine::builder()
+            .config(EngineConfig {
+                journal: true,
+                audit: true,
+                ..Default::default()
+            })
+            .handler(
+                "Adjustment",
+                |tx_detail: &TransactionDetail, record: &RawRecord, account: &mut AccountHandle| {
+                    //the amount can also be given in points, a column the engine doesn't know
+                    let amount = match tx_detail.amount {
+                        Some(amount) => amount,
+                        None => {
+                            record
+                                .get("points")
+                                .ok_or_else(|| anyhow::anyhow!("missing amount"))?
+                                .parse::<f64>()?
+                                / 100.0
+                        }
+                    };
+                    account.post(SubLedger::External, SubLedger::Available, amount)?;
+                    if account.account().available < 0.0 {
+                        anyhow::bail!("negative balance");
+                    }
+                    Ok(())
+                },
+            )
+            .build();
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0)))));
+        assert!(engine.process_transaction(Unknown(
+            "adjustment".into(),
+            TransactionDetail::new(1, 2, Some(2.0)),
+            RawRecord::default()
+        )));
+        //the account is left untouched when the handler fails after a posting
+        assert!(!engine.process_transaction(Unknown(
+            "adjustment".into(),
+            TransactionDetail::new(1, 3, Some(-10.0)),
+            RawRecord::default()
+        )));
+        //no handler for the type
+        assert!(!engine.process_transaction(Unknown(
+            "bonus".into(),
+            TransactionDetail::new(1, 4, Some(1.0)),
+            RawRecord::default()
+        )));
+        let headers = ["type", "client", "tx", "amount", "Points"]
+            .into_iter()
+            .map(SmolStr::new)
+            .collect();
+        let fields = ["adjustment", "1", "5", "", "150"]
+            .into_iter()
+            .map(SmolStr::new)
+            .collect();
+        assert!(engine.process_transaction(Unknown(
+            "adjustment".into(),
+            TransactionDetail::new(1, 5, None),
+            RawRecord::new(Some(headers), fields)
+        )));
+        check_account(&engine, 1, 8.5, 0_f64, 8.5, 1, 0, false);
+
+        let postings = engine.journal.postings();
+        assert_eq!(postings.len(), 3);
+        assert_eq!(postings[1].tx, 2);
+        assert_eq!(postings[1].debit, SubLedger::Available);
+        let events = engine
+            .take_audit_records()
+            .iter()
+            .map(|r| r.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![AuditEvent::Deposit, AuditEvent::Custom, AuditEvent::Custom]
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut engine = TransactionEngine::with_config(EngineConfig {
+            journal: true,
+            checkpoint_records: true,
+            validation: ValidationRules {
+                max_amount: Some(100.0),
+                ..Default::default()
+            },
+            record_rejects: true,
+            ..Default::default()
+        });
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(10.0)))));
+        assert!(engine.process_transaction(Checkpoint("a".into())));
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(5.0)))));
+        assert!(engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        assert!(!engine.process_transaction(Deposit(TransactionDetail::new(2, 3, Some(500.0)))));
+        engine.checkpoint("b").unwrap();
+        assert!(engine.process_transaction(Withdrawal(TransactionDetail::new(1, 4, Some(1.0)))));
+        check_account(&engine, 1, 4.0, 10.0, 14.0, 2, 1, false);
+
+        assert!(engine.process_transaction(Rollback("a".into())));
+        check_account(&engine, 1, 10.0, 0_f64, 10.0, 1, 0, false);
+        check_transaction(&engine, 1, TranactionState::Normal);
+        assert_eq!(engine.journal.postings().len(), 1);
+        assert!(engine.rejects.as_deref().unwrap().is_empty());
+        //the checkpoints taken after the one rolled back to are dropped
+        assert!(!engine.process_transaction(Rollback("b".into())));
+
+        //the corrected tail is applied again with the same tx ids
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(6.0)))));
+        check_account(&engine, 1, 16.0, 0_f64, 16.0, 2, 0, false);
+        //the checkpoint is kept
+        engine.rollback("a").unwrap();
+        check_account(&engine, 1, 10.0, 0_f64, 10.0, 1, 0, false);
+        assert!(engine.rollback("c").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_limits() {
+        //the checkpoint and rollback rows are rejected unless they are allowed
+        let mut engine = get_transaction_engine();
+        assert!(!engine.process_transaction(Checkpoint("a".into())));
+        assert!(engine.checkpoints.is_empty());
+        engine.checkpoint("a").unwrap();
+        assert!(!engine.process_transaction(Rollback("a".into())));
+
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            checkpoint_records: true,
+            max_checkpoints: Some(2),
+            ..Default::default()
+        });
+        assert!(engine.process_transaction(Checkpoint("a".into())));
+        assert!(engine.process_transaction(Checkpoint("b".into())));
+        assert!(!engine.process_transaction(Checkpoint("c".into())));
+        //a checkpoint of the same name replaces the earlier one
+        assert!(engine.process_transaction(Checkpoint("a".into())));
+        assert_eq!(engine.checkpoints.len(), 2);
+        //rolling back drops the later checkpoints, which makes room for new ones
+        engine.rollback("b").unwrap();
+        engine.checkpoint("c").unwrap();
+        assert!(engine.checkpoint("d").is_err());
+    }
+
+    #[test]
+    fn test_assert_balance() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            checkpoint_records: true,
+            ..Default::default()
+        });
+        let assert = |client, available, held, total| {
+            Transaction::AssertBalance(BalanceAssertion {
+                client,
+                available,
+                held,
+                total,
+                ledger: None,
+            })
+        };
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(0.1))));
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(0.2))));
+        assert!(engine.process_transaction(assert(1, Some(0.3), Some(0.0), Some(0.3))));
+        //an account that doesn't exist has zero balances
+        assert!(engine.process_transaction(assert(2, None, None, Some(0.0))));
+        assert!(engine.balance_mismatches().is_empty());
+
+        engine.process_transaction(Checkpoint("a".into()));
+        engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        assert!(!engine.process_transaction(assert(1, Some(0.3), Some(0.0), None)));
+        let mismatches = engine.balance_mismatches();
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(|m| (m.balance, m.expected, m.actual))
+                .collect::<Vec<_>>(),
+            vec![("available", 0.3, 0.2), ("held", 0.0, 0.1)]
+        );
+        //the assertion doesn't change the account
+        check_account(&engine, 1, 0.2, 0.1, 0.3, 2, 0, false);
+
+        //the failed assertions of a rolled back tail are withdrawn
+        engine.process_transaction(Rollback("a".into()));
+        assert!(engine.balance_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_event_log() {
+        let mut engine = TransactionEngine::with_config(EngineConfig {
+            events: true,
+            checkpoint_records: true,
+            dispute_ttl: Some(10),
+            ..Default::default()
+        });
+        let mut deposit = TransactionDetail::new(1, 1, Some(10.0));
+        deposit.timestamp = Some(100);
+        assert!(engine.process_transaction(Deposit(deposit)));
+        assert!(engine.process_transaction(Checkpoint("a".into())));
+        assert!(engine.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(4.0)))));
+        assert!(engine.process_transaction(Rollback("a".into())));
+        assert!(!engine.process_transaction(Withdrawal(TransactionDetail::new(1, 3, Some(50.0)))));
+        assert!(engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        engine.sweep(200);
+        check_transaction(&engine, 1, TranactionState::Resolve);
+
+        let events = engine.take_events();
+        let types = events
+            .iter()
+            .map(|event| event.r#type.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                "deposit",
+                "checkpoint",
+                "withdrawal",
+                "rollback",
+                "dispute",
+                "sweep"
+            ]
+        );
+        assert_eq!(events[4].state, Some(TranactionState::Dispute));
+
+        //a new engine gets the same state from the events
+        let mut replayed = TransactionEngine::with_config(EngineConfig {
+            dispute_ttl: Some(10),
+            ..Default::default()
+        });
+        for event in &events {
+            assert!(replayed.replay(event));
+        }
+        check_account(&replayed, 1, 10.0, 0_f64, 10.0, 1, 0, false);
+        check_transaction(&replayed, 1, TranactionState::Resolve);
+    }
+
+    #[test]
+    fn test_account_filter() {
+        let mut engine = TransactionEngine::with_config(EngineConfig {
+            account_filter: AccountFilter {
+                clients: vec![
+                    ClientRange { from: 2, to: 4 },
+                    ClientRange { from: 9, to: 9 },
+                ],
+                nonzero_only: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for client in 1..=9 {
+            engine.process_transaction(Deposit(TransactionDetail::new(
+                client,
+                client.into(),
+                Some(1.0),
+            )));
+        }
+        engine.process_transaction(Withdrawal(TransactionDetail::new(3, 10, Some(1.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(4, 4, None)));
+        engine.process_transaction(ChargeBack(TransactionDetail::new(4, 4, None)));
+        let clients = |engine: &TransactionEngine| {
+            let mut clients = engine
+                .report_accounts()
+                .map(|(_, account)| account.client)
+                .collect::<Vec<_>>();
+            clients.sort();
+            clients
+        };
+        assert_eq!(clients(&engine), vec![2, 9]);
+
+        engine.config.account_filter = AccountFilter {
+            only_locked: true,
+            ..Default::default()
+        };
+        assert_eq!(clients(&engine), vec![4]);
+    }
+
+    #[test]
+    fn test_ledgers() {
+        let mut engine = get_transaction_engine();
+        let in_ledger = |ledger: &str, client, tx, amount| TransactionDetail {
+            ledger: Some(ledger.into()),
+            ..TransactionDetail::new(client, tx, amount)
+        };
+        //the same client and tx ids are used by different tenants
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0)))));
+        assert!(engine.process_transaction(Deposit(in_ledger("acme", 1, 1, Some(2.0)))));
+        //a tx id is only unique within its ledger
+        assert!(!engine.process_transaction(Deposit(in_ledger("acme", 1, 1, Some(2.0)))));
+        //disputes only see the transactions of their ledger
+        assert!(engine.process_transaction(Deposit(in_ledger("acme", 1, 2, Some(1.0)))));
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None))));
+        assert!(engine.process_transaction(Dispute(in_ledger("acme", 1, 1, None))));
+        check_account(&engine, 1, 5.0, 0.0, 5.0, 1, 0, false);
+        let acme = &engine.books["acme"].accounts[&1];
+        assert_approx_eq!(acme.available, 1.0);
+        assert_approx_eq!(acme.held, 2.0);
+        assert_eq!(
+            engine
+                .ledger_accounts()
+                .map(|(ledger, account)| (ledger.as_str(), account.client))
+                .collect::<Vec<_>>(),
+            vec![("", 1), ("acme", 1)]
+        );
+        assert_eq!(engine.open_disputes()[0].ledger, "acme");
+
+        let mut restored = get_transaction_engine();
+        restored.restore(engine.snapshot());
+        assert_eq!(restored.books.len(), 2);
+        assert!(restored.process_transaction(Resolve(in_ledger("acme", 1, 1, None))));
+        assert_approx_eq!(restored.books["acme"].accounts[&1].available, 3.0);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let mut engine = get_transaction_engine_with_config(EngineConfig {
+            quarantine: Some(QuarantineRules {
+                amount_above: Some(100.0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(10.0)))));
+        //parked instead of applied
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 2, Some(500.0)))));
+        check_account(&engine, 1, 10.0, 0.0, 10.0, 1, 0, false);
+        //the parked deposit can't be disputed
+        assert!(!engine.process_transaction(Dispute(TransactionDetail::new(1, 2, None))));
+        assert_eq!(engine.quarantined().len(), 1);
+        assert_eq!(engine.quarantined()[0].tx, 2);
+        assert_eq!(engine.quarantined()[0].rule, "amount_above");
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let replay = || TransactionDetail::new(1, 1, Some(10.0));
+        let conflict = || TransactionDetail::new(1, 1, Some(20.0));
+        for (policy, replay_accepted) in [
+            (DuplicatePolicy::Reject, false),
+            (DuplicatePolicy::IdempotentSkip, true),
+            (DuplicatePolicy::ErrorOnConflict, true),
+        ] {
+            let mut engine = get_transaction_engine_with_config(EngineConfig {
+                duplicate_policy: policy,
+                ..Default::default()
+            });
+            engine.process_deposit(replay()).unwrap();
+            assert_eq!(engine.process_deposit(replay()).is_ok(), replay_accepted);
+            //a replay is never applied twice and a conflicting one is always rejected
+            check_account(&engine, 1, 10.0, 0.0, 10.0, 1, 0, false);
+            let error = format!("{}", engine.process_deposit(conflict()).unwrap_err());
+            if policy == DuplicatePolicy::ErrorOnConflict {
+                assert_eq!(error, "Duplicate transaction id 1 conflicts on amount");
+            } else {
+                assert_eq!(error, "Duplicate transaction id 1");
+            }
+        }
+    }
+
+    #[test]
+    fn test_archive_inactive() {
+        let mut engine = get_transaction_engine();
+        for (client, tx) in [(1, 1), (2, 2), (3, 3)] {
+            engine.process_transaction(Deposit(TransactionDetail::new(client, tx, Some(5.0))));
+        }
+        //client 1 is back to zero, client 2 has a disputed withdrawal and client 3 still has funds
+        engine.process_transaction(Withdrawal(TransactionDetail::new(1, 4, Some(5.0))));
+        engine.process_transaction(Withdrawal(TransactionDetail::new(2, 5, Some(5.0))));
+        engine.process_transaction(Dispute(TransactionDetail::new(2, 5, None)));
+        let archived = engine
+            .archive_inactive()
+            .iter()
+            .map(|(_, account)| account.client)
+            .collect::<Vec<_>>();
+        assert_eq!(archived, vec![1]);
+        assert_eq!(engine.default_book().accounts.len(), 2);
+
+        //the archived account is re-created by the next transaction of the client
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 6, Some(2.0)))));
+        check_account(&engine, 1, 2.0, 0.0, 2.0, 4, 2, false);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut engine = get_transaction_engine();
+        let empty = engine.memory_usage();
+        engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0))));
+        let one = engine.memory_usage();
+        assert!(one > empty);
+        engine.process_transaction(Deposit(TransactionDetail::new(2, 2, Some(5.0))));
+        assert!(engine.memory_usage() > one);
+
+        //an archived account is no longer counted, its transaction still is
+        engine.process_transaction(Withdrawal(TransactionDetail::new(2, 3, Some(5.0))));
+        let before = engine.memory_usage();
+        engine.archive_inactive();
+        assert!(engine.memory_usage() < before);
+    }
+
+    #[test]
+    fn test_skip_applied() {
+        let mut first = TransactionEngine::with_config(EngineConfig {
+            record_applied: true,
+            ..Default::default()
+        });
+        first.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0))));
+        first.process_transaction(Dispute(TransactionDetail::new(1, 1, None)));
+        //a rejected transaction is not applied, so it is not skipped by the next run
+        first.process_transaction(Withdrawal(TransactionDetail::new(1, 2, Some(50.0))));
+        let applied = first.applied.clone().unwrap();
+        assert_eq!(
+            applied,
+            vec![
+                AppliedId {
+                    r#type: TransactionType::Deposit,
+                    tx: 1,
+                    ledger: "".into()
+                },
+                AppliedId {
+                    r#type: TransactionType::Dispute,
+                    tx: 1,
+                    ledger: "".into()
+                },
+            ]
+        );
+
+        let mut engine = TransactionEngine::with_config(EngineConfig {
+            applied: Some(applied.into_iter().collect()),
+            ..Default::default()
+        });
+        engine.restore(first.snapshot());
+        //the old file is skipped without an error, the new transactions are applied
+        assert!(engine.process_transaction(Deposit(TransactionDetail::new(1, 1, Some(5.0)))));
+        assert!(engine.process_transaction(Dispute(TransactionDetail::new(1, 1, None))));
+        assert!(engine.process_transaction(Resolve(TransactionDetail::new(1, 1, None))));
+        check_account(&engine, 1, 5.0, 0.0, 5.0, 1, 0, false);
+    }
+
+    #[test]
+    fn test_process_batch() {
+        let mut engine = TransactionEngine::with_config(EngineConfig {
+            checkpoint_records: true,
+            quarantine: Some(QuarantineRules {
+                amount_above: Some(100.0),
+                rapid_fire: None,
+            }),
+            ..Default::default()
+        });
+        let outcomes = engine.process_batch(vec![
+            Deposit(TransactionDetail::new(1, 1, Some(5.0))),
+            Withdrawal(TransactionDetail::new(1, 2, Some(50.0))),
+            Deposit(TransactionDetail::new(1, 3, Some(500.0))),
+            Transaction::Checkpoint("start".into()),
+            Unknown(
+                "bonus".into(),
+                TransactionDetail::new(1, 4, Some(1.0)),
+                RawRecord::default(),
+            ),
+        ]);
+        let outcomes = outcomes
+            .into_iter()
+            .map(|outcome| outcome.map_err(|e| e.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(TransactionOutcome::Applied),
+                Err("Withdraw error for tx 2".to_string()),
+                Ok(TransactionOutcome::Quarantined),
+                Ok(TransactionOutcome::Applied),
+                Err("Unknown transaction type bonus".to_string()),
+            ]
+        );
+        let accounts = engine.accounts().collect::<Vec<_>>();
+        assert_eq!(accounts.len(), 1);
+        assert_approx_eq!(accounts[0].available, 5.0);
+
+        //the errors can be matched instead of their message
+        let outcomes = engine.process_batch(vec![
+            Withdrawal(TransactionDetail::new(1, 5, Some(50.0))),
+            Unknown(
+                "bonus".into(),
+                TransactionDetail::new(1, 6, Some(1.0)),
+                RawRecord::default(),
+            ),
+        ]);
+        let errors = outcomes
+            .iter()
+            .map(|outcome| {
+                outcome
+                    .as_ref()
+                    .unwrap_err()
+                    .downcast_ref::<TransactionErrors>()
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            errors[0],
+            Some(TransactionErrors::Withdrawal(WithdrawalError { tx: 5 }))
+        ));
+        assert!(
+            matches!(errors[1], Some(TransactionErrors::UnknownType(r#type)) if r#type == "bonus")
+        );
+
+        //a flush row is a no-op outside of the incremental mode
+        let outcomes = engine.process_batch(vec![Transaction::Flush]);
+        assert_eq!(
+            outcomes[0].as_ref().ok(),
+            Some(&TransactionOutcome::Applied)
+        );
+    }
+
+    #[test]
+    fn test_process_batch_replay() {
+        for policy in [
+            DuplicatePolicy::IdempotentSkip,
+            DuplicatePolicy::ErrorOnConflict,
+        ] {
+            let mut engine = TransactionEngine::with_config(EngineConfig {
+                duplicate_policy: policy,
+                audit: true,
+                events: true,
+                record_applied: true,
+                ..Default::default()
+            });
+            let outcomes = engine.process_batch(vec![
+                Deposit(TransactionDetail::new(1, 1, Some(5.0))),
+                Deposit(TransactionDetail::new(1, 1, Some(5.0))),
+                Authorize(TransactionDetail::new(1, 2, Some(1.0))),
+                Authorize(TransactionDetail::new(1, 2, Some(1.0))),
+            ]);
+            let outcomes = outcomes
+                .into_iter()
+                .map(|outcome| outcome.unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                outcomes,
+                vec![
+                    TransactionOutcome::Applied,
+                    TransactionOutcome::Skipped,
+                    TransactionOutcome::Applied,
+                    TransactionOutcome::Skipped,
+                ]
+            );
+            //the replays are not recorded
+            assert_eq!(engine.take_audit_records().len(), 2);
+            assert_eq!(engine.take_events().len(), 2);
+            assert_eq!(engine.applied.as_ref().map(Vec::len), Some(2));
+            check_account(&engine, 1, 4.0, 1.0, 5.0, 1, 0, false);
+        }
+    }
 }
@@ -0,0 +1,287 @@
+//re-exported so that library users can report the violations of their own validators
+pub use super::errors::ValidationError;
+use crate::models::{TransactionDetail, TransactionType, TxId};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::collections::BTreeMap;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::BufReader;
+use std::str::FromStr;
+
+//Inclusive range of client ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ClientRange {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl ClientRange {
+    pub fn contains(&self, client: u16) -> bool {
+        (self.from..=self.to).contains(&client)
+    }
+}
+
+//either a single client, e.g. 5, or a range, e.g. 100-200
+impl FromStr for ClientRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |client: &str| {
+            client
+                .trim()
+                .parse::<u16>()
+                .map_err(|e| format!("invalid client {client}: {e}"))
+        };
+        let (from, to) = match s.split_once('-') {
+            Some((from, to)) => (parse(from)?, parse(to)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if from > to {
+            return Err(format!("invalid client range {s}, {from} is after {to}"));
+        }
+        Ok(Self { from, to })
+    }
+}
+
+//Contractual limits of a partner, loaded from a json file, e.g.
+//{"min_amount": 0.01, "max_amount": 10000, "allowed_types": ["deposit", "withdrawal"],
+// "client_ranges": [{"from": 1, "to": 999}], "currency": "USD", "tiers": {"retail": {"max_amount": 1000}}}
+//Every rule is optional and the transactions that break a rule are rejected before they reach the accounts. The
+//rules of a tier are checked on top of the others for the clients of that tier in the client profiles
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationRules {
+    pub min_amount: Option<f64>,
+    pub max_amount: Option<f64>,
+    pub allowed_types: Option<Vec<TransactionType>>,
+    //an empty list allows every client
+    #[serde(default)]
+    pub client_ranges: Vec<ClientRange>,
+    pub currency: Option<SmolStr>,
+    #[serde(default)]
+    pub tiers: BTreeMap<SmolStr, ValidationRules>,
+}
+
+impl ValidationRules {
+    //used as a clap value parser so that an invalid file is reported like any other invalid argument
+    #[cfg(feature = "runtime")]
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|e| format!("{path}: {e}"))?);
+        serde_json::from_reader(reader).map_err(|e| format!("{path}: {e}"))
+    }
+
+    //the amount and currency rules only apply to the transactions that carry an amount
+    pub fn check(
+        &self,
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+    ) -> Result<(), ValidationError> {
+        if let Some(allowed_types) = &self.allowed_types {
+            if !allowed_types.contains(&transaction_type) {
+                return Err(ValidationError::TypeNotAllowed(transaction_type));
+            }
+        }
+        if !self.client_ranges.is_empty()
+            && !self
+                .client_ranges
+                .iter()
+                .any(|range| range.contains(tx_detail.client))
+        {
+            return Err(ValidationError::ClientNotAllowed(tx_detail.client));
+        }
+        if let Some(amount) = tx_detail.amount {
+            if let Some(min) = self.min_amount.filter(|min| amount < *min) {
+                return Err(ValidationError::AmountBelowMin { amount, min });
+            }
+            if let Some(max) = self.max_amount.filter(|max| amount > *max) {
+                return Err(ValidationError::AmountAboveMax { amount, max });
+            }
+            if let Some(required) = &self.currency {
+                match &tx_detail.currency {
+                    Some(currency) if currency.eq_ignore_ascii_case(required) => {}
+                    currency => {
+                        return Err(ValidationError::Currency {
+                            currency: currency.clone(),
+                            required: required.clone(),
+                        })
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//A rule checked before a transaction is applied. The validators of library users are checked after the validation
+//rules of the config, e.g. the format of the tx ids of a partner
+pub trait Validator: Send {
+    fn check(
+        &self,
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+    ) -> Result<(), ValidationError>;
+}
+
+impl Validator for ValidationRules {
+    fn check(
+        &self,
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+    ) -> Result<(), ValidationError> {
+        ValidationRules::check(self, transaction_type, tx_detail)
+    }
+}
+
+impl<F> Validator for F
+where
+    F: Fn(TransactionType, &TransactionDetail) -> Result<(), ValidationError> + Send,
+{
+    fn check(
+        &self,
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+    ) -> Result<(), ValidationError> {
+        self(transaction_type, tx_detail)
+    }
+}
+
+//A transaction rejected by the validation rules, written to the rejects file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RejectRecord {
+    pub client: u16,
+    pub tx: TxId,
+    pub r#type: TransactionType,
+    pub rule: &'static str,
+    pub reason: String,
+    pub ledger: Option<SmolStr>,
+}
+
+impl RejectRecord {
+    pub fn new(
+        transaction_type: TransactionType,
+        tx_detail: &TransactionDetail,
+        error: &ValidationError,
+    ) -> Self {
+        Self {
+            client: tx_detail.client,
+            tx: tx_detail.tx,
+            r#type: transaction_type,
+            rule: error.rule(),
+            reason: error.to_string(),
+            ledger: tx_detail.ledger.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientRange, ValidationRules};
+    use crate::models::{TransactionDetail, TransactionType};
+    use crate::tranasction::errors::ValidationError;
+
+    #[test]
+    fn client_range_from_str() {
+        assert_eq!("5".parse(), Ok(ClientRange { from: 5, to: 5 }));
+        assert_eq!("100-200".parse(), Ok(ClientRange { from: 100, to: 200 }));
+        assert!("200-100".parse::<ClientRange>().is_err());
+        assert!("1-x".parse::<ClientRange>().is_err());
+    }
+
+    #[test]
+    fn deserialize() {
+        let rules: ValidationRules = serde_json::from_str(
+            r#"{"max_amount": 100, "allowed_types": ["deposit", "chargeback"],
+                "client_ranges": [{"from": 1, "to": 9}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            ValidationRules {
+                max_amount: Some(100.0),
+                allowed_types: Some(vec![TransactionType::Deposit, TransactionType::ChargeBack]),
+                client_ranges: vec![ClientRange { from: 1, to: 9 }],
+                ..Default::default()
+            }
+        );
+        //typos are not silently ignored
+        assert!(serde_json::from_str::<ValidationRules>(r#"{"max_amout": 100}"#).is_err());
+    }
+
+    #[test]
+    fn check() {
+        let rules = ValidationRules {
+            min_amount: Some(1.0),
+            max_amount: Some(100.0),
+            allowed_types: Some(vec![TransactionType::Deposit, TransactionType::Dispute]),
+            client_ranges: vec![
+                ClientRange { from: 1, to: 9 },
+                ClientRange { from: 20, to: 20 },
+            ],
+            currency: Some("USD".into()),
+            ..Default::default()
+        };
+        let mut deposit = TransactionDetail::new(20, 1, Some(50.0));
+        deposit.currency = Some("usd".into());
+        assert_eq!(rules.check(TransactionType::Deposit, &deposit), Ok(()));
+
+        assert_eq!(
+            rules.check(TransactionType::Withdrawal, &deposit),
+            Err(ValidationError::TypeNotAllowed(TransactionType::Withdrawal))
+        );
+        assert_eq!(
+            rules.check(
+                TransactionType::Deposit,
+                &TransactionDetail {
+                    client: 10,
+                    ..deposit.clone()
+                }
+            ),
+            Err(ValidationError::ClientNotAllowed(10))
+        );
+        assert_eq!(
+            rules.check(
+                TransactionType::Deposit,
+                &TransactionDetail {
+                    amount: Some(0.5),
+                    ..deposit.clone()
+                }
+            ),
+            Err(ValidationError::AmountBelowMin {
+                amount: 0.5,
+                min: 1.0
+            })
+        );
+        assert_eq!(
+            rules.check(
+                TransactionType::Deposit,
+                &TransactionDetail {
+                    amount: Some(100.5),
+                    ..deposit.clone()
+                }
+            ),
+            Err(ValidationError::AmountAboveMax {
+                amount: 100.5,
+                max: 100.0
+            })
+        );
+        assert_eq!(
+            rules.check(
+                TransactionType::Deposit,
+                &TransactionDetail {
+                    currency: None,
+                    ..deposit.clone()
+                }
+            ),
+            Err(ValidationError::Currency {
+                currency: None,
+                required: "USD".into()
+            })
+        );
+
+        //disputes don't have an amount or a currency
+        let dispute = TransactionDetail::new(1, 1, None);
+        assert_eq!(rules.check(TransactionType::Dispute, &dispute), Ok(()));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::models::{Account, Transaction};
+use crate::tranasction::accounts_handle::AccountsHandle;
+use crate::tranasction::transaction_engine::EngineStats;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::{Hide, Show};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Gauge, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::WeakSender;
+use tokio::sync::oneshot;
+
+//number of accounts in the held funds table
+const TOP_ACCOUNTS: usize = 10;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+//Where the dashboard reads the progress of the run from. None of them keeps the pipeline alive: the channel is only
+//upgraded while its depth is read, so the engine still stops once the parser is done
+pub struct Probes {
+    pub accounts: AccountsHandle,
+    pub channel: WeakSender<Vec<Transaction>>,
+    pub bytes_read: Arc<AtomicU64>,
+    //size of the input, None when it is read from stdin so there is no ETA
+    pub input_size: Option<u64>,
+}
+
+//State of the run at one refresh of the dashboard
+#[derive(Debug, Default)]
+struct Sample {
+    elapsed: Duration,
+    stats: EngineStats,
+    //transactions per second since the previous refresh
+    throughput: f64,
+    channel_depth: usize,
+    channel_capacity: usize,
+    bytes_read: u64,
+    input_size: Option<u64>,
+    //bytes read per second since the dashboard started
+    read_rate: f64,
+    top_held: Vec<Account>,
+}
+
+impl Sample {
+    fn progress(&self) -> Option<f64> {
+        let size = self.input_size.filter(|size| *size > 0)?;
+        Some((self.bytes_read as f64 / size as f64).min(1.0))
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let size = self.input_size?;
+        if self.read_rate <= 0.0 {
+            return None;
+        }
+        let remaining = size.saturating_sub(self.bytes_read) as f64;
+        Some(Duration::from_secs_f64(remaining / self.read_rate))
+    }
+}
+
+impl Probes {
+    async fn sample(&self, previous: &Sample, start: Instant, start_bytes: u64) -> Sample {
+        let elapsed = start.elapsed();
+        //keep the last counters once the engine has stopped
+        let stats = self
+            .accounts
+            .stats()
+            .await
+            .unwrap_or_else(|| previous.stats.clone());
+        let interval = (elapsed - previous.elapsed).as_secs_f64();
+        let throughput = if interval > 0.0 {
+            stats.processed.saturating_sub(previous.stats.processed) as f64 / interval
+        } else {
+            0.0
+        };
+        let (channel_depth, channel_capacity) = self
+            .channel
+            .upgrade()
+            .map(|tx| (tx.max_capacity() - tx.capacity(), tx.max_capacity()))
+            .unwrap_or_default();
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        let read_rate = bytes_read.saturating_sub(start_bytes) as f64 / elapsed.as_secs_f64();
+        Sample {
+            elapsed,
+            stats,
+            throughput,
+            channel_depth,
+            channel_capacity,
+            bytes_read,
+            input_size: self.input_size,
+            read_rate,
+            top_held: self.accounts.top_held(TOP_ACCOUNTS).await,
+        }
+    }
+}
+
+//draw the dashboard on stderr until done is received, stdout is left to the account report
+pub async fn run(probes: Probes, mut done: oneshot::Receiver<()>) -> io::Result<()> {
+    execute!(io::stderr(), EnterAlternateScreen, Hide)?;
+    let result = draw_until_done(&probes, &mut done).await;
+    execute!(io::stderr(), LeaveAlternateScreen, Show)?;
+    result
+}
+
+async fn draw_until_done(probes: &Probes, done: &mut oneshot::Receiver<()>) -> io::Result<()> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+    let start = Instant::now();
+    let start_bytes = probes.bytes_read.load(Ordering::Relaxed);
+    let mut sample = Sample::default();
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut *done => return Ok(()),
+        }
+        sample = probes.sample(&sample, start, start_bytes).await;
+        terminal.draw(|frame| render(frame, &sample))?;
+    }
+}
+
+fn render(frame: &mut Frame, sample: &Sample) {
+    let [progress_area, counters_area, accounts_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(8),
+        Constraint::Min(0),
+    ])
+    .areas(frame.area());
+
+    let mut label = format!("elapsed {}", format_duration(sample.elapsed));
+    if let Some(eta) = sample.eta() {
+        label.push_str(&format!("  ETA {}", format_duration(eta)));
+    }
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title("Progress"))
+            .ratio(sample.progress().unwrap_or_default())
+            .label(label),
+        progress_area,
+    );
+
+    let [throughput_area, rejected_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(counters_area);
+    frame.render_widget(
+        Paragraph::new(vec![
+            format!("processed      {}", sample.stats.processed).into(),
+            format!("throughput     {:.0} tx/s", sample.throughput).into(),
+            format!(
+                "memory         {:.1} MB",
+                sample.stats.memory as f64 / (1024.0 * 1024.0)
+            )
+            .into(),
+            format!(
+                "channel depth  {}/{}",
+                sample.channel_depth, sample.channel_capacity
+            )
+            .into(),
+        ])
+        .block(Block::bordered().title("Engine")),
+        throughput_area,
+    );
+    let mut rejected = vec![format!("total          {}", sample.stats.rejected).into()];
+    rejected.extend(sample.stats.rejected_by_type.iter().map(|(r#type, count)| {
+        format!("{:<15}{count}", format!("{type:?}").to_lowercase()).into()
+    }));
+    frame.render_widget(
+        Paragraph::new(rejected).block(Block::bordered().title("Rejected")),
+        rejected_area,
+    );
+
+    let rows = sample.top_held.iter().map(|account| {
+        Row::new([
+            account.client.to_string(),
+            format!("{:.4}", account.held),
+            format!("{:.4}", account.available),
+            format!("{:.4}", account.total),
+            account.locked.to_string(),
+        ])
+    });
+    frame.render_widget(
+        Table::new(rows, [Constraint::Length(10); 5])
+            .header(Row::new(["client", "held", "available", "total", "locked"]))
+            .block(Block::bordered().title("Top accounts by held funds")),
+        accounts_area,
+    );
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_duration, render, Sample};
+    use crate::models::{Account, TransactionType};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::time::Duration;
+
+    #[test]
+    fn eta() {
+        let mut sample = Sample {
+            elapsed: Duration::from_secs(10),
+            bytes_read: 250,
+            input_size: Some(1000),
+            read_rate: 25.0,
+            ..Default::default()
+        };
+        assert_eq!(sample.progress(), Some(0.25));
+        assert_eq!(sample.eta(), Some(Duration::from_secs(30)));
+        //no ETA for stdin
+        sample.input_size = None;
+        assert_eq!(sample.eta(), None);
+        assert_eq!(format_duration(Duration::from_secs(3725)), "01:02:05");
+    }
+
+    #[test]
+    fn render_sample() {
+        let mut sample = Sample {
+            channel_depth: 3,
+            channel_capacity: 10,
+            top_held: vec![Account {
+                client: 42,
+                held: 7.5,
+                total: 7.5,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        sample.stats.rejected = 2;
+        sample
+            .stats
+            .rejected_by_type
+            .insert(TransactionType::Withdrawal, 2);
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        terminal.draw(|frame| render(frame, &sample)).unwrap();
+        let screen = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect::<String>();
+        assert!(screen.contains("channel depth  3/10"));
+        assert!(screen.contains("withdrawal     2"));
+        assert!(screen.contains("42         7.5000"));
+    }
+}
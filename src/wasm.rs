@@ -0,0 +1,87 @@
+use crate::models::Transaction;
+use crate::parser::columns::ColumnPositions;
+use crate::tranasction::config::EngineConfig;
+use crate::tranasction::transaction_engine::{LedgerAccount, TransactionEngine};
+use csv::StringRecord;
+use wasm_bindgen::prelude::*;
+
+//Engine for what-if simulations in the browser. The transactions are given in batches of csv rows with the same
+//columns as the input file (type,client,tx and the optional amount, timestamp, reference, currency and ledger) and a
+//header row, and the accounts are returned as json. The engine keeps its state between the batches
+#[wasm_bindgen]
+pub struct WasmEngine {
+    engine: TransactionEngine,
+}
+
+#[wasm_bindgen]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            engine: TransactionEngine::with_config(EngineConfig::default()),
+        }
+    }
+
+    //process a batch of csv rows and return the number of transactions that are rejected. The columns are mapped by
+    //the names of the header like for an input file, so they can be in any order. The whole batch is rejected if a
+    //row can't be parsed, so that a typo doesn't leave the simulation half applied
+    #[wasm_bindgen(js_name = processBatch)]
+    pub fn process_batch(&mut self, batch: &str) -> Result<u32, JsError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(batch.as_bytes());
+        let columns =
+            ColumnPositions::from_headers(rdr.headers()?).map_err(|e| JsError::new(&e))?;
+        let mut canonical = StringRecord::new();
+        let mut transactions = vec![];
+        for record in rdr.records() {
+            columns.to_canonical(&record?, &mut canonical);
+            transactions.push(canonical.deserialize::<Transaction>(None)?);
+        }
+        let outcomes = self.engine.process_batch(transactions);
+        Ok(outcomes.iter().filter(|outcome| outcome.is_err()).count() as u32)
+    }
+
+    //the accounts of every ledger with their ledger, sorted by ledger and client, as a json array
+    pub fn accounts(&self) -> Result<String, JsError> {
+        let mut accounts = self.engine.ledger_accounts().collect::<Vec<_>>();
+        accounts.sort_by_key(|(ledger, account)| (*ledger, account.client));
+        let accounts = accounts
+            .into_iter()
+            .map(|(ledger, account)| LedgerAccount::new(ledger, account))
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_string(&accounts)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WasmEngine;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn accounts_of_every_ledger() {
+        let mut engine = WasmEngine::new();
+        let rejected = engine
+            .process_batch(
+                "ledger,type,client,tx,amount
+                 globex,deposit,2,1,3.0
+                 acme,deposit,2,2,1.0
+                 acme,withdrawal,2,3,2.0
+                 ,deposit,1,4,1.5",
+            )
+            .unwrap();
+        assert_eq!(rejected, 1);
+        let accounts: Value = serde_json::from_str(&engine.accounts().unwrap()).unwrap();
+        assert_eq!(
+            accounts,
+            json!([
+                {"client": 1, "available": 1.5, "held": 0.0, "total": 1.5, "locked": false, "ledger": ""},
+                {"client": 2, "available": 1.0, "held": 0.0, "total": 1.0, "locked": false, "ledger": "acme"},
+                {"client": 2, "available": 3.0, "held": 0.0, "total": 3.0, "locked": false, "ledger": "globex"},
+            ])
+        );
+    }
+}
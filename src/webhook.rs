@@ -0,0 +1,408 @@
+use crate::models::TxId;
+use crate::tranasction::audit::{AuditEvent, AuditRecord};
+use serde::Serialize;
+use smol_str::SmolStr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+//the engine of serve drops the records once the channel is full, which only happens if the notifications can't be
+//queued as fast as the disputes are made
+const RECORD_CHANNEL_SIZE: usize = 65_536;
+//notifications waiting for an endpoint, the ones of an endpoint that is down for long are dropped once it is full
+const NOTIFICATION_QUEUE_SIZE: usize = 65_536;
+//the status line of the response, the rest of the response is not read
+const MAX_STATUS_LINE: u64 = 8192;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(clap::Args)]
+pub struct WebhookArgs {
+    /// POST a json notification to this http url when a dispute is opened, resolved (or auto-resolved) and charged
+    /// back, with the client, the tx, the disputed amount and the balances of the account after it, e.g.
+    /// http://127.0.0.1:8080/disputes. Can be repeated, each url is notified on its own. Only plain http is
+    /// supported, not https: every notification opens a new connection, and only the status line of the response
+    /// is read, so the endpoint can answer with any body
+    #[arg(long)]
+    webhook: Vec<WebhookUrl>,
+    /// number of times a notification that fails is sent again, the delay between two attempts doubles from 1 second
+    #[arg(long, default_value_t = 3)]
+    webhook_retries: u32,
+    /// number of seconds to wait for an endpoint to answer a notification
+    #[arg(long, default_value_t = 5)]
+    webhook_timeout: u64,
+}
+
+//Http url of a webhook. Https is not supported, the endpoint should be on a private network or behind a proxy
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookUrl {
+    //host:port
+    address: String,
+    host: String,
+    path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("only http urls are supported, got {s}"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {s}"));
+        }
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+//Step of the dispute workflow a notification is sent for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeEvent {
+    Opened,
+    Resolved,
+    Chargeback,
+}
+
+//Body of a notification: the disputed transaction, the amount it holds and the balances of the account once the
+//event is applied
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Notification {
+    event: DisputeEvent,
+    //the dispute was auto-resolved because it wasn't decided within the dispute ttl
+    expired: bool,
+    client: u16,
+    tx: TxId,
+    amount: f64,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ledger: Option<SmolStr>,
+}
+
+impl Notification {
+    //None for the records that are not part of the dispute workflow
+    fn new(record: &AuditRecord) -> Option<Self> {
+        let (event, expired) = match record.event {
+            AuditEvent::Dispute => (DisputeEvent::Opened, false),
+            AuditEvent::Resolve => (DisputeEvent::Resolved, false),
+            AuditEvent::DisputeExpired => (DisputeEvent::Resolved, true),
+            AuditEvent::Chargeback => (DisputeEvent::Chargeback, false),
+            _ => return None,
+        };
+        //the disputed amount is what moves in or out of held, round so that the float error doesn't show
+        let amount = ((record.held_after - record.held_before).abs() * 10_000.0).round() / 10_000.0;
+        Some(Self {
+            event,
+            expired,
+            client: record.client,
+            tx: record.tx,
+            amount,
+            available: record.available_after,
+            held: record.held_after,
+            total: record.total_after,
+            locked: record.locked,
+            ledger: record.ledger.clone(),
+        })
+    }
+}
+
+//Notifies the case management system of the disputes of serve and stream, so that it doesn't poll the reports. The
+//notifications are derived from the audit records of the engine and queued for every url, each url has its own task
+//that sends them one at a time in the order of the records, so that an endpoint that is down doesn't delay the
+//others. A notification that still fails after the retries is logged and dropped
+pub struct Webhooks {
+    urls: Vec<WebhookUrl>,
+    retries: u32,
+    timeout: Duration,
+}
+
+impl Webhooks {
+    //None without --webhook
+    pub fn new(args: &WebhookArgs) -> Option<Self> {
+        (!args.webhook.is_empty()).then(|| Self {
+            urls: args.webhook.clone(),
+            retries: args.webhook_retries,
+            timeout: Duration::from_secs(args.webhook_timeout),
+        })
+    }
+
+    //deliver the notifications of the records sent to the channel until it is closed, so that awaiting the task
+    //once the engine has stopped sends the last ones
+    pub fn spawn(self) -> (mpsc::Sender<AuditRecord>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(RECORD_CHANNEL_SIZE);
+        (tx, tokio::spawn(self.run(rx)))
+    }
+
+    async fn run(self, mut records: mpsc::Receiver<AuditRecord>) {
+        let (queues, tasks): (Vec<_>, Vec<_>) = self
+            .urls
+            .iter()
+            .map(|url| {
+                let (tx, rx) = mpsc::channel(NOTIFICATION_QUEUE_SIZE);
+                let endpoint = Endpoint {
+                    url: url.clone(),
+                    retries: self.retries,
+                    timeout: self.timeout,
+                };
+                (tx, tokio::spawn(endpoint.run(rx)))
+            })
+            .unzip();
+        while let Some(record) = records.recv().await {
+            let Some(notification) = Notification::new(&record) else {
+                continue;
+            };
+            let body = match serde_json::to_string(&notification) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!(tx = record.tx, "Fail to serialize notification: {e}");
+                    continue;
+                }
+            };
+            for (url, queue) in self.urls.iter().zip(&queues) {
+                if queue.try_send((record.tx, body.clone())).is_err() {
+                    tracing::error!(
+                        tx = record.tx,
+                        "Dropped notification, {}{} is too far behind",
+                        url.host,
+                        url.path
+                    );
+                }
+            }
+        }
+        //the endpoints send what is queued before they stop
+        drop(queues);
+        for task in tasks {
+            if let Err(e) = task.await {
+                tracing::error!("Webhook failed: {e}");
+            }
+        }
+    }
+}
+
+//Url of a webhook with the notifications queued for it
+struct Endpoint {
+    url: WebhookUrl,
+    retries: u32,
+    timeout: Duration,
+}
+
+impl Endpoint {
+    async fn run(self, mut queue: mpsc::Receiver<(TxId, String)>) {
+        while let Some((tx, body)) = queue.recv().await {
+            self.deliver(&body, tx).await;
+        }
+    }
+
+    async fn deliver(&self, body: &str, tx: TxId) {
+        let url = &self.url;
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            let sent = match tokio::time::timeout(self.timeout, post(url, body)).await {
+                Ok(sent) => sent,
+                Err(_) => Err("timed out".to_string()),
+            };
+            match sent {
+                Ok(()) => return,
+                Err(e) => tracing::warn!(
+                    tx,
+                    attempt,
+                    "Failed to notify {}{}: {e}",
+                    url.host,
+                    url.path
+                ),
+            }
+        }
+        tracing::error!(
+            tx,
+            "Gave up notifying {}{} after {} retries",
+            url.host,
+            url.path,
+            self.retries
+        );
+    }
+}
+
+//a notification is delivered once the endpoint answers with a 2xx status
+async fn post(url: &WebhookUrl, body: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect(&url.address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut status_line = String::new();
+    BufReader::new(stream.take(MAX_STATUS_LINE))
+        .read_line(&mut status_line)
+        .await
+        .map_err(|e| e.to_string())?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some(status) => Err(format!("status {status}")),
+        None => Err(format!("invalid response {:?}", status_line.trim())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{post, DisputeEvent, Notification, WebhookUrl, Webhooks};
+    use crate::models::{Account, TranactionState, TxId};
+    use crate::tranasction::audit::{AuditEvent, AuditRecord};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    //endpoint that answers every request with the status and sends the requests it received to the channel
+    async fn endpoint(status: &'static str) -> (WebhookUrl, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/disputes", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                let _ = tx.send(String::from_utf8_lossy(&request[..read]).into_owned());
+                let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url.parse().unwrap(), rx)
+    }
+
+    fn dispute(tx: TxId) -> AuditRecord {
+        let account = Account::new(1);
+        AuditRecord::new(
+            AuditEvent::Dispute,
+            tx,
+            &account,
+            &account,
+            Some(TranactionState::Dispute),
+            None,
+        )
+    }
+
+    #[test]
+    fn parse_url() {
+        assert_eq!(
+            "http://127.0.0.1:8080/disputes".parse::<WebhookUrl>(),
+            Ok(WebhookUrl {
+                address: "127.0.0.1:8080".to_string(),
+                host: "127.0.0.1:8080".to_string(),
+                path: "/disputes".to_string(),
+            })
+        );
+        let url = "http://cases.internal".parse::<WebhookUrl>().unwrap();
+        assert_eq!(
+            (url.address.as_str(), url.path.as_str()),
+            ("cases.internal:80", "/")
+        );
+        assert!("https://cases.internal/disputes"
+            .parse::<WebhookUrl>()
+            .is_err());
+        assert!("http:///disputes".parse::<WebhookUrl>().is_err());
+    }
+
+    #[test]
+    fn notifications() {
+        let before = Account {
+            client: 1,
+            available: 3.0,
+            total: 3.0,
+            ..Default::default()
+        };
+        let after = Account {
+            client: 1,
+            available: 2.9,
+            held: 0.1,
+            total: 3.0,
+            locked: false,
+        };
+        let record = |event| {
+            AuditRecord::new(
+                event,
+                7,
+                &before,
+                &after,
+                Some(TranactionState::Dispute),
+                None,
+            )
+        };
+        let opened = Notification::new(&record(AuditEvent::Dispute)).unwrap();
+        assert_eq!(
+            (opened.event, opened.expired, opened.amount, opened.held),
+            (DisputeEvent::Opened, false, 0.1, 0.1)
+        );
+        let expired = Notification::new(&record(AuditEvent::DisputeExpired)).unwrap();
+        assert_eq!(
+            (expired.event, expired.expired),
+            (DisputeEvent::Resolved, true)
+        );
+        assert_eq!(Notification::new(&record(AuditEvent::Deposit)), None);
+    }
+
+    #[tokio::test]
+    async fn post_notification() {
+        let (url, mut requests) = endpoint("204 No Content").await;
+        post(&url, "{\"tx\":7}").await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST /disputes HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"tx\":7}"));
+
+        let (url, _requests) = endpoint("503 Service Unavailable").await;
+        assert_eq!(post(&url, "{}").await, Err("status 503".to_string()));
+    }
+
+    //an endpoint that doesn't answer doesn't hold back the notifications of the other ones
+    #[tokio::test]
+    async fn endpoint_down() {
+        let (url, mut requests) = endpoint("200 OK").await;
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}/", silent.local_addr().unwrap());
+        let webhooks = Webhooks {
+            urls: vec![down.parse().unwrap(), url],
+            retries: 0,
+            timeout: Duration::from_secs(30),
+        };
+        let (records, _handle) = webhooks.spawn();
+        records.send(dispute(1)).await.unwrap();
+        records.send(dispute(2)).await.unwrap();
+        for tx in 1..=2 {
+            let request = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(request.contains(&format!("\"tx\":{tx}")));
+        }
+    }
+}